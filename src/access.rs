@@ -0,0 +1,107 @@
+use std::fmt::Write as FmtWrite;
+use std::fs::{File, OpenOptions};
+use std::io::{Error as IoError, Write};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime};
+use log::warn;
+
+use crate::CloseReason;
+use crate::logger::{json_str, rfc3339, LogFormat};
+
+/// Summary of a pair, written once it is closed
+#[derive(Debug)]
+pub struct AccessRecord<'a> {
+    pub conn_id: usize,
+    pub peer: Option<&'a str>,
+    /// client identity established by the listener, e.g. the subject of its TLS certificate
+    pub identity: Option<&'a str>,
+    pub start: SystemTime,
+    pub duration: Duration,
+    /// bytes received from the client
+    pub rx: u64,
+    /// bytes sent to the client
+    pub tx: u64,
+    pub reason: CloseReason,
+}
+
+/// File receiving a line per closed pair, shared by all the workers
+#[derive(Clone)]
+pub struct AccessLog {
+    format: LogFormat,
+    out: Arc<Mutex<File>>,
+}
+
+impl AccessLog {
+    /// open `path` for appending, creating it if needed
+    pub fn open(path: &str, format: LogFormat) -> Result<AccessLog, IoError> {
+        let out = OpenOptions::new().create(true).append(true).open(path)?;
+
+        Ok(AccessLog { format, out: Arc::new(Mutex::new(out)) })
+    }
+
+    fn format(&self, rec: &AccessRecord) -> String {
+        let mut out = String::with_capacity(192);
+
+        match self.format {
+            LogFormat::Text => {
+                let _ = write!(
+                    out,
+                    "{} conn_id={} peer={} identity=",
+                    rfc3339(rec.start), rec.conn_id, rec.peer.unwrap_or("-"),
+                );
+
+                // certificate subjects may contain spaces
+                match rec.identity {
+                    Some(x) => json_str(&mut out, x),
+                    None => out.push('-'),
+                }
+
+                let _ = write!(
+                    out,
+                    " duration_ms={} rx={} tx={} reason={}",
+                    rec.duration.as_millis(), rec.rx, rec.tx, rec.reason,
+                );
+            }
+            LogFormat::Json => {
+                out.push_str("{\"start\":");
+                json_str(&mut out, &rfc3339(rec.start));
+                let _ = write!(out, ",\"conn_id\":{},\"peer\":", rec.conn_id);
+
+                match rec.peer {
+                    Some(x) => json_str(&mut out, x),
+                    None => out.push_str("null"),
+                }
+
+                out.push_str(",\"identity\":");
+
+                match rec.identity {
+                    Some(x) => json_str(&mut out, x),
+                    None => out.push_str("null"),
+                }
+
+                let _ = write!(
+                    out,
+                    ",\"duration_ms\":{},\"rx\":{},\"tx\":{},\"reason\":\"{}\"}}",
+                    rec.duration.as_millis(), rec.rx, rec.tx, rec.reason,
+                );
+            }
+        }
+
+        out.push('\n');
+        out
+    }
+
+    pub fn write(&self, rec: &AccessRecord) {
+        let line = self.format(rec);
+
+        let res = match self.out.lock() {
+            Ok(mut x) => x.write_all(line.as_bytes()),
+            // a worker panicked while writing, the file itself is still usable
+            Err(x) => x.into_inner().write_all(line.as_bytes()),
+        };
+
+        if let Err(x) = res {
+            warn!(conn_id = rec.conn_id; "failed to write the access log: {}", x);
+        }
+    }
+}
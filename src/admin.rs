@@ -0,0 +1,205 @@
+use std::fmt::Write as FmtWrite;
+use std::io::{BufRead, BufReader, Error as IoError, Write};
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::sync::{Arc, Mutex};
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+use clap::{App, Arg, ArgMatches, SubCommand};
+use log::{debug, info};
+
+use crate::{Command, FwCtl, Reply};
+use crate::logger::json_str;
+
+/// how long a worker may take to answer a command
+const REPLY_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// how long a client may stay idle, or take to read a reply, before it is dropped
+const CLIENT_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Serve the admin socket from its own thread, passing the commands on to the event loops in `ctls`.
+///
+/// Clients send a command per line and get zero or more lines back followed by `ok` or `err <reason>`:
+/// `list`, `close <conn_id>`, `pause`, `resume` and `reload`. Every client is served on a thread of its own,
+/// so that an idle one does not hold the others up. The thread runs until the process exits.
+pub fn spawn(listener: UnixListener, ctls: Arc<Mutex<Vec<FwCtl>>>) -> Result<JoinHandle<()>, IoError> {
+    thread::Builder::new()
+        .name("admin".to_string())
+        .spawn(move || {
+            for stream in listener.incoming() {
+                let stream = match stream {
+                    Ok(x) => x,
+                    Err(x) => {
+                        debug!("failed to accept an admin client: {}", x);
+                        continue;
+                    }
+                };
+
+                let ctls = ctls.clone();
+
+                let spawned = thread::Builder::new()
+                    .name("admin-client".to_string())
+                    .spawn(move || {
+                        if let Err(x) = serve(stream, &ctls) {
+                            debug!("admin client failed: {}", x);
+                        }
+                    });
+
+                if let Err(x) = spawned {
+                    debug!("failed to serve an admin client: {}", x);
+                }
+            }
+        })
+}
+
+fn serve(stream: UnixStream, ctls: &Mutex<Vec<FwCtl>>) -> Result<(), IoError> {
+    stream.set_read_timeout(Some(CLIENT_TIMEOUT))?;
+    stream.set_write_timeout(Some(CLIENT_TIMEOUT))?;
+
+    let mut out = stream.try_clone()?;
+
+    for line in BufReader::new(stream).lines() {
+        let line = line?;
+
+        if line.trim().is_empty() {
+            continue;
+        }
+
+        info!(command = line.trim(); "admin command");
+
+        let res = match parse(&line) {
+            Ok(cmd) => {
+                let ctls = match ctls.lock() {
+                    Ok(x) => x.clone(),
+                    Err(_) => Vec::new(),
+                };
+
+                execute(cmd, &ctls)
+            }
+            Err(x) => Err(x.to_string()),
+        };
+
+        let res = match res {
+            Ok(mut x) => {
+                x.push_str("ok\n");
+                x
+            }
+            Err(x) => format!("err {}\n", x),
+        };
+
+        out.write_all(res.as_bytes())?;
+    }
+
+    Ok(())
+}
+
+fn parse(line: &str) -> Result<Command, &'static str> {
+    let mut words = line.split_whitespace();
+
+    let cmd = match words.next() {
+        Some("list") => Command::List,
+        Some("close") => {
+            let conn_id = words.next().ok_or("close needs a connection id")?;
+            Command::Close(conn_id.parse().map_err(|_| "invalid connection id")?)
+        }
+        Some("pause") => Command::Pause,
+        Some("resume") => Command::Resume,
+        Some("reload") => Command::Reload,
+        _ => return Err("unknown command"),
+    };
+
+    if words.next().is_some() {
+        return Err("too many arguments");
+    }
+
+    Ok(cmd)
+}
+
+/// send `cmd` to every worker and render what they replied
+fn execute(cmd: Command, ctls: &[FwCtl]) -> Result<String, String> {
+    let replies: Vec<Reply> = ctls.iter()
+        .map(|x| x.command(cmd))
+        .collect::<Vec<_>>()
+        .into_iter()
+        .filter_map(|x| x.recv_timeout(REPLY_TIMEOUT).ok())
+        .collect();
+
+    let mut out = String::new();
+    let mut found = false;
+
+    for reply in replies {
+        match reply {
+            Reply::Pairs(pairs) => {
+                for x in pairs {
+                    let _ = write!(
+                        out,
+                        "conn_id={} route={} state={}/{} peer={} identity=",
+                        x.conn_id, x.route, x.state.0, x.state.1, x.peer.as_deref().unwrap_or("-"),
+                    );
+
+                    match &x.identity {
+                        Some(x) => json_str(&mut out, x),
+                        None => out.push('-'),
+                    }
+
+                    let _ = writeln!(out, " age_ms={} rx={} tx={}", x.age.as_millis(), x.rx, x.tx);
+                }
+            }
+            Reply::Done => found = true,
+            Reply::NotFound => {}
+            Reply::Failed(x) => return Err(x),
+        }
+    }
+
+    if let Command::Close(_) = cmd {
+        if !found {
+            return Err("no such connection".to_string());
+        }
+    }
+
+    Ok(out)
+}
+
+/// add the `ctl` subcommand, talking to the admin socket of a running instance
+pub fn parser<'a, 'b>(app: App<'a, 'b>) -> App<'a, 'b> {
+    app.subcommand(
+        SubCommand::with_name("ctl")
+            .about("send a command to the admin socket of a running instance")
+            .arg(
+                Arg::with_name("socket")
+                    .required(true)
+                    .index(1)
+            )
+            .arg(
+                Arg::with_name("command")
+                    .help("list, close <conn_id>, pause, resume or reload")
+                    .required(true)
+                    .multiple(true)
+                    .index(2)
+            )
+    )
+}
+
+/// send the command in `matches` and copy the reply to `out`, returns `Ok(false)` if the command failed
+pub fn ctl(matches: &ArgMatches, out: &mut impl Write) -> Result<bool, IoError> {
+    let path = matches.value_of("socket").unwrap_or_default();
+    let command: Vec<&str> = matches.values_of("command").map(|x| x.collect()).unwrap_or_default();
+
+    let mut stream = UnixStream::connect(path)?;
+    stream.write_all(format!("{}\n", command.join(" ")).as_bytes())?;
+
+    for line in BufReader::new(stream).lines() {
+        let line = line?;
+
+        if line == "ok" {
+            return Ok(true);
+        }
+
+        writeln!(out, "{}", line)?;
+
+        if line.starts_with("err ") {
+            return Ok(false);
+        }
+    }
+
+    Ok(false)
+}
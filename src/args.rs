@@ -17,7 +17,7 @@ impl<'a, R: Parsable<R>> ArgMatchesExt<R> for ArgMatches<'a> {
 
 impl<'a, 'b, R, T: Parsable<R>> AppExt<R, T> for App<'a, 'b> {
     fn parser(self) -> Self {
-        return T::parser(self);
+        T::parser(self)
     }
 }
 
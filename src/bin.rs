@@ -1,76 +1,125 @@
-use clap::{App, SubCommand};
+use std::error::Error;
+use std::fmt::{Debug, Display};
+use std::io::{stdout, Error as IoError};
+use std::sync::atomic::Ordering;
+use clap::{App, ArgMatches};
+use log::info;
 
 use sockfw::*;
+use sockfw::admin;
 use sockfw::args::*;
+use sockfw::config::{self, ConfigError};
+use sockfw::logger::{self, LogConf};
+use sockfw::registry::{self, ConnectorVisitor, ListenerVisitor, RegistryError};
 
-const TLS: &str = "tls";
-const TCP: &str = "tcp";
-const UNIX: &str = "unix";
+struct Forward<'a> {
+    conf: &'a FwConf,
+}
+
+struct Run<'a, LL> {
+    conf: &'a FwConf,
+    listener: LL,
+}
 
-fn parser_for_in<'a, 'b>(app: App<'a, 'b>, x: &str) -> App<'a, 'b> {
-    match x {
-        TCP => proto::tcp::TcpListener::parser(app),
-        TLS => proto::ssl::SslListener::parser(app),
-        _ => unreachable!(x)
+impl<'a> ListenerVisitor for Forward<'a> {
+    type R = Result<Result<(), IoError>, RegistryError>;
+
+    fn visit<
+        Le: Debug + Display + Classify, Lc: Chan<Err=Le> + Pollable, Lp: MidChan<C=Lc, Err=Le> + Pollable,
+        LL: Listener<C=Lc, PC=Lp, Err=Le> + SharedListener + Pollable + Send + 'static,
+    >(self, listener: LL, matches: &ArgMatches) -> Self::R {
+        registry::connector(matches, Run { conf: self.conf, listener })
     }
 }
 
-fn parser_for_out<'a, 'b>(app: App<'a, 'b>, x: &str) -> App<'a, 'b> {
-    match x {
-        UNIX => proto::unix::UnixConnector::parser(app),
-        _ => unreachable!(x)
+impl<
+    'a,
+    Le: Debug + Display + Classify, Lc: Chan<Err=Le> + Pollable, Lp: MidChan<C=Lc, Err=Le> + Pollable,
+    LL: Listener<C=Lc, PC=Lp, Err=Le> + SharedListener + Pollable + Send + 'static,
+> ConnectorVisitor for Run<'a, LL> {
+    type R = Result<(), IoError>;
+
+    fn visit<
+        Se: Debug + Display + Classify, Sc: Chan<Err=Se> + Pollable, Sp: MidChan<C=Sc, Err=Se> + Pollable,
+        SS: Connector<C=Sc, PC=Sp, Err=Se> + Clone + Send + 'static,
+    >(self, connector: SS) -> Self::R {
+        let stats = workers::run(self.conf, self.listener, connector)?;
+
+        done(&stats);
+
+        Ok(())
     }
 }
 
+fn done(stats: &FwStats) {
+    info!(
+        accepted = stats.accepted.load(Ordering::Relaxed),
+        failed = stats.failed.load(Ordering::Relaxed),
+        rx = stats.rx.load(Ordering::Relaxed),
+        tx = stats.tx.load(Ordering::Relaxed);
+        "done"
+    );
+}
+
 fn main() {
     let mut app = App::new("universal forwarder")
         .version("0.1")
         .author("Andrey Cizov <acizov@gmail.com>");
 
     app = FwConf::parser(app);
+    app = LogConf::parser(app);
+    app = config::parser(app);
+    app = registry::parser(app);
+    app = admin::parser(app);
 
-    for in_name in vec![TCP, TLS] {
-        let mut sc = SubCommand::with_name(in_name);
-
-        sc = parser_for_in(sc, in_name);
-
-        for out_name in vec![UNIX] {
-            let mut sco = SubCommand::with_name(out_name);
-
-            sco = parser_for_out(sco, out_name);
+    let matches = app.get_matches();
 
-            sc = sc.subcommand(sco);
+    if let ("ctl", Some(matches)) = matches.subcommand() {
+        match admin::ctl(matches, &mut stdout()) {
+            Ok(true) => return,
+            Ok(false) => ::std::process::exit(1),
+            Err(x) => fail(&x, ErrorClass::Other),
         }
-
-        app = app.subcommand(sc);
     }
 
-    let matches = app.get_matches();
+    let conf = match FwConf::parse(&matches) {
+        Ok(x) => x,
+        Err(x) => fail(&x, x.class()),
+    };
 
-    let conf = FwConf::parse(&matches).unwrap();
+    match LogConf::parse(&matches) {
+        Ok(x) => logger::init(&x).unwrap_or_else(|x| fail(&x, ErrorClass::Other)),
+        Err(x) => fail(&x, x.class()),
+    }
 
-    if let Some(matches) = matches.subcommand_matches(TCP) {
-        let c_i = proto::tcp::TcpListener::parse(matches).unwrap();
+    if let Some(path) = matches.value_of("config") {
+        if matches.subcommand_name().is_some() {
+            fail(&ConfigError::Protocols, ErrorClass::Config);
+        }
 
-        if let Some(matches) = matches.subcommand_matches(UNIX) {
-            let c_o = proto::unix::UnixConnector::parse(matches).unwrap();
+        let routes = config::load(path).unwrap_or_else(|x| fail(&x, x.class()));
 
-            Fw::from_conf(&conf, c_i, c_o).unwrap().run();
-        } else {
-            unreachable!();
+        let routes = routes.iter()
+            .map(registry::route)
+            .collect::<Result<Vec<_>, _>>()
+            .unwrap_or_else(|x| fail(&x, x.class()));
+
+        match workers::run_routes(&conf, routes) {
+            Ok(stats) => done(&stats),
+            Err(x) => fail(&x, ErrorClass::Other),
         }
-    } else if let Some(matches) = matches.subcommand_matches(TLS) {
-        let c_i = proto::ssl::SslListener::parse(matches).unwrap();
 
-        if let Some(matches) = matches.subcommand_matches(UNIX) {
-            let c_o = proto::unix::UnixConnector::parse(matches).unwrap();
+        return;
+    }
 
-            Fw::from_conf(&conf, c_i, c_o).unwrap().run();
-        } else {
-            unreachable!();
-        }
-    } else {
-        eprintln!("invalid command {:?}", matches);
-        ::std::process::exit(-1);
+    match registry::listener(&matches, Forward { conf: &conf }).and_then(|x| x) {
+        Ok(Ok(())) => {}
+        Ok(Err(x)) => fail(&x, ErrorClass::Other),
+        Err(x) => fail(&x, x.class()),
     }
 }
+
+fn fail(err: &dyn Error, class: ErrorClass) -> ! {
+    eprintln!("sfw: {}", describe(err));
+    ::std::process::exit(class.exit_code());
+}
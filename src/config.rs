@@ -0,0 +1,608 @@
+use std::error::Error;
+use std::fmt::{Display, Formatter, Result as FmtResult};
+use std::fs;
+use std::io::Error as IoError;
+use std::iter::Peekable;
+use std::str::Chars;
+use clap::{App, Arg};
+
+use crate::{Classify, ErrorClass, MAX_ROUTES};
+
+/// Value of a key in the configuration file
+#[derive(Debug, Clone, PartialEq)]
+pub enum Value {
+    Str(String),
+    Int(i64),
+    Bool(bool),
+    Array(Vec<Value>),
+}
+
+/// Table of the configuration file, with its keys in the order they were given
+#[derive(Debug)]
+pub struct Table {
+    /// dotted path of the header, e.g. `["route", "docker"]`
+    pub path: Vec<String>,
+    pub line: usize,
+    pub keys: Vec<(String, Value)>,
+}
+
+#[derive(Debug)]
+pub enum ConfigError {
+    File(String, IoError),
+    /// carries the line the error was found on
+    Syntax(usize, String),
+    /// a route is incomplete or has an invalid key, carries the name of the route
+    Route(String, String),
+    /// protocols were given on the command line as well
+    Protocols,
+}
+
+/// Route described by the configuration file, its `listen` and `connect` arguments are
+/// what would follow `sfw` on the command line
+#[derive(Debug, Clone)]
+pub struct RouteConf {
+    pub name: String,
+    pub listen: Vec<String>,
+    pub connect: Vec<String>,
+    /// pairs the route may have open at the same time in each worker
+    pub max_connections: Option<usize>,
+}
+
+struct Lexer<'a> {
+    chars: Peekable<Chars<'a>>,
+    line: usize,
+}
+
+impl<'a> Lexer<'a> {
+    fn err<T>(&self, msg: &str) -> Result<T, ConfigError> {
+        Err(ConfigError::Syntax(self.line, msg.to_string()))
+    }
+
+    fn peek(&mut self) -> Option<char> {
+        self.chars.peek().cloned()
+    }
+
+    /// the text ahead starts with `x`
+    fn ahead(&self, x: &str) -> bool {
+        let mut chars = self.chars.clone();
+        x.chars().all(|c| chars.next() == Some(c))
+    }
+
+    fn next(&mut self) -> Option<char> {
+        let c = self.chars.next();
+
+        if c == Some('\n') {
+            self.line += 1;
+        }
+
+        c
+    }
+
+    /// skip spaces and tabs
+    fn blank(&mut self) {
+        while let Some(' ') | Some('\t') = self.peek() {
+            self.next();
+        }
+    }
+
+    /// skip blanks, comments and, if `newlines`, line breaks as well
+    fn space(&mut self, newlines: bool) {
+        loop {
+            match self.peek() {
+                Some(' ') | Some('\t') | Some('\r') => {}
+                Some('\n') if newlines => {}
+                Some('#') => {
+                    while !matches!(self.peek(), Some('\n') | None) {
+                        self.next();
+                    }
+
+                    continue;
+                }
+                _ => return,
+            }
+
+            self.next();
+        }
+    }
+
+    /// anything but a comment left on the line is an error
+    fn eol(&mut self) -> Result<(), ConfigError> {
+        self.space(false);
+
+        match self.next() {
+            Some('\n') | None => Ok(()),
+            _ => self.err("expected the end of the line"),
+        }
+    }
+
+    fn key(&mut self) -> Result<String, ConfigError> {
+        match self.peek() {
+            Some('"') => self.basic(),
+            Some('\'') => self.literal(),
+            _ => {
+                let mut ret = String::new();
+
+                while let Some(c) = self.peek() {
+                    if !(c.is_ascii_alphanumeric() || c == '_' || c == '-') {
+                        break;
+                    }
+
+                    ret.push(c);
+                    self.next();
+                }
+
+                if ret.is_empty() {
+                    return self.err("expected a key");
+                }
+
+                Ok(ret)
+            }
+        }
+    }
+
+    /// `"..."` with escapes
+    fn basic(&mut self) -> Result<String, ConfigError> {
+        if self.ahead("\"\"\"") {
+            return self.err("multi-line strings are not supported");
+        }
+
+        self.next();
+
+        let mut ret = String::new();
+
+        loop {
+            if let Some('\n') | None = self.peek() {
+                return self.err("unterminated string");
+            }
+
+            match self.next() {
+                Some('"') => return Ok(ret),
+                Some('\\') => match self.next() {
+                    Some('"') => ret.push('"'),
+                    Some('\\') => ret.push('\\'),
+                    Some('n') => ret.push('\n'),
+                    Some('t') => ret.push('\t'),
+                    Some('r') => ret.push('\r'),
+                    Some('b') => ret.push('\u{8}'),
+                    Some('f') => ret.push('\u{c}'),
+                    Some(x @ ('u' | 'U')) => {
+                        let len = if x == 'u' { 4 } else { 8 };
+                        let hex = (0..len).filter_map(|_| self.next()).collect::<String>();
+
+                        let c = match hex.len() == len && hex.chars().all(|x| x.is_ascii_hexdigit()) {
+                            true => u32::from_str_radix(&hex, 16).ok().and_then(std::char::from_u32),
+                            false => None,
+                        };
+
+                        match c {
+                            Some(x) => ret.push(x),
+                            None => return self.err("invalid unicode escape"),
+                        }
+                    }
+                    _ => return self.err("invalid escape sequence"),
+                },
+                Some(c) => ret.push(c),
+                None => return self.err("unterminated string"),
+            }
+        }
+    }
+
+    /// `'...'` taken as is
+    fn literal(&mut self) -> Result<String, ConfigError> {
+        if self.ahead("\'\'\'") {
+            return self.err("multi-line strings are not supported");
+        }
+
+        self.next();
+
+        let mut ret = String::new();
+
+        loop {
+            if let Some('\n') | None = self.peek() {
+                return self.err("unterminated string");
+            }
+
+            match self.next() {
+                Some('\'') => return Ok(ret),
+                Some(c) => ret.push(c),
+                None => return self.err("unterminated string"),
+            }
+        }
+    }
+
+    fn value(&mut self) -> Result<Value, ConfigError> {
+        match self.peek() {
+            Some('"') => Ok(Value::Str(self.basic()?)),
+            Some('\'') => Ok(Value::Str(self.literal()?)),
+            Some('[') => {
+                self.next();
+
+                let mut ret = Vec::new();
+
+                loop {
+                    self.space(true);
+
+                    if let Some(']') = self.peek() {
+                        self.next();
+                        return Ok(Value::Array(ret));
+                    }
+
+                    ret.push(self.value()?);
+                    self.space(true);
+
+                    match self.next() {
+                        Some(',') => {}
+                        Some(']') => return Ok(Value::Array(ret)),
+                        _ => return self.err("expected `,` or `]` in an array"),
+                    }
+                }
+            }
+            Some('{') => self.err("inline tables are not supported"),
+            _ => {
+                let mut word = String::new();
+
+                // wide enough to tell floats and dates apart
+                while let Some(c) = self.peek() {
+                    if !(c.is_ascii_alphanumeric() || matches!(c, '_' | '-' | '+' | '.' | ':')) {
+                        break;
+                    }
+
+                    word.push(c);
+                    self.next();
+                }
+
+                match word.as_str() {
+                    "true" => Ok(Value::Bool(true)),
+                    "false" => Ok(Value::Bool(false)),
+                    "inf" | "+inf" | "-inf" | "nan" | "+nan" | "-nan" => self.err("floats are not supported"),
+                    x => match integer(x) {
+                        Some(x) => Ok(Value::Int(x)),
+                        None if x.contains(':') || x.len() >= 10 && x.as_bytes()[4] == b'-' => self.err("dates and times are not supported"),
+                        None if x.starts_with(|x: char| x.is_ascii_digit() || x == '+' || x == '-') && x.contains(['.', 'e', 'E']) && !x.starts_with("0x") => {
+                            self.err("floats are not supported")
+                        }
+                        None => self.err("expected a string, an integer, a boolean or an array"),
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// TOML integer: decimal without leading zeros, or `0x`, `0o` and `0b` without a sign, with `_` only between digits
+fn integer(x: &str) -> Option<i64> {
+    let (radix, digits, sign) = match x.get(..2) {
+        Some("0x") => (16, &x[2..], ""),
+        Some("0o") => (8, &x[2..], ""),
+        Some("0b") => (2, &x[2..], ""),
+        _ => match x.strip_prefix(['+', '-']) {
+            Some(rest) => (10, rest, &x[..1]),
+            None => (10, x, ""),
+        },
+    };
+
+    let bytes = digits.as_bytes();
+
+    let valid = !bytes.is_empty()
+        && bytes.iter().enumerate().all(|(i, c)| match c {
+            b'_' => i > 0 && i + 1 < bytes.len() && bytes[i - 1] != b'_',
+            c => (*c as char).is_digit(radix),
+        })
+        && !(radix == 10 && bytes.len() > 1 && bytes[0] == b'0');
+
+    if !valid {
+        return None;
+    }
+
+    i64::from_str_radix(&format!("{}{}", sign, digits.replace('_', "")), radix).ok()
+}
+
+/// Parse the subset of TOML the configuration file is written in: tables, arrays, strings,
+/// integers and booleans. Keys given before the first table header go into a table with an empty path.
+/// Multi-line strings, floats, dates, inline tables, arrays of tables and dotted keys are refused.
+pub fn parse(text: &str) -> Result<Vec<Table>, ConfigError> {
+    let mut lexer = Lexer { chars: text.chars().peekable(), line: 1 };
+    let mut tables = vec![Table { path: Vec::new(), line: 1, keys: Vec::new() }];
+
+    loop {
+        lexer.space(true);
+
+        match lexer.peek() {
+            None => break,
+            Some('[') => {
+                lexer.next();
+
+                if lexer.peek() == Some('[') {
+                    return lexer.err("arrays of tables are not supported");
+                }
+
+                lexer.blank();
+
+                let line = lexer.line;
+                let mut path = vec![lexer.key()?];
+
+                loop {
+                    lexer.blank();
+
+                    match lexer.next() {
+                        Some('.') => {
+                            lexer.blank();
+                            path.push(lexer.key()?);
+                        }
+                        Some(']') => break,
+                        _ => return lexer.err("expected `.` or `]` in a table header"),
+                    }
+                }
+
+                lexer.eol()?;
+
+                if tables.iter().any(|x| x.path == path) {
+                    return Err(ConfigError::Syntax(line, format!("table [{}] is defined twice", path.join("."))));
+                }
+
+                tables.push(Table { path, line, keys: Vec::new() });
+            }
+            Some(_) => {
+                let key = lexer.key()?;
+                lexer.blank();
+
+                if lexer.peek() == Some('.') {
+                    return lexer.err("dotted keys are not supported, use a [table] header");
+                }
+
+                if lexer.next() != Some('=') {
+                    return lexer.err("expected `=` after the key");
+                }
+
+                lexer.blank();
+
+                let line = lexer.line;
+                let value = lexer.value()?;
+                lexer.eol()?;
+
+                // every table is in the list, the one being filled is the last one
+                let table = tables.last_mut().ok_or(ConfigError::Syntax(line, "no table".to_string()))?;
+
+                if table.keys.iter().any(|(x, _)| *x == key) {
+                    return Err(ConfigError::Syntax(line, format!("key `{}` is defined twice", key)));
+                }
+
+                table.keys.push((key, value));
+            }
+        }
+    }
+
+    Ok(tables)
+}
+
+/// arguments given either as a single string split on whitespace or as an array of strings
+fn args(name: &str, key: &str, value: &Value) -> Result<Vec<String>, ConfigError> {
+    let invalid = || ConfigError::Route(name.to_string(), format!("`{}` must be a string or an array of strings", key));
+
+    match value {
+        Value::Str(x) => Ok(x.split_whitespace().map(|x| x.to_string()).collect()),
+        Value::Array(xs) => xs.iter()
+            .map(|x| match x {
+                Value::Str(x) => Ok(x.clone()),
+                _ => Err(invalid()),
+            })
+            .collect(),
+        _ => Err(invalid()),
+    }
+}
+
+/// read the routes out of the configuration file at `path`, every `[route.<name>]` table describes one
+pub fn load(path: &str) -> Result<Vec<RouteConf>, ConfigError> {
+    let text = fs::read_to_string(path).map_err(|x| ConfigError::File(path.to_string(), x))?;
+
+    routes(&text)
+}
+
+fn routes(text: &str) -> Result<Vec<RouteConf>, ConfigError> {
+    let mut routes = Vec::new();
+
+    for table in parse(text)? {
+        let name = match table.path.as_slice() {
+            [] if table.keys.is_empty() => continue,
+            [route, name] if route == "route" => name.clone(),
+            _ => return Err(ConfigError::Syntax(table.line, "expected a [route.<name>] table".to_string())),
+        };
+
+        let mut route = RouteConf { name: name.clone(), listen: Vec::new(), connect: Vec::new(), max_connections: None };
+
+        for (key, value) in &table.keys {
+            match (key.as_str(), value) {
+                ("listen", x) => route.listen = args(&name, key, x)?,
+                ("connect", x) => route.connect = args(&name, key, x)?,
+                ("max_connections", Value::Int(x)) if *x > 0 => route.max_connections = Some(*x as usize),
+                ("max_connections", _) => {
+                    return Err(ConfigError::Route(name, "`max_connections` must be a positive integer".to_string()));
+                }
+                (x, _) => return Err(ConfigError::Route(name, format!("unknown key `{}`", x))),
+            }
+        }
+
+        if route.listen.is_empty() {
+            return Err(ConfigError::Route(name, "`listen` is missing".to_string()));
+        }
+
+        if route.connect.is_empty() {
+            return Err(ConfigError::Route(name, "`connect` is missing".to_string()));
+        }
+
+        routes.push(route);
+    }
+
+    if routes.is_empty() {
+        return Err(ConfigError::Syntax(1, "no routes defined".to_string()));
+    }
+
+    if routes.len() > MAX_ROUTES {
+        return Err(ConfigError::Syntax(1, format!("more than {} routes defined", MAX_ROUTES)));
+    }
+
+    Ok(routes)
+}
+
+/// add `--config`, which replaces the input and output protocols given on the command line
+pub fn parser<'a, 'b>(app: App<'a, 'b>) -> App<'a, 'b> {
+    app.arg(
+        Arg::with_name("config")
+            .long("config")
+            .help("file describing the routes to serve, instead of the protocols given on the command line")
+            .takes_value(true)
+            .required(false)
+    )
+}
+
+impl Display for ConfigError {
+    fn fmt(&self, f: &mut Formatter) -> FmtResult {
+        match self {
+            ConfigError::File(path, _) => write!(f, "failed to read {}", path),
+            ConfigError::Syntax(line, x) => write!(f, "line {}: {}", line, x),
+            ConfigError::Route(name, x) => write!(f, "route {}: {}", name, x),
+            ConfigError::Protocols => write!(f, "--config replaces the protocols given on the command line"),
+        }
+    }
+}
+
+impl Error for ConfigError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            ConfigError::File(_, x) => Some(x),
+            _ => None,
+        }
+    }
+}
+
+impl Classify for ConfigError {
+    fn class(&self) -> ErrorClass {
+        ErrorClass::Config
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn value(x: &str) -> Result<Value, String> {
+        match parse(&format!("k = {}", x)) {
+            Ok(mut x) => Ok(x.remove(0).keys.remove(0).1),
+            Err(x) => Err(x.to_string()),
+        }
+    }
+
+    fn error(text: &str) -> String {
+        parse(text).unwrap_err().to_string()
+    }
+
+    #[test]
+    fn parses_tables_and_keys() {
+        let text = "# comment\ntop = 1\n\n[ route . \"a b\" ]  # trailing\nx = 'y' \r\n[route.'c']\n\"quoted key\" = true\nbare-key_2 = false\n";
+        let tables = parse(text).unwrap();
+
+        assert_eq!(tables.len(), 3);
+        assert_eq!(tables[0].path, Vec::<String>::new());
+        assert_eq!(tables[0].keys, vec![("top".to_string(), Value::Int(1))]);
+        assert_eq!(tables[1].path, vec!["route", "a b"]);
+        assert_eq!(tables[1].line, 4);
+        assert_eq!(tables[1].keys, vec![("x".to_string(), Value::Str("y".into()))]);
+        assert_eq!(tables[2].path, vec!["route", "c"]);
+        assert_eq!(tables[2].keys, vec![("quoted key".to_string(), Value::Bool(true)), ("bare-key_2".to_string(), Value::Bool(false))]);
+    }
+
+    #[test]
+    fn parses_values() {
+        assert_eq!(value(r#""a\"\\\n\t\r\b\f\u00e9\U0001F600""#), Ok(Value::Str("a\"\\\n\t\r\u{8}\u{c}é😀".into())));
+        assert_eq!(value(r#"'C:\path\n'"#), Ok(Value::Str("C:\\path\\n".into())));
+        assert_eq!(value(r#""""#), Ok(Value::Str("".into())));
+        assert_eq!(value("''"), Ok(Value::Str("".into())));
+
+        assert_eq!(value("0"), Ok(Value::Int(0)));
+        assert_eq!(value("+17"), Ok(Value::Int(17)));
+        assert_eq!(value("-17"), Ok(Value::Int(-17)));
+        assert_eq!(value("1_000_000"), Ok(Value::Int(1_000_000)));
+        assert_eq!(value("0xdead_BEEF"), Ok(Value::Int(0xdead_beef)));
+        assert_eq!(value("0o755"), Ok(Value::Int(0o755)));
+        assert_eq!(value("0b1010"), Ok(Value::Int(10)));
+        assert_eq!(value("-9223372036854775808"), Ok(Value::Int(i64::MIN)));
+
+        assert_eq!(value("[]"), Ok(Value::Array(vec![])));
+        assert_eq!(value("[ 1, 'a', [true], ]"), Ok(Value::Array(vec![
+            Value::Int(1), Value::Str("a".into()), Value::Array(vec![Value::Bool(true)]),
+        ])));
+        assert_eq!(value("[\n  1, # one\n  2\n]"), Ok(Value::Array(vec![Value::Int(1), Value::Int(2)])));
+    }
+
+    #[test]
+    fn refuses_invalid_integers() {
+        for x in &["01", "1__0", "_1", "1_", "+0x1", "0x", "0xg", "1a", "9223372036854775808", "--1", "0b2", "+"] {
+            assert!(value(x).is_err(), "{}", x);
+        }
+    }
+
+    #[test]
+    fn refuses_unsupported_toml() {
+        assert_eq!(value(r#""""a""""#).unwrap_err(), "line 1: multi-line strings are not supported");
+        assert_eq!(value("'''a'''").unwrap_err(), "line 1: multi-line strings are not supported");
+        assert_eq!(value("1.5").unwrap_err(), "line 1: floats are not supported");
+        assert_eq!(value("1e3").unwrap_err(), "line 1: floats are not supported");
+        assert_eq!(value("-inf").unwrap_err(), "line 1: floats are not supported");
+        assert_eq!(value("1979-05-27").unwrap_err(), "line 1: dates and times are not supported");
+        assert_eq!(value("07:32:00").unwrap_err(), "line 1: dates and times are not supported");
+        assert_eq!(value("{ a = 1 }").unwrap_err(), "line 1: inline tables are not supported");
+        assert_eq!(error("[[route]]\n"), "line 1: arrays of tables are not supported");
+        assert_eq!(error("a.b = 1\n"), "line 1: dotted keys are not supported, use a [table] header");
+    }
+
+    #[test]
+    fn refuses_invalid_syntax() {
+        assert_eq!(error("a = \"b\n"), "line 1: unterminated string");
+        assert_eq!(error("a = 'b"), "line 1: unterminated string");
+        assert_eq!(error("a = \"\\x\""), "line 1: invalid escape sequence");
+        assert_eq!(error("a = \"\\u12\""), "line 1: invalid unicode escape");
+        assert_eq!(error("a = \"\\ud800\""), "line 1: invalid unicode escape");
+        assert_eq!(error("\na = 1 2"), "line 2: expected the end of the line");
+        assert_eq!(error("a 1"), "line 1: expected `=` after the key");
+        assert_eq!(error("= 1"), "line 1: expected a key");
+        assert_eq!(error("a = yes"), "line 1: expected a string, an integer, a boolean or an array");
+        assert_eq!(error("a = [1 2]"), "line 1: expected `,` or `]` in an array");
+        assert_eq!(error("a = [1,"), "line 1: expected a string, an integer, a boolean or an array");
+        assert_eq!(error("[a"), "line 1: expected `.` or `]` in a table header");
+        assert_eq!(error("[a] x"), "line 1: expected the end of the line");
+        assert_eq!(error("a = 1\na = 2"), "line 2: key `a` is defined twice");
+        assert_eq!(error("[a]\n[b]\n[a]"), "line 3: table [a] is defined twice");
+    }
+
+    #[test]
+    fn reads_routes() {
+        let text = "[route.docker]\nlisten = \"tls 0.0.0.0:2376  ca.pem\"\nconnect = ['unix', '/var/run/docker.sock']\nmax_connections = 64\n";
+        let routes = routes(text).unwrap();
+
+        assert_eq!(routes.len(), 1);
+        assert_eq!(routes[0].name, "docker");
+        assert_eq!(routes[0].listen, vec!["tls", "0.0.0.0:2376", "ca.pem"]);
+        assert_eq!(routes[0].connect, vec!["unix", "/var/run/docker.sock"]);
+        assert_eq!(routes[0].max_connections, Some(64));
+    }
+
+    #[test]
+    fn refuses_invalid_routes() {
+        let error = |x: &str| routes(x).unwrap_err().to_string();
+
+        assert_eq!(error(""), "line 1: no routes defined");
+        assert_eq!(error("# nothing\n"), "line 1: no routes defined");
+        assert_eq!(error("x = 1\n[route.a]\nlisten = 'tcp'\nconnect = 'tcp'"), "line 1: expected a [route.<name>] table");
+        assert_eq!(error("[routes.a]"), "line 1: expected a [route.<name>] table");
+        assert_eq!(error("[route]\nlisten = 'a'"), "line 1: expected a [route.<name>] table");
+        assert_eq!(error("[route.a]\nconnect = 'tcp'"), "route a: `listen` is missing");
+        assert_eq!(error("[route.a]\nlisten = 'tcp'"), "route a: `connect` is missing");
+        assert_eq!(error("[route.a]\nlisten = 1\nconnect = 'tcp'"), "route a: `listen` must be a string or an array of strings");
+        assert_eq!(error("[route.a]\nlisten = ['tcp', 1]\nconnect = 'tcp'"), "route a: `listen` must be a string or an array of strings");
+        assert_eq!(error("[route.a]\nlisten = 'tcp'\nconnect = 'tcp'\nmax_connections = 0"), "route a: `max_connections` must be a positive integer");
+        assert_eq!(error("[route.a]\nlisten = 'tcp'\nconnect = 'tcp'\nmax_connection = 1"), "route a: unknown key `max_connection`");
+
+        let many = (0..=MAX_ROUTES).map(|x| format!("[route.r{}]\nlisten = 'tcp'\nconnect = 'tcp'\n", x)).collect::<String>();
+        assert_eq!(error(&many), format!("line 1: more than {} routes defined", MAX_ROUTES));
+    }
+}
@@ -1,17 +1,35 @@
-use mio::Poll;
-use mio::Events;
-use std::collections::HashMap;
-use std::io::Error as IoError;
-use mio::Token;
-use std::fmt::{Debug, Formatter, Result as FmtResult};
+use mio::{Events, Poll, PollOpt, Ready, Registration, SetReadiness, Token};
+use std::collections::{BTreeMap, HashMap};
+use std::io::{Error as IoError, ErrorKind};
+use std::fs;
+use std::net::{SocketAddr, TcpListener as StdTcpListener};
+use std::os::unix::fs::FileTypeExt;
+use std::os::unix::net::UnixListener as StdUnixListener;
+use std::sync::{mpsc, Arc, Mutex};
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+use std::error::Error;
+use std::fmt::{Debug, Display, Formatter, Result as FmtResult};
 use std::mem;
+use std::time::{Duration, Instant, SystemTime};
 use clap::{App, Arg, ArgMatches};
+use log::{debug, error, info, warn};
+use crate::access::{AccessLog, AccessRecord};
 use crate::args::*;
+use crate::logger::LogFormat;
+use crate::health::{self, HealthConf, Probe, ProbeStatus, ReadyCheck, Upstream};
+use crate::metrics::{self, MetricsServer};
+use crate::proto::http::Head;
+use crate::signal::Signals;
+use crate::watch::{Change, SocketWatch};
 
 #[derive(Debug)]
 pub enum FwConfError {
     Io(IoError),
     Str(String),
+    /// a file named in the configuration could not be opened
+    File(String, IoError),
+    /// carries the address or the path of the socket
+    Bind(String, IoError),
 }
 
 #[derive(Debug)]
@@ -20,7 +38,8 @@ pub enum FwError<E: Debug> {
     Register(IoError),
     Disconnected,
     Lost,
-    Custom(usize),
+    /// connector refused the destination requested by the client
+    Denied,
 }
 
 #[derive(Debug)]
@@ -41,34 +60,140 @@ pub enum NextState<
     Active(A),
 }
 
+/// Outcome of connecting or making progress on a channel
+pub type NextResult<E, A, B> = Result<NextState<E, A, B>, FwError<E>>;
+
+/// Outcome of accepting, `None` when there is nothing left to accept
+pub type Accepted<E, A, B> = Result<Option<NextState<E, A, B>>, FwError<E>>;
+
 pub enum State<
     E: Debug,
     A: Chan<Err=E> + Pollable,
     B: MidChan<Err=E, C=A> + Pollable
 > {
+    /// not connected yet, the connector waits for the other side of the pair to become active
+    Idle,
     Pending(B),
     Active(A),
     Swapping,
     Lost,
 }
 
+/// Destination requested by the client of a proxying listener
+#[derive(Debug, Clone, PartialEq)]
+pub struct Dest {
+    pub host: String,
+    pub port: u16,
+}
+
+/// Reason a routed connection could not be established, reported back to the client
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum RouteErr {
+    Denied,
+    Unreachable,
+}
+
+/// Why a pair was closed, as reported by the access log
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum CloseReason {
+    /// one of the sides closed its end
+    Eof,
+    ClientError,
+    UpstreamError,
+    /// connector refused the destination requested by the client
+    Denied,
+    /// forwarder was stopped or the drain timeout was over
+    Shutdown,
+    /// closed through the admin socket
+    Closed,
+}
+
+impl<Le: Debug, Se: Debug> From<&FwPairError<Le, Se>> for CloseReason {
+    fn from(x: &FwPairError<Le, Se>) -> Self {
+        match x {
+            FwPairError::Disconnected => CloseReason::Eof,
+            FwPairError::L(FwError::Disconnected) | FwPairError::S(FwError::Disconnected) => CloseReason::Eof,
+            FwPairError::S(FwError::Denied) => CloseReason::Denied,
+            FwPairError::L(_) => CloseReason::ClientError,
+            FwPairError::S(_) | FwPairError::Lost => CloseReason::UpstreamError,
+        }
+    }
+}
+
+impl Display for CloseReason {
+    fn fmt(&self, f: &mut Formatter) -> FmtResult {
+        let s = match self {
+            CloseReason::Eof => "eof",
+            CloseReason::ClientError => "client_error",
+            CloseReason::UpstreamError => "upstream_error",
+            CloseReason::Denied => "denied",
+            CloseReason::Shutdown => "shutdown",
+            CloseReason::Closed => "closed",
+        };
+
+        f.write_str(s)
+    }
+}
+
+/// What is known about the client at the time its upstream is connected
+#[derive(Debug)]
+pub struct ConnInfo<'a> {
+    pub conn_id: usize,
+    pub dest: Option<&'a Dest>,
+    pub peer: Option<&'a str>,
+    /// only known once the client channel is active, so for deferred connectors
+    pub identity: Option<&'a str>,
+}
+
 impl<
     E: Debug,
     A: Chan<Err=E> + Pollable,
     B: MidChan<Err=E, C=A> + Pollable
 > Debug for State<E, A, B> {
     fn fmt(&self, f: &mut Formatter) -> FmtResult {
-        let s = match &self {
-            State::Pending(_) => "Pending",
-            State::Active(_) => "Active",
-            State::Swapping => "Swapping",
-            State::Lost => "Lost",
-        };
-
-        write!(f, "({})", s)
+        write!(f, "({})", self.name())
     }
 }
 
+/// Request handled by the event loop of a forwarder, see `FwCtl::command`
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Command {
+    /// describe the open pairs
+    List,
+    Close(usize),
+    /// stop accepting clients, they wait in the backlog of the listening socket
+    Pause,
+    Resume,
+    /// load the certificates and other files of the listener again
+    Reload,
+}
+
+#[derive(Debug)]
+pub enum Reply {
+    Pairs(Vec<PairInfo>),
+    Done,
+    /// the forwarder has no pair with this id
+    NotFound,
+    Failed(String),
+}
+
+/// Snapshot of an open pair
+#[derive(Debug, Clone)]
+pub struct PairInfo {
+    pub conn_id: usize,
+    /// name of the route the client connected through
+    pub route: String,
+    /// states of the client and upstream channels
+    pub state: (&'static str, &'static str),
+    pub peer: Option<String>,
+    pub identity: Option<String>,
+    pub age: Duration,
+    /// bytes received from the client
+    pub rx: u64,
+    /// bytes sent to the client
+    pub tx: u64,
+}
+
 #[derive(Debug)]
 struct Pair<
     Le: Debug,
@@ -79,20 +204,265 @@ struct Pair<
     Sp: MidChan<C=Sc, Err=Se> + Pollable
 > {
     conn_id: usize,
+    /// index of the route the client connected through
+    route: usize,
+    /// address of the client, for logging
+    peer: Option<String>,
+    /// known once the client channel is active
+    identity: Option<String>,
+    start: SystemTime,
     ca: State<Le, Lc, Lp>,
     cb: State<Se, Sc, Sp>,
-    tx: usize,
-    rx: usize,
+    /// bytes sent to the client
+    tx: u64,
+    /// bytes received from the client
+    rx: u64,
     tok_a: usize,
     tok_b: usize,
     b: Vec<u8>,
+    /// the client was told about the outcome of connecting its destination
+    routed: bool,
+    /// the upstream could not be connected yet and is tried again
+    retry: Option<Retry>,
+}
+
+/// How long clients are kept while their upstream cannot be connected
+#[derive(Debug, Clone, Copy)]
+pub struct RetryConf {
+    pub timeout: Duration,
+    /// wait before the first retry, doubled after each failed one up to `MAX_BACKOFF`
+    pub backoff: Duration,
+}
+
+/// Upstream of a pair waiting to be connected again
+#[derive(Debug)]
+struct Retry {
+    /// the client is dropped if the upstream still fails by then
+    deadline: Instant,
+    at: Instant,
+    delay: Duration,
+    attempts: u32,
+}
+
+impl Retry {
+    fn new(conf: RetryConf, now: Instant) -> Self {
+        Retry { deadline: now + conf.timeout, at: now + conf.backoff, delay: conf.backoff, attempts: 1 }
+    }
+
+    /// schedule the next attempt after one failed at `now`, `false` once the deadline is over
+    fn next(&mut self, now: Instant) -> bool {
+        if now >= self.deadline {
+            return false;
+        }
+
+        self.delay = (self.delay * 2).min(MAX_BACKOFF);
+        self.at = (now + self.delay).min(self.deadline);
+        self.attempts += 1;
+
+        true
+    }
+}
+
+/// Upstream connection opened ahead of the client it will be paired with
+struct Warm<E: Debug, A: Chan<Err=E> + Pollable, B: MidChan<Err=E, C=A> + Pollable> {
+    route: usize,
+    state: State<E, A, B>,
+    since: Instant,
+}
+
+/// Health check of a single upstream of a route
+struct UpstreamCheck<E: Debug, A: Chan<Err=E> + Pollable, B: MidChan<Err=E, C=A> + Pollable> {
+    route: usize,
+    /// index into the upstreams of the connector of the route
+    upstream: usize,
+    probe: Probe<E, A, B>,
+}
+
+/// upper bounds of the buckets of the event loop iteration histogram, in seconds
+pub const LOOP_BUCKETS: [f64; 8] = [0.0001, 0.00025, 0.0005, 0.001, 0.0025, 0.005, 0.01, 0.05];
+
+/// Counters of a forwarder, shared by all the workers serving the same listener
+#[derive(Debug, Default)]
+pub struct FwStats {
+    /// pairs created so far, their ids are handed out from it so that they are unique across the workers
+    pub conn_ids: AtomicUsize,
+    pub accepted: AtomicUsize,
+    /// clients dropped while being accepted
+    pub rejected: AtomicUsize,
+    pub active: AtomicUsize,
+    /// channels of the open pairs that are still connecting or in a handshake
+    pub pending: AtomicUsize,
+    /// pairs closed because of an error rather than a disconnect
+    pub failed: AtomicUsize,
+    pub upstream_failures: AtomicUsize,
+    /// upstream connections opened ahead of the clients, waiting to be paired
+    pub pooled: AtomicUsize,
+    /// failed TLS handshakes by reason
+    pub tls_failures: Mutex<BTreeMap<&'static str, u64>>,
+    /// bytes received from clients
+    pub rx: AtomicU64,
+    /// bytes sent to clients
+    pub tx: AtomicU64,
+    /// iterations of the event loops that took up to the matching bound of `LOOP_BUCKETS`
+    pub loop_buckets: [AtomicU64; 8],
+    pub loop_count: AtomicU64,
+    /// total time spent in the iterations, in microseconds
+    pub loop_micros: AtomicU64,
+    /// time each event loop last turned, in milliseconds since the epoch, 0 once it is done
+    pub heartbeats: Mutex<Vec<Arc<AtomicU64>>>,
+    /// upstreams of the connectors picking from several, by the name of their route
+    pub upstreams: Mutex<Vec<(String, Arc<[Upstream]>)>>,
+}
+
+impl FwStats {
+    pub fn observe_loop(&self, took: Duration) {
+        let secs = took.as_secs_f64();
+
+        if let Some(idx) = LOOP_BUCKETS.iter().position(|x| secs <= *x) {
+            self.loop_buckets[idx].fetch_add(1, Ordering::Relaxed);
+        }
+
+        self.loop_count.fetch_add(1, Ordering::Relaxed);
+        self.loop_micros.fetch_add(took.as_micros() as u64, Ordering::Relaxed);
+    }
+
+    /// export the upstreams of `route`, once however many workers share them
+    pub fn add_upstreams(&self, route: &str, upstreams: Arc<[Upstream]>) {
+        if let Ok(mut x) = self.upstreams.lock() {
+            if !x.iter().any(|(_, y)| Arc::ptr_eq(y, &upstreams)) {
+                x.push((route.to_string(), upstreams));
+            }
+        }
+    }
+
+    /// count the error if it is a failed TLS handshake
+    pub fn handshake_failed<E: Debug + Classify>(&self, err: &FwError<E>) {
+        if let Some(reason) = err.tls_failure() {
+            if let Ok(mut x) = self.tls_failures.lock() {
+                *x.entry(reason).or_insert(0) += 1;
+            }
+        }
+    }
+}
+
+/// Handle to stop a running forwarder from another thread
+#[derive(Clone)]
+pub struct FwCtl {
+    readiness: SetReadiness,
+    stop: Arc<AtomicBool>,
+    drain: Arc<AtomicBool>,
+    commands: Arc<Mutex<Vec<Queued>>>,
+}
+
+/// Command waiting for the event loop, along with where its reply goes
+type Queued = (Command, mpsc::Sender<Reply>);
+
+impl FwCtl {
+    /// close all the pairs and return from `Fw::run` right away
+    pub fn stop(&self) {
+        self.stop.store(true, Ordering::SeqCst);
+        self.wake();
+    }
+
+    /// stop accepting clients and return from `Fw::run` once the pairs are done or the drain timeout is over
+    pub fn shutdown(&self) {
+        self.drain.store(true, Ordering::SeqCst);
+        self.wake();
+    }
+
+    /// queue `cmd` for the event loop, the reply arrives on the returned receiver
+    ///
+    /// A forwarder that is done never replies, so the receiver should be read with a timeout.
+    pub fn command(&self, cmd: Command) -> mpsc::Receiver<Reply> {
+        let (tx, rx) = mpsc::channel();
+
+        if let Ok(mut x) = self.commands.lock() {
+            x.push((cmd, tx));
+        }
+
+        self.wake();
+        rx
+    }
+
+    fn wake(&self) {
+        if let Err(x) = self.readiness.set_readiness(Ready::readable()) {
+            error!("failed to wake the forwarder: {}", x);
+        }
+    }
 }
 
+/// Stage of the lifetime of a forwarder
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Phase {
+    /// not polled yet
+    Idle,
+    Running,
+    /// not accepting clients anymore, the pairs left are closed at the deadline
+    Draining(Instant),
+    Done,
+}
 
+const CONTROL: Token = Token(1);
+const SIGNAL: Token = Token(2);
+const METRICS: Token = Token(3);
+const PROBE: Token = Token(4);
+const WATCH: Token = Token(5);
+/// longest wait for events while the health of the event loop is reported
+const TICK: Duration = Duration::from_secs(1);
+
+/// pooled connections of a route dropped in a row for receiving data unasked, before the route is no longer pooled
+const MAX_BANNERS: usize = 3;
+/// listener of the n-th route is polled under `LISTENERS + n * MAX_BINDS`
+const LISTENERS: usize = 16;
+/// sockets a single listener can be bound to, each is polled under a token of its own
+pub const MAX_BINDS: usize = 16;
+/// routes a single forwarder can serve
+pub const MAX_ROUTES: usize = 64;
+/// tokens below are used by the forwarder itself, pairs get the ones above
+const RESERVED_TOKENS: usize = LISTENERS + MAX_ROUTES * MAX_BINDS;
+/// longest wait between two attempts to connect the upstream of a client
+const MAX_BACKOFF: Duration = Duration::from_secs(2);
+
+#[derive(Clone)]
 pub struct FwConf {
     capacity: usize,
     event_buffer_size: usize,
     client_buffer_size: usize,
+    workers: usize,
+    drain_timeout: Duration,
+    access_log: Option<AccessLog>,
+    /// bound once, every worker serves it through a copy
+    metrics: Option<Arc<StdTcpListener>>,
+    admin: Option<Arc<StdUnixListener>>,
+    ready_check: Option<ReadyCheck>,
+    ready_interval: Duration,
+    retry: Option<RetryConf>,
+    pool: usize,
+    pool_max_idle: Duration,
+}
+
+/// Listener served by a forwarder together with the connector its clients are forwarded to
+pub struct Route<LL, SS> {
+    name: String,
+    /// dropped once draining starts, so that new clients are refused by the kernel
+    listener: Option<LL>,
+    connector: SS,
+    /// pairs the route may have open at the same time
+    limit: Option<usize>,
+    active: usize,
+    /// outcome of the last readiness check of the upstream
+    ready: Result<(), String>,
+    /// pooled connections dropped in a row because the upstream spoke first, e.g. with an SSH banner
+    banners: usize,
+}
+
+/// What became of an idle upstream connection
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Idle {
+    Usable,
+    Closed,
+    /// the upstream sent data nobody asked for
+    Spoke,
 }
 
 pub struct Fw<
@@ -102,13 +472,43 @@ pub struct Fw<
     SS: Connector<C=Sc, PC=Sp, Err=Se>,
 >
 {
-    listener: LL,
-    connector: SS,
+    routes: Vec<Route<LL, SS>>,
     conns: HashMap<usize, Pair<Le, Lc, Lp, Se, Sc, Sp>>,
     poll: Poll,
-    next_conn_id: usize,
+    stats: Arc<FwStats>,
+    ctl: FwCtl,
+    ctl_registration: Registration,
+    signals: Option<Signals>,
+    drain_timeout: Duration,
+    access_log: Option<AccessLog>,
+    metrics: Option<MetricsServer>,
+    heartbeat: Arc<AtomicU64>,
+    ready_check: Option<ReadyCheck>,
+    ready_interval: Duration,
+    probe: Option<Probe<Se, Sc, Sp>>,
+    /// health checks of the upstreams of the routes, by the connection id they are polled under
+    checks: HashMap<usize, UpstreamCheck<Se, Sc, Sp>>,
+    retry: Option<RetryConf>,
+    /// pairs whose upstream is retried
+    waiting: Vec<usize>,
+    /// upstream connections each route keeps open ahead of its clients
+    pool: usize,
+    pool_max_idle: Duration,
+    /// pooled upstream connections, by the connection id they are polled under
+    warm: HashMap<usize, Warm<Se, Sc, Sp>>,
+    /// pools are not filled before, so that an upstream that is down is not connected over and over
+    next_fill: Instant,
+    watch: Option<SocketWatch>,
+    /// route, upstream and path of the sockets being watched, by the key they were added to the watch with
+    watched: Vec<(usize, usize, String)>,
+    /// route whose upstream is checked next
+    probe_route: usize,
+    next_probe: Instant,
+    phase: Phase,
+    /// listeners are polled for new clients
+    accepting: bool,
+    events: Events,
 
-    event_buffer_size: usize,
     client_buffer_size: usize,
 }
 
@@ -117,20 +517,57 @@ pub trait Chan {
     type Err: Debug;
     fn send(&mut self, buff: &[u8]) -> Result<usize, FwError<Self::Err>>;
     fn recv(&mut self, buff: &mut [u8]) -> Result<Option<usize>, FwError<Self::Err>>;
+
+    /// channel holds data that was already taken off the socket and will be returned by `recv`
+    fn pending(&self) -> bool {
+        false
+    }
+
+    /// address of the remote end, if it has one
+    fn peer(&self) -> Option<String> {
+        None
+    }
+
+    /// identity the remote end authenticated with, e.g. the subject of its TLS certificate
+    fn identity(&self) -> Option<String> {
+        None
+    }
+
+    /// destination requested by the client, for protocols that carry one
+    fn dest(&self) -> Option<&Dest> {
+        None
+    }
+
+    /// tell the client whether its destination could be connected, called before any data is forwarded
+    fn routed(&mut self, _res: Result<(), RouteErr>) -> Result<(), FwError<Self::Err>> {
+        Ok(())
+    }
 }
 
 pub trait Pollable {
     /// return a pollable instance to put into `Poll` instance
+    ///
+    /// Listeners bound to several sockets register them under `tok` and the tokens that follow, up to `MAX_BINDS`.
     fn register(&self, poll: &Poll, tok: usize) -> Result<(), IoError>;
     fn deregister(&self, poll: &Poll) -> Result<(), IoError>;
 }
 
+/// Listener whose socket can be shared with the event loops of other worker threads
+pub trait SharedListener: Listener + Sized {
+    fn try_clone(&self) -> Result<Self, IoError>;
+}
+
 /// This channel is still initializing
 pub trait MidChan {
     type Err: Debug;
     type C: Chan<Err=Self::Err>;
 
-    fn try_channel(self, poll: &Poll) -> Result<NextState<Self::Err, Self::C, Self>, FwError<Self::Err>>
+    /// address of the remote end, if it has one
+    fn peer(&self) -> Option<String> {
+        None
+    }
+
+    fn try_channel(self, poll: &Poll) -> NextResult<Self::Err, Self::C, Self>
         where Self: std::marker::Sized;
 }
 
@@ -139,15 +576,53 @@ pub trait Listener {
     type C: Chan<Err=Self::Err>;
     type PC: MidChan<Err=Self::Err, C=Self::C>;
     /// accept a single connection and return it
-    fn accept(&mut self) -> Result<Option<NextState<Self::Err, Self::C, Self::PC>>, FwError<Self::Err>>;
+    fn accept(&mut self) -> Accepted<Self::Err, Self::C, Self::PC>;
+
+    /// load the certificates and other files the listener was set up from again
+    fn reload(&mut self) -> Result<(), FwError<Self::Err>> {
+        Ok(())
+    }
 }
 
 pub trait Connector {
     type Err: Debug;
     type C: Chan<Err=Self::Err>;
     type PC: MidChan<C=Self::C, Err=Self::Err>;
-    /// create a single connection and return it
-    fn connect(&mut self) -> Result<NextState<Self::Err, Self::C, Self::PC>, FwError<Self::Err>>;
+    /// create a single connection for the client described by `info` and return it
+    fn connect(&mut self, info: &ConnInfo) -> NextResult<Self::Err, Self::C, Self::PC>;
+
+    /// upstream depends on the client (e.g. the destination it requested),
+    /// so it is only connected once the client channel is active
+    fn deferred(&self) -> bool {
+        false
+    }
+
+    /// upstream connections may be opened ahead of the client they are given to,
+    /// which is not the case when they are tied to the client, e.g. log under its connection id
+    fn pooled(&self) -> bool {
+        !self.deferred()
+    }
+
+    /// upstreams the connector picks from, shared by the copies of the connector
+    fn upstreams(&self) -> Option<Arc<[Upstream]>> {
+        None
+    }
+
+    /// how the event loop checks `upstreams()` in the background, `None` leaves them unchecked
+    fn health(&self) -> Option<&HealthConf> {
+        None
+    }
+
+    /// Unix sockets the event loop watches for being removed and created again,
+    /// with the index into `upstreams()` of the upstream each belongs to, 0 if there is a single one
+    fn sockets(&self) -> Vec<(usize, String)> {
+        Vec::new()
+    }
+
+    /// connect the upstream `idx` of `upstreams()` and nothing else, to check it
+    fn connect_upstream(&mut self, _idx: usize, info: &ConnInfo) -> NextResult<Self::Err, Self::C, Self::PC> {
+        self.connect(info)
+    }
 }
 
 
@@ -160,6 +635,7 @@ State<E, A, B> {
     pub fn is_active(&self) -> bool {
         match &self {
             State::Active(_) => true,
+            State::Idle => false,
             State::Pending(_) => false,
             State::Lost => false,
             State::Swapping => unreachable!("must never happen"),
@@ -172,6 +648,28 @@ State<E, A, B> {
             _=> unreachable!("must never happen 1"),
         }
     }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            State::Idle => "Idle",
+            State::Pending(_) => "Pending",
+            State::Active(_) => "Active",
+            State::Swapping => "Swapping",
+            State::Lost => "Lost",
+        }
+    }
+
+    pub fn is_pending(&self) -> bool {
+        matches!(self, State::Pending(_))
+    }
+
+    pub fn peer(&self) -> Option<String> {
+        match self {
+            State::Active(x) => x.peer(),
+            State::Pending(x) => x.peer(),
+            _ => None,
+        }
+    }
 }
 
 impl<E: Debug, A: Chan<Err=E> + Pollable, B: MidChan<Err=E, C=A> + Pollable>
@@ -193,20 +691,22 @@ impl<
 Pollable for
 State<E, A, B> {
     fn register(&self, poll: &Poll, tok: usize) -> Result<(), IoError> {
-        return match self {
-            State::Active(x) => x.register(&poll, tok),
-            State::Pending(x) => x.register(&poll, tok),
+        match self {
+            State::Active(x) => x.register(poll, tok),
+            State::Pending(x) => x.register(poll, tok),
+            State::Idle => Ok(()),
             x => unreachable!("{:?} 2", x),
-        };
+        }
     }
 
     fn deregister(&self, poll: &Poll) -> Result<(), IoError> {
-        return match self {
-            State::Active(x) => x.deregister(&poll),
-            State::Pending(x) => x.deregister(&poll),
+        match self {
+            State::Active(x) => x.deregister(poll),
+            State::Pending(x) => x.deregister(poll),
+            State::Idle => Ok(()),
             State::Lost => Ok(()),
             x => unreachable!("{:?} 3", x),
-        };
+        }
     }
 }
 
@@ -231,10 +731,76 @@ Pair<Le, Lc, Lp, Se, Sc, Sp> {
             i += 1;
         }
 
-        return i
+        i
+    }
+
+    /// number of channels still connecting or in a handshake
+    pub fn pendings(&self) -> usize {
+        self.ca.is_pending() as usize + self.cb.is_pending() as usize
+    }
+}
+
+
+impl<LL, SS> Route<LL, SS> {
+    pub fn new(name: &str, listener: LL, connector: SS) -> Self {
+        Route {
+            name: name.to_string(),
+            listener: Some(listener),
+            connector,
+            limit: None,
+            active: 0,
+            ready: Ok(()),
+            banners: 0,
+        }
+    }
+
+    /// drop the clients accepted while `limit` pairs of the route are open
+    pub fn with_limit(mut self, limit: usize) -> Self {
+        self.limit = Some(limit);
+        self
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+}
+
+impl<LL, SS: Connector> Route<LL, SS> {
+    /// whether upstream connections are opened ahead of the clients
+    fn pooled(&self) -> bool {
+        self.connector.pooled() && self.banners < MAX_BANNERS
+    }
+}
+
+impl<LL: SharedListener, SS: Clone> Route<LL, SS> {
+    /// copy of the route for another worker, sharing the listening socket
+    pub fn try_clone(&self) -> Result<Self, IoError> {
+        let listener = match &self.listener {
+            Some(x) => Some(x.try_clone()?),
+            None => None,
+        };
+
+        Ok(Route {
+            name: self.name.clone(),
+            listener,
+            connector: self.connector.clone(),
+            limit: self.limit,
+            active: 0,
+            ready: Ok(()),
+            banners: 0,
+        })
     }
 }
 
+impl FwConf {
+    pub fn workers(&self) -> usize {
+        self.workers
+    }
+
+    pub fn admin(&self) -> Option<&StdUnixListener> {
+        self.admin.as_deref()
+    }
+}
 
 impl Parsable<Result<FwConf, FwConfError>> for FwConf {
     fn parser<'a, 'b>(app: App<'a, 'b>) -> App<'a, 'b> {
@@ -257,27 +823,191 @@ impl Parsable<Result<FwConf, FwConfError>> for FwConf {
                     .default_value("2048")
                     .required(false)
             )
+            .arg(
+                Arg::with_name("drain_timeout")
+                    .long("drain-timeout")
+                    .help("seconds the open connections may take to finish on SIGTERM/SIGINT before they are closed")
+                    .default_value("30")
+                    .required(false)
+            )
+            .arg(
+                Arg::with_name("access_log")
+                    .long("access-log")
+                    .help("file receiving a line per closed connection")
+                    .takes_value(true)
+                    .required(false)
+            )
+            .arg(
+                Arg::with_name("access_log_format")
+                    .long("access-log-format")
+                    .possible_values(&["text", "json"])
+                    .default_value("text")
+                    .required(false)
+            )
+            .arg(
+                Arg::with_name("metrics")
+                    .long("metrics")
+                    .help("address of an HTTP listener exporting Prometheus metrics on /metrics")
+                    .takes_value(true)
+                    .required(false)
+            )
+            .arg(
+                Arg::with_name("ready_check")
+                    .long("ready-check")
+                    .help("what /readyz checks on the upstream, it only reflects whether clients are accepted otherwise")
+                    .possible_values(&["connect", "docker-ping"])
+                    .takes_value(true)
+                    .required(false)
+            )
+            .arg(
+                Arg::with_name("ready_interval")
+                    .long("ready-interval")
+                    .help("seconds between two readiness checks")
+                    .default_value("5")
+                    .required(false)
+            )
+            .arg(
+                Arg::with_name("connect_retry")
+                    .long("connect-retry")
+                    .help("seconds a client is kept while its upstream cannot be connected, retrying with a backoff")
+                    .default_value("0")
+                    .required(false)
+            )
+            .arg(
+                Arg::with_name("connect_backoff")
+                    .long("connect-backoff")
+                    .help("milliseconds before the first retry, doubled after each failed one")
+                    .default_value("100")
+                    .required(false)
+            )
+            .arg(
+                Arg::with_name("pool")
+                    .long("pool")
+                    .help("upstream connections each worker keeps open per route ahead of the clients")
+                    .default_value("0")
+                    .required(false)
+            )
+            .arg(
+                Arg::with_name("pool_max_idle")
+                    .long("pool-max-idle")
+                    .help("seconds a pooled upstream connection may wait for a client before it is replaced")
+                    .default_value("30")
+                    .required(false)
+            )
+            .arg(
+                Arg::with_name("admin")
+                    .long("admin")
+                    .help("path of a unix socket taking commands from `sfw ctl`")
+                    .takes_value(true)
+                    .required(false)
+            )
+            .arg(
+                Arg::with_name("workers")
+                    .long("workers")
+                    .help("number of event loops sharing the listener, each running in its own thread")
+                    .default_value("1")
+                    .required(false)
+            )
     }
 
     fn parse<'a>(matches: &ArgMatches) -> Result<FwConf, FwConfError> {
         let event_buffer_size = matches.value_of("event_buffer_size").ok_or("event_buffer_size")?;
         let client_buffer_size = matches.value_of("client_buffer_size").ok_or("client_buffer_size")?;
         let capacity = matches.value_of("capacity").ok_or("capacity")?;
+        let workers = matches.value_of("workers").ok_or("workers")?;
+        let drain_timeout = matches.value_of("drain_timeout").ok_or("drain_timeout")?;
 
         let event_buffer_size = event_buffer_size.parse::<usize>().map_err(|_| "event_buffer_size")?;
         let client_buffer_size = client_buffer_size.parse::<usize>().map_err(|_| "client_buffer_size")?;
         let capacity = capacity.parse::<usize>().map_err(|_| "capacity")?;
+        let workers = workers.parse::<usize>().map_err(|_| "workers")?;
+        let drain_timeout = Duration::from_secs(drain_timeout.parse::<u64>().map_err(|_| "drain_timeout")?);
+
+        if workers == 0 {
+            return Err("workers".into());
+        }
+
+        let access_log = match matches.value_of("access_log") {
+            Some(path) => {
+                let format = matches.value_of("access_log_format").ok_or("access_log_format")?;
+                let format = format.parse::<LogFormat>().map_err(|_| "access_log_format")?;
+
+                Some(AccessLog::open(path, format).map_err(|x| FwConfError::File(path.to_string(), x))?)
+            }
+            None => None,
+        };
+
+        let metrics = match matches.value_of("metrics") {
+            Some(addr) => {
+                let addr = addr.parse::<SocketAddr>().map_err(|_| "metrics")?;
+
+                Some(Arc::new(StdTcpListener::bind(addr).map_err(|x| FwConfError::Bind(addr.to_string(), x))?))
+            }
+            None => None,
+        };
+
+        let admin = match matches.value_of("admin") {
+            Some(path) => {
+                // a socket left behind by a previous run would fail the bind
+                if fs::symlink_metadata(path).map(|x| x.file_type().is_socket()).unwrap_or(false) {
+                    fs::remove_file(path).map_err(|x| FwConfError::Bind(path.to_string(), x))?;
+                }
+
+                Some(Arc::new(StdUnixListener::bind(path).map_err(|x| FwConfError::Bind(path.to_string(), x))?))
+            }
+            None => None,
+        };
+
+        let ready_check = match matches.value_of("ready_check") {
+            Some(x) => Some(x.parse::<ReadyCheck>().map_err(|_| "ready_check")?),
+            None => None,
+        };
+
+        let ready_interval = matches.value_of("ready_interval").ok_or("ready_interval")?;
+        let ready_interval = Duration::from_secs(ready_interval.parse::<u64>().map_err(|_| "ready_interval")?);
+
+        if ready_interval.as_secs() == 0 {
+            return Err("ready_interval".into());
+        }
+
+        let connect_retry = matches.value_of("connect_retry").ok_or("connect_retry")?;
+        let connect_retry = Duration::from_secs(connect_retry.parse::<u64>().map_err(|_| "connect_retry")?);
+
+        let connect_backoff = matches.value_of("connect_backoff").ok_or("connect_backoff")?;
+        let connect_backoff = Duration::from_millis(connect_backoff.parse::<u64>().map_err(|_| "connect_backoff")?);
+
+        if connect_backoff.as_millis() == 0 {
+            return Err("connect_backoff".into());
+        }
+
+        let retry = match connect_retry.as_secs() {
+            0 => None,
+            _ => Some(RetryConf { timeout: connect_retry, backoff: connect_backoff }),
+        };
+
+        let pool = matches.value_of("pool").ok_or("pool")?;
+        let pool = pool.parse::<usize>().map_err(|_| "pool")?;
+
+        let pool_max_idle = matches.value_of("pool_max_idle").ok_or("pool_max_idle")?;
+        let pool_max_idle = Duration::from_secs(pool_max_idle.parse::<u64>().map_err(|_| "pool_max_idle")?);
+
+        if pool_max_idle.as_secs() == 0 {
+            return Err("pool_max_idle".into());
+        }
 
         Ok(
-            FwConf { capacity, event_buffer_size, client_buffer_size }
+            FwConf {
+                capacity, event_buffer_size, client_buffer_size, workers, drain_timeout, access_log, metrics, admin,
+                ready_check, ready_interval, retry, pool, pool_max_idle,
+            }
         )
     }
 }
 
 impl
 <
-    Le: Debug, Lc: Chan<Err=Le> + Pollable, Lp: MidChan<C=Lc, Err=Le> + Pollable,
-    Se: Debug, Sc: Chan<Err=Se> + Pollable, Sp: MidChan<C=Sc, Err=Se> + Pollable,
+    Le: Debug + Display + Classify, Lc: Chan<Err=Le> + Pollable, Lp: MidChan<C=Lc, Err=Le> + Pollable,
+    Se: Debug + Display + Classify, Sc: Chan<Err=Se> + Pollable, Sp: MidChan<C=Sc, Err=Se> + Pollable,
     LL: Listener<C=Lc, PC=Lp, Err=Le> + Pollable,
     SS: Connector<C=Sc, PC=Sp, Err=Se>,
 >
@@ -287,7 +1017,35 @@ Fw<Le, Lc, Lp, Se, Sc, Sp, LL, SS> {
         listener: LL,
         connector: SS,
     ) -> Result<Self, IoError> {
-        Self::new(listener, connector, conf.capacity, conf.event_buffer_size, conf.client_buffer_size)
+        Self::from_conf_routes(conf, vec![Route::new("default", listener, connector)])
+    }
+
+    /// serve all of `routes` from the same event loop
+    pub fn from_conf_routes(conf: &FwConf, routes: Vec<Route<LL, SS>>) -> Result<Self, IoError> {
+        let mut fw = Self::new_routes(routes, conf.capacity, conf.event_buffer_size, conf.client_buffer_size)?
+            .with_drain_timeout(conf.drain_timeout);
+
+        if let Some(access_log) = &conf.access_log {
+            fw = fw.with_access_log(access_log.clone());
+        }
+
+        if let Some(metrics) = &conf.metrics {
+            fw = fw.with_metrics(MetricsServer::new(metrics.try_clone()?)?);
+        }
+
+        if let Some(check) = &conf.ready_check {
+            fw = fw.with_ready_check(check.clone(), conf.ready_interval);
+        }
+
+        if let Some(retry) = conf.retry {
+            fw = fw.with_retry(retry);
+        }
+
+        if conf.pool > 0 {
+            fw = fw.with_pool(conf.pool, conf.pool_max_idle);
+        }
+
+        Ok(fw)
     }
 
     pub fn new(
@@ -297,36 +1055,132 @@ Fw<Le, Lc, Lp, Se, Sc, Sp, LL, SS> {
         event_buffer_size: usize,
         client_buffer_size: usize,
     ) -> Result<Self, IoError> {
-        Ok(Fw {
-            poll: Poll::new()?,
-            listener,
-            connector,
-            conns: HashMap::<usize, Pair<Le, Lc, Lp, Se, Sc, Sp>>::with_capacity(capacity),
-            next_conn_id: 1,
-            event_buffer_size,
-            client_buffer_size,
+        Self::new_routes(vec![Route::new("default", listener, connector)], capacity, event_buffer_size, client_buffer_size)
+    }
+
+    pub fn new_routes(
+        routes: Vec<Route<LL, SS>>,
+        capacity: usize,
+        event_buffer_size: usize,
+        client_buffer_size: usize,
+    ) -> Result<Self, IoError> {
+        if routes.is_empty() || routes.len() > MAX_ROUTES {
+            return Err(IoError::new(ErrorKind::InvalidInput, format!("expected 1 to {} routes", MAX_ROUTES)));
+        }
+
+        let (ctl_registration, readiness) = Registration::new2();
+
+        Ok(Fw {
+            poll: Poll::new()?,
+            routes,
+            conns: HashMap::<usize, Pair<Le, Lc, Lp, Se, Sc, Sp>>::with_capacity(capacity),
+            stats: Arc::new(FwStats::default()),
+            ctl: FwCtl {
+                readiness,
+                stop: Arc::new(AtomicBool::new(false)),
+                drain: Arc::new(AtomicBool::new(false)),
+                commands: Arc::new(Mutex::new(Vec::new())),
+            },
+            ctl_registration,
+            signals: None,
+            drain_timeout: Duration::from_secs(30),
+            access_log: None,
+            metrics: None,
+            heartbeat: Arc::new(AtomicU64::new(0)),
+            ready_check: None,
+            ready_interval: Duration::from_secs(5),
+            probe: None,
+            checks: HashMap::new(),
+            retry: None,
+            waiting: Vec::new(),
+            pool: 0,
+            pool_max_idle: Duration::from_secs(30),
+            warm: HashMap::new(),
+            next_fill: Instant::now(),
+            watch: None,
+            watched: Vec::new(),
+            probe_route: 0,
+            next_probe: Instant::now(),
+            phase: Phase::Idle,
+            accepting: true,
+            events: Events::with_capacity(event_buffer_size),
+            client_buffer_size,
         })
     }
 
-    fn get(
-        conns: &mut HashMap<usize, Pair<Le, Lc, Lp, Se, Sc, Sp>>,
-        idx: usize) ->
-        Result<&mut Pair<Le, Lc, Lp, Se, Sc, Sp>, FwPairError<Le, Se>> {
+    /// count into `stats` instead of the counters of this forwarder alone
+    pub fn with_stats(mut self, stats: Arc<FwStats>) -> Self {
+        self.stats = stats;
+        self
+    }
+
+    /// shut down gracefully on SIGTERM/SIGINT, a second signal closes the remaining pairs right away
+    pub fn with_signals(mut self, signals: Signals) -> Self {
+        self.signals = Some(signals);
+        self
+    }
+
+    pub fn with_drain_timeout(mut self, drain_timeout: Duration) -> Self {
+        self.drain_timeout = drain_timeout;
+        self
+    }
+
+    /// write a record per closed pair into `access_log`
+    pub fn with_access_log(mut self, access_log: AccessLog) -> Self {
+        self.access_log = Some(access_log);
+        self
+    }
+
+    /// serve the counters over HTTP from this forwarder's event loop
+    pub fn with_metrics(mut self, metrics: MetricsServer) -> Self {
+        self.metrics = Some(metrics);
+        self
+    }
+
+    /// report ready only while `check` on the upstream succeeds, it is repeated every `interval`
+    pub fn with_ready_check(mut self, check: ReadyCheck, interval: Duration) -> Self {
+        self.ready_check = Some(check);
+        self.ready_interval = interval;
+        self
+    }
+
+    /// keep clients whose upstream cannot be connected and try again, as described by `retry`
+    pub fn with_retry(mut self, retry: RetryConf) -> Self {
+        self.retry = Some(retry);
+        self
+    }
+
+    /// keep `size` upstream connections of every route open for the next clients, replaced after `max_idle`.
+    /// Routes whose connector is deferred or ties connections to their client are not pooled,
+    /// and neither are those whose upstream speaks first.
+    pub fn with_pool(mut self, size: usize, max_idle: Duration) -> Self {
+        self.pool = size;
+        self.pool_max_idle = max_idle;
+        self
+    }
+
+    pub fn stats(&self) -> &Arc<FwStats> {
+        &self.stats
+    }
 
-        Ok(conns.get_mut(&idx).ok_or(FwPairError::Lost)?)
+    pub fn ctl(&self) -> FwCtl {
+        self.ctl.clone()
     }
 
+
+    // `is_multiple_of` needs Rust 1.87
+    #[allow(clippy::manual_is_multiple_of)]
     fn tok_to_conn(tok_idx: usize) -> (usize, bool) {
+        let tok_idx = tok_idx - RESERVED_TOKENS;
         let is_l = tok_idx % 2 == 0;
         let idx = tok_idx / 2;
 
         (idx, is_l)
     }
 
-    fn create_conn_idents(&mut self) -> (usize, usize, usize) {
-        let idx = self.next_conn_id;
-        self.next_conn_id += 1;
-        let tok_a = idx * 2;
+    fn create_conn_idents(stats: &FwStats) -> (usize, usize, usize) {
+        let idx = stats.conn_ids.fetch_add(1, Ordering::Relaxed) + 1;
+        let tok_a = RESERVED_TOKENS + idx * 2;
         let tok_b = tok_a + 1;
         (idx, tok_a, tok_b)
     }
@@ -341,7 +1195,7 @@ Fw<Le, Lc, Lp, Se, Sc, Sp, LL, SS> {
 
         match prev {
             State::Pending(x) => {
-                match x.try_channel(&poll) {
+                match x.try_channel(poll) {
                     Ok(x) => match x {
                         NextState::Pending(x) => {
                             *st = State::Pending(x);
@@ -362,19 +1216,91 @@ Fw<Le, Lc, Lp, Se, Sc, Sp, LL, SS> {
         }
     }
 
-    fn accept(&mut self) -> Result<(), FwPairError<Le, Se>> {
-        if let Some(chan_l) = self.listener.accept().map_err(FwPairError::ml)? {
-            let chan_s = self.connector.connect().map_err(FwPairError::ms)?;
-            let (conn_id, tok_a, tok_b) = self.create_conn_idents();
+    fn accept(&mut self, route_idx: usize) -> Result<(), FwPairError<Le, Se>> {
+        let stats = &self.stats;
+        let retry_conf = self.retry;
 
-            let ca: State<_, _, _> = chan_l.into();
-            let cb: State<_, _, _> = chan_s.into();
+        let route = match self.routes.get_mut(route_idx) {
+            Some(x) => x,
+            None => return Ok(()),
+        };
+
+        let chan_l = match &mut route.listener {
+            Some(x) => x.accept().map_err(|x| {
+                stats.handshake_failed(&x);
+                FwPairError::ml(x)
+            })?,
+            None => None,
+        };
+
+        if let Some(chan_l) = chan_l {
+            let mut ca: State<_, _, _> = chan_l.into();
+            let peer = ca.peer();
+
+            if let Some(limit) = route.limit {
+                if route.active >= limit {
+                    warn!(route = route.name.as_str(), peer = peer.as_deref().unwrap_or("-"), limit; "too many connections, client dropped");
+                    stats.rejected.fetch_add(1, Ordering::Relaxed);
+                    return Ok(());
+                }
+            }
+
+            let (conn_id, tok_a, tok_b) = Self::create_conn_idents(stats);
+
+            let deferred = route.connector.deferred();
+
+            let identity = if ca.is_active() { ca.chan().identity() } else { None };
+
+            info!(conn_id, route = route.name.as_str(), peer = peer.as_deref().unwrap_or("-"); "accepted");
+
+            let mut retry = None;
+
+            let warm = match route.pooled() {
+                true => Self::take_warm(&mut self.warm, &self.poll, stats, route_idx, route, &mut self.next_fill),
+                false => None,
+            };
+
+            let cb: State<_, _, _> = if deferred {
+                State::Idle
+            } else if let Some(x) = warm {
+                debug!(conn_id; "paired with a pooled upstream connection");
+                x
+            } else {
+                let info = ConnInfo { conn_id, dest: None, peer: peer.as_deref(), identity: identity.as_deref() };
+
+                match route.connector.connect(&info) {
+                    Ok(x) => x.into(),
+                    Err(x) => match retry_conf {
+                        Some(conf) if !matches!(x, FwError::Denied) => {
+                            warn!(conn_id, peer = peer.as_deref().unwrap_or("-"); "upstream unavailable, retrying: {}", x);
+                            retry = Some(Retry::new(conf, Instant::now()));
+                            State::Idle
+                        }
+                        _ => {
+                            stats.upstream_failures.fetch_add(1, Ordering::Relaxed);
+                            return Err(FwPairError::ms(x));
+                        }
+                    }
+                }
+            };
+
+            // an active channel stays paused until the other side becomes active too
+            let both = ca.is_active() && cb.is_active();
+
+            if both || !ca.is_active() {
+                ca.register(&self.poll, tok_a).map_err(|x| FwPairError::L(FwError::Register(x)))?;
+            }
 
-            ca.register(&self.poll, tok_a).map_err(|x| FwPairError::L(FwError::Register(x)))?;
-            cb.register(&self.poll, tok_b).map_err(|x| FwPairError::S(FwError::Register(x)))?;
+            if both || !cb.is_active() {
+                cb.register(&self.poll, tok_b).map_err(|x| FwPairError::S(FwError::Register(x)))?;
+            }
 
             let pair = Pair {
-                conn_id: conn_id.clone(),
+                conn_id,
+                route: route_idx,
+                peer,
+                identity,
+                start: SystemTime::now(),
                 ca,
                 cb,
                 b: vec![0; self.client_buffer_size],
@@ -382,9 +1308,20 @@ Fw<Le, Lc, Lp, Se, Sc, Sp, LL, SS> {
                 rx: 0,
                 tok_a,
                 tok_b,
+                routed: false,
+                retry,
             };
 
-            self.conns.insert(conn_id.clone(), pair);
+            if pair.retry.is_some() {
+                self.waiting.push(conn_id);
+            }
+
+            stats.pending.fetch_add(pair.pendings(), Ordering::Relaxed);
+            self.conns.insert(conn_id, pair);
+
+            route.active += 1;
+            stats.accepted.fetch_add(1, Ordering::Relaxed);
+            stats.active.fetch_add(1, Ordering::Relaxed);
         }
         Ok(())
     }
@@ -401,26 +1338,123 @@ Fw<Le, Lc, Lp, Se, Sc, Sp, LL, SS> {
             }
         };
 
-        //dbg!(read);
-
         Ok(read)
     }
 
+    /// forward from `chan_a` to `chan_b` until there is nothing left to read, adding the bytes to `total`
     fn handle_rw <Re: Debug, We: Debug, R: Chan<Err=Re>, W: Chan<Err=We>>
-    (buff: &mut [u8], chan_a: &mut R, chan_b: &mut W) -> Result<Option<usize>, FwPairError<Re, We>> {
-        let mut total = 0;
+    (buff: &mut [u8], chan_a: &mut R, chan_b: &mut W, total: &mut u64) -> Result<(), FwPairError<Re, We>> {
         while let Some(x) = Self::handle_once(buff, chan_a, chan_b)? {
+            *total += x as u64;
+        }
+
+        Ok(())
+    }
+
+    /// connect the upstream of a pair, once its client channel became active if the connector is deferred,
+    /// or again once its retry is due. Failing with `retry` set schedules another attempt until the deadline.
+    fn connect_pair(
+        connector: &mut SS,
+        poll: &Poll,
+        stats: &FwStats,
+        retry: Option<RetryConf>,
+        pair: &mut Pair<Le, Lc, Lp, Se, Sc, Sp>,
+    ) -> Result<(), FwPairError<Le, Se>> {
+        let res = {
+            let dest = match pair.ca.is_active() {
+                true => pair.ca.chan().dest(),
+                false => None,
+            };
+
+            let info = ConnInfo {
+                conn_id: pair.conn_id,
+                dest,
+                peer: pair.peer.as_deref(),
+                identity: pair.identity.as_deref(),
+            };
+            connector.connect(&info)
+        };
+
+        match res {
+            Ok(x) => {
+                pair.cb = x.into();
+
+                // an upstream still connecting may fail yet, its retry is kept until then
+                if pair.cb.is_active() {
+                    Self::reached(pair);
+                }
+
+                // the client channel is paused while active on its own
+                if pair.cb.is_active() && pair.ca.is_active() {
+                    pair.ca.register(poll, pair.tok_a).map_err(|x| FwPairError::L(FwError::Register(x)))?;
+                }
+
+                if !pair.cb.is_active() || pair.ca.is_active() {
+                    pair.cb.register(poll, pair.tok_b).map_err(|x| FwPairError::S(FwError::Register(x)))?;
+                }
+
+                Ok(())
+            }
+            Err(err) => {
+                if Self::retry_later(retry, pair, &err) {
+                    return Ok(());
+                }
+
+                stats.upstream_failures.fetch_add(1, Ordering::Relaxed);
+
+                let reason = match err {
+                    FwError::Denied => RouteErr::Denied,
+                    _ => RouteErr::Unreachable,
+                };
+
+                if pair.ca.is_active() {
+                    pair.routed = true;
+                    pair.ca.chan().routed(Err(reason)).map_err(FwPairError::ml)?;
+                }
+
+                Err(FwPairError::S(err))
+            }
+        }
+    }
+
+    fn reached(pair: &mut Pair<Le, Lc, Lp, Se, Sc, Sp>) {
+        if let Some(x) = pair.retry.take() {
+            info!(conn_id = pair.conn_id, attempts = x.attempts; "upstream reached after retrying");
+        }
+    }
+
+    /// leave the upstream of `pair` idle and schedule another attempt to connect it, unless `err` is final
+    fn retry_later(retry: Option<RetryConf>, pair: &mut Pair<Le, Lc, Lp, Se, Sc, Sp>, err: &FwError<Se>) -> bool {
+        let conf = match retry {
+            Some(x) if !matches!(err, FwError::Denied) => x,
+            _ => return false,
+        };
+
+        let now = Instant::now();
 
-            total += x;
+        let again = match &mut pair.retry {
+            Some(x) => {
+                debug!(conn_id = pair.conn_id, attempts = x.attempts; "upstream still unavailable: {}", err);
+                x.next(now)
+            }
+            None => {
+                warn!(conn_id = pair.conn_id, peer = pair.peer.as_deref().unwrap_or("-"); "upstream unavailable, retrying: {}", err);
+                pair.retry = Some(Retry::new(conf, now));
+                true
+            }
+        };
+
+        if again {
+            pair.cb = State::Idle;
         }
 
-        Ok(Some(total))
+        again
     }
 
     fn polled(&mut self, idx: usize) -> Result<(), FwPairError<Le, Se>> {
         let (conn_idx, is_a) = Self::tok_to_conn(idx);
 
-        let pair = Self::get(&mut self.conns, conn_idx)?;
+        let pair = self.conns.get_mut(&conn_idx).ok_or(FwPairError::Lost)?;
 
         let actives = pair.actives();
 
@@ -429,145 +1463,1113 @@ Fw<Le, Lc, Lp, Se, Sc, Sp, LL, SS> {
             // one could think we'd need to keep the status of the channels active
             // but we already change activity flag in try_proceed
             if is_a && !pair.ca.is_active() {
-                let f = Self::try_proceed(&self.poll, &mut pair.ca).map_err(FwPairError::ml)?;
+                let stats = &self.stats;
+
+                let f = Self::try_proceed(&self.poll, &mut pair.ca).map_err(|x| {
+                    stats.handshake_failed(&x);
+                    FwPairError::ml(x)
+                })?;
 
                 if f {
+                    pair.identity = pair.ca.chan().identity();
+
                     if actives == 1 {
                         pair.cb.register(&self.poll, pair.tok_b).map_err(|x| FwPairError::L(FwError::Register(x)))?;
-                        //dbg!("enabling S");
                     } else {
                         pair.ca.deregister(&self.poll).map_err(|x| FwPairError::L(FwError::Register(x)))?;
-                        //dbg!("pausing L as S not ready");
+                    }
+
+                    // an upstream being retried is connected once the retry is due
+                    if let (State::Idle, None) = (&pair.cb, &pair.retry) {
+                        let connector = &mut self.routes[pair.route].connector;
+                        Self::connect_pair(connector, &self.poll, &self.stats, self.retry, pair)?;
+
+                        if pair.retry.is_some() {
+                            self.waiting.push(conn_idx);
+                        }
                     }
                 }
             } else if !is_a && !pair.cb.is_active() {
-                let f = Self::try_proceed(&self.poll, &mut pair.cb).map_err(FwPairError::ms)?;
+                let stats = &self.stats;
+
+                let f = match Self::try_proceed(&self.poll, &mut pair.cb) {
+                    Ok(x) => x,
+                    Err(x) => {
+                        if Self::retry_later(self.retry, pair, &x) {
+                            if !self.waiting.contains(&conn_idx) {
+                                self.waiting.push(conn_idx);
+                            }
+
+                            return Ok(());
+                        }
+
+                        stats.handshake_failed(&x);
+                        stats.upstream_failures.fetch_add(1, Ordering::Relaxed);
+                        return Err(FwPairError::ms(x));
+                    }
+                };
 
                 if f {
+                    Self::reached(pair);
+
                     if actives == 1 {
                         pair.ca.register(&self.poll, pair.tok_a).map_err(|x| FwPairError::S(FwError::Register(x)))?;
-                        //dbg!("enabling L");
                     } else {
                         pair.cb.deregister(&self.poll).map_err(|x| FwPairError::S(FwError::Register(x)))?;
-                        //dbg!("pausing S as L not ready");
                     }
                 }
-            } {
-                //dbg!((is_a, pair.ca.is_active(), pair.cb.is_active()));
             }
         }
 
         if pair.actives() == 2 {
-            if is_a {
-                Self::handle_rw(
+            // a pair that has just become active may already hold data read during the handshakes
+            let fresh = actives < 2;
+
+            if !pair.routed {
+                pair.routed = true;
+                pair.ca.chan().routed(Ok(())).map_err(FwPairError::ml)?;
+            }
+
+            if is_a || fresh || pair.ca.chan().pending() {
+                let before = pair.rx;
+
+                let res = Self::handle_rw(
                     &mut pair.b,
                     pair.ca.chan(),
                     pair.cb.chan(),
-                )?;
-            } else {
-                Self::handle_rw(
+                    &mut pair.rx,
+                );
+
+                self.stats.rx.fetch_add(pair.rx - before, Ordering::Relaxed);
+                res?;
+            }
+
+            if !is_a || fresh || pair.cb.chan().pending() {
+                let before = pair.tx;
+
+                let res = Self::handle_rw(
                     &mut pair.b,
                     pair.cb.chan(),
                     pair.ca.chan(),
-                ).map_err(|x| x.swap())?;
-            }
+                    &mut pair.tx,
+                );
 
+                self.stats.tx.fetch_add(pair.tx - before, Ordering::Relaxed);
+                res.map_err(|x| x.swap())?;
+            }
         }
 
         Ok(())
     }
 
-    pub fn free(&mut self, conn_idx: usize) {
-        match Self::get(&mut self.conns, conn_idx) {
-            Ok(pair) => {
-                if let Err(err_a) = pair.ca.deregister(&self.poll) {
-                    dbg!(("err_a", conn_idx, err_a));
+    pub fn free(&mut self, conn_idx: usize, reason: CloseReason) {
+        match self.conns.get_mut(&conn_idx) {
+            Some(pair) => {
+                let peer = pair.peer.as_deref().unwrap_or("-");
+
+                if !pair.routed && pair.ca.is_active() {
+                    pair.routed = true;
+
+                    if let Err(err) = pair.ca.chan().routed(Err(RouteErr::Unreachable)) {
+                        debug!(conn_id = conn_idx, peer, side = "L"; "failed to report the route: {}", err);
+                    }
+                }
+
+                if let Err(err) = pair.ca.deregister(&self.poll) {
+                    debug!(conn_id = conn_idx, peer, side = "L"; "failed to deregister: {}", err);
                 };
 
-                if let Err(err_b) = pair.cb.deregister(&self.poll) {
-                    dbg!(("err_b", conn_idx, err_b));
+                if let Err(err) = pair.cb.deregister(&self.poll) {
+                    debug!(conn_id = conn_idx, peer, side = "S"; "failed to deregister: {}", err);
                 };
             },
-            Err(fetch_error) => {
-                dbg!(("fetch_error", conn_idx, fetch_error));
+            None => {
+                debug!(conn_id = conn_idx; "already freed");
+                return;
             }
         };
 
-        if self.conns.remove(&conn_idx).is_none() {
-            dbg!(("Disconnect", conn_idx, "Already freed"));
+        if let Some(pair) = self.conns.remove(&conn_idx) {
+            if let Some(route) = self.routes.get_mut(pair.route) {
+                route.active -= 1;
+            }
+
+            self.stats.active.fetch_sub(1, Ordering::Relaxed);
+            self.stats.pending.fetch_sub(pair.pendings(), Ordering::Relaxed);
+
+            info!(conn_id = conn_idx, peer = pair.peer.as_deref().unwrap_or("-"), active = self.conns.len(), reason:%; "closed");
+
+            if let Some(access_log) = &self.access_log {
+                access_log.write(&AccessRecord {
+                    conn_id: conn_idx,
+                    peer: pair.peer.as_deref(),
+                    identity: pair.identity.as_deref(),
+                    start: pair.start,
+                    duration: pair.start.elapsed().unwrap_or_default(),
+                    rx: pair.rx,
+                    tx: pair.tx,
+                    reason,
+                });
+            }
+        }
+    }
+
+    /// stop accepting clients, the pairs left are closed once `drain_timeout` is over
+    fn drain(&mut self) {
+        if let Phase::Draining(_) = self.phase {
+            return;
+        }
+
+        for route in &mut self.routes {
+            if let Some(listener) = route.listener.take() {
+                if self.accepting {
+                    if let Err(x) = listener.deregister(&self.poll) {
+                        debug!(route = route.name.as_str(); "failed to deregister the listener: {}", x);
+                    }
+                }
+            }
         }
 
-        dbg!(("Disconnect", conn_idx, self.conns.len()));
+        self.phase = Phase::Draining(Instant::now() + self.drain_timeout);
+
+        info!(active = self.conns.len(), timeout = self.drain_timeout.as_secs(); "draining");
     }
 
-    pub fn run(&mut self) {
-        self.listener.register(&self.poll, 0).unwrap();
+    fn start(&mut self) -> Result<(), IoError> {
+        for (idx, route) in self.routes.iter().enumerate() {
+            if let Some(listener) = &route.listener {
+                if self.accepting {
+                    listener.register(&self.poll, LISTENERS + idx * MAX_BINDS)?;
+                }
+            }
+        }
+
+        self.poll.register(&self.ctl_registration, CONTROL, Ready::readable(), PollOpt::edge())?;
+
+        if let Some(signals) = &self.signals {
+            signals.register(&self.poll, SIGNAL.0)?;
+        }
 
-        let mut events = Events::with_capacity(self.event_buffer_size);
+        if let Some(metrics) = &self.metrics {
+            metrics.register(&self.poll, METRICS.0)?;
 
-        loop {
-            self.poll.poll(&mut events, None).unwrap();
+            self.heartbeat.store(health::now_millis(), Ordering::Relaxed);
 
-            for event in &events {
-                match event.token() {
-                    Token(0) => {
-                        if let Err(x) = self.accept() {
-                            dbg!(("accept", x));
-                        }
+            if let Ok(mut x) = self.stats.heartbeats.lock() {
+                x.push(self.heartbeat.clone());
+            }
+        }
+
+        for route in &self.routes {
+            if let Some(x) = route.connector.upstreams() {
+                self.stats.add_upstreams(&route.name, x);
+            }
+        }
+
+        for (route_idx, route) in self.routes.iter().enumerate() {
+            for (upstream, path) in route.connector.sockets() {
+                self.watched.push((route_idx, upstream, path));
+            }
+        }
+
+        if !self.watched.is_empty() {
+            let mut watch = SocketWatch::new()?;
+
+            for (key, (route_idx, _, path)) in self.watched.iter().enumerate() {
+                if let Err(x) = watch.add(path, key) {
+                    warn!(route = self.routes[*route_idx].name.as_str(), path = path.as_str(); "failed to watch the socket: {}", x);
+                }
+            }
+
+            watch.register(&self.poll, WATCH.0)?;
+            self.watch = Some(watch);
+        }
+
+        if self.ready_check.is_some() && self.routes.iter().all(|x| x.connector.deferred()) {
+            warn!("the upstream depends on the client, readiness is not checked");
+            self.ready_check = None;
+        }
+
+        if self.ready_check.is_some() {
+            for route in &mut self.routes {
+                route.ready = match route.connector.deferred() {
+                    true => {
+                        warn!(route = route.name.as_str(); "the upstream depends on the client, readiness is not checked");
+                        Ok(())
                     }
-                    Token(idx) => {
-                        if let Err(err) = self.polled(idx) {
-                            let (conn_idx, _) = Self::tok_to_conn(idx);
-                            match err {
-                                FwPairError::Disconnected => {
-                                    self.free(conn_idx);
-                                }
-                                other_err => {
-                                    dbg!(("other_err", conn_idx, other_err));
-                                    self.free(conn_idx);
-                                }
-                            }
-                        }
+                    false => Err("not checked yet".to_string()),
+                };
+            }
+        }
+
+        self.phase = Phase::Running;
+
+        Ok(())
+    }
+
+    /// close the remaining pairs
+    fn finish(&mut self) {
+        let conns: Vec<usize> = self.conns.keys().cloned().collect();
+
+        for conn_idx in conns {
+            self.free(conn_idx, CloseReason::Shutdown);
+        }
+
+        for route in &self.routes {
+            if let Some(listener) = &route.listener {
+                if self.accepting {
+                    if let Err(x) = listener.deregister(&self.poll) {
+                        debug!(route = route.name.as_str(); "failed to deregister the listener: {}", x);
                     }
                 }
             }
         }
-    }
-}
 
+        if let Some(metrics) = self.metrics.take() {
+            if let Err(x) = metrics.deregister(&self.poll) {
+                debug!("failed to deregister the metrics listener: {}", x);
+            }
+        }
 
+        if let Some(probe) = self.probe.take() {
+            probe.stop(&self.poll);
+        }
 
-impl From<&str> for FwConfError {
-    fn from(x: &str) -> FwConfError {
-        FwConfError::Str(x.to_string())
-    }
-}
+        for (_, x) in self.warm.drain() {
+            let _ = x.state.deregister(&self.poll);
+            self.stats.pooled.fetch_sub(1, Ordering::Relaxed);
+        }
 
-impl<E: Debug> From<E> for FwError<E> {
-    fn from(x: E) -> Self {
-        FwError::Io(x)
-    }
-}
+        if let Some(watch) = self.watch.take() {
+            if let Err(x) = watch.deregister(&self.poll) {
+                debug!("failed to deregister the socket watch: {}", x);
+            }
+        }
 
-impl<Le: Debug, Se: Debug> FwPairError<Le, Se> {
-    pub fn ml(x: FwError<Le>) -> Self {
-        FwPairError::L(x)
-    }
+        for (_, check) in self.checks.drain() {
+            check.probe.stop(&self.poll);
+        }
 
-    pub fn l(x: Le) -> Self {
-        FwPairError::L(FwError::Io(x))
+        self.heartbeat.store(0, Ordering::Relaxed);
+        self.phase = Phase::Done;
     }
 
-    pub fn ms(x: FwError<Se>) -> Self {
-        FwPairError::S(x)
-    }
+    fn handle(&mut self, tok: Token) {
+        match tok {
+            Token(idx) if idx >= LISTENERS && idx < LISTENERS + self.routes.len() * MAX_BINDS => {
+                let route_idx = (idx - LISTENERS) / MAX_BINDS;
 
-    pub fn s(x: Se) -> Self {
-        FwPairError::S(FwError::Io(x))
+                if let Err(x) = self.accept(route_idx) {
+                    warn!(route = self.routes[route_idx].name.as_str(), side = x.side(); "failed to accept: {}", x);
+                    self.stats.failed.fetch_add(1, Ordering::Relaxed);
+                    self.stats.rejected.fetch_add(1, Ordering::Relaxed);
+                }
+            }
+            CONTROL => {
+                if let Err(x) = self.ctl.readiness.set_readiness(Ready::empty()) {
+                    error!("failed to reset the control readiness: {}", x);
+                }
+
+                if self.ctl.drain.load(Ordering::SeqCst) {
+                    self.drain();
+                }
+
+                let commands = match self.ctl.commands.lock() {
+                    Ok(mut x) => mem::take(&mut *x),
+                    Err(_) => Vec::new(),
+                };
+
+                for (cmd, tx) in commands {
+                    let _ = tx.send(self.execute(cmd));
+                }
+            }
+            SIGNAL => {
+                if let Some(signals) = &mut self.signals {
+                    if let Err(x) = signals.take() {
+                        error!("failed to read the signals: {}", x);
+                    }
+                }
+
+                if let Phase::Draining(_) = self.phase {
+                    self.ctl.stop.store(true, Ordering::SeqCst);
+                }
+
+                self.drain();
+            }
+            METRICS => self.accept_metrics(),
+            PROBE => {
+                if let Some(probe) = &mut self.probe {
+                    let status = probe.poll(&self.poll);
+                    self.probed(status);
+                }
+            }
+            WATCH => {
+                let changes = match &mut self.watch {
+                    Some(x) => x.take().unwrap_or_else(|x| {
+                        error!("failed to read the socket watch: {}", x);
+                        Vec::new()
+                    }),
+                    None => Vec::new(),
+                };
+
+                for change in changes {
+                    self.socket_changed(change);
+                }
+            }
+            Token(idx) if idx < RESERVED_TOKENS => {}
+            Token(idx) => {
+                let (conn_idx, _) = Self::tok_to_conn(idx);
+
+                let ready = self.readiness();
+
+                if let Some(metrics) = &mut self.metrics {
+                    if metrics.owns(conn_idx) {
+                        let stats = &self.stats;
+                        metrics.handle(&self.poll, conn_idx, |head| Self::respond(stats, &ready, head));
+                        return;
+                    }
+                }
+
+                if self.warm.contains_key(&conn_idx) {
+                    self.warm_polled(conn_idx);
+                    return;
+                }
+
+                if let Some(check) = self.checks.get_mut(&conn_idx) {
+                    let status = check.probe.poll(&self.poll);
+                    self.checked(conn_idx, status);
+                    return;
+                }
+
+                // events left over for a pair that was closed while handling the same batch
+                if !self.conns.contains_key(&conn_idx) {
+                    return;
+                }
+
+                let before = self.conns.get(&conn_idx).map(|x| x.pendings()).unwrap_or(0);
+                let res = self.polled(idx);
+                let after = self.conns.get(&conn_idx).map(|x| x.pendings()).unwrap_or(0);
+
+                self.stats.pending.fetch_add(after, Ordering::Relaxed);
+                self.stats.pending.fetch_sub(before, Ordering::Relaxed);
+
+                if let Err(err) = res {
+                    let reason = CloseReason::from(&err);
+
+                    match err {
+                        FwPairError::Disconnected => {
+                            self.free(conn_idx, reason);
+                        }
+                        other_err => {
+                            let peer = self.conns.get(&conn_idx).and_then(|x| x.peer.as_deref()).unwrap_or("-");
+
+                            warn!(conn_id = conn_idx, peer, side = other_err.side(); "{}", other_err);
+                            self.stats.failed.fetch_add(1, Ordering::Relaxed);
+                            self.free(conn_idx, reason);
+                        }
+                    }
+                }
+            }
+        }
     }
 
-    pub fn swap(self) -> FwPairError<Se, Le> {
-        match self {
+    fn execute(&mut self, cmd: Command) -> Reply {
+        match cmd {
+            Command::List => {
+                let pairs = self.conns.values()
+                    .map(|x| PairInfo {
+                        conn_id: x.conn_id,
+                        route: self.routes[x.route].name.clone(),
+                        state: (x.ca.name(), x.cb.name()),
+                        peer: x.peer.clone(),
+                        identity: x.identity.clone(),
+                        age: x.start.elapsed().unwrap_or_default(),
+                        rx: x.rx,
+                        tx: x.tx,
+                    })
+                    .collect();
+
+                Reply::Pairs(pairs)
+            }
+            Command::Close(conn_id) => {
+                if !self.conns.contains_key(&conn_id) {
+                    return Reply::NotFound;
+                }
+
+                self.free(conn_id, CloseReason::Closed);
+                Reply::Done
+            }
+            Command::Pause | Command::Resume => {
+                let accepting = cmd == Command::Resume;
+
+                // not listening anymore or nothing to change
+                if self.accepting == accepting || self.routes.iter().all(|x| x.listener.is_none()) {
+                    return Reply::Done;
+                }
+
+                for (idx, route) in self.routes.iter().enumerate() {
+                    let res = match (&route.listener, accepting) {
+                        (Some(x), true) => x.register(&self.poll, LISTENERS + idx * MAX_BINDS),
+                        (Some(x), false) => x.deregister(&self.poll),
+                        (None, _) => Ok(()),
+                    };
+
+                    if let Err(x) = res {
+                        return Reply::Failed(format!("{}: {}", route.name, x));
+                    }
+                }
+
+                info!(accepting; "accepting toggled");
+                self.accepting = accepting;
+                Reply::Done
+            }
+            Command::Reload => {
+                let mut reloaded = false;
+
+                for route in &mut self.routes {
+                    if let Some(x) = &mut route.listener {
+                        if let Err(x) = x.reload() {
+                            return Reply::Failed(format!("{}: {}", route.name, x));
+                        }
+
+                        info!(route = route.name.as_str(); "listener reloaded");
+                        reloaded = true;
+                    }
+                }
+
+                match reloaded {
+                    true => Reply::Done,
+                    false => Reply::Failed("not listening".to_string()),
+                }
+            }
+        }
+    }
+
+    fn accept_metrics(&mut self) {
+        loop {
+            let stream = match &mut self.metrics {
+                Some(metrics) => metrics.accept(),
+                None => return,
+            };
+
+            match stream {
+                Ok(Some(stream)) => {
+                    let (conn_id, tok, _) = Self::create_conn_idents(&self.stats);
+
+                    if let Some(metrics) = &mut self.metrics {
+                        if let Err(x) = metrics.add(&self.poll, conn_id, tok, stream) {
+                            debug!(conn_id; "failed to register the metrics client: {}", x);
+                        }
+                    }
+                }
+                Ok(None) => return,
+                Err(x) => {
+                    debug!("failed to accept a metrics client: {}", x);
+                    return;
+                }
+            }
+        }
+    }
+
+    fn respond(stats: &FwStats, ready: &Result<(), String>, head: &Head) -> Vec<u8> {
+        match (head.method.as_str(), head.path()) {
+            ("GET", "/metrics") => metrics::respond_with("200 OK", "text/plain; version=0.0.4", &metrics::render(stats)),
+            ("GET", "/healthz") => match health::alive(stats) {
+                true => metrics::respond_with("200 OK", "text/plain", "ok\n"),
+                false => metrics::respond_with("503 Service Unavailable", "text/plain", "event loop stalled\n"),
+            },
+            ("GET", "/readyz") => match ready {
+                Ok(()) => metrics::respond_with("200 OK", "text/plain", "ready\n"),
+                Err(x) => metrics::respond_with("503 Service Unavailable", "text/plain", &format!("{}\n", x)),
+            },
+            _ => metrics::respond_with("404 Not Found", "text/plain", "not found\n"),
+        }
+    }
+
+    /// whether new clients would be served, with the reason if not
+    fn readiness(&self) -> Result<(), String> {
+        match self.phase {
+            Phase::Draining(_) | Phase::Done => return Err("shutting down".to_string()),
+            _ => {}
+        }
+
+        if !self.accepting {
+            return Err("paused".to_string());
+        }
+
+        for route in &self.routes {
+            if let Err(x) = &route.ready {
+                return match self.routes.len() {
+                    1 => Err(x.clone()),
+                    _ => Err(format!("{}: {}", route.name, x)),
+                };
+            }
+        }
+
+        Ok(())
+    }
+
+    fn probed(&mut self, status: ProbeStatus) {
+        let res = match status {
+            ProbeStatus::Waiting => return,
+            ProbeStatus::Ready => Ok(()),
+            ProbeStatus::Failed(x) => Err(x),
+        };
+
+        if let Some(probe) = self.probe.take() {
+            probe.stop(&self.poll);
+        }
+
+        let route = &mut self.routes[self.probe_route];
+
+        match (&route.ready, &res) {
+            (Err(_), Ok(())) => info!(route = route.name.as_str(); "upstream is ready"),
+            (Ok(()), Err(x)) => warn!(route = route.name.as_str(); "upstream is not ready: {}", x),
+            _ => {}
+        }
+
+        route.ready = res;
+
+        // the routes are checked one after the other, then again once the interval is over
+        self.probe_route = (self.probe_route + 1) % self.routes.len();
+
+        self.next_probe = match self.probe_route {
+            0 => Instant::now() + self.ready_interval,
+            _ => Instant::now(),
+        };
+    }
+
+    /// start a readiness check when it is due and fail the one that timed out
+    fn check_ready(&mut self) {
+        let check = match &self.ready_check {
+            Some(x) => x.clone(),
+            None => return,
+        };
+
+        let now = Instant::now();
+
+        match &self.probe {
+            Some(x) if x.deadline() <= now => self.probed(ProbeStatus::Failed("timed out".to_string())),
+            Some(_) => {}
+            None if self.next_probe <= now => {
+                let route = &mut self.routes[self.probe_route];
+
+                if route.connector.deferred() {
+                    self.probed(ProbeStatus::Ready);
+                    return;
+                }
+
+                // the check must be over before the next one is due
+                let deadline = now + self.ready_interval;
+
+                let conn = route.connector.connect(&ConnInfo { conn_id: 0, dest: None, peer: None, identity: None });
+
+                match Probe::start(conn, &self.poll, PROBE.0, check, deadline) {
+                    Ok(x) => self.probe = Some(x),
+                    Err(x) => self.probed(ProbeStatus::Failed(x)),
+                }
+            }
+            None => {}
+        }
+    }
+
+    /// take the pooled upstream connection of `route` waiting the longest, an established one if there is any
+    fn take_warm(
+        warm: &mut HashMap<usize, Warm<Se, Sc, Sp>>,
+        poll: &Poll,
+        stats: &FwStats,
+        route_idx: usize,
+        route: &mut Route<LL, SS>,
+        next_fill: &mut Instant,
+    ) -> Option<State<Se, Sc, Sp>> {
+        loop {
+            let conn_id = warm.iter()
+                .filter(|(_, x)| x.route == route_idx)
+                .min_by_key(|(_, x)| (!x.state.is_active(), x.since))
+                .map(|(x, _)| *x)?;
+
+            let mut x = warm.remove(&conn_id)?;
+            stats.pooled.fetch_sub(1, Ordering::Relaxed);
+
+            let _ = x.state.deregister(poll);
+
+            match Self::idle(&mut x.state) {
+                Idle::Usable => {}
+                idle => {
+                    Self::idle_dropped(conn_id, idle, route, next_fill);
+                    continue;
+                }
+            }
+
+            if x.state.is_active() {
+                route.banners = 0;
+            }
+
+            // it is registered again under the token of its pair
+            return Some(x.state);
+        }
+    }
+
+    /// whether an idle upstream connection was closed by the peer or received data nobody asked for
+    fn idle(state: &mut State<Se, Sc, Sp>) -> Idle {
+        if !state.is_active() {
+            return Idle::Usable;
+        }
+
+        let mut buff = [0u8; 1];
+
+        match state.chan().recv(&mut buff) {
+            Ok(None) => Idle::Usable,
+            Ok(Some(x)) if x > 0 => Idle::Spoke,
+            _ => Idle::Closed,
+        }
+    }
+
+    /// the pool is filled again no sooner than the next tick, and no longer if the upstream keeps speaking first
+    fn idle_dropped(conn_id: usize, idle: Idle, route: &mut Route<LL, SS>, next_fill: &mut Instant) {
+        *next_fill = Instant::now() + TICK;
+
+        if idle == Idle::Closed {
+            debug!(conn_id; "pooled upstream connection closed by the peer");
+            return;
+        }
+
+        debug!(conn_id; "pooled upstream connection received data before a client");
+
+        route.banners += 1;
+
+        if route.banners == MAX_BANNERS {
+            warn!(route = route.name.as_str(); "upstream sends data before the client, its connections are no longer pooled");
+        }
+    }
+
+    fn drop_warm(&mut self, conn_id: usize) {
+        if let Some(x) = self.warm.remove(&conn_id) {
+            let _ = x.state.deregister(&self.poll);
+            self.stats.pooled.fetch_sub(1, Ordering::Relaxed);
+        }
+    }
+
+    /// make progress on a pooled upstream connection, or make sure an established one is still usable
+    fn warm_polled(&mut self, conn_id: usize) {
+        let warm = match self.warm.get_mut(&conn_id) {
+            Some(x) => x,
+            None => return,
+        };
+
+        if warm.state.is_active() {
+            let idle = Self::idle(&mut warm.state);
+
+            if idle != Idle::Usable {
+                Self::idle_dropped(conn_id, idle, &mut self.routes[warm.route], &mut self.next_fill);
+                self.drop_warm(conn_id);
+            }
+            return;
+        }
+
+        if let Err(x) = Self::try_proceed(&self.poll, &mut warm.state) {
+            debug!(conn_id; "failed to pool an upstream connection: {}", x);
+            self.stats.upstream_failures.fetch_add(1, Ordering::Relaxed);
+            self.next_fill = Instant::now() + TICK;
+            self.drop_warm(conn_id);
+        }
+    }
+
+    /// replace the pooled upstream connections that waited too long and open the ones missing
+    fn fill_pools(&mut self) {
+        if self.pool == 0 {
+            return;
+        }
+
+        let now = Instant::now();
+        let max_idle = self.pool_max_idle;
+
+        let routes = &self.routes;
+
+        let expired: Vec<usize> = self.warm.iter()
+            .filter(|(_, x)| x.since + max_idle <= now || !routes[x.route].pooled())
+            .map(|(x, _)| *x)
+            .collect();
+
+        for conn_id in expired {
+            self.drop_warm(conn_id);
+        }
+
+        if self.phase != Phase::Running || now < self.next_fill {
+            return;
+        }
+
+        for (route_idx, route) in self.routes.iter_mut().enumerate() {
+            if !route.pooled() {
+                continue;
+            }
+
+            let pooled = self.warm.values().filter(|x| x.route == route_idx).count();
+
+            for _ in pooled..self.pool {
+                let (conn_id, tok, _) = Self::create_conn_idents(&self.stats);
+
+                let state: State<_, _, _> = match route.connector.connect(&ConnInfo { conn_id, dest: None, peer: None, identity: None }) {
+                    Ok(x) => x.into(),
+                    Err(x) => {
+                        debug!(route = route.name.as_str(); "failed to pool an upstream connection: {}", x);
+                        self.stats.upstream_failures.fetch_add(1, Ordering::Relaxed);
+                        self.next_fill = now + TICK;
+                        break;
+                    }
+                };
+
+                if let Err(x) = state.register(&self.poll, tok) {
+                    debug!(route = route.name.as_str(); "failed to register a pooled upstream connection: {}", x);
+                    break;
+                }
+
+                self.warm.insert(conn_id, Warm { route: route_idx, state, since: now });
+                self.stats.pooled.fetch_add(1, Ordering::Relaxed);
+            }
+        }
+    }
+
+    /// react to a watched socket being removed or created again without waiting for a connect to fail or succeed
+    fn socket_changed(&mut self, change: Change) {
+        let (key, created) = match change {
+            Change::Created(x) => (x, true),
+            Change::Removed(x) => (x, false),
+        };
+
+        let (route_idx, upstream_idx, path) = match self.watched.get(key) {
+            Some(x) => x,
+            None => return,
+        };
+
+        let route = &mut self.routes[*route_idx];
+
+        match created {
+            true => info!(route = route.name.as_str(), path = path.as_str(); "socket created"),
+            false => warn!(route = route.name.as_str(), path = path.as_str(); "socket removed"),
+        }
+
+        if let Some(upstream) = route.connector.upstreams().as_ref().and_then(|x| x.get(*upstream_idx)) {
+            if upstream.force(created) {
+                match created {
+                    true => info!(route = route.name.as_str(), upstream = upstream.name(); "upstream is up"),
+                    false => warn!(route = route.name.as_str(), upstream = upstream.name(); "upstream is down: socket removed"),
+                }
+            }
+        }
+
+        if self.ready_check.is_some() && !route.connector.deferred() {
+            if !created {
+                if route.ready.is_ok() {
+                    warn!(route = route.name.as_str(); "upstream is not ready: socket removed");
+                }
+
+                route.ready = Err("socket removed".to_string());
+            } else if self.probe.is_none() {
+                // check the route right away instead of waiting for its turn
+                self.probe_route = *route_idx;
+                self.next_probe = Instant::now();
+            }
+        }
+
+        if created {
+            let now = Instant::now();
+
+            for conn_id in &self.waiting {
+                if let Some(Pair { route, retry: Some(retry), .. }) = self.conns.get_mut(conn_id) {
+                    if *route == *route_idx {
+                        retry.at = now;
+                    }
+                }
+            }
+        }
+    }
+
+    /// count the outcome of the upstream check polled under `conn_id` once it is known
+    fn checked(&mut self, conn_id: usize, status: ProbeStatus) {
+        let res = match status {
+            ProbeStatus::Waiting => return,
+            ProbeStatus::Ready => Ok(()),
+            ProbeStatus::Failed(x) => Err(x),
+        };
+
+        if let Some(check) = self.checks.remove(&conn_id) {
+            check.probe.stop(&self.poll);
+            Self::record_check(&self.routes[check.route], check.upstream, res);
+        }
+    }
+
+    fn record_check(route: &Route<LL, SS>, idx: usize, res: Result<(), String>) {
+        let (conf, upstreams) = match (route.connector.health(), route.connector.upstreams()) {
+            (Some(x), Some(y)) => (x, y),
+            _ => return,
+        };
+
+        let upstream = match upstreams.get(idx) {
+            Some(x) => x,
+            None => return,
+        };
+
+        if let Err(x) = &res {
+            debug!(route = route.name.as_str(), upstream = upstream.name(); "check failed: {}", x);
+        }
+
+        match (upstream.record(res.is_ok(), conf), res) {
+            (Some(true), _) => info!(route = route.name.as_str(), upstream = upstream.name(); "upstream is up"),
+            (Some(false), Err(x)) => warn!(route = route.name.as_str(), upstream = upstream.name(); "upstream is down: {}", x),
+            _ => {}
+        }
+    }
+
+    /// start the checks of the upstreams that are due and fail the ones that timed out
+    fn check_upstreams(&mut self) {
+        let now = Instant::now();
+
+        let expired: Vec<usize> = self.checks.iter()
+            .filter(|(_, x)| x.probe.deadline() <= now)
+            .map(|(x, _)| *x)
+            .collect();
+
+        for conn_id in expired {
+            self.checked(conn_id, ProbeStatus::Failed("timed out".to_string()));
+        }
+
+        if self.phase != Phase::Running {
+            return;
+        }
+
+        let millis = health::now_millis();
+
+        for (route_idx, route) in self.routes.iter_mut().enumerate() {
+            let (conf, upstreams) = match (route.connector.health(), route.connector.upstreams()) {
+                (Some(x), Some(y)) => (x.clone(), y),
+                _ => continue,
+            };
+
+            for (idx, upstream) in upstreams.iter().enumerate() {
+                if self.checks.values().any(|x| x.route == route_idx && x.upstream == idx) {
+                    continue;
+                }
+
+                // the workers share the upstreams, whichever sees the check due first runs it
+                if !upstream.due(millis, conf.interval) {
+                    continue;
+                }
+
+                let (conn_id, tok, _) = Self::create_conn_idents(&self.stats);
+
+                let conn = route.connector.connect_upstream(idx, &ConnInfo { conn_id, dest: None, peer: None, identity: None });
+
+                match Probe::start(conn, &self.poll, tok, conf.check.clone(), now + conf.timeout) {
+                    Ok(probe) => {
+                        self.checks.insert(conn_id, UpstreamCheck { route: route_idx, upstream: idx, probe });
+                    }
+                    Err(x) => Self::record_check(route, idx, Err(x)),
+                }
+            }
+        }
+    }
+
+    /// connect the upstreams of the pairs whose retry is due, closing the pairs out of time
+    fn retry_pairs(&mut self) {
+        let now = Instant::now();
+        let conns = &self.conns;
+
+        let due: Vec<usize> = self.waiting.iter()
+            .filter(|x| conns.get(x).and_then(|x| x.retry.as_ref()).map(|x| x.at <= now).unwrap_or(true))
+            .cloned()
+            .collect();
+
+        self.waiting.retain(|x| !due.contains(x));
+
+        for conn_id in due {
+            let pair = match self.conns.get_mut(&conn_id) {
+                Some(x) if x.retry.is_some() => x,
+                _ => continue,
+            };
+
+            let before = pair.pendings();
+            let res = Self::connect_pair(&mut self.routes[pair.route].connector, &self.poll, &self.stats, self.retry, pair);
+            let after = pair.pendings();
+
+            self.stats.pending.fetch_add(after, Ordering::Relaxed);
+            self.stats.pending.fetch_sub(before, Ordering::Relaxed);
+
+            match res {
+                Ok(()) if matches!(pair.cb, State::Idle) => self.waiting.push(conn_id),
+                Ok(()) => {}
+                Err(err) => {
+                    warn!(conn_id, peer = pair.peer.as_deref().unwrap_or("-"), side = err.side(); "upstream still unavailable: {}", err);
+                    self.stats.failed.fetch_add(1, Ordering::Relaxed);
+                    self.free(conn_id, CloseReason::from(&err));
+                }
+            }
+        }
+    }
+
+    /// Wait up to `timeout` for events and handle them, `None` waits until there are some.
+    ///
+    /// Returns `false` once the forwarder is done: it was stopped through `FwCtl` or a signal, or it was drained.
+    /// The remaining pairs are closed by then. Errors are fatal, the forwarder should not be polled any further.
+    pub fn run_once(&mut self, timeout: Option<Duration>) -> Result<bool, IoError> {
+        match self.phase {
+            Phase::Idle => self.start()?,
+            Phase::Done => return Ok(false),
+            _ => {}
+        }
+
+        let now = Instant::now();
+
+        let timeout = match self.phase {
+            Phase::Draining(x) if self.conns.is_empty() || x <= now => {
+                self.finish();
+                return Ok(false);
+            }
+            Phase::Draining(x) => Some(timeout.map(|y| y.min(x - now)).unwrap_or(x - now)),
+            _ => timeout,
+        };
+
+        let timeout = match &mut self.metrics {
+            Some(metrics) => {
+                metrics.expire(&self.poll);
+                Some(timeout.map(|x| x.min(TICK)).unwrap_or(TICK))
+            }
+            None => timeout,
+        };
+
+        self.check_ready();
+        self.check_upstreams();
+        self.retry_pairs();
+        self.fill_pools();
+
+        let timeout = match self.pool {
+            0 => timeout,
+            _ => Some(timeout.map(|x| x.min(TICK)).unwrap_or(TICK)),
+        };
+
+        let due = self.waiting.iter().filter_map(|x| self.conns.get(x)).filter_map(|x| x.retry.as_ref()).map(|x| x.at).min();
+
+        let timeout = match due {
+            Some(x) => {
+                let due = x.saturating_duration_since(Instant::now());
+                Some(timeout.map(|y| y.min(due)).unwrap_or(due))
+            }
+            None => timeout,
+        };
+
+        let timeout = match self.routes.iter().any(|x| x.connector.health().is_some()) {
+            true => Some(timeout.map(|x| x.min(TICK)).unwrap_or(TICK)),
+            false => timeout,
+        };
+
+        let timeout = match (&self.probe, &self.ready_check) {
+            (Some(x), _) => Some(timeout.map(|y| y.min(x.deadline() - now)).unwrap_or(x.deadline() - now)),
+            (None, Some(_)) => {
+                let due = self.next_probe.saturating_duration_since(Instant::now());
+                Some(timeout.map(|x| x.min(due)).unwrap_or(due))
+            }
+            _ => timeout,
+        };
+
+        if self.ctl.stop.load(Ordering::SeqCst) {
+            self.finish();
+            return Ok(false);
+        }
+
+        let mut events = mem::replace(&mut self.events, Events::with_capacity(0));
+
+        let res = self.poll.poll(&mut events, timeout);
+
+        if let Err(x) = res {
+            self.events = events;
+
+            return match x.kind() {
+                ErrorKind::Interrupted => Ok(true),
+                _ => Err(x),
+            };
+        }
+
+        let started = Instant::now();
+
+        for event in &events {
+            self.handle(event.token());
+        }
+
+        self.stats.observe_loop(started.elapsed());
+        self.heartbeat.store(health::now_millis(), Ordering::Relaxed);
+        self.events = events;
+
+        if self.ctl.stop.load(Ordering::SeqCst) {
+            self.finish();
+            return Ok(false);
+        }
+
+        Ok(true)
+    }
+
+    /// serve clients until stopped through `FwCtl` or a signal, the remaining pairs are closed on return
+    pub fn run(&mut self) -> Result<(), IoError> {
+        while self.run_once(None)? {}
+
+        Ok(())
+    }
+}
+
+
+
+impl From<&str> for FwConfError {
+    fn from(x: &str) -> FwConfError {
+        FwConfError::Str(x.to_string())
+    }
+}
+
+impl<E: Debug> From<E> for FwError<E> {
+    fn from(x: E) -> Self {
+        FwError::Io(x)
+    }
+}
+
+impl<E: Debug> FwError<E> {
+    /// wrap the protocol error, used by channels layered on top of other channels
+    pub fn map<F: Debug>(self, f: impl FnOnce(E) -> F) -> FwError<F> {
+        match self {
+            FwError::Io(x) => FwError::Io(f(x)),
+            FwError::Register(x) => FwError::Register(x),
+            FwError::Disconnected => FwError::Disconnected,
+            FwError::Lost => FwError::Lost,
+            FwError::Denied => FwError::Denied,
+        }
+    }
+}
+
+impl<Le: Debug, Se: Debug> FwPairError<Le, Se> {
+    pub fn ml(x: FwError<Le>) -> Self {
+        FwPairError::L(x)
+    }
+
+    pub fn l(x: Le) -> Self {
+        FwPairError::L(FwError::Io(x))
+    }
+
+    pub fn ms(x: FwError<Se>) -> Self {
+        FwPairError::S(x)
+    }
+
+    pub fn s(x: Se) -> Self {
+        FwPairError::S(FwError::Io(x))
+    }
+
+    /// side of the pair the error comes from, `L` for the client and `S` for the upstream
+    pub fn side(&self) -> &'static str {
+        match self {
+            FwPairError::L(_) => "L",
+            FwPairError::S(_) => "S",
+            _ => "-",
+        }
+    }
+
+    pub fn swap(self) -> FwPairError<Se, Le> {
+        match self {
             FwPairError::L(x) => FwPairError::S(x),
             FwPairError::S(x) => FwPairError::L(x),
             FwPairError::Disconnected => FwPairError::Disconnected,
@@ -575,3 +2577,138 @@ impl<Le: Debug, Se: Debug> FwPairError<Le, Se> {
         }
     }
 }
+
+/// What a setup error was about, `main` exits with a distinct status for each
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ErrorClass {
+    /// invalid arguments or configuration
+    Config,
+    /// a listening socket could not be bound
+    Bind,
+    /// certificates, keys or other TLS setup
+    Tls,
+    Other,
+}
+
+impl ErrorClass {
+    pub fn exit_code(&self) -> i32 {
+        match self {
+            ErrorClass::Other => 1,
+            ErrorClass::Config => 2,
+            ErrorClass::Bind => 3,
+            ErrorClass::Tls => 4,
+        }
+    }
+}
+
+pub trait Classify {
+    fn class(&self) -> ErrorClass;
+
+    /// short label of what went wrong if this is a failed TLS handshake
+    fn tls_failure(&self) -> Option<&'static str> {
+        None
+    }
+}
+
+/// render an error followed by the chain of its sources
+pub fn describe(err: &dyn Error) -> String {
+    let mut ret = err.to_string();
+    let mut source = err.source();
+
+    while let Some(x) = source {
+        ret.push_str(": ");
+        ret.push_str(&x.to_string());
+        source = x.source();
+    }
+
+    ret
+}
+
+impl Display for FwConfError {
+    fn fmt(&self, f: &mut Formatter) -> FmtResult {
+        match self {
+            FwConfError::Io(_) => write!(f, "failed to read configuration"),
+            FwConfError::Str(x) => write!(f, "invalid value for {}", x),
+            FwConfError::File(path, _) => write!(f, "failed to open {}", path),
+            FwConfError::Bind(addr, _) => write!(f, "failed to bind {}", addr),
+        }
+    }
+}
+
+impl Error for FwConfError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            FwConfError::Io(x) => Some(x),
+            FwConfError::File(_, x) | FwConfError::Bind(_, x) => Some(x),
+            FwConfError::Str(_) => None,
+        }
+    }
+}
+
+impl Classify for FwConfError {
+    fn class(&self) -> ErrorClass {
+        match self {
+            FwConfError::Bind(..) => ErrorClass::Bind,
+            _ => ErrorClass::Config,
+        }
+    }
+}
+
+impl<E: Debug + Display> Display for FwError<E> {
+    fn fmt(&self, f: &mut Formatter) -> FmtResult {
+        match self {
+            FwError::Io(x) => Display::fmt(x, f),
+            FwError::Register(_) => write!(f, "failed to register with the event loop"),
+            FwError::Disconnected => write!(f, "disconnected"),
+            FwError::Lost => write!(f, "connection lost"),
+            FwError::Denied => write!(f, "destination denied"),
+        }
+    }
+}
+
+impl<E: Error + 'static> Error for FwError<E> {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            FwError::Io(x) => x.source(),
+            FwError::Register(x) => Some(x),
+            _ => None,
+        }
+    }
+}
+
+impl<E: Debug + Classify> Classify for FwError<E> {
+    fn class(&self) -> ErrorClass {
+        match self {
+            FwError::Io(x) => x.class(),
+            _ => ErrorClass::Other,
+        }
+    }
+
+    fn tls_failure(&self) -> Option<&'static str> {
+        match self {
+            FwError::Io(x) => x.tls_failure(),
+            _ => None,
+        }
+    }
+}
+
+impl<Le: Debug + Display, Se: Debug + Display> Display for FwPairError<Le, Se> {
+    fn fmt(&self, f: &mut Formatter) -> FmtResult {
+        match self {
+            FwPairError::L(x) => write!(f, "client side: {}", x),
+            FwPairError::S(x) => write!(f, "upstream side: {}", x),
+            FwPairError::Disconnected => write!(f, "disconnected"),
+            FwPairError::Lost => write!(f, "connection lost"),
+        }
+    }
+}
+
+impl<Le: Error + 'static, Se: Error + 'static> Error for FwPairError<Le, Se> {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            FwPairError::L(x) => x.source(),
+            FwPairError::S(x) => x.source(),
+            _ => None,
+        }
+    }
+}
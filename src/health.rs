@@ -0,0 +1,406 @@
+use std::fmt::{Debug, Display};
+use std::mem;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicBool, AtomicU32, AtomicU64, AtomicUsize, Ordering};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use clap::{App, Arg, ArgMatches};
+use mio::Poll;
+
+use crate::{Chan, FwError, FwStats, MidChan, NextState, Pollable, State};
+use crate::args::Parsable;
+use crate::proto::common::{fill, send_all};
+
+/// a worker whose event loop has not turned for this long is reported as stalled
+pub const STALL_MS: u64 = 10_000;
+
+/// a reply that has not matched by this size never will
+const MAX_REPLY: usize = 64 * 1024;
+
+/// What has to succeed for a forwarder to report itself ready, or for an upstream to be counted as up
+#[derive(Debug, Clone, PartialEq)]
+pub enum ReadyCheck {
+    /// the connector establishes a connection
+    Connect,
+    /// the upstream answers `GET /_ping` of the Docker Engine API with `200`
+    DockerPing,
+    /// the upstream replies to `send` with something containing `expect`
+    Expect { send: Vec<u8>, expect: Vec<u8> },
+}
+
+impl FromStr for ReadyCheck {
+    type Err = ();
+
+    fn from_str(x: &str) -> Result<Self, Self::Err> {
+        match x {
+            "connect" => Ok(ReadyCheck::Connect),
+            "docker-ping" => Ok(ReadyCheck::DockerPing),
+            _ => Err(()),
+        }
+    }
+}
+
+pub enum ProbeStatus {
+    Waiting,
+    Ready,
+    Failed(String),
+}
+
+/// Upstream connection opened to check readiness, polled by the event loop like any other channel
+pub struct Probe<E: Debug, A: Chan<Err=E> + Pollable, B: MidChan<Err=E, C=A> + Pollable> {
+    state: State<E, A, B>,
+    check: ReadyCheck,
+    deadline: Instant,
+    sent: bool,
+    buff: Vec<u8>,
+}
+
+impl<E: Debug + Display, A: Chan<Err=E> + Pollable, B: MidChan<Err=E, C=A> + Pollable> Probe<E, A, B> {
+    /// check the upstream `conn` was connected to, its channel is registered under `tok`
+    pub fn start(
+        conn: Result<NextState<E, A, B>, FwError<E>>,
+        poll: &Poll,
+        tok: usize,
+        check: ReadyCheck,
+        deadline: Instant,
+    ) -> Result<Self, String> {
+        let state: State<E, A, B> = conn.map_err(|x| x.to_string())?.into();
+
+        state.register(poll, tok).map_err(|x| x.to_string())?;
+
+        Ok(Probe { state, check, deadline, sent: false, buff: Vec::new() })
+    }
+
+    pub fn deadline(&self) -> Instant {
+        self.deadline
+    }
+
+    /// make progress on the check
+    pub fn poll(&mut self, poll: &Poll) -> ProbeStatus {
+        if let State::Pending(_) = self.state {
+            let prev = mem::replace(&mut self.state, State::Swapping);
+
+            self.state = match prev {
+                State::Pending(x) => match x.try_channel(poll) {
+                    Ok(NextState::Pending(x)) => State::Pending(x),
+                    Ok(NextState::Active(x)) => State::Active(x),
+                    Err(x) => {
+                        self.state = State::Lost;
+                        return ProbeStatus::Failed(x.to_string());
+                    }
+                },
+                x => x,
+            };
+        }
+
+        let chan = match &mut self.state {
+            State::Active(x) => x,
+            _ => return ProbeStatus::Waiting,
+        };
+
+        if self.check == ReadyCheck::Connect {
+            return ProbeStatus::Ready;
+        }
+
+        if !self.sent {
+            let req: &[u8] = match &self.check {
+                ReadyCheck::Expect { send, .. } => send,
+                _ => b"GET /_ping HTTP/1.0\r\nHost: docker\r\n\r\n",
+            };
+
+            if let Err(x) = send_all(chan, req) {
+                return ProbeStatus::Failed(x.to_string());
+            }
+
+            self.sent = true;
+        }
+
+        let mut closed = false;
+
+        loop {
+            match fill(chan, &mut self.buff) {
+                Ok(Some(0)) => {
+                    closed = true;
+                    break;
+                }
+                Ok(Some(_)) if self.buff.len() > MAX_REPLY => break,
+                Ok(Some(_)) => {}
+                Ok(None) => break,
+                Err(x) => return ProbeStatus::Failed(x.to_string()),
+            }
+        }
+
+        if let ReadyCheck::Expect { expect, .. } = &self.check {
+            if expect.is_empty() || self.buff.windows(expect.len()).any(|x| x == expect.as_slice()) {
+                return ProbeStatus::Ready;
+            }
+
+            return match closed || self.buff.len() > MAX_REPLY {
+                true => ProbeStatus::Failed(format!("unexpected reply: {}", String::from_utf8_lossy(&self.buff).trim())),
+                false => ProbeStatus::Waiting,
+            };
+        }
+
+        let line = match self.buff.windows(2).position(|x| x == b"\r\n") {
+            Some(x) => String::from_utf8_lossy(&self.buff[..x]).to_string(),
+            None if closed => return ProbeStatus::Failed("upstream closed the connection".to_string()),
+            None => return ProbeStatus::Waiting,
+        };
+
+        match line.split(' ').nth(1) {
+            Some("200") => ProbeStatus::Ready,
+            _ => ProbeStatus::Failed(format!("unexpected reply to /_ping: {}", line)),
+        }
+    }
+
+    pub fn stop(self, poll: &Poll) {
+        let _ = self.state.deregister(poll);
+    }
+}
+
+/// milliseconds since the epoch, which is what the heartbeats of the workers hold
+pub fn now_millis() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map(|x| x.as_millis() as u64).unwrap_or(0)
+}
+
+/// every event loop that is still running has turned recently
+pub fn alive(stats: &FwStats) -> bool {
+    let now = now_millis();
+
+    match stats.heartbeats.lock() {
+        // a heartbeat of 0 belongs to a worker that is done
+        Ok(x) => x.iter().map(|x| x.load(Ordering::Relaxed)).all(|x| x == 0 || now.saturating_sub(x) < STALL_MS),
+        Err(_) => false,
+    }
+}
+
+/// How the upstreams of a connector picking from several are checked
+#[derive(Debug, Clone)]
+pub struct HealthConf {
+    pub check: ReadyCheck,
+    pub interval: Duration,
+    /// a check still running by then fails
+    pub timeout: Duration,
+    /// checks in a row that must succeed for an upstream that is down to be counted as up
+    pub rise: u32,
+    /// checks in a row that must fail for an upstream that is up to be counted as down
+    pub fall: u32,
+}
+
+/// bytes given on the command line, with `\r`, `\n`, `\t`, `\\` and `\xNN` escapes
+pub fn unescape(x: &str) -> Result<Vec<u8>, &'static str> {
+    let mut ret = Vec::with_capacity(x.len());
+    let mut bytes = x.bytes();
+
+    while let Some(c) = bytes.next() {
+        if c != b'\\' {
+            ret.push(c);
+            continue;
+        }
+
+        match bytes.next() {
+            Some(b'r') => ret.push(b'\r'),
+            Some(b'n') => ret.push(b'\n'),
+            Some(b't') => ret.push(b'\t'),
+            Some(b'\\') => ret.push(b'\\'),
+            Some(b'x') => {
+                let hex = [bytes.next().ok_or("truncated \\x escape")?, bytes.next().ok_or("truncated \\x escape")?];
+                let hex = std::str::from_utf8(&hex).map_err(|_| "invalid \\x escape")?;
+                ret.push(u8::from_str_radix(hex, 16).map_err(|_| "invalid \\x escape")?);
+            }
+            _ => return Err("unsupported escape sequence"),
+        }
+    }
+
+    Ok(ret)
+}
+
+/// `None` unless a check is given
+impl Parsable<Result<Option<HealthConf>, &'static str>> for HealthConf {
+    fn parser<'a, 'b>(app: App<'a, 'b>) -> App<'a, 'b> {
+        app
+            .arg(
+                Arg::with_name("check")
+                    .long("check")
+                    .help("check the upstreams in the background, those failing are only tried once the others failed too")
+                    .possible_values(&["connect", "docker-ping", "expect"])
+                    .takes_value(true)
+                    .required(false)
+            )
+            .arg(
+                Arg::with_name("check_send")
+                    .long("check-send")
+                    .help("bytes the expect check sends, \\r, \\n, \\t and \\xNN are unescaped")
+                    .takes_value(true)
+                    .required(false)
+            )
+            .arg(
+                Arg::with_name("check_expect")
+                    .long("check-expect")
+                    .help("bytes the reply to the expect check must contain, unescaped as for --check-send")
+                    .takes_value(true)
+                    .required_if("check", "expect")
+            )
+            .arg(
+                Arg::with_name("check_interval")
+                    .long("check-interval")
+                    .help("seconds between the checks of an upstream")
+                    .default_value("5")
+            )
+            .arg(
+                Arg::with_name("check_timeout")
+                    .long("check-timeout")
+                    .help("seconds a check may take, at most the interval [default: 2]")
+                    .takes_value(true)
+                    .required(false)
+            )
+            .arg(
+                Arg::with_name("rise")
+                    .long("rise")
+                    .help("checks in a row that must succeed for an upstream to be counted as up again")
+                    .default_value("2")
+            )
+            .arg(
+                Arg::with_name("fall")
+                    .long("fall")
+                    .help("checks in a row that must fail for an upstream to be counted as down")
+                    .default_value("3")
+            )
+    }
+
+    fn parse(matches: &ArgMatches) -> Result<Option<HealthConf>, &'static str> {
+        let check = match matches.value_of("check") {
+            Some("expect") => ReadyCheck::Expect {
+                send: unescape(matches.value_of("check_send").unwrap_or_default())?,
+                expect: unescape(matches.value_of("check_expect").ok_or("check_expect not found")?)?,
+            },
+            Some(x) => x.parse::<ReadyCheck>().map_err(|_| "check")?,
+            None => return Ok(None),
+        };
+
+        let interval = matches.value_of("check_interval").ok_or("check_interval not found")?;
+        let interval = interval.parse::<u64>().map_err(|_| "check_interval not int")?;
+
+        let timeout = match matches.value_of("check_timeout") {
+            Some(x) => x.parse::<u64>().map_err(|_| "check_timeout not int")?,
+            None => interval.min(2),
+        };
+
+        if interval == 0 || timeout == 0 || timeout > interval {
+            return Err("check_timeout must be between 1 and check_interval");
+        }
+
+        let rise = matches.value_of("rise").ok_or("rise not found")?;
+        let rise = rise.parse::<u32>().map_err(|_| "rise not int")?.max(1);
+
+        let fall = matches.value_of("fall").ok_or("fall not found")?;
+        let fall = fall.parse::<u32>().map_err(|_| "fall not int")?.max(1);
+
+        Ok(Some(HealthConf {
+            check,
+            interval: Duration::from_secs(interval),
+            timeout: Duration::from_secs(timeout),
+            rise,
+            fall,
+        }))
+    }
+}
+
+/// Upstream of a connector picking from several, shared by the workers
+#[derive(Debug)]
+pub struct Upstream {
+    name: String,
+    /// connections open to the upstream, including the ones still connecting
+    active: AtomicUsize,
+    up: AtomicBool,
+    /// results in a row that disagree with `up`
+    streak: AtomicU32,
+    /// when the next check is due, in milliseconds since the epoch
+    next_check: AtomicU64,
+    failed_checks: AtomicU64,
+}
+
+impl Upstream {
+    /// counted as up until checked
+    pub fn new(name: &str) -> Self {
+        Upstream {
+            name: name.to_string(),
+            active: AtomicUsize::new(0),
+            up: AtomicBool::new(true),
+            streak: AtomicU32::new(0),
+            next_check: AtomicU64::new(0),
+            failed_checks: AtomicU64::new(0),
+        }
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn active(&self) -> usize {
+        self.active.load(Ordering::Relaxed)
+    }
+
+    pub fn opened(&self) {
+        self.active.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn closed(&self) {
+        self.active.fetch_sub(1, Ordering::Relaxed);
+    }
+
+    pub fn is_up(&self) -> bool {
+        self.up.load(Ordering::Relaxed)
+    }
+
+    pub fn failed_checks(&self) -> u64 {
+        self.failed_checks.load(Ordering::Relaxed)
+    }
+
+    /// whether a check is due at `now`, only one of the workers asking is told so
+    pub fn due(&self, now: u64, interval: Duration) -> bool {
+        let next = self.next_check.load(Ordering::Relaxed);
+
+        if now < next {
+            return false;
+        }
+
+        self.next_check.compare_exchange(next, now + interval.as_millis() as u64, Ordering::Relaxed, Ordering::Relaxed).is_ok()
+    }
+
+    /// count the upstream as up or down right away, returns whether that changed its state
+    pub fn force(&self, up: bool) -> bool {
+        self.streak.store(0, Ordering::Relaxed);
+
+        if up {
+            // confirm it with a check as soon as possible
+            self.next_check.store(0, Ordering::Relaxed);
+        }
+
+        self.up.swap(up, Ordering::Relaxed) != up
+    }
+
+    /// count the result of a check, returns the new state if it changed
+    pub fn record(&self, ok: bool, conf: &HealthConf) -> Option<bool> {
+        if !ok {
+            self.failed_checks.fetch_add(1, Ordering::Relaxed);
+        }
+
+        let up = self.is_up();
+
+        if ok == up {
+            self.streak.store(0, Ordering::Relaxed);
+            return None;
+        }
+
+        let needed = if up { conf.fall } else { conf.rise };
+
+        if self.streak.fetch_add(1, Ordering::Relaxed) + 1 < needed {
+            return None;
+        }
+
+        self.streak.store(0, Ordering::Relaxed);
+        self.up.store(ok, Ordering::Relaxed);
+
+        Some(ok)
+    }
+}
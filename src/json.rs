@@ -0,0 +1,430 @@
+use std::fmt::{Display, Formatter, Result as FmtResult};
+
+use crate::logger::json_str;
+
+/// Deepest nesting of arrays and objects accepted
+const MAX_DEPTH: usize = 64;
+
+/// JSON document, numbers are kept as they were written and objects keep the order of their keys
+#[derive(Debug, Clone, PartialEq)]
+pub enum Json {
+    Null,
+    Bool(bool),
+    Num(String),
+    Str(String),
+    Array(Vec<Json>),
+    Object(Vec<(String, Json)>),
+}
+
+impl Json {
+    pub fn parse(x: &[u8]) -> Result<Json, &'static str> {
+        let mut parser = Parser { x, pos: 0 };
+
+        let ret = parser.value(0)?;
+        parser.space();
+
+        if parser.pos != x.len() {
+            return Err("trailing data after the json document");
+        }
+
+        Ok(ret)
+    }
+
+    /// value of `key` looked up like Go does, which the Docker daemon is written in:
+    /// ignoring the case of the key and taking the last one given
+    pub fn get(&self, key: &str) -> Option<&Json> {
+        match self {
+            Json::Object(x) => x.iter().rev().find(|(k, _)| same_key(k, key)).map(|(_, v)| v),
+            _ => None,
+        }
+    }
+
+    /// value of `key` in an object decoded into a map, where keys are matched exactly
+    pub fn key(&self, key: &str) -> Option<&Json> {
+        match self {
+            Json::Object(x) => x.iter().rev().find(|(k, _)| k == key).map(|(_, v)| v),
+            _ => None,
+        }
+    }
+
+    /// replace every value `get(key)` could return, or add it if the object has none
+    pub fn set(&mut self, key: &str, value: Json) {
+        if let Json::Object(x) = self {
+            x.retain(|(k, _)| !same_key(k, key));
+            x.push((key.to_string(), value));
+        }
+    }
+
+    /// first key of an object that `get` would not look up with any of `known`
+    pub fn unknown(&self, known: &[&str]) -> Option<&str> {
+        match self {
+            Json::Object(x) => x.iter().map(|(k, _)| k.as_str()).find(|k| !known.iter().any(|x| same_key(k, x))),
+            _ => None,
+        }
+    }
+
+    pub fn as_bool(&self) -> Option<bool> {
+        match self {
+            Json::Bool(x) => Some(*x),
+            _ => None,
+        }
+    }
+
+    pub fn as_str(&self) -> Option<&str> {
+        match self {
+            Json::Str(x) => Some(x),
+            _ => None,
+        }
+    }
+
+    /// items of an array, nothing for `null` and anything else
+    pub fn items(&self) -> &[Json] {
+        match self {
+            Json::Array(x) => x,
+            _ => &[],
+        }
+    }
+}
+
+impl Display for Json {
+    fn fmt(&self, f: &mut Formatter) -> FmtResult {
+        match self {
+            Json::Null => f.write_str("null"),
+            Json::Bool(x) => write!(f, "{}", x),
+            Json::Num(x) => f.write_str(x),
+            Json::Str(x) => {
+                let mut out = String::new();
+                json_str(&mut out, x);
+                f.write_str(&out)
+            }
+            Json::Array(x) => {
+                f.write_str("[")?;
+
+                for (i, v) in x.iter().enumerate() {
+                    if i > 0 {
+                        f.write_str(",")?;
+                    }
+
+                    write!(f, "{}", v)?;
+                }
+
+                f.write_str("]")
+            }
+            Json::Object(x) => {
+                f.write_str("{")?;
+
+                for (i, (k, v)) in x.iter().enumerate() {
+                    if i > 0 {
+                        f.write_str(",")?;
+                    }
+
+                    let mut key = String::new();
+                    json_str(&mut key, k);
+                    write!(f, "{}:{}", key, v)?;
+                }
+
+                f.write_str("}")
+            }
+        }
+    }
+}
+
+/// keys equal when their case is ignored, `ſ` and the Kelvin sign fold to `s` and `k` in Go
+fn same_key(a: &str, b: &str) -> bool {
+    fn fold(c: char) -> char {
+        match c {
+            '\u{17f}' => 's',
+            '\u{212a}' => 'k',
+            c => c.to_ascii_lowercase(),
+        }
+    }
+
+    a.chars().map(fold).eq(b.chars().map(fold))
+}
+
+struct Parser<'a> {
+    x: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Parser<'a> {
+    fn space(&mut self) {
+        while let Some(b' ' | b'\t' | b'\r' | b'\n') = self.x.get(self.pos) {
+            self.pos += 1;
+        }
+    }
+
+    fn eat(&mut self, c: u8) -> bool {
+        self.space();
+
+        if self.x.get(self.pos) == Some(&c) {
+            self.pos += 1;
+            return true;
+        }
+
+        false
+    }
+
+    fn literal(&mut self, word: &str, value: Json) -> Result<Json, &'static str> {
+        match self.x[self.pos..].starts_with(word.as_bytes()) {
+            true => {
+                self.pos += word.len();
+                Ok(value)
+            }
+            false => Err("invalid literal"),
+        }
+    }
+
+    fn value(&mut self, depth: usize) -> Result<Json, &'static str> {
+        if depth > MAX_DEPTH {
+            return Err("json nested too deep");
+        }
+
+        self.space();
+
+        match self.x.get(self.pos).ok_or("json value missing")? {
+            b'n' => self.literal("null", Json::Null),
+            b't' => self.literal("true", Json::Bool(true)),
+            b'f' => self.literal("false", Json::Bool(false)),
+            b'"' => Ok(Json::Str(self.string()?)),
+            b'[' => {
+                self.pos += 1;
+                let mut items = Vec::new();
+
+                if self.eat(b']') {
+                    return Ok(Json::Array(items));
+                }
+
+                loop {
+                    items.push(self.value(depth + 1)?);
+
+                    if self.eat(b']') {
+                        return Ok(Json::Array(items));
+                    }
+
+                    if !self.eat(b',') {
+                        return Err("expected , or ] in array");
+                    }
+                }
+            }
+            b'{' => {
+                self.pos += 1;
+                let mut keys = Vec::new();
+
+                if self.eat(b'}') {
+                    return Ok(Json::Object(keys));
+                }
+
+                loop {
+                    self.space();
+
+                    if self.x.get(self.pos) != Some(&b'"') {
+                        return Err("expected a key in object");
+                    }
+
+                    let key = self.string()?;
+
+                    if !self.eat(b':') {
+                        return Err("expected : in object");
+                    }
+
+                    keys.push((key, self.value(depth + 1)?));
+
+                    if self.eat(b'}') {
+                        return Ok(Json::Object(keys));
+                    }
+
+                    if !self.eat(b',') {
+                        return Err("expected , or } in object");
+                    }
+                }
+            }
+            b'-' | b'0'..=b'9' => {
+                let start = self.pos;
+
+                // `-?(0|[1-9][0-9]*)(.[0-9]+)?([eE][+-]?[0-9]+)?`, as strict as the decoder of the upstream
+                self.skip(b"-");
+
+                match self.x.get(self.pos) {
+                    Some(b'0') => self.pos += 1,
+                    Some(b'1'..=b'9') => { self.digits(); }
+                    _ => return Err("invalid number"),
+                }
+
+                if self.skip(b".") && self.digits() == 0 {
+                    return Err("invalid number");
+                }
+
+                if self.skip(b"eE") {
+                    self.skip(b"+-");
+
+                    if self.digits() == 0 {
+                        return Err("invalid number");
+                    }
+                }
+
+                let num = std::str::from_utf8(&self.x[start..self.pos]).map_err(|_| "invalid number")?;
+
+                Ok(Json::Num(num.to_string()))
+            }
+            _ => Err("invalid json value"),
+        }
+    }
+
+    /// step over one of `chars`
+    fn skip(&mut self, chars: &[u8]) -> bool {
+        match self.x.get(self.pos) {
+            Some(x) if chars.contains(x) => {
+                self.pos += 1;
+                true
+            }
+            _ => false,
+        }
+    }
+
+    fn digits(&mut self) -> usize {
+        let start = self.pos;
+
+        while let Some(b'0'..=b'9') = self.x.get(self.pos) {
+            self.pos += 1;
+        }
+
+        self.pos - start
+    }
+
+    fn hex(&mut self) -> Result<u32, &'static str> {
+        let hex = self.x.get(self.pos..self.pos + 4).ok_or("truncated escape")?;
+        let hex = std::str::from_utf8(hex).map_err(|_| "invalid escape")?;
+        self.pos += 4;
+
+        u32::from_str_radix(hex, 16).map_err(|_| "invalid escape")
+    }
+
+    fn string(&mut self) -> Result<String, &'static str> {
+        // opening quote
+        self.pos += 1;
+
+        let mut out = Vec::new();
+
+        loop {
+            let c = *self.x.get(self.pos).ok_or("unterminated string")?;
+            self.pos += 1;
+
+            match c {
+                b'"' => return String::from_utf8(out).map_err(|_| "string is not utf-8"),
+                b'\\' => {
+                    let c = *self.x.get(self.pos).ok_or("unterminated string")?;
+                    self.pos += 1;
+
+                    let c = match c {
+                        b'"' => '"',
+                        b'\\' => '\\',
+                        b'/' => '/',
+                        b'b' => '\u{8}',
+                        b'f' => '\u{c}',
+                        b'n' => '\n',
+                        b'r' => '\r',
+                        b't' => '\t',
+                        b'u' => {
+                            let mut code = self.hex()?;
+
+                            if (0xd800..0xdc00).contains(&code) && self.x[self.pos..].starts_with(b"\\u") {
+                                self.pos += 2;
+                                let low = self.hex()?;
+                                code = 0x10000 + ((code - 0xd800) << 10) + (low.wrapping_sub(0xdc00) & 0x3ff);
+                            }
+
+                            // lone surrogates are replaced, as Go does
+                            std::char::from_u32(code).unwrap_or('\u{fffd}')
+                        }
+                        _ => return Err("invalid escape"),
+                    };
+
+                    let mut buff = [0; 4];
+                    out.extend_from_slice(c.encode_utf8(&mut buff).as_bytes());
+                }
+                x if x < 0x20 => return Err("control character in string"),
+                x => out.push(x),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(x: &str) -> Result<Json, &'static str> {
+        Json::parse(x.as_bytes())
+    }
+
+    #[test]
+    fn parses_values() {
+        let doc = parse(r#" {"a": [1, -2.5e3, true, false, null], "b": {"c": "d"}, "e": ""} "#).unwrap();
+
+        for x in &["0", "-0", "10", "0.5", "1E+2", "-1.25e-10"] {
+            assert_eq!(parse(x).unwrap(), Json::Num(x.to_string()));
+        }
+
+        assert_eq!(doc.get("a").unwrap().items(), &[
+            Json::Num("1".into()), Json::Num("-2.5e3".into()), Json::Bool(true), Json::Bool(false), Json::Null,
+        ]);
+        assert_eq!(doc.get("b").and_then(|x| x.get("c")).and_then(Json::as_str), Some("d"));
+        assert_eq!(doc.get("e").and_then(Json::as_str), Some(""));
+        assert_eq!(parse("[]").unwrap(), Json::Array(vec![]));
+        assert_eq!(parse("{}").unwrap(), Json::Object(vec![]));
+    }
+
+    #[test]
+    fn decodes_strings() {
+        assert_eq!(parse(r#""a\"\\\/\b\f\n\r\t""#).unwrap(), Json::Str("a\"\\/\u{8}\u{c}\n\r\t".into()));
+        assert_eq!(parse(r#""\u00e9\ud83d\ude00""#).unwrap(), Json::Str("é😀".into()));
+        assert_eq!(parse(r#""\ud83d""#).unwrap(), Json::Str("\u{fffd}".into()));
+        assert_eq!(parse("\"é\"").unwrap(), Json::Str("é".into()));
+    }
+
+    #[test]
+    fn refuses_invalid_documents() {
+        for x in &[
+            "", "{", "[1,]", "{\"a\":1,}", "{a:1}", "{\"a\" 1}", "tru", "nul", "01x", "1 2", "\"a", "\"\\x\"", "\"\\u12\"",
+            "\"a\nb\"", "[1] x", "-", "1e", "+1", "01", "1.", ".5", "-01", "1e+", "--1", "1.e3", "0x1",
+        ] {
+            assert!(parse(x).is_err(), "{}", x);
+        }
+
+        assert!(Json::parse(b"\"\xff\"").is_err());
+        assert!(parse(&"[".repeat(MAX_DEPTH + 2)).is_err());
+        assert!(parse(&format!("{}{}", "[".repeat(MAX_DEPTH), "]".repeat(MAX_DEPTH))).is_ok());
+    }
+
+    #[test]
+    fn looks_keys_up_like_go() {
+        let doc = parse(r#"{"Privileged": false, "privileged": true, "HOſTCONFIG": 1, "\u212aey": 2}"#).unwrap();
+
+        assert_eq!(doc.get("Privileged").and_then(Json::as_bool), Some(true));
+        assert_eq!(doc.get("HostConfig"), Some(&Json::Num("1".into())));
+        assert_eq!(doc.get("key"), Some(&Json::Num("2".into())));
+        assert_eq!(doc.get("Missing"), None);
+
+        // maps are decoded with their keys as they are
+        assert_eq!(doc.key("Privileged").and_then(Json::as_bool), Some(false));
+        assert_eq!(doc.key("hostconfig"), None);
+
+        assert_eq!(doc.unknown(&["privileged", "hostconfig"]), Some("\u{212a}ey"));
+        assert_eq!(doc.unknown(&["privileged", "hostconfig", "KEY"]), None);
+    }
+
+    #[test]
+    fn sets_keys() {
+        let mut doc = parse(r#"{"labels": {"a": "b"}, "Image": "x", "Labels": null}"#).unwrap();
+        doc.set("Labels", Json::Object(vec![("o".into(), Json::Str("CN=a".into()))]));
+
+        assert_eq!(doc.to_string(), r#"{"Image":"x","Labels":{"o":"CN=a"}}"#);
+    }
+
+    #[test]
+    fn serializes_back() {
+        let text = r#"{"a":[1,-2.5e3,true,null],"b":{"c":"\"q\"\n"},"d":"é"}"#;
+        assert_eq!(parse(text).unwrap().to_string(), text);
+    }
+}
@@ -4,5 +4,16 @@ mod tests;
 pub mod proto;
 pub mod args;
 pub mod fw;
+pub mod registry;
+pub mod workers;
+pub mod signal;
+pub mod watch;
+pub mod logger;
+pub mod access;
+pub mod metrics;
+pub mod admin;
+pub mod health;
+pub mod config;
+pub mod json;
 
 pub use fw::*;
\ No newline at end of file
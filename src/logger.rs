@@ -0,0 +1,332 @@
+use std::ffi::CString;
+use std::fmt::Write as FmtWrite;
+use std::io::{stderr, Write};
+use std::str::FromStr;
+use std::time::{SystemTime, UNIX_EPOCH};
+use clap::{App, Arg, ArgMatches};
+use log::{Level, LevelFilter, Log, Metadata, Record, SetLoggerError};
+use log::kv::{Error as KvError, Key, Value, VisitSource};
+
+use crate::FwConfError;
+use crate::args::Parsable;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum LogFormat {
+    Text,
+    Json,
+}
+
+impl FromStr for LogFormat {
+    type Err = ();
+
+    fn from_str(x: &str) -> Result<Self, Self::Err> {
+        match x {
+            "text" => Ok(LogFormat::Text),
+            "json" => Ok(LogFormat::Json),
+            _ => Err(()),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum LogTarget {
+    Stderr,
+    /// local syslog daemon, which is where journald picks the messages up as well
+    Syslog,
+}
+
+#[derive(Debug, Clone)]
+pub struct LogConf {
+    pub level: LevelFilter,
+    pub format: LogFormat,
+    pub target: LogTarget,
+}
+
+struct Logger {
+    format: LogFormat,
+    target: LogTarget,
+}
+
+/// Collects the key-values of a record, either as ` key=value` or as JSON members
+struct Fields<'a> {
+    format: LogFormat,
+    out: &'a mut String,
+}
+
+/// write `x` as a JSON string
+pub(crate) fn json_str(out: &mut String, x: &str) {
+    out.push('"');
+
+    for c in x.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\r' => out.push_str("\\r"),
+            '\t' => out.push_str("\\t"),
+            c if (c as u32) < 0x20 => {
+                let _ = write!(out, "\\u{:04x}", c as u32);
+            }
+            c => out.push(c),
+        }
+    }
+
+    out.push('"');
+}
+
+/// write `x` as the value of a ` key=value` field, quoted and escaped like a JSON string unless it is a single word
+fn text_value(out: &mut String, x: &str) {
+    if !x.is_empty() && x.chars().all(|c| c.is_ascii_graphic() && c != '"' && c != '\\' && c != '=') {
+        out.push_str(x);
+    } else {
+        json_str(out, x);
+    }
+}
+
+/// write the message of a text record, with the line breaks and other control characters a client may have sent escaped
+fn text_msg(out: &mut String, x: &str) {
+    for c in x.chars() {
+        match c {
+            '\n' => out.push_str("\\n"),
+            '\r' => out.push_str("\\r"),
+            '\t' => out.push_str("\\t"),
+            c if c.is_control() => {
+                let _ = write!(out, "\\u{:04x}", c as u32);
+            }
+            c => out.push(c),
+        }
+    }
+}
+
+/// current UTC time as RFC 3339 with milliseconds
+fn timestamp() -> String {
+    rfc3339(SystemTime::now())
+}
+
+/// `time` in UTC as RFC 3339 with milliseconds
+pub(crate) fn rfc3339(time: SystemTime) -> String {
+    let now = time.duration_since(UNIX_EPOCH).unwrap_or_default();
+    let secs = now.as_secs() as i64;
+    let (days, rem) = (secs.div_euclid(86400), secs.rem_euclid(86400));
+
+    // civil date from the number of days since the epoch, after Howard Hinnant
+    let z = days + 719_468;
+    let era = z.div_euclid(146_097);
+    let doe = z.rem_euclid(146_097);
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + if month <= 2 { 1 } else { 0 };
+
+    format!(
+        "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}.{:03}Z",
+        year, month, day, rem / 3600, rem / 60 % 60, rem % 60, now.subsec_millis()
+    )
+}
+
+impl<'a, 'kvs> VisitSource<'kvs> for Fields<'a> {
+    fn visit_pair(&mut self, key: Key<'kvs>, value: Value<'kvs>) -> Result<(), KvError> {
+        match self.format {
+            LogFormat::Text => {
+                let _ = write!(self.out, " {}=", key);
+                text_value(self.out, &value.to_string());
+            }
+            LogFormat::Json => {
+                self.out.push(',');
+                json_str(self.out, key.as_str());
+                self.out.push(':');
+
+                if let Some(x) = value.to_u64() {
+                    let _ = write!(self.out, "{}", x);
+                } else if let Some(x) = value.to_i64() {
+                    let _ = write!(self.out, "{}", x);
+                } else if let Some(x) = value.to_bool() {
+                    let _ = write!(self.out, "{}", x);
+                } else {
+                    json_str(self.out, &value.to_string());
+                }
+            }
+        }
+
+        Ok(())
+    }
+}
+
+impl Logger {
+    fn format(&self, record: &Record, with_ts: bool) -> String {
+        let mut out = String::with_capacity(128);
+
+        match self.format {
+            LogFormat::Text => {
+                if with_ts {
+                    out.push_str(&timestamp());
+                    out.push(' ');
+                }
+
+                let _ = write!(out, "{:<5} ", record.level());
+                text_msg(&mut out, &record.args().to_string());
+            }
+            LogFormat::Json => {
+                out.push('{');
+
+                if with_ts {
+                    out.push_str("\"ts\":");
+                    json_str(&mut out, &timestamp());
+                    out.push(',');
+                }
+
+                out.push_str("\"level\":");
+                json_str(&mut out, record.level().as_str());
+                out.push_str(",\"msg\":");
+                json_str(&mut out, &record.args().to_string());
+            }
+        }
+
+        let _ = record.key_values().visit(&mut Fields { format: self.format, out: &mut out });
+
+        if self.format == LogFormat::Json {
+            out.push('}');
+        }
+
+        out
+    }
+}
+
+impl Log for Logger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        metadata.level() <= log::max_level()
+    }
+
+    fn log(&self, record: &Record) {
+        if !self.enabled(record.metadata()) {
+            return;
+        }
+
+        match self.target {
+            LogTarget::Stderr => {
+                let mut line = self.format(record, true);
+                line.push('\n');
+
+                let _ = stderr().write_all(line.as_bytes());
+            }
+            LogTarget::Syslog => {
+                // syslog adds the time itself
+                let line = self.format(record, false);
+
+                let priority = match record.level() {
+                    Level::Error => libc::LOG_ERR,
+                    Level::Warn => libc::LOG_WARNING,
+                    Level::Info => libc::LOG_INFO,
+                    Level::Debug | Level::Trace => libc::LOG_DEBUG,
+                };
+
+                if let Ok(line) = CString::new(line) {
+                    unsafe {
+                        libc::syslog(priority, b"%s\0".as_ptr() as *const libc::c_char, line.as_ptr());
+                    }
+                }
+            }
+        }
+    }
+
+    fn flush(&self) {
+        let _ = stderr().flush();
+    }
+}
+
+/// install the logger for the whole process, may only be called once
+pub fn init(conf: &LogConf) -> Result<(), SetLoggerError> {
+    if conf.target == LogTarget::Syslog {
+        unsafe {
+            libc::openlog(b"sfw\0".as_ptr() as *const libc::c_char, libc::LOG_PID, libc::LOG_DAEMON);
+        }
+    }
+
+    log::set_boxed_logger(Box::new(Logger { format: conf.format, target: conf.target }))?;
+    log::set_max_level(conf.level);
+
+    Ok(())
+}
+
+impl Parsable<Result<LogConf, FwConfError>> for LogConf {
+    fn parser<'a, 'b>(app: App<'a, 'b>) -> App<'a, 'b> {
+        app
+            .arg(
+                Arg::with_name("log_level")
+                    .long("log-level")
+                    .possible_values(&["off", "error", "warn", "info", "debug", "trace"])
+                    .default_value("info")
+                    .required(false)
+            )
+            .arg(
+                Arg::with_name("log_format")
+                    .long("log-format")
+                    .possible_values(&["text", "json"])
+                    .default_value("text")
+                    .required(false)
+            )
+            .arg(
+                Arg::with_name("log_target")
+                    .long("log-target")
+                    .help("syslog is picked up by journald as well")
+                    .possible_values(&["stderr", "syslog"])
+                    .default_value("stderr")
+                    .required(false)
+            )
+    }
+
+    fn parse(matches: &ArgMatches) -> Result<LogConf, FwConfError> {
+        let level = matches.value_of("log_level").ok_or("log_level")?;
+        let level = level.parse::<LevelFilter>().map_err(|_| "log_level")?;
+
+        let format = matches.value_of("log_format").ok_or("log_format")?;
+        let format = format.parse::<LogFormat>().map_err(|_| "log_format")?;
+
+        let target = match matches.value_of("log_target").ok_or("log_target")? {
+            "stderr" => LogTarget::Stderr,
+            "syslog" => LogTarget::Syslog,
+            _ => return Err("log_target".into()),
+        };
+
+        Ok(LogConf { level, format, target })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn text(msg: &str, kvs: &[(&str, &str)]) -> String {
+        let logger = Logger { format: LogFormat::Text, target: LogTarget::Stderr };
+        let args = format_args!("{}", msg);
+        let record = Record::builder().level(Level::Warn).args(args).key_values(&kvs).build();
+
+        logger.format(&record, false)
+    }
+
+    #[test]
+    fn formats_text() {
+        assert_eq!(text("closed", &[("conn_id", "520"), ("peer", "10.0.0.5:47502")]), "WARN  closed conn_id=520 peer=10.0.0.5:47502");
+    }
+
+    #[test]
+    fn quotes_text_values() {
+        assert_eq!(text("x", &[("identity", "CN=ci,O=example")]), r#"WARN  x identity="CN=ci,O=example""#);
+        assert_eq!(text("x", &[("command", "close 520")]), r#"WARN  x command="close 520""#);
+        assert_eq!(text("x", &[("peer", "")]), r#"WARN  x peer="""#);
+        assert_eq!(text("x", &[("a", "b\" c=\"d\nWARN  forged")]), r#"WARN  x a="b\" c=\"d\nWARN  forged""#);
+        assert_eq!(text("denied GET /\r\nERROR forged", &[]), r"WARN  denied GET /\r\nERROR forged");
+    }
+
+    #[test]
+    fn formats_json() {
+        let logger = Logger { format: LogFormat::Json, target: LogTarget::Stderr };
+        let kvs = [("peer", "a\"b")];
+        let args = format_args!("line\nbreak");
+        let record = Record::builder().level(Level::Info).args(args).key_values(&kvs).build();
+
+        assert_eq!(logger.format(&record, false), r#"{"level":"INFO","msg":"line\nbreak","peer":"a\"b"}"#);
+    }
+}
@@ -0,0 +1,272 @@
+use std::collections::HashMap;
+use std::fmt::Write as FmtWrite;
+use std::io::{Error as IoError, ErrorKind, Read, Write};
+use std::net::TcpListener as StdTcpListener;
+use std::sync::atomic::Ordering;
+use std::time::{Duration, Instant};
+use mio::tcp::{TcpListener as MioTcpListener, TcpStream};
+use mio::{Poll, PollOpt, Ready, Token};
+use log::debug;
+
+use crate::{FwStats, Pollable, LOOP_BUCKETS};
+use crate::proto::http::{reply, Head, MAX_HEAD};
+
+/// Clients served at once, the ones accepted past it are dropped
+const MAX_CLIENTS: usize = 64;
+
+/// Time a client has to send its request and read the response
+const CLIENT_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Scrape in progress, the response is written once the request head is complete
+struct HttpClient {
+    stream: TcpStream,
+    req: Vec<u8>,
+    resp: Vec<u8>,
+    sent: usize,
+    deadline: Instant,
+}
+
+/// HTTP listener exporting the counters of the forwarder, polled by the event loop of the forwarder itself
+pub struct MetricsServer {
+    listener: MioTcpListener,
+    clients: HashMap<usize, HttpClient>,
+}
+
+impl MetricsServer {
+    pub fn new(listener: StdTcpListener) -> Result<Self, IoError> {
+        Ok(MetricsServer {
+            listener: MioTcpListener::from_std(listener)?,
+            clients: HashMap::new(),
+        })
+    }
+
+    /// accept a single client, `None` once there is no one left to accept
+    pub fn accept(&mut self) -> Result<Option<TcpStream>, IoError> {
+        match self.listener.accept() {
+            Ok((x, _)) => Ok(Some(x)),
+            Err(x) => match x.kind() {
+                ErrorKind::WouldBlock => Ok(None),
+                _ => Err(x),
+            }
+        }
+    }
+
+    /// start serving `stream` under `tok`, it is dropped if too many clients are served already
+    pub fn add(&mut self, poll: &Poll, conn_id: usize, tok: usize, stream: TcpStream) -> Result<(), IoError> {
+        if self.clients.len() >= MAX_CLIENTS {
+            debug!(conn_id; "too many metrics clients, client dropped");
+            return Ok(());
+        }
+
+        poll.register(&stream, Token(tok), Ready::readable() | Ready::writable(), PollOpt::edge())?;
+
+        let client = HttpClient { stream, req: Vec::with_capacity(512), resp: Vec::new(), sent: 0, deadline: Instant::now() + CLIENT_TIMEOUT };
+        self.clients.insert(conn_id, client);
+
+        Ok(())
+    }
+
+    /// drop the clients that did not get their response in time
+    pub fn expire(&mut self, poll: &Poll) {
+        let now = Instant::now();
+
+        let expired: Vec<usize> = self.clients.iter()
+            .filter(|(_, x)| x.deadline <= now)
+            .map(|(x, _)| *x)
+            .collect();
+
+        for conn_id in expired {
+            debug!(conn_id; "metrics client timed out");
+            self.remove(poll, conn_id);
+        }
+    }
+
+    fn remove(&mut self, poll: &Poll, conn_id: usize) {
+        if let Some(client) = self.clients.remove(&conn_id) {
+            if let Err(x) = poll.deregister(&client.stream) {
+                debug!(conn_id; "failed to deregister: {}", x);
+            }
+        }
+    }
+
+    pub fn owns(&self, conn_id: usize) -> bool {
+        self.clients.contains_key(&conn_id)
+    }
+
+    /// make progress on the client `conn_id`, which is answered by `respond` once its request head is complete
+    pub fn handle(&mut self, poll: &Poll, conn_id: usize, respond: impl FnOnce(&Head) -> Vec<u8>) {
+        let done = match self.clients.get_mut(&conn_id) {
+            Some(client) => client.handle(respond).unwrap_or_else(|x| {
+                debug!(conn_id; "metrics client failed: {}", x);
+                true
+            }),
+            None => return,
+        };
+
+        if done {
+            self.remove(poll, conn_id);
+        }
+    }
+}
+
+impl HttpClient {
+    /// returns `true` once the client can be dropped
+    fn handle(&mut self, respond: impl FnOnce(&Head) -> Vec<u8>) -> Result<bool, IoError> {
+        if self.resp.is_empty() {
+            let mut buff = [0; 1024];
+
+            loop {
+                match self.stream.read(&mut buff) {
+                    Ok(0) => return Ok(true),
+                    Ok(x) => self.req.extend_from_slice(&buff[..x]),
+                    Err(x) => match x.kind() {
+                        ErrorKind::WouldBlock => break,
+                        ErrorKind::Interrupted => continue,
+                        _ => return Err(x),
+                    }
+                }
+
+                if self.req.len() > MAX_HEAD {
+                    break;
+                }
+            }
+
+            self.resp = match Head::parse(&self.req) {
+                Ok(Some((head, _))) => respond(&head),
+                Ok(None) => return Ok(false),
+                Err(_) => reply("400 Bad Request", &[("Content-Length", "0"), ("Connection", "close")]),
+            };
+        }
+
+        while self.sent < self.resp.len() {
+            match self.stream.write(&self.resp[self.sent..]) {
+                Ok(x) => self.sent += x,
+                Err(x) => match x.kind() {
+                    ErrorKind::WouldBlock => return Ok(false),
+                    ErrorKind::Interrupted => continue,
+                    _ => return Err(x),
+                }
+            }
+        }
+
+        Ok(true)
+    }
+}
+
+impl Pollable for MetricsServer {
+    fn register(&self, poll: &Poll, tok: usize) -> Result<(), IoError> {
+        poll.register(&self.listener, Token(tok), Ready::readable(), PollOpt::level())
+    }
+
+    fn deregister(&self, poll: &Poll) -> Result<(), IoError> {
+        for client in self.clients.values() {
+            poll.deregister(&client.stream)?;
+        }
+
+        poll.deregister(&self.listener)
+    }
+}
+
+/// a complete response carrying `body`
+pub fn respond_with(status: &str, content_type: &str, body: &str) -> Vec<u8> {
+    let len = body.len().to_string();
+
+    let mut res = reply(status, &[("Content-Type", content_type), ("Content-Length", &len), ("Connection", "close")]);
+    res.extend_from_slice(body.as_bytes());
+    res
+}
+
+fn metric(out: &mut String, name: &str, kind: &str, help: &str) {
+    let _ = writeln!(out, "# HELP {} {}", name, help);
+    let _ = writeln!(out, "# TYPE {} {}", name, kind);
+}
+
+/// escape `x` for the value of a label
+fn label(x: &str) -> String {
+    x.replace('\\', "\\\\").replace('"', "\\\"").replace('\n', "\\n")
+}
+
+/// render `stats` in the Prometheus text format
+pub fn render(stats: &FwStats) -> String {
+    let mut out = String::with_capacity(2048);
+
+    metric(&mut out, "sfw_connections_active", "gauge", "Pairs currently open.");
+    let _ = writeln!(out, "sfw_connections_active {}", stats.active.load(Ordering::Relaxed));
+
+    metric(&mut out, "sfw_handshakes_pending", "gauge", "Channels of open pairs still connecting or in a handshake.");
+    let _ = writeln!(out, "sfw_handshakes_pending {}", stats.pending.load(Ordering::Relaxed));
+
+    metric(&mut out, "sfw_connections_accepted_total", "counter", "Clients accepted.");
+    let _ = writeln!(out, "sfw_connections_accepted_total {}", stats.accepted.load(Ordering::Relaxed));
+
+    metric(&mut out, "sfw_connections_rejected_total", "counter", "Clients dropped while being accepted.");
+    let _ = writeln!(out, "sfw_connections_rejected_total {}", stats.rejected.load(Ordering::Relaxed));
+
+    metric(&mut out, "sfw_connections_failed_total", "counter", "Pairs closed because of an error.");
+    let _ = writeln!(out, "sfw_connections_failed_total {}", stats.failed.load(Ordering::Relaxed));
+
+    metric(&mut out, "sfw_bytes_total", "counter", "Bytes forwarded, rx from the clients and tx to the clients.");
+    let _ = writeln!(out, "sfw_bytes_total{{direction=\"rx\"}} {}", stats.rx.load(Ordering::Relaxed));
+    let _ = writeln!(out, "sfw_bytes_total{{direction=\"tx\"}} {}", stats.tx.load(Ordering::Relaxed));
+
+    metric(&mut out, "sfw_upstream_connect_failures_total", "counter", "Upstream connections that could not be established.");
+    let _ = writeln!(out, "sfw_upstream_connect_failures_total {}", stats.upstream_failures.load(Ordering::Relaxed));
+
+    metric(&mut out, "sfw_upstream_pool_idle", "gauge", "Upstream connections opened ahead of the clients and not paired yet.");
+    let _ = writeln!(out, "sfw_upstream_pool_idle {}", stats.pooled.load(Ordering::Relaxed));
+
+    metric(&mut out, "sfw_tls_handshake_failures_total", "counter", "TLS handshakes that failed, by reason.");
+
+    if let Ok(x) = stats.tls_failures.lock() {
+        for (reason, count) in x.iter() {
+            let _ = writeln!(out, "sfw_tls_handshake_failures_total{{reason=\"{}\"}} {}", reason, count);
+        }
+    }
+
+    if let Ok(x) = stats.upstreams.lock() {
+        if !x.is_empty() {
+            let labels = |route: &str, upstream: &str| format!("route=\"{}\",upstream=\"{}\"", label(route), label(upstream));
+
+            metric(&mut out, "sfw_upstream_up", "gauge", "Whether the health checks count the upstream as up.");
+
+            for (route, upstreams) in x.iter() {
+                for u in upstreams.iter() {
+                    let _ = writeln!(out, "sfw_upstream_up{{{}}} {}", labels(route, u.name()), u.is_up() as u8);
+                }
+            }
+
+            metric(&mut out, "sfw_upstream_connections_active", "gauge", "Connections open to the upstream.");
+
+            for (route, upstreams) in x.iter() {
+                for u in upstreams.iter() {
+                    let _ = writeln!(out, "sfw_upstream_connections_active{{{}}} {}", labels(route, u.name()), u.active());
+                }
+            }
+
+            metric(&mut out, "sfw_upstream_checks_failed_total", "counter", "Health checks of the upstream that failed.");
+
+            for (route, upstreams) in x.iter() {
+                for u in upstreams.iter() {
+                    let _ = writeln!(out, "sfw_upstream_checks_failed_total{{{}}} {}", labels(route, u.name()), u.failed_checks());
+                }
+            }
+        }
+    }
+
+    metric(&mut out, "sfw_loop_iteration_seconds", "histogram", "Time spent handling the events of a single poll.");
+
+    let mut cumulative = 0;
+
+    for (bound, count) in LOOP_BUCKETS.iter().zip(stats.loop_buckets.iter()) {
+        cumulative += count.load(Ordering::Relaxed);
+        let _ = writeln!(out, "sfw_loop_iteration_seconds_bucket{{le=\"{}\"}} {}", bound, cumulative);
+    }
+
+    let count = stats.loop_count.load(Ordering::Relaxed);
+
+    let _ = writeln!(out, "sfw_loop_iteration_seconds_bucket{{le=\"+Inf\"}} {}", count);
+    let _ = writeln!(out, "sfw_loop_iteration_seconds_sum {}", stats.loop_micros.load(Ordering::Relaxed) as f64 / 1e6);
+    let _ = writeln!(out, "sfw_loop_iteration_seconds_count {}", count);
+
+    out
+}
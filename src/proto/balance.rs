@@ -0,0 +1,467 @@
+use std::cell::Cell;
+use std::collections::hash_map::DefaultHasher;
+use std::hash::{Hash, Hasher};
+use std::io::Error as IoError;
+use std::str::FromStr;
+use std::sync::Arc;
+use std::time::{SystemTime, UNIX_EPOCH};
+use clap::{App, Arg, ArgMatches};
+use log::warn;
+use mio::Poll;
+
+use crate::{Chan, Connector, ConnInfo, Dest, FwError, MidChan, NextState, Pollable, RouteErr};
+use crate::args::Parsable;
+use crate::health::{HealthConf, Upstream};
+use crate::proto::common::StreamConf;
+use crate::proto::either::{Either, EitherErr};
+use crate::proto::tcp::{TcpChan, TcpConnector, TcpErr, MidTcpChan};
+use crate::proto::unix::{UnixChan, UnixConnector, UnixErr, MidUnixChan};
+
+pub type BalanceErr = EitherErr<UnixErr, TcpErr>;
+
+type BackendConnector = Either<UnixConnector, TcpConnector>;
+type BackendChan = Either<UnixChan, TcpChan>;
+type BackendMidChan = Either<MidUnixChan, MidTcpChan>;
+type BackendState = NextState<BalanceErr, BackendChan, BackendMidChan>;
+
+/// Order the backends are tried in for a new client
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Strategy {
+    RoundRobin,
+    /// fewest open connections first, counted over every worker
+    LeastConn,
+    Random,
+    /// same backend for the same client identity, or the address of the client if it has none
+    Sticky,
+}
+
+impl FromStr for Strategy {
+    type Err = ();
+
+    fn from_str(x: &str) -> Result<Self, Self::Err> {
+        match x {
+            "round-robin" => Ok(Strategy::RoundRobin),
+            "least-conn" => Ok(Strategy::LeastConn),
+            "random" => Ok(Strategy::Random),
+            "sticky" => Ok(Strategy::Sticky),
+            _ => Err(()),
+        }
+    }
+}
+
+/// `unix:<path>` or an absolute path for a Unix socket, `tcp:<host:port>` or `<host:port>` for a TCP address
+pub fn backend(x: &str, conf: &StreamConf) -> Result<BackendConnector, &'static str> {
+    if let Some(path) = x.strip_prefix("unix:") {
+        return Ok(Either::A(UnixConnector::new(path)));
+    }
+
+    if x.starts_with('/') {
+        return Ok(Either::A(UnixConnector::new(x)));
+    }
+
+    let addr = x.strip_prefix("tcp:").unwrap_or(x);
+
+    if !addr.contains(':') {
+        return Err("backend must be a unix socket path or host:port");
+    }
+
+    Ok(Either::B(TcpConnector::new(addr, conf)))
+}
+
+/// Connection counted against a backend until it is dropped
+struct Lease {
+    upstreams: Arc<[Upstream]>,
+    idx: usize,
+}
+
+impl Lease {
+    fn new(upstreams: &Arc<[Upstream]>, idx: usize) -> Self {
+        upstreams[idx].opened();
+
+        Lease { upstreams: upstreams.clone(), idx }
+    }
+
+    fn upstream(&self) -> &Upstream {
+        &self.upstreams[self.idx]
+    }
+}
+
+impl Drop for Lease {
+    fn drop(&mut self) {
+        self.upstreams[self.idx].closed();
+    }
+}
+
+/// Backends left to try for a single client
+struct Attempt {
+    conn_id: usize,
+    backends: Arc<[BackendConnector]>,
+    upstreams: Arc<[Upstream]>,
+    /// indexes of the backends not tried yet, the next one last
+    left: Vec<usize>,
+}
+
+impl Attempt {
+    /// connect the next backend whose `connect()` succeeds
+    fn next(&mut self) -> Result<(Lease, BackendState), FwError<BalanceErr>> {
+        let mut last = None;
+
+        while let Some(idx) = self.left.pop() {
+            let lease = Lease::new(&self.upstreams, idx);
+            let info = ConnInfo { conn_id: self.conn_id, dest: None, peer: None, identity: None };
+
+            match self.backends[idx].clone().connect(&info) {
+                Ok(x) => return Ok((lease, x)),
+                Err(x) => {
+                    self.failed(&lease, &x);
+                    last = Some(x);
+                }
+            }
+        }
+
+        Err(last.unwrap_or_else(|| "no backend left to try".into()))
+    }
+
+    fn failed(&self, lease: &Lease, err: &FwError<BalanceErr>) {
+        if !self.left.is_empty() {
+            warn!(conn_id = self.conn_id, backend = lease.upstream().name(); "backend failed, trying the next one: {}", err);
+        }
+    }
+}
+
+/// Upstream channel of a balanced connection
+pub struct BalanceChan {
+    inner: BackendChan,
+    lease: Lease,
+}
+
+impl BalanceChan {
+    pub fn backend(&self) -> &str {
+        self.lease.upstream().name()
+    }
+}
+
+impl Pollable for BalanceChan {
+    fn register(&self, poll: &Poll, tok: usize) -> Result<(), IoError> {
+        self.inner.register(poll, tok)
+    }
+
+    fn deregister(&self, poll: &Poll) -> Result<(), IoError> {
+        self.inner.deregister(poll)
+    }
+}
+
+impl Chan for BalanceChan {
+    type Err = BalanceErr;
+
+    fn send(&mut self, buff: &[u8]) -> Result<usize, FwError<Self::Err>> {
+        self.inner.send(buff)
+    }
+
+    fn recv(&mut self, buff: &mut [u8]) -> Result<Option<usize>, FwError<Self::Err>> {
+        self.inner.recv(buff)
+    }
+
+    fn pending(&self) -> bool {
+        self.inner.pending()
+    }
+
+    fn peer(&self) -> Option<String> {
+        self.inner.peer()
+    }
+
+    fn identity(&self) -> Option<String> {
+        self.inner.identity()
+    }
+
+    fn dest(&self) -> Option<&Dest> {
+        self.inner.dest()
+    }
+
+    fn routed(&mut self, res: Result<(), RouteErr>) -> Result<(), FwError<Self::Err>> {
+        self.inner.routed(res)
+    }
+}
+
+/// Upstream connection to a backend that is not established yet,
+/// the next backend is connected in its place if it fails
+pub struct MidBalanceChan {
+    inner: BackendMidChan,
+    lease: Lease,
+    attempt: Attempt,
+    /// token the channel was registered under, the replacement is registered under the same one
+    tok: Cell<Option<usize>>,
+}
+
+impl Pollable for MidBalanceChan {
+    fn register(&self, poll: &Poll, tok: usize) -> Result<(), IoError> {
+        self.tok.set(Some(tok));
+        self.inner.register(poll, tok)
+    }
+
+    fn deregister(&self, poll: &Poll) -> Result<(), IoError> {
+        self.tok.set(None);
+        self.inner.deregister(poll)
+    }
+}
+
+impl MidChan for MidBalanceChan {
+    type Err = BalanceErr;
+    type C = BalanceChan;
+
+    fn peer(&self) -> Option<String> {
+        self.inner.peer()
+    }
+
+    fn try_channel(self, poll: &Poll) -> Result<NextState<Self::Err, Self::C, Self>, FwError<Self::Err>> {
+        let MidBalanceChan { inner, lease, mut attempt, tok } = self;
+
+        let err = match inner.try_channel(poll) {
+            Ok(NextState::Pending(inner)) => return Ok(NextState::Pending(MidBalanceChan { inner, lease, attempt, tok })),
+            Ok(NextState::Active(inner)) => return Ok(NextState::Active(BalanceChan { inner, lease })),
+            Err(x) => x,
+        };
+
+        if attempt.left.is_empty() {
+            return Err(err);
+        }
+
+        attempt.failed(&lease, &err);
+        drop(lease);
+
+        let (lease, next) = attempt.next()?;
+
+        match next {
+            NextState::Pending(inner) => {
+                let next = MidBalanceChan { inner, lease, attempt, tok };
+
+                if let Some(x) = next.tok.get() {
+                    next.inner.register(poll, x).map_err(FwError::Register)?;
+                }
+
+                Ok(NextState::Pending(next))
+            }
+            NextState::Active(inner) => {
+                if let Some(x) = tok.get() {
+                    inner.register(poll, x).map_err(FwError::Register)?;
+                }
+
+                Ok(NextState::Active(BalanceChan { inner, lease }))
+            }
+        }
+    }
+}
+
+/// Connects every client to one of several backends, picked by `strategy`.
+/// Backends that fail to connect are skipped and the next one is tried for the same client,
+/// those the health checks found down are only tried once all of the others failed.
+#[derive(Clone)]
+pub struct BalanceConnector {
+    backends: Arc<[BackendConnector]>,
+    upstreams: Arc<[Upstream]>,
+    strategy: Strategy,
+    health: Option<HealthConf>,
+    /// the event loop watches the sockets of the Unix backends for re-creation
+    watch: bool,
+    /// backend round-robin starts from next time
+    next: usize,
+    /// state of the xorshift generator behind `Strategy::Random`
+    seed: u64,
+}
+
+impl BalanceConnector {
+    /// `backends` are named as given, for the logs and the metrics
+    pub fn new(backends: Vec<(String, BackendConnector)>, strategy: Strategy) -> Self {
+        let seed = SystemTime::now().duration_since(UNIX_EPOCH).map(|x| x.as_nanos() as u64).unwrap_or(0);
+        let upstreams: Vec<Upstream> = backends.iter().map(|(x, _)| Upstream::new(x)).collect();
+        let backends: Vec<BackendConnector> = backends.into_iter().map(|(_, x)| x).collect();
+
+        BalanceConnector {
+            backends: backends.into(),
+            upstreams: upstreams.into(),
+            strategy,
+            health: None,
+            watch: false,
+            next: 0,
+            seed: seed | 1,
+        }
+    }
+
+    pub fn with_watch(mut self, watch: bool) -> Self {
+        self.watch = watch;
+        self
+    }
+
+    /// check the backends in the background as described by `health`
+    pub fn with_health(mut self, health: HealthConf) -> Self {
+        self.health = Some(health);
+        self
+    }
+
+    fn random(&mut self, conn_id: usize) -> usize {
+        // workers start from the same seed, the connection id keeps them from picking the same backends
+        let mut x = self.seed ^ (conn_id as u64).wrapping_mul(0x9e37_79b9_7f4a_7c15);
+
+        x ^= x << 13;
+        x ^= x >> 7;
+        x ^= x << 17;
+
+        self.seed = if x == 0 { 1 } else { x };
+
+        (x % self.backends.len() as u64) as usize
+    }
+
+    /// what a sticky client is known by: its identity, or its address without the port
+    fn client_key<'a>(info: &ConnInfo<'a>) -> Option<&'a str> {
+        if let Some(x) = info.identity {
+            return Some(x);
+        }
+
+        info.peer.map(|x| match x.rfind(':') {
+            Some(sep) if !x.ends_with(']') => x[..sep].trim_start_matches('[').trim_end_matches(']'),
+            _ => x,
+        })
+    }
+
+    /// indexes of the backends in the order they are tried for the client described by `info`
+    fn order(&mut self, info: &ConnInfo) -> Vec<usize> {
+        let len = self.backends.len();
+
+        let start = match self.strategy {
+            Strategy::Random => self.random(info.conn_id),
+            Strategy::Sticky if Self::client_key(info).is_some() => {
+                let mut hasher = DefaultHasher::new();
+                Self::client_key(info).hash(&mut hasher);
+                (hasher.finish() % len as u64) as usize
+            }
+            _ => {
+                let x = self.next;
+                self.next = (x + 1) % len;
+                x
+            }
+        };
+
+        let mut order: Vec<usize> = (0..len).map(|x| (start + x) % len).collect();
+        let upstreams = &self.upstreams;
+
+        // stable, so backends as busy as each other still take turns
+        match self.strategy {
+            Strategy::LeastConn => order.sort_by_key(|x| (!upstreams[*x].is_up(), upstreams[*x].active())),
+            _ => order.sort_by_key(|x| !upstreams[*x].is_up()),
+        }
+
+        order
+    }
+
+    fn attempt(&self, conn_id: usize, mut order: Vec<usize>) -> Result<NextState<BalanceErr, BalanceChan, MidBalanceChan>, FwError<BalanceErr>> {
+        order.reverse();
+
+        let mut attempt = Attempt { conn_id, backends: self.backends.clone(), upstreams: self.upstreams.clone(), left: order };
+
+        match attempt.next()? {
+            (lease, NextState::Pending(inner)) => {
+                Ok(NextState::Pending(MidBalanceChan { inner, lease, attempt, tok: Cell::new(None) }))
+            }
+            (lease, NextState::Active(inner)) => Ok(NextState::Active(BalanceChan { inner, lease })),
+        }
+    }
+}
+
+impl Connector for BalanceConnector {
+    type Err = BalanceErr;
+    type C = BalanceChan;
+    type PC = MidBalanceChan;
+
+    fn connect(&mut self, info: &ConnInfo) -> Result<NextState<Self::Err, Self::C, Self::PC>, FwError<Self::Err>> {
+        let order = self.order(info);
+
+        self.attempt(info.conn_id, order)
+    }
+
+    /// sticky clients may only be known by the identity they authenticate with once active
+    fn deferred(&self) -> bool {
+        self.strategy == Strategy::Sticky
+    }
+
+    fn upstreams(&self) -> Option<Arc<[Upstream]>> {
+        Some(self.upstreams.clone())
+    }
+
+    fn health(&self) -> Option<&HealthConf> {
+        self.health.as_ref()
+    }
+
+    fn sockets(&self) -> Vec<(usize, String)> {
+        if !self.watch {
+            return Vec::new();
+        }
+
+        self.backends.iter()
+            .enumerate()
+            .filter_map(|(idx, x)| match x {
+                Either::A(x) => Some((idx, x.path().to_string())),
+                Either::B(_) => None,
+            })
+            .collect()
+    }
+
+    fn connect_upstream(&mut self, idx: usize, info: &ConnInfo) -> Result<NextState<Self::Err, Self::C, Self::PC>, FwError<Self::Err>> {
+        if idx >= self.backends.len() {
+            return Err("no such backend".into());
+        }
+
+        self.attempt(info.conn_id, vec![idx])
+    }
+}
+
+impl Parsable<Result<BalanceConnector, FwError<BalanceErr>>> for BalanceConnector {
+    fn parser<'a, 'b>(app: App<'a, 'b>) -> App<'a, 'b> {
+        let app = app
+            .arg(
+                Arg::with_name("backend")
+                    .help("unix:<path>, tcp:<host:port>, or just the path or host:port of a backend")
+                    .required(true)
+                    .multiple(true)
+                    .index(1)
+            )
+            .arg(
+                Arg::with_name("strategy")
+                    .long("strategy")
+                    .help("order the backends are tried in for a new client")
+                    .possible_values(&["round-robin", "least-conn", "random", "sticky"])
+                    .default_value("round-robin")
+                    .required(false)
+            )
+            .arg(
+                Arg::with_name("watch")
+                    .long("watch")
+                    .help("count Unix backends as down or up as soon as their socket file is removed or created")
+            );
+
+        StreamConf::parser(HealthConf::parser(app))
+    }
+
+    fn parse(matches: &ArgMatches) -> Result<BalanceConnector, FwError<BalanceErr>> {
+        let conf = StreamConf::parse(matches)?;
+
+        let mut backends = Vec::new();
+
+        for x in matches.values_of("backend").into_iter().flatten() {
+            backends.push((x.to_string(), backend(x, &conf)?));
+        }
+
+        if backends.is_empty() {
+            return Err("no backends given".into());
+        }
+
+        let strategy = matches.value_of("strategy").unwrap_or("round-robin");
+        let strategy = strategy.parse::<Strategy>().map_err(|_| "invalid strategy")?;
+
+        let connector = BalanceConnector::new(backends, strategy).with_watch(matches.is_present("watch"));
+
+        match HealthConf::parse(matches)? {
+            Some(x) => Ok(connector.with_health(x)),
+            None => Ok(connector),
+        }
+    }
+}
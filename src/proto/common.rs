@@ -1,8 +1,12 @@
+use std::io::Error as IoError;
+use std::os::unix::io::AsRawFd;
 use std::time::Duration;
 use crate::args::Parsable;
 use clap::{Arg, App};
 use clap::ArgMatches;
 
+use crate::{Chan, FwError};
+
 #[derive(Debug, Clone)]
 pub struct StreamConf {
     pub linger: Option<Duration>,
@@ -48,4 +52,47 @@ impl Parsable<Result<StreamConf, &'static str>> for StreamConf {
             StreamConf { linger, keepalive }
         )
     }
-}
\ No newline at end of file
+}
+/// read whatever is available from `chan` and append it to `buff`,
+/// returns `None` when the channel would block and `Some(0)` when it was closed
+pub fn fill<C: Chan>(chan: &mut C, buff: &mut Vec<u8>) -> Result<Option<usize>, FwError<C::Err>> {
+    let len = buff.len();
+    buff.resize(len + 4096, 0);
+
+    let read = chan.recv(&mut buff[len..]);
+
+    let got = match &read {
+        Ok(Some(x)) => *x,
+        _ => 0,
+    };
+
+    buff.truncate(len + got);
+
+    read
+}
+
+/// write a short handshake reply, which is expected to fit into the socket buffer
+pub fn send_all<C: Chan>(chan: &mut C, mut buff: &[u8]) -> Result<(), FwError<C::Err>> {
+    while !buff.is_empty() {
+        match chan.send(buff)? {
+            0 => return Err(FwError::Disconnected),
+            x => buff = &buff[x..],
+        }
+    }
+
+    Ok(())
+}
+
+pub fn set_nonblocking(fd: &impl AsRawFd) -> Result<(), IoError> {
+    let fd = fd.as_raw_fd();
+
+    unsafe {
+        let flags = libc::fcntl(fd, libc::F_GETFL);
+
+        if flags < 0 || libc::fcntl(fd, libc::F_SETFL, flags | libc::O_NONBLOCK) < 0 {
+            return Err(IoError::last_os_error());
+        }
+    }
+
+    Ok(())
+}
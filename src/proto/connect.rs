@@ -0,0 +1,298 @@
+use std::io::Error as IoError;
+use std::error::Error;
+use std::fmt::{Debug, Display, Formatter, Result as FmtResult};
+use mio::Poll;
+use clap::{App, ArgMatches};
+
+use crate::{Classify, ErrorClass, Listener, MidChan, Chan, Dest, FwError, NextState, Pollable, RouteErr, SharedListener};
+use crate::args::Parsable;
+use crate::proto::common::{fill, send_all};
+use crate::proto::http::{Head, reply};
+
+#[derive(Debug)]
+pub enum ConnectErr<E: Debug> {
+    Inner(E),
+    Str(String),
+}
+
+impl<E: Debug + Display> Display for ConnectErr<E> {
+    fn fmt(&self, f: &mut Formatter) -> FmtResult {
+        match self {
+            ConnectErr::Inner(x) => Display::fmt(x, f),
+            ConnectErr::Str(x) => f.write_str(x),
+        }
+    }
+}
+
+impl<E: Error + 'static> Error for ConnectErr<E> {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            ConnectErr::Inner(x) => x.source(),
+            ConnectErr::Str(_) => None,
+        }
+    }
+}
+
+impl<E: Debug + Classify> Classify for ConnectErr<E> {
+    fn class(&self) -> ErrorClass {
+        match self {
+            ConnectErr::Inner(x) => x.class(),
+            ConnectErr::Str(_) => ErrorClass::Config,
+        }
+    }
+
+    fn tls_failure(&self) -> Option<&'static str> {
+        match self {
+            ConnectErr::Inner(x) => x.tls_failure(),
+            ConnectErr::Str(_) => None,
+        }
+    }
+}
+
+impl<E: Debug> From<&str> for FwError<ConnectErr<E>> {
+    fn from(x: &str) -> FwError<ConnectErr<E>> {
+        FwError::Io(ConnectErr::Str(x.to_string()))
+    }
+}
+
+fn lift<E: Debug>(x: FwError<E>) -> FwError<ConnectErr<E>> {
+    x.map(ConnectErr::Inner)
+}
+
+/// parse the authority form `host:port` of a CONNECT request target
+pub fn parse_authority(x: &str) -> Result<Dest, &'static str> {
+    let sep = x.rfind(':').ok_or("port missing")?;
+
+    let host = &x[..sep];
+    let host = match host.strip_prefix('[') {
+        Some(x) => x.strip_suffix(']').ok_or("invalid ipv6 address")?,
+        None => host,
+    };
+
+    if host.is_empty() {
+        return Err("host missing");
+    }
+
+    let port = x[sep + 1..].parse::<u16>().map_err(|_| "invalid port")?;
+
+    Ok(Dest { host: host.to_string(), port })
+}
+
+/// A tunnel requested through `CONNECT host:port`
+pub struct ConnectChan<C> {
+    chan: C,
+    dest: Dest,
+    /// data the client sent right behind its request
+    rbuf: Vec<u8>,
+}
+
+/// CONNECT request is being received over an already established inner channel
+pub struct ConnectRequest<C> {
+    chan: C,
+    buff: Vec<u8>,
+}
+
+pub enum ConnectMidChan<C, PC> {
+    Inner(PC),
+    Request(ConnectRequest<C>),
+}
+
+/// HTTP proxy accepting `CONNECT` requests, the destination is picked by a routing connector
+pub struct ConnectListener<L> {
+    inner: L,
+}
+
+enum Step<C> {
+    Pending(ConnectRequest<C>),
+    Active(ConnectChan<C>),
+}
+
+impl<C: Chan> ConnectRequest<C> {
+    fn refuse(&mut self, status: &str, reason: &str) -> FwError<ConnectErr<C::Err>> {
+        let _ = send_all(&mut self.chan, &reply(status, &[("Connection", "close"), ("Content-Length", "0")]));
+        reason.into()
+    }
+
+    fn step(mut self) -> Result<Step<C>, FwError<ConnectErr<C::Err>>> {
+        let (head, len) = loop {
+            match Head::parse(&self.buff) {
+                Ok(Some(x)) => break x,
+                Ok(None) => {}
+                Err(x) => return Err(self.refuse("400 Bad Request", x)),
+            }
+
+            match fill(&mut self.chan, &mut self.buff).map_err(lift)? {
+                Some(0) => return Err(FwError::Disconnected),
+                Some(_) => continue,
+                None => return Ok(Step::Pending(self)),
+            }
+        };
+
+        if head.method != "CONNECT" {
+            return Err(self.refuse("405 Method Not Allowed", "only CONNECT requests are served"));
+        }
+
+        let dest = match parse_authority(&head.target) {
+            Ok(x) => x,
+            Err(x) => return Err(self.refuse("400 Bad Request", x)),
+        };
+
+        self.buff.drain(..len);
+
+        Ok(Step::Active(ConnectChan { chan: self.chan, dest, rbuf: self.buff }))
+    }
+}
+
+impl<C: Chan> Chan for ConnectChan<C> {
+    type Err = ConnectErr<C::Err>;
+
+    fn peer(&self) -> Option<String> {
+        self.chan.peer()
+    }
+
+    fn identity(&self) -> Option<String> {
+        self.chan.identity()
+    }
+
+    fn send(&mut self, buff: &[u8]) -> Result<usize, FwError<Self::Err>> {
+        self.chan.send(buff).map_err(lift)
+    }
+
+    fn recv(&mut self, buff: &mut [u8]) -> Result<Option<usize>, FwError<Self::Err>> {
+        if !self.rbuf.is_empty() {
+            let len = buff.len().min(self.rbuf.len());
+            buff[..len].copy_from_slice(&self.rbuf[..len]);
+            self.rbuf.drain(..len);
+            return Ok(Some(len));
+        }
+
+        self.chan.recv(buff).map_err(lift)
+    }
+
+    fn pending(&self) -> bool {
+        !self.rbuf.is_empty()
+    }
+
+    fn dest(&self) -> Option<&Dest> {
+        Some(&self.dest)
+    }
+
+    fn routed(&mut self, res: Result<(), RouteErr>) -> Result<(), FwError<Self::Err>> {
+        let status = match res {
+            Ok(()) => "200 Connection established",
+            Err(RouteErr::Denied) => "403 Forbidden",
+            Err(RouteErr::Unreachable) => "502 Bad Gateway",
+        };
+
+        let headers: &[(&str, &str)] = match res {
+            Ok(()) => &[],
+            Err(_) => &[("Connection", "close"), ("Content-Length", "0")],
+        };
+
+        send_all(&mut self.chan, &reply(status, headers)).map_err(lift)
+    }
+}
+
+impl<C: Pollable> Pollable for ConnectChan<C> {
+    fn register(&self, poll: &Poll, tok: usize) -> Result<(), IoError> {
+        self.chan.register(poll, tok)
+    }
+
+    fn deregister(&self, poll: &Poll) -> Result<(), IoError> {
+        self.chan.deregister(poll)
+    }
+}
+
+impl<C: Pollable, PC: Pollable> Pollable for ConnectMidChan<C, PC> {
+    fn register(&self, poll: &Poll, tok: usize) -> Result<(), IoError> {
+        match self {
+            ConnectMidChan::Inner(x) => x.register(poll, tok),
+            ConnectMidChan::Request(x) => x.chan.register(poll, tok),
+        }
+    }
+
+    fn deregister(&self, poll: &Poll) -> Result<(), IoError> {
+        match self {
+            ConnectMidChan::Inner(x) => x.deregister(poll),
+            ConnectMidChan::Request(x) => x.chan.deregister(poll),
+        }
+    }
+}
+
+impl<E: Debug, C: Chan<Err=E>, PC: MidChan<Err=E, C=C>> MidChan for ConnectMidChan<C, PC> {
+    type Err = ConnectErr<E>;
+    type C = ConnectChan<C>;
+
+    fn peer(&self) -> Option<String> {
+        match self {
+            ConnectMidChan::Inner(x) => x.peer(),
+            ConnectMidChan::Request(x) => x.chan.peer(),
+        }
+    }
+
+    fn try_channel(self, poll: &Poll) -> Result<NextState<Self::Err, Self::C, Self>, FwError<Self::Err>> {
+        let request = match self {
+            ConnectMidChan::Inner(x) => match x.try_channel(poll).map_err(lift)? {
+                NextState::Pending(x) => return Ok(NextState::Pending(ConnectMidChan::Inner(x))),
+                NextState::Active(chan) => ConnectRequest { chan, buff: Vec::new() },
+            },
+            ConnectMidChan::Request(x) => x,
+        };
+
+        match request.step()? {
+            Step::Pending(x) => Ok(NextState::Pending(ConnectMidChan::Request(x))),
+            Step::Active(x) => Ok(NextState::Active(x)),
+        }
+    }
+}
+
+impl<L> ConnectListener<L> {
+    pub fn new(inner: L) -> Self {
+        ConnectListener { inner }
+    }
+}
+
+impl<L: SharedListener> SharedListener for ConnectListener<L> {
+    fn try_clone(&self) -> Result<Self, IoError> {
+        Ok(ConnectListener { inner: self.inner.try_clone()? })
+    }
+}
+
+impl<L: Pollable> Pollable for ConnectListener<L> {
+    fn register(&self, poll: &Poll, tok: usize) -> Result<(), IoError> {
+        self.inner.register(poll, tok)
+    }
+
+    fn deregister(&self, poll: &Poll) -> Result<(), IoError> {
+        self.inner.deregister(poll)
+    }
+}
+
+impl<L: Listener> Listener for ConnectListener<L> {
+    type Err = ConnectErr<L::Err>;
+    type C = ConnectChan<L::C>;
+    type PC = ConnectMidChan<L::C, L::PC>;
+
+    fn accept(&mut self) -> Result<Option<NextState<Self::Err, Self::C, Self::PC>>, FwError<Self::Err>> {
+        Ok(
+            self.inner.accept().map_err(lift)?.map(|x| match x {
+                NextState::Pending(x) => NextState::Pending(ConnectMidChan::Inner(x)),
+                NextState::Active(chan) => NextState::Pending(ConnectMidChan::Request(ConnectRequest { chan, buff: Vec::new() })),
+            })
+        )
+    }
+
+    fn reload(&mut self) -> Result<(), FwError<Self::Err>> {
+        self.inner.reload().map_err(lift)
+    }
+}
+
+impl<E: Debug, L: Parsable<Result<L, FwError<E>>>> Parsable<Result<ConnectListener<L>, FwError<ConnectErr<E>>>> for ConnectListener<L> {
+    fn parser<'a, 'b>(app: App<'a, 'b>) -> App<'a, 'b> {
+        L::parser(app)
+    }
+
+    fn parse(matches: &ArgMatches) -> Result<ConnectListener<L>, FwError<ConnectErr<E>>> {
+        Ok(ConnectListener::new(L::parse(matches).map_err(lift)?))
+    }
+}
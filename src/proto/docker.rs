@@ -0,0 +1,2205 @@
+use std::collections::VecDeque;
+use std::fmt::Write as FmtWrite;
+use std::fs::{File, OpenOptions};
+use std::io::{Error as IoError, Write};
+use std::mem;
+use std::str::FromStr;
+use std::sync::{Arc, Mutex};
+use std::time::SystemTime;
+use clap::{App, Arg, ArgMatches};
+use log::{debug, warn};
+use mio::Poll;
+
+use crate::{Chan, Connector, ConnInfo, Dest, FwError, MidChan, NextState, Pollable, RouteErr};
+use crate::args::Parsable;
+use crate::json::Json;
+use crate::logger::{json_str, rfc3339};
+use crate::proto::balance::backend;
+use crate::proto::common::{send_all, StreamConf};
+use crate::proto::either::{Either, EitherErr};
+use crate::proto::http::{Head, Status, MAX_HEAD};
+use crate::proto::tcp::{TcpChan, TcpConnector, TcpErr, MidTcpChan};
+use crate::proto::unix::{UnixChan, UnixConnector, UnixErr, MidUnixChan};
+
+pub type DockerErr = EitherErr<UnixErr, TcpErr>;
+
+type DaemonConnector = Either<UnixConnector, TcpConnector>;
+type DaemonChan = Either<UnixChan, TcpChan>;
+type DaemonMidChan = Either<MidUnixChan, MidTcpChan>;
+
+/// Longest chunk size or trailer line accepted in a chunked body
+const MAX_LINE: usize = 1024;
+
+/// Most client data held back while a request waits for the daemon to take over the connection
+const MAX_HELD: usize = 8 * MAX_HEAD;
+
+/// Largest request body read in full to be checked
+const MAX_BODY: usize = 1 << 20;
+
+/// Endpoints the daemon takes the connection over with, answering `200` without framing the stream
+/// when the client did not ask for an upgrade
+const HIJACKS: &[&str] = &["/containers/*/attach", "/exec/*/start"];
+
+pub const PROFILES: &[&str] = &["read-only"];
+
+/// Inspecting the daemon and its containers, images, networks and volumes without changing them
+const READ_ONLY: &[&str] = &[
+    "GET /_ping",
+    "HEAD /_ping",
+    "GET /version",
+    "GET /info",
+    "GET /system/df",
+    "GET /containers/json",
+    "GET /containers/*/json",
+    "GET /containers/*/top",
+    "GET /containers/*/logs",
+    "GET /containers/*/stats",
+    "GET /containers/*/changes",
+    "GET /images/json",
+    "GET /images/**/json",
+    "GET /images/**/history",
+    "GET /networks",
+    "GET /networks/*",
+    "GET /volumes",
+    "GET /volumes/*",
+];
+
+pub const ESCAPES: &[&str] = &[
+    "privileged", "host-mounts", "host-pid", "host-network", "host-ipc", "host-uts", "host-userns", "host-cgroupns",
+    "cap-add", "devices", "security-opt", "all",
+];
+
+/// Capabilities the daemon gives containers by default
+const DEFAULT_CAPS: &[&str] = &[
+    "CHOWN", "DAC_OVERRIDE", "FSETID", "FOWNER", "MKNOD", "NET_RAW", "SETGID", "SETUID", "SETFCAP", "SETPCAP",
+    "NET_BIND_SERVICE", "SYS_CHROOT", "KILL", "AUDIT_WRITE",
+];
+
+/// Fields of `HostConfig` that are checked, or that give no way into the host. Others are refused once an escape is denied,
+/// in case a daemon newer than this list reads them
+const HOST_CONFIG: &[&str] = &[
+    "Binds", "ContainerIDFile", "LogConfig", "NetworkMode", "PortBindings", "RestartPolicy", "AutoRemove", "VolumeDriver",
+    "VolumesFrom", "ConsoleSize", "Annotations", "CapAdd", "CapDrop", "Capabilities", "CgroupnsMode", "Dns", "DnsOptions",
+    "DnsSearch", "ExtraHosts", "GroupAdd", "IpcMode", "Cgroup", "Links", "OomScoreAdj", "PidMode", "Privileged",
+    "PublishAllPorts", "ReadonlyRootfs", "SecurityOpt", "StorageOpt", "Tmpfs", "UTSMode", "UsernsMode", "ShmSize", "Sysctls",
+    "Runtime", "Isolation", "MaskedPaths", "ReadonlyPaths", "Init", "Mounts",
+    // resources
+    "CpuShares", "Memory", "NanoCpus", "CgroupParent", "BlkioWeight", "BlkioWeightDevice", "BlkioDeviceReadBps",
+    "BlkioDeviceWriteBps", "BlkioDeviceReadIOps", "BlkioDeviceWriteIOps", "CpuPeriod", "CpuQuota", "CpuRealtimePeriod",
+    "CpuRealtimeRuntime", "CpusetCpus", "CpusetMems", "Devices", "DeviceCgroupRules", "DeviceRequests", "KernelMemory",
+    "KernelMemoryTCP", "MemoryReservation", "MemorySwap", "MemorySwappiness", "OomKillDisable", "PidsLimit", "Ulimits",
+    "CpuCount", "CpuPercent", "IOMaximumIOps", "IOMaximumBandwidth",
+];
+
+/// Options of the `local` volume driver
+const LOCAL_VOLUME_OPTS: &[&str] = &["type", "o", "device", "size"];
+
+/// Way out of a container into its host, which containers created or changed through the forwarder may be denied
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Escape {
+    /// `Privileged` containers and exec sessions
+    Privileged,
+    /// `Binds` and `Mounts` of host paths
+    HostMounts,
+    HostPid,
+    HostNetwork,
+    HostIpc,
+    HostUts,
+    HostUserns,
+    HostCgroupns,
+    /// `CapAdd`, and `Capabilities` beyond the default ones
+    CapAdd,
+    /// `Devices`, `DeviceRequests` and `DeviceCgroupRules` giving access to devices of the host
+    Devices,
+    /// `SecurityOpt` turning off confinement, `MaskedPaths`, `ReadonlyPaths` and other runtimes
+    SecurityOpt,
+}
+
+impl Escape {
+    pub const ALL: &'static [Escape] = &[
+        Escape::Privileged, Escape::HostMounts, Escape::HostPid, Escape::HostNetwork, Escape::HostIpc, Escape::HostUts,
+        Escape::HostUserns, Escape::HostCgroupns, Escape::CapAdd, Escape::Devices, Escape::SecurityOpt,
+    ];
+}
+
+impl FromStr for Escape {
+    type Err = ();
+
+    fn from_str(x: &str) -> Result<Self, Self::Err> {
+        match x {
+            "privileged" => Ok(Escape::Privileged),
+            "host-mounts" => Ok(Escape::HostMounts),
+            "host-pid" => Ok(Escape::HostPid),
+            "host-network" => Ok(Escape::HostNetwork),
+            "host-ipc" => Ok(Escape::HostIpc),
+            "host-uts" => Ok(Escape::HostUts),
+            "host-userns" => Ok(Escape::HostUserns),
+            "host-cgroupns" => Ok(Escape::HostCgroupns),
+            "security-opt" => Ok(Escape::SecurityOpt),
+            "cap-add" => Ok(Escape::CapAdd),
+            "devices" => Ok(Escape::Devices),
+            _ => Err(()),
+        }
+    }
+}
+
+/// Request whose JSON body is read in full and checked before it is forwarded
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Inspected {
+    /// `POST /containers/create`
+    Create,
+    /// `POST /containers/{id}/update`
+    Update,
+    /// `POST /containers/{id}/exec`
+    Exec,
+    /// `POST /volumes/create`
+    VolumeCreate,
+    /// `POST /networks/create`
+    NetworkCreate,
+    /// `POST /networks/{id}/connect` and `POST /networks/{id}/disconnect`
+    NetworkConnect,
+}
+
+impl Inspected {
+    fn creates(self) -> bool {
+        matches!(self, Inspected::Create | Inspected::VolumeCreate | Inspected::NetworkCreate)
+    }
+}
+
+/// Networks every container may join, which belong to no one
+const SHARED_NETWORKS: &[&str] = &["", "default", "bridge", "host", "none"];
+
+/// Object of the daemon that belongs to the client that created it through the forwarder
+#[derive(Debug, Clone, PartialEq)]
+enum Object {
+    Container(String),
+    Volume(String),
+    Network(String),
+    /// exec sessions belong to the owner of their container
+    Exec(String),
+}
+
+impl Object {
+    /// request the forwarder sends in between those of the client to find out the owner
+    fn lookup(&self) -> Vec<u8> {
+        let path = match self {
+            Object::Container(x) => format!("/containers/{}/json", encode(x)),
+            Object::Volume(x) => format!("/volumes/{}", encode(x)),
+            Object::Network(x) => format!("/networks/{}", encode(x)),
+            Object::Exec(x) => format!("/exec/{}/json", encode(x)),
+        };
+
+        format!("GET {} HTTP/1.1\r\nHost: docker\r\n\r\n", path).into_bytes()
+    }
+
+    /// labels in the description of the object by the daemon
+    fn labels<'a>(&self, x: &'a Json) -> Option<&'a Json> {
+        match self {
+            Object::Container(_) => x.get("Config")?.get("Labels"),
+            _ => x.get("Labels"),
+        }
+    }
+
+    /// objects of others are answered like the daemon answers for missing ones
+    fn missing(&self) -> String {
+        match self {
+            Object::Container(x) => format!("No such container: {}", x),
+            Object::Volume(x) => format!("get {}: no such volume", x),
+            Object::Network(x) => format!("network {} not found", x),
+            Object::Exec(x) => format!("No such exec instance: {}", x),
+        }
+    }
+}
+
+/// objects named by a request to `path` or by its `query`
+fn path_objects(path: &str, query: &str) -> Vec<Object> {
+    let mut segs = path[1..].split('/');
+
+    let object = match (segs.next(), segs.next()) {
+        (Some("containers"), Some(x)) if !matches!(x, "json" | "create" | "prune") => Object::Container(x.to_string()),
+        (Some("volumes"), Some(x)) if !matches!(x, "create" | "prune") => Object::Volume(x.to_string()),
+        (Some("networks"), Some(x)) if !matches!(x, "create" | "prune") => Object::Network(x.to_string()),
+        (Some("exec"), Some(x)) => Object::Exec(x.to_string()),
+        (Some("commit"), None) => {
+            return query_param(query, "container").map(Object::Container).into_iter().collect();
+        }
+        _ => return Vec::new(),
+    };
+
+    vec![object]
+}
+
+/// objects the body of an inspected request refers to
+fn body_objects(kind: Inspected, body: &Json) -> Vec<Object> {
+    let mut ret = Vec::new();
+
+    let network = |x: &str| match SHARED_NETWORKS.contains(&x) || x.starts_with("container:") {
+        true => None,
+        false => Some(Object::Network(x.to_string())),
+    };
+
+    match kind {
+        Inspected::Create => {
+            let host = body.get("HostConfig").unwrap_or(&Json::Null);
+            fn strs(x: Option<&Json>) -> Vec<&str> {
+                x.map(Json::items).unwrap_or_default().iter().filter_map(Json::as_str).collect()
+            }
+
+            // `<id>[:ro]`, `<name>:<alias>`
+            for x in strs(host.get("VolumesFrom")).into_iter().chain(strs(host.get("Links"))) {
+                let id = x.split(':').next().unwrap_or("").trim_start_matches('/');
+                ret.push(Object::Container(id.to_string()));
+            }
+
+            for mode in &["NetworkMode", "PidMode", "IpcMode"] {
+                if let Some(x) = host.get(mode).and_then(Json::as_str).and_then(|x| x.strip_prefix("container:")) {
+                    ret.push(Object::Container(x.to_string()));
+                }
+            }
+
+            for x in strs(host.get("Binds")) {
+                let source = x.split(':').next().unwrap_or("");
+
+                if binds_volume(source) {
+                    ret.push(Object::Volume(source.to_string()));
+                }
+            }
+
+            for x in host.get("Mounts").map(Json::items).unwrap_or_default() {
+                let volume = x.get("Type").and_then(Json::as_str).map(|x| x.eq_ignore_ascii_case("volume")).unwrap_or(false);
+
+                match x.get("Source").and_then(Json::as_str) {
+                    Some(source) if volume && !source.is_empty() => ret.push(Object::Volume(source.to_string())),
+                    _ => {}
+                }
+            }
+
+            ret.extend(host.get("NetworkMode").and_then(Json::as_str).and_then(network));
+
+            if let Some(Json::Object(x)) = body.get("NetworkingConfig").and_then(|x| x.get("EndpointsConfig")) {
+                ret.extend(x.iter().filter_map(|(k, _)| network(k)));
+            }
+        }
+        Inspected::NetworkConnect => {
+            if let Some(x) = body.get("Container").and_then(Json::as_str) {
+                ret.push(Object::Container(x.to_string()));
+            }
+        }
+        _ => {}
+    }
+
+    ret
+}
+
+/// first value of `name` in a query, decoded
+fn query_param(query: &str, name: &str) -> Option<String> {
+    query.split('&')
+        .filter_map(|x| {
+            let (k, v) = x.split_once('=').unwrap_or((x, ""));
+            match decode(k, true)? == name {
+                true => decode(v, true),
+                false => None,
+            }
+        })
+        .next()
+}
+
+/// `target` with a filter on `label` being `owner` added to its `filters`, those of the client still apply
+fn scope(target: &str, label: &str, owner: &str) -> Result<String, &'static str> {
+    let (path, query) = target.split_once('?').unwrap_or((target, ""));
+
+    let mut filters = match query_param(query, "filters") {
+        Some(x) if !x.is_empty() => Json::parse(x.as_bytes())?,
+        _ => Json::Object(Vec::new()),
+    };
+
+    let entry = format!("{}={}", label, owner);
+
+    let keys = match &mut filters {
+        Json::Object(x) => x,
+        _ => return Err("filters must be an object"),
+    };
+
+    // a map to the daemon, so keys are matched exactly and the last one counts
+    let value = keys.iter().rev().find(|(k, _)| k == "label").map(|(_, v)| v.clone());
+    keys.retain(|(k, _)| k != "label");
+
+    // `["<label>=<value>"]`, or `{"<label>=<value>": true}` from older clients
+    let value = match value {
+        None | Some(Json::Null) => Json::Array(vec![Json::Str(entry)]),
+        Some(Json::Array(mut x)) => {
+            x.push(Json::Str(entry));
+            Json::Array(x)
+        }
+        Some(Json::Object(mut x)) => {
+            x.retain(|(k, _)| *k != entry);
+            x.push((entry, Json::Bool(true)));
+            Json::Object(x)
+        }
+        Some(_) => return Err("invalid label filter"),
+    };
+
+    keys.push(("label".to_string(), value));
+
+    let mut ret = format!("{}?", path);
+
+    for x in query.split('&').filter(|x| !x.is_empty()) {
+        let key = x.split('=').next().unwrap_or("");
+
+        if decode(key, true).as_deref() != Some("filters") {
+            ret.push_str(x);
+            ret.push('&');
+        }
+    }
+
+    ret.push_str("filters=");
+    ret.push_str(&encode(&filters.to_string()));
+
+    Ok(ret)
+}
+
+/// request `head` sent to `target`, and with `body` in place of the one it announced if given
+fn rewrite(head: &Head, target: &str, body: Option<&[u8]>) -> Vec<u8> {
+    let mut ret = format!("{} {} {}\r\n", head.method, target, head.version);
+
+    for (k, v) in &head.headers {
+        if body.is_some() && (k.eq_ignore_ascii_case("Content-Length") || k.eq_ignore_ascii_case("Transfer-Encoding")) {
+            continue;
+        }
+
+        ret.push_str(&format!("{}: {}\r\n", k, v));
+    }
+
+    if let Some(x) = body {
+        ret.push_str(&format!("Content-Length: {}\r\n", x.len()));
+    }
+
+    ret.push_str("\r\n");
+
+    let mut ret = ret.into_bytes();
+    ret.extend_from_slice(body.unwrap_or_default());
+    ret
+}
+
+/// `CAP_SYS_ADMIN`, `sys_admin` and `SYS_ADMIN` are the same capability to the daemon
+fn capability(x: &str) -> String {
+    let x = x.trim().to_ascii_uppercase();
+
+    match x.strip_prefix("CAP_") {
+        Some(x) => x.to_string(),
+        None => x,
+    }
+}
+
+/// Requests allowed through, `<method> <path>` where the method may be `*` and the path is a pattern
+/// in which `*` matches within a single segment and `**` across segments
+#[derive(Debug, Clone, PartialEq)]
+pub struct Rule {
+    method: String,
+    path: String,
+}
+
+impl FromStr for Rule {
+    type Err = &'static str;
+
+    fn from_str(x: &str) -> Result<Self, Self::Err> {
+        let mut parts = x.split_whitespace();
+
+        let method = parts.next().ok_or("rule must be <method> <path>")?;
+        let path = parts.next().filter(|x| x.starts_with('/')).ok_or("rule must be <method> <path>")?;
+
+        if parts.next().is_some() {
+            return Err("rule must be <method> <path>");
+        }
+
+        Ok(Rule { method: method.to_ascii_uppercase(), path: path.to_string() })
+    }
+}
+
+impl Rule {
+    fn allows(&self, method: &str, path: &str) -> bool {
+        (self.method == "*" || self.method == method) && glob(self.path.as_bytes(), path.as_bytes())
+    }
+}
+
+fn glob(pat: &[u8], x: &[u8]) -> bool {
+    match pat {
+        [] => x.is_empty(),
+        [b'*', b'*', rest @ ..] => (0..=x.len()).any(|i| glob(rest, &x[i..])),
+        [b'*', rest @ ..] => {
+            let seg = x.iter().position(|x| *x == b'/').unwrap_or(x.len());
+            (0..=seg).any(|i| glob(rest, &x[i..]))
+        }
+        [c, rest @ ..] => x.first() == Some(c) && glob(rest, &x[1..]),
+    }
+}
+
+/// decode the percent escapes of `x`, and `+` as a space in a query like Go does
+fn decode(x: &str, query: bool) -> Option<String> {
+    let raw = x.as_bytes();
+    let mut decoded = Vec::with_capacity(raw.len());
+    let mut i = 0;
+
+    while i < raw.len() {
+        match raw[i] {
+            b'%' => {
+                let hex = std::str::from_utf8(raw.get(i + 1..i + 3)?).ok()?;
+                decoded.push(u8::from_str_radix(hex, 16).ok()?);
+                i += 3;
+            }
+            b'+' if query => {
+                decoded.push(b' ');
+                i += 1;
+            }
+            x => {
+                decoded.push(x);
+                i += 1;
+            }
+        }
+    }
+
+    String::from_utf8(decoded).ok()
+}
+
+/// percent escape everything in `x` but the unreserved characters
+fn encode(x: &str) -> String {
+    let mut out = String::with_capacity(x.len());
+
+    for c in x.bytes() {
+        match c {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' => out.push(c as char),
+            c => out.push_str(&format!("%{:02X}", c)),
+        }
+    }
+
+    out
+}
+
+/// decode the percent escapes of `path` and drop its API version prefix,
+/// `None` if it could reach something else than it looks like
+fn canonical(path: &str) -> Option<String> {
+    let path = decode(path, false)?;
+
+    if !path.starts_with('/') || (path != "/" && path.split('/').skip(1).any(|x| x.is_empty() || x == "." || x == "..")) {
+        return None;
+    }
+
+    // `/v1.43/containers/json`
+    let version = path[1..].split('/').next().unwrap_or("");
+
+    match version.strip_prefix('v') {
+        Some(x) if !x.is_empty() && x.bytes().all(|x| x.is_ascii_digit() || x == b'.') => Some(path[1 + version.len()..].to_string()),
+        _ => Some(path),
+    }
+}
+
+/// Requests to the Docker Engine API the clients may send, anything else is refused
+#[derive(Debug, Default)]
+pub struct Policy {
+    rules: Vec<Rule>,
+    deny: Vec<Escape>,
+    /// host paths that may still be mounted when `Escape::HostMounts` is denied, along with what is below them
+    binds: Vec<String>,
+    /// capabilities that may still be added when `Escape::CapAdd` is denied
+    caps: Vec<String>,
+    /// label holding the identity of the client that created a container, volume or network
+    owner: Option<String>,
+}
+
+impl Policy {
+    pub fn new(rules: Vec<Rule>) -> Self {
+        Policy { rules, ..Default::default() }
+    }
+
+    /// refuse the containers and exec sessions asking for any of `deny`
+    pub fn with_deny(mut self, deny: Vec<Escape>) -> Self {
+        self.deny = deny;
+        self
+    }
+
+    pub fn with_binds(mut self, binds: Vec<String>) -> Self {
+        self.binds = binds.into_iter().map(|x| x.trim_end_matches('/').to_string()).collect();
+        self
+    }
+
+    pub fn with_caps(mut self, caps: Vec<String>) -> Self {
+        self.caps = caps.iter().map(|x| capability(x)).collect();
+        self
+    }
+
+    /// stamp the containers, volumes and networks created by a client with its identity under `label`,
+    /// and keep it from seeing or using those of others
+    pub fn with_owner(mut self, label: &str) -> Self {
+        self.owner = Some(label.to_string());
+        self
+    }
+
+    /// rules of the built-in profile `name`
+    pub fn profile(name: &str) -> Option<Vec<Rule>> {
+        let rules = match name {
+            "read-only" => READ_ONLY,
+            _ => return None,
+        };
+
+        Some(rules.iter().filter_map(|x| x.parse().ok()).collect())
+    }
+
+    fn check(&self, head: &Head) -> Result<(), &'static str> {
+        let path = canonical(head.path()).ok_or("path is not canonical")?;
+
+        match self.rules.iter().any(|x| x.allows(&head.method, &path)) {
+            true => Ok(()),
+            false => Err("not allowed"),
+        }
+    }
+
+    fn inspected(&self, head: &Head) -> Option<Inspected> {
+        if head.method != "POST" {
+            return None;
+        }
+
+        let path = canonical(head.path())?;
+
+        let kind = match path.as_str() {
+            "/containers/create" => Inspected::Create,
+            "/volumes/create" => Inspected::VolumeCreate,
+            "/networks/create" => Inspected::NetworkCreate,
+            _ => match (path.strip_prefix("/containers/"), path.strip_prefix("/networks/")) {
+                (Some(x), _) => match x.split_once('/') {
+                    Some((_, "update")) => Inspected::Update,
+                    Some((_, "exec")) => Inspected::Exec,
+                    _ => return None,
+                },
+                (_, Some(x)) => match x.split_once('/') {
+                    Some((_, "connect")) | Some((_, "disconnect")) => Inspected::NetworkConnect,
+                    _ => return None,
+                },
+                _ => return None,
+            },
+        };
+
+        let escapes = match kind {
+            Inspected::Create | Inspected::Update | Inspected::Exec => !self.deny.is_empty(),
+            Inspected::VolumeCreate => self.deny.contains(&Escape::HostMounts),
+            _ => false,
+        };
+        let owned = self.owner.is_some() && (kind.creates() || kind == Inspected::NetworkConnect);
+
+        match escapes || owned {
+            true => Some(kind),
+            false => None,
+        }
+    }
+
+    /// lists and prunes that only get to see the objects of the client
+    fn scoped(method: &str, path: &str) -> bool {
+        match method {
+            "GET" => matches!(path, "/containers/json" | "/volumes" | "/networks" | "/events"),
+            "POST" => matches!(path, "/containers/prune" | "/volumes/prune" | "/networks/prune"),
+            _ => false,
+        }
+    }
+
+    fn bind_allowed(&self, path: &str) -> bool {
+        if path.split('/').any(|x| x == "..") {
+            return false;
+        }
+
+        let path = path.trim_end_matches('/');
+
+        self.binds.iter().any(|x| path == x || path.starts_with(x.as_str()) && path[x.len()..].starts_with('/'))
+    }
+
+    /// the first denied escape the body of an inspected request asks for
+    fn check_body(&self, kind: Inspected, body: &Json) -> Result<(), String> {
+        let host = body.get("HostConfig").unwrap_or(&Json::Null);
+
+        let is_true = |x: Option<&Json>| x.and_then(Json::as_bool) == Some(true);
+        let is_host = |x: Option<&Json>| x.and_then(Json::as_str).map(|x| x.eq_ignore_ascii_case("host")) == Some(true);
+        let is_set = |x: Option<&Json>| !matches!(x, None | Some(Json::Null));
+
+        fn items(x: Option<&Json>) -> &[Json] {
+            x.map(Json::items).unwrap_or_default()
+        }
+
+        if kind == Inspected::Create && !self.deny.is_empty() {
+            if let Some(x) = host.unknown(HOST_CONFIG) {
+                return Err(format!("unknown host config {}", x));
+            }
+        }
+
+        for escape in &self.deny {
+            match (escape, kind) {
+                (Escape::Privileged, Inspected::Create) if is_true(host.get("Privileged")) => {
+                    return Err("privileged".into());
+                }
+                (Escape::Privileged, Inspected::Exec) if is_true(body.get("Privileged")) => {
+                    return Err("privileged".into());
+                }
+                (Escape::HostMounts, Inspected::Create) => {
+                    // named volumes of `Binds` are created with it when missing
+                    if let Some(x) = host.get("VolumeDriver").and_then(Json::as_str).filter(|x| !x.is_empty() && *x != "local") {
+                        return Err(format!("volume driver {}", x));
+                    }
+
+                    // `<source>:<target>[:<options>]`, sources not starting with `/` are volumes
+                    for x in items(host.get("Binds")) {
+                        match x.as_str().and_then(|x| x.split(':').next()) {
+                            Some(x) if binds_volume(x) || self.bind_allowed(x) => {}
+                            Some(x) => return Err(format!("mount of {}", x)),
+                            None => return Err("mount without a source".into()),
+                        }
+                    }
+
+                    for x in items(host.get("Mounts")) {
+                        self.check_mount(x)?;
+                    }
+                }
+                (Escape::HostMounts, Inspected::VolumeCreate) => {
+                    self.check_volume(body.get("Driver"), body.get("DriverOpts"))?;
+                }
+                (Escape::HostPid, Inspected::Create) if is_host(host.get("PidMode")) => {
+                    return Err("host PID namespace".into());
+                }
+                (Escape::HostNetwork, Inspected::Create) if is_host(host.get("NetworkMode")) => {
+                    return Err("host network".into());
+                }
+                (Escape::HostIpc, Inspected::Create) if is_host(host.get("IpcMode")) => {
+                    return Err("host IPC namespace".into());
+                }
+                (Escape::HostUts, Inspected::Create) if is_host(host.get("UTSMode")) => {
+                    return Err("host UTS namespace".into());
+                }
+                (Escape::HostUserns, Inspected::Create) if is_host(host.get("UsernsMode")) => {
+                    return Err("host user namespace".into());
+                }
+                (Escape::HostCgroupns, Inspected::Create) if is_host(host.get("CgroupnsMode")) => {
+                    return Err("host cgroup namespace".into());
+                }
+                (Escape::CapAdd, Inspected::Create) => {
+                    for x in items(host.get("CapAdd")) {
+                        let cap = x.as_str().map(capability).unwrap_or_default();
+
+                        if !self.caps.contains(&cap) {
+                            return Err(format!("capability {}", cap));
+                        }
+                    }
+
+                    // replaces the default capabilities
+                    for x in items(host.get("Capabilities")) {
+                        let cap = x.as_str().map(capability).unwrap_or_default();
+
+                        if !DEFAULT_CAPS.contains(&cap.as_str()) && !self.caps.contains(&cap) {
+                            return Err(format!("capability {}", cap));
+                        }
+                    }
+                }
+                (Escape::Devices, Inspected::Create | Inspected::Update) => {
+                    let res = match kind {
+                        Inspected::Create => host,
+                        _ => body,
+                    };
+
+                    for field in &["Devices", "DeviceRequests", "DeviceCgroupRules"] {
+                        if !items(res.get(field)).is_empty() {
+                            return Err("host devices".into());
+                        }
+                    }
+                }
+                (Escape::SecurityOpt, Inspected::Create) => {
+                    for x in items(host.get("SecurityOpt")) {
+                        let opt = x.as_str().unwrap_or_default();
+
+                        if !security_opt_allowed(opt) {
+                            return Err(format!("security option {}", opt));
+                        }
+                    }
+
+                    // an empty list unmasks the paths of the host kernel hidden by default
+                    for field in &["MaskedPaths", "ReadonlyPaths"] {
+                        if is_set(host.get(field)) {
+                            return Err(format!("custom {}", field));
+                        }
+                    }
+
+                    if let Some(x) = host.get("Runtime").and_then(Json::as_str).filter(|x| !x.is_empty() && *x != "runc") {
+                        return Err(format!("runtime {}", x));
+                    }
+                }
+                _ => {}
+            }
+        }
+
+        Ok(())
+    }
+
+    /// entry of `HostConfig.Mounts`
+    fn check_mount(&self, mount: &Json) -> Result<(), String> {
+        let source = mount.get("Source").and_then(Json::as_str);
+
+        // matched exactly by the daemon
+        match mount.get("Type").and_then(Json::as_str).unwrap_or_default() {
+            "bind" => match source {
+                Some(x) if self.bind_allowed(x) => Ok(()),
+                Some(x) => Err(format!("mount of {}", x)),
+                None => Err("mount without a source".into()),
+            },
+            // the volume is created with these options when missing
+            "volume" => match mount.get("VolumeOptions").and_then(|x| x.get("DriverConfig")) {
+                Some(x) => self.check_volume(x.get("Name"), x.get("Options")),
+                None => Ok(()),
+            },
+            "tmpfs" => Ok(()),
+            x => Err(format!("mount type {}", x)),
+        }
+    }
+
+    /// driver and options of a volume, the `local` driver mounts whatever it is given
+    fn check_volume(&self, driver: Option<&Json>, opts: Option<&Json>) -> Result<(), String> {
+        match driver {
+            None | Some(Json::Null) => {}
+            Some(Json::Str(x)) if x.is_empty() || x == "local" => {}
+            Some(x) => return Err(format!("volume driver {}", x.as_str().unwrap_or("?"))),
+        }
+
+        let opts = match opts {
+            None | Some(Json::Null) => return Ok(()),
+            Some(Json::Object(x)) => x,
+            Some(_) => return Err("invalid volume options".into()),
+        };
+
+        // decoded into a map, where the last of the keys given twice wins
+        let opt = |name: &str| opts.iter().rev().find(|(k, _)| k == name).and_then(|(_, v)| v.as_str());
+
+        if let Some((k, _)) = opts.iter().find(|(k, _)| !LOCAL_VOLUME_OPTS.contains(&k.as_str())) {
+            return Err(format!("volume option {}", k));
+        }
+
+        if let Some(x) = opt("type").filter(|x| matches!(*x, "none" | "bind" | "rbind")) {
+            return Err(format!("volume of type {}", x));
+        }
+
+        if let Some(x) = opt("o").and_then(|x| x.split(',').find(|x| matches!(*x, "bind" | "rbind"))) {
+            return Err(format!("volume with option {}", x));
+        }
+
+        match opt("device") {
+            Some(x) if x.starts_with('/') && !self.bind_allowed(x) => Err(format!("volume of {}", x)),
+            _ => Ok(()),
+        }
+    }
+}
+
+/// `SecurityOpt` entry that keeps the container confined, anything unknown is refused
+fn security_opt_allowed(opt: &str) -> bool {
+    // `<key>=<value>`, or `<key>:<value>` from older clients
+    let (key, value) = match opt.find(['=', ':']) {
+        Some(x) => (&opt[..x], &opt[x + 1..]),
+        None => (opt, ""),
+    };
+
+    match key {
+        "no-new-privileges" => true,
+        "apparmor" => !value.is_empty() && value != "unconfined",
+        "label" => !value.is_empty() && value != "disable",
+        // a profile of the client may allow every syscall
+        _ => false,
+    }
+}
+
+/// bind source naming a volume rather than a host path
+fn binds_volume(x: &str) -> bool {
+    !x.is_empty() && !x.contains('/')
+}
+
+/// How the end of a message body is found
+enum Body {
+    Length(u64),
+    Chunked { state: Chunk, line: Vec<u8> },
+    /// runs until the connection is closed
+    Close,
+}
+
+#[derive(Clone, Copy)]
+enum Chunk {
+    Size,
+    Data(u64),
+    /// line break behind the data of a chunk
+    DataEnd,
+    Trailer,
+}
+
+impl Body {
+    fn chunked() -> Body {
+        Body::Chunked { state: Chunk::Size, line: Vec::new() }
+    }
+
+    /// body announced by the `Transfer-Encoding` and `Content-Length` headers of a message, `None` if they announce none
+    fn of(te: bool, len: Option<&str>) -> Result<Option<Body>, &'static str> {
+        match (te, len) {
+            (true, Some(_)) => Err("both transfer-encoding and content-length given"),
+            (true, None) => Ok(Some(Body::chunked())),
+            (false, Some(x)) => match x.parse::<u64>().map_err(|_| "invalid content-length")? {
+                0 => Ok(None),
+                x => Ok(Some(Body::Length(x))),
+            },
+            (false, None) => Ok(None),
+        }
+    }
+
+    /// how much of `buff` belongs to the body, and whether the body ends there.
+    /// The content of the body, without the chunk framing, is appended to `data` if given.
+    fn consume(&mut self, buff: &[u8], mut data: Option<&mut Vec<u8>>) -> Result<(usize, bool), &'static str> {
+        let (state, line) = match self {
+            Body::Length(left) => {
+                let len = (*left).min(buff.len() as u64);
+                *left -= len;
+
+                if let Some(x) = data {
+                    x.extend_from_slice(&buff[..len as usize]);
+                }
+
+                return Ok((len as usize, *left == 0));
+            }
+            Body::Close => {
+                if let Some(x) = data {
+                    x.extend_from_slice(buff);
+                }
+
+                return Ok((buff.len(), false));
+            }
+            Body::Chunked { state, line } => (state, line),
+        };
+
+        let mut pos = 0;
+
+        while pos < buff.len() {
+            if let Chunk::Data(left) = *state {
+                let len = left.min((buff.len() - pos) as u64);
+
+                if let Some(x) = data.as_deref_mut() {
+                    x.extend_from_slice(&buff[pos..pos + len as usize]);
+                }
+
+                pos += len as usize;
+
+                *state = match left - len {
+                    0 => Chunk::DataEnd,
+                    x => Chunk::Data(x),
+                };
+
+                continue;
+            }
+
+            let byte = buff[pos];
+            pos += 1;
+
+            if byte != b'\n' {
+                if line.len() >= MAX_LINE {
+                    return Err("chunk line too long");
+                }
+
+                line.push(byte);
+                continue;
+            }
+
+            let text = mem::take(line);
+            let text = text.strip_suffix(b"\r").ok_or("chunk line not ended with CRLF")?;
+
+            match *state {
+                Chunk::Size => {
+                    // hex digits only, `u64::from_str_radix` would take a sign
+                    let size = text.split(|x| *x == b';').next().unwrap_or(b"");
+
+                    if size.is_empty() || size.len() > 16 || !size.iter().all(u8::is_ascii_hexdigit) {
+                        return Err("invalid chunk size");
+                    }
+
+                    let size = std::str::from_utf8(size).ok()
+                        .and_then(|x| u64::from_str_radix(x, 16).ok())
+                        .ok_or("invalid chunk size")?;
+
+                    *state = match size {
+                        0 => Chunk::Trailer,
+                        x => Chunk::Data(x),
+                    };
+                }
+                Chunk::DataEnd if text.is_empty() => *state = Chunk::Size,
+                Chunk::DataEnd => return Err("chunk not terminated"),
+                Chunk::Trailer if text.is_empty() => return Ok((pos, true)),
+                Chunk::Trailer => {}
+                Chunk::Data(_) => unreachable!("data is consumed above"),
+            }
+        }
+
+        Ok((pos, false))
+    }
+}
+
+/// Query parameters written to the audit log, naming what a request acts on or how forcefully
+const AUDITED_QUERY: &[&str] = &["name", "fromImage", "fromSrc", "repo", "tag", "t", "container", "force", "v", "link", "signal", "noprune"];
+
+/// File receiving a JSON line per request, shared by all the workers
+#[derive(Clone)]
+pub struct AuditLog {
+    out: Arc<Mutex<File>>,
+}
+
+impl AuditLog {
+    /// open `path` for appending, creating it if needed
+    pub fn open(path: &str) -> Result<AuditLog, IoError> {
+        let out = OpenOptions::new().create(true).append(true).open(path)?;
+
+        Ok(AuditLog { out: Arc::new(Mutex::new(out)) })
+    }
+
+    fn write(&self, conn_id: usize, line: &str) {
+        let res = match self.out.lock() {
+            Ok(mut x) => x.write_all(line.as_bytes()),
+            // a worker panicked while writing, the file itself is still usable
+            Err(x) => x.into_inner().write_all(line.as_bytes()),
+        };
+
+        if let Err(x) = res {
+            warn!(conn_id = conn_id; "failed to write the audit log: {}", x);
+        }
+    }
+}
+
+/// Request of a client, written to the audit log once it was answered
+struct Call {
+    time: SystemTime,
+    method: String,
+    path: String,
+    query: Vec<(&'static str, String)>,
+    /// status of the answer, none if the connection was closed before it came
+    status: Option<u16>,
+    /// the answer came from the daemon rather than the filter
+    forwarded: bool,
+    /// body bytes received from the client, and sent to it
+    rx: u64,
+    tx: u64,
+}
+
+impl Call {
+    fn new(head: &Head) -> Self {
+        let query = head.target.split_once('?').map(|(_, x)| x).unwrap_or("");
+
+        Call {
+            time: SystemTime::now(),
+            method: head.method.clone(),
+            path: head.path().to_string(),
+            query: AUDITED_QUERY.iter().filter_map(|x| Some((*x, query_param(query, x)?))).collect(),
+            status: None,
+            forwarded: false,
+            rx: 0,
+            tx: 0,
+        }
+    }
+
+    fn line(&self, conn_id: usize, peer: Option<&str>, identity: Option<&str>) -> String {
+        fn opt(out: &mut String, x: Option<&str>) {
+            match x {
+                Some(x) => json_str(out, x),
+                None => out.push_str("null"),
+            }
+        }
+
+        let mut out = String::with_capacity(256);
+
+        out.push_str("{\"time\":");
+        json_str(&mut out, &rfc3339(self.time));
+        let _ = write!(out, ",\"conn_id\":{},\"peer\":", conn_id);
+        opt(&mut out, peer);
+        out.push_str(",\"identity\":");
+        opt(&mut out, identity);
+        out.push_str(",\"method\":");
+        json_str(&mut out, &self.method);
+        out.push_str(",\"path\":");
+        json_str(&mut out, &self.path);
+        out.push_str(",\"query\":{");
+
+        for (i, (k, v)) in self.query.iter().enumerate() {
+            if i > 0 {
+                out.push(',');
+            }
+
+            json_str(&mut out, k);
+            out.push(':');
+            json_str(&mut out, v);
+        }
+
+        out.push_str("},\"status\":");
+
+        match self.status {
+            Some(x) => { let _ = write!(out, "{}", x); }
+            None => out.push_str("null"),
+        }
+
+        let duration = self.time.elapsed().unwrap_or_default();
+
+        let _ = write!(
+            out,
+            ",\"forwarded\":{},\"rx\":{},\"tx\":{},\"duration_ms\":{}}}",
+            self.forwarded, self.rx, self.tx, duration.as_millis(),
+        );
+
+        out.push('\n');
+
+        out
+    }
+}
+
+/// Answer the client is waiting for, in the order of its requests
+enum Due {
+    /// from the daemon, without a body if the request was `HEAD`.
+    /// The request asked for an upgrade, or went to an endpoint the daemon may take the connection over with
+    Daemon { head: bool, upgrade: bool, hijack: bool, call: Option<Call> },
+    /// made up by the filter, closing the connection behind it if `close`
+    Local { reply: Vec<u8>, close: bool, call: Option<Call> },
+    /// from the daemon to the forwarder, about the owner of `object` used by the request through `of`
+    Lookup { object: Object, of: Object },
+}
+
+/// Follows the requests of a client and the responses of the daemon on a single connection,
+/// forwarding the requests the policy allows and answering the others in their place
+struct Filter {
+    policy: Arc<Policy>,
+    conn_id: usize,
+    peer: Option<String>,
+    identity: Option<String>,
+    /// client data not forwarded yet: an incomplete request head, or whatever follows a request held back
+    input: Vec<u8>,
+    /// rest of the body of the request being forwarded
+    request: Option<Body>,
+    queue: VecDeque<Due>,
+    /// incomplete response head
+    head: Vec<u8>,
+    /// rest of the body of the response being forwarded
+    response: Option<Body>,
+    /// data for the client, the responses of the daemon and the refusals in between
+    output: Vec<u8>,
+    /// a request asked the daemon to take over the connection, the client data behind it waits for the response
+    upgrading: bool,
+    /// the daemon took over the connection (`attach`, `exec`), everything is forwarded as it is
+    raw: bool,
+    /// requests are no longer read, the connection is closed once the answers are out
+    closing: bool,
+    /// objects of the request at the start of `input` whose owner is still to be looked up, the next one last
+    lookups: Vec<(Object, Object)>,
+    /// status and body of the lookup being answered
+    found: Option<(u16, Vec<u8>)>,
+    /// whether the request at the start of `input` only uses objects of the client, once they were all looked up
+    verdict: Option<Result<(), Object>>,
+    audit: Option<AuditLog>,
+    /// request at the start of `input`, once it is audited
+    call: Option<Call>,
+    /// request the daemon took over the connection with, audited once it is closed
+    upgraded: Option<Call>,
+}
+
+impl Filter {
+    fn new(policy: Arc<Policy>, audit: Option<AuditLog>, info: &ConnInfo) -> Self {
+        Filter {
+            policy,
+            conn_id: info.conn_id,
+            peer: info.peer.map(|x| x.to_string()),
+            identity: info.identity.map(|x| x.to_string()),
+            input: Vec::new(),
+            request: None,
+            queue: VecDeque::new(),
+            head: Vec::new(),
+            response: None,
+            output: Vec::new(),
+            upgrading: false,
+            raw: false,
+            closing: false,
+            lookups: Vec::new(),
+            found: None,
+            verdict: None,
+            audit,
+            call: None,
+            upgraded: None,
+        }
+    }
+
+    fn write(&self, call: &Call) {
+        if let Some(audit) = &self.audit {
+            audit.write(self.conn_id, &call.line(self.conn_id, self.peer.as_deref(), self.identity.as_deref()));
+        }
+    }
+
+    fn peer(&self) -> &str {
+        self.peer.as_deref().unwrap_or("-")
+    }
+
+    /// answer a request in place of the daemon with a JSON error like its own
+    fn refuse(&mut self, status: &str, message: &str, head: bool, close: bool) {
+        let mut body = String::from("{\"message\":");
+        json_str(&mut body, message);
+        body.push_str("}\n");
+
+        let mut reply = format!("HTTP/1.1 {}\r\nContent-Type: application/json\r\nContent-Length: {}\r\n", status, body.len());
+
+        if close {
+            reply.push_str("Connection: close\r\n");
+        }
+
+        reply.push_str("\r\n");
+
+        if !head {
+            reply.push_str(&body);
+        }
+
+        let call = self.call.take().map(|mut x| {
+            x.status = status.split(' ').next().and_then(|x| x.parse().ok());
+            x.tx = if head { 0 } else { body.len() as u64 };
+            x
+        });
+
+        self.queue.push_back(Due::Local { reply: reply.into_bytes(), close, call });
+        self.closing |= close;
+    }
+
+    /// move the refusals whose turn came to the output
+    fn advance(&mut self) {
+        while let Some(Due::Local { .. }) = self.queue.front() {
+            if let Some(Due::Local { reply, close, call }) = self.queue.pop_front() {
+                self.output.extend_from_slice(&reply);
+
+                if let Some(x) = &call {
+                    self.write(x);
+                }
+
+                if close {
+                    self.queue.clear();
+                }
+            }
+        }
+    }
+
+    /// the connection is over once this was sent
+    fn done(&self) -> bool {
+        self.closing && self.queue.is_empty()
+    }
+
+    /// the request at the start of `input` waits for its objects to be looked up
+    fn looking(&self) -> bool {
+        !self.lookups.is_empty() || self.queue.iter().any(|x| matches!(x, Due::Lookup { .. }))
+    }
+
+    /// the client data left is not read until the daemon answered
+    fn held(&self) -> bool {
+        self.looking() || self.upgrading && self.request.is_none()
+    }
+
+    /// ask the daemon about the next object of the request held back
+    fn lookup(&mut self, fwd: &mut Vec<u8>) {
+        if let Some((object, of)) = self.lookups.pop() {
+            fwd.extend_from_slice(&object.lookup());
+            self.queue.push_back(Due::Lookup { object, of });
+        }
+    }
+
+    /// forward the allowed requests from `input` up to the first that is incomplete or held back
+    fn requests(&mut self, daemon: &mut DaemonChan) -> Result<(), FwError<DockerErr>> {
+        let policy = self.policy.clone();
+
+        let mut fwd = Vec::new();
+        let mut pos = 0;
+
+        while pos < self.input.len() && !self.closing && !self.held() {
+            if let Some(body) = &mut self.request {
+                let (len, end) = match body.consume(&self.input[pos..], None) {
+                    Ok(x) => x,
+                    Err(x) => {
+                        self.refuse("400 Bad Request", x, false, true);
+                        break;
+                    }
+                };
+
+                fwd.extend_from_slice(&self.input[pos..pos + len]);
+                pos += len;
+
+                if let Some(Due::Daemon { call: Some(x), .. }) = self.queue.back_mut() {
+                    x.rx += len as u64;
+                }
+
+                if end {
+                    self.request = None;
+                }
+
+                continue;
+            }
+
+            let (head, len) = match Head::parse(&self.input[pos..]) {
+                Ok(Some(x)) => x,
+                Ok(None) => break,
+                Err(x) => {
+                    self.refuse("400 Bad Request", x, false, true);
+                    break;
+                }
+            };
+
+            if self.audit.is_some() && self.call.is_none() {
+                self.call = Some(Call::new(&head));
+            }
+
+            let is_head = head.method == "HEAD";
+
+            let te = head.header_has("Transfer-Encoding", "chunked");
+            let mut body = match Body::of(te, head.header("Content-Length")) {
+                Ok(x) => x,
+                Err(x) => {
+                    self.refuse("400 Bad Request", x, is_head, true);
+                    break;
+                }
+            };
+
+            if let Err(x) = policy.check(&head) {
+                warn!(conn_id = self.conn_id, peer = self.peer(); "denied {} {}: {}", head.method, head.target, x);
+
+                // the body of a refused request is not waited for, the client may never send it
+                let message = format!("{} {} is not allowed by the forwarder", head.method, head.path());
+                self.refuse("403 Forbidden", &message, is_head, body.is_some());
+
+                pos += len;
+                continue;
+            }
+
+            let mut end = pos + len;
+            let kind = policy.inspected(&head);
+            let mut json = None;
+
+            // the body is read in full and forwarded along with the head once it passed
+            if let Some(kind) = kind {
+                let size = match &body {
+                    None => 0,
+                    Some(Body::Length(x)) if *x <= MAX_BODY as u64 => *x as usize,
+                    Some(Body::Length(_)) => {
+                        self.refuse("413 Payload Too Large", "request body too large to be checked", false, true);
+                        break;
+                    }
+                    Some(_) => {
+                        self.refuse("411 Length Required", "request body must have a content-length to be checked", false, true);
+                        break;
+                    }
+                };
+
+                if self.input.len() < end + size {
+                    break;
+                }
+
+                let parsed = match size {
+                    0 => Ok(Json::Object(Vec::new())),
+                    _ => Json::parse(&self.input[end..end + size]),
+                };
+
+                end += size;
+                body = None;
+
+                if let Some(x) = &mut self.call {
+                    x.rx = size as u64;
+                }
+
+                let parsed = match parsed {
+                    Ok(Json::Object(x)) => Json::Object(x),
+                    Ok(_) => {
+                        self.refuse("400 Bad Request", "request body must be a json object", false, false);
+                        pos = end;
+                        continue;
+                    }
+                    Err(x) => {
+                        self.refuse("400 Bad Request", x, false, false);
+                        pos = end;
+                        continue;
+                    }
+                };
+
+                if let Err(x) = policy.check_body(kind, &parsed) {
+                    warn!(conn_id = self.conn_id, peer = self.peer(); "denied {} {}: {}", head.method, head.target, x);
+
+                    let message = format!("{} {} denied by the forwarder: {}", head.method, head.path(), x);
+                    self.refuse("403 Forbidden", &message, false, false);
+
+                    pos = end;
+                    continue;
+                }
+
+                json = Some(parsed);
+            }
+
+            let mut fwd_head = None;
+
+            if let Some(label) = &policy.owner {
+                let path = canonical(head.path()).unwrap_or_default();
+                let query = head.target.split_once('?').map(|(_, x)| x).unwrap_or("");
+
+                let mut objects = path_objects(&path, query);
+
+                if let (Some(kind), Some(json)) = (kind, &json) {
+                    objects.extend(body_objects(kind, json));
+                }
+
+                let creates = kind.map(Inspected::creates).unwrap_or(false);
+                let scoped = Policy::scoped(&head.method, &path);
+
+                let owner = match &self.identity {
+                    Some(x) => x.clone(),
+                    None if objects.is_empty() && !creates && !scoped => String::new(),
+                    None => {
+                        warn!(conn_id = self.conn_id, peer = self.peer(); "denied {} {}: no client identity", head.method, head.target);
+
+                        let message = "containers, volumes and networks are only available to clients with a certificate";
+                        self.refuse("403 Forbidden", message, is_head, body.is_some());
+
+                        pos = end;
+                        continue;
+                    }
+                };
+
+                if !objects.is_empty() {
+                    match self.verdict.take() {
+                        None => {
+                            // held back until the daemon told who the objects belong to
+                            self.lookups = objects.into_iter().rev().map(|x| (x.clone(), x)).collect();
+                            self.lookup(&mut fwd);
+                            break;
+                        }
+                        Some(Err(x)) => {
+                            warn!(conn_id = self.conn_id, peer = self.peer(); "denied {} {}: not the owner of {:?}", head.method, head.target, x);
+
+                            self.refuse("404 Not Found", &x.missing(), is_head, body.is_some());
+
+                            pos = end;
+                            continue;
+                        }
+                        Some(Ok(())) => {}
+                    }
+                }
+
+                if let (true, Some(json)) = (creates, &mut json) {
+                    let mut labels = match json.get("Labels") {
+                        Some(Json::Object(x)) => x.clone(),
+                        _ => Vec::new(),
+                    };
+
+                    labels.retain(|(k, _)| k != label);
+                    labels.push((label.clone(), Json::Str(owner.clone())));
+
+                    json.set("Labels", Json::Object(labels));
+
+                    fwd_head = Some(rewrite(&head, &head.target, Some(json.to_string().as_bytes())));
+                }
+
+                if scoped {
+                    // the daemon would take filters from a form in the body of a POST over those of the query
+                    if body.is_some() {
+                        self.refuse("400 Bad Request", "request body not expected", false, true);
+                        break;
+                    }
+
+                    match scope(&head.target, label, &owner) {
+                        Ok(x) => fwd_head = Some(rewrite(&head, &x, None)),
+                        Err(x) => {
+                            self.refuse("400 Bad Request", x, is_head, false);
+                            pos = end;
+                            continue;
+                        }
+                    }
+                }
+            }
+
+            debug!(conn_id = self.conn_id, peer = self.peer(); "allowed {} {}", head.method, head.target);
+
+            let upgrade = head.header_has("Connection", "upgrade");
+
+            let hijack = head.method == "POST" && canonical(head.path())
+                .map(|x| HIJACKS.iter().any(|p| glob(p.as_bytes(), x.as_bytes())))
+                .unwrap_or(false);
+
+            match fwd_head {
+                Some(x) => fwd.extend_from_slice(&x),
+                None => fwd.extend_from_slice(&self.input[pos..end]),
+            }
+
+            let call = self.call.take().map(|mut x| {
+                x.forwarded = true;
+                x
+            });
+
+            self.queue.push_back(Due::Daemon { head: is_head, upgrade, hijack, call });
+            self.upgrading = upgrade || hijack;
+            self.request = body;
+
+            pos = end;
+        }
+
+        match self.closing {
+            true => self.input.clear(),
+            false => { self.input.drain(..pos); }
+        }
+
+        if self.upgrading && self.input.len() > MAX_HELD || self.looking() && self.input.len() > MAX_BODY + MAX_HEAD {
+            return Err("too much data sent ahead of the response".into());
+        }
+
+        send_all(daemon, &fwd)?;
+        self.advance();
+
+        Ok(())
+    }
+
+    /// the daemon described `object` with `code` and `body`, the request held back goes on once all of its objects are known
+    fn looked_up(&mut self, daemon: &mut DaemonChan, object: Object, of: Object, code: u16, body: Vec<u8>) -> Result<(), FwError<DockerErr>> {
+        let found = match code {
+            200 => Json::parse(&body).ok(),
+            _ => None,
+        };
+
+        let label = self.policy.owner.as_deref().unwrap_or("");
+        let identity = self.identity.as_deref();
+
+        let owned = match (&object, &found) {
+            // the owner of an exec session is the owner of its container
+            (Object::Exec(_), Some(x)) => match x.get("ContainerID").and_then(Json::as_str) {
+                Some(id) => {
+                    self.lookups.push((Object::Container(id.to_string()), of));
+
+                    let mut fwd = Vec::new();
+                    self.lookup(&mut fwd);
+                    return send_all(daemon, &fwd);
+                }
+                None => false,
+            },
+            (_, Some(x)) => object.labels(x).and_then(|x| x.key(label)).and_then(Json::as_str) == identity,
+            (_, None) => false,
+        };
+
+        if !owned {
+            self.lookups.clear();
+            self.verdict = Some(Err(of));
+        } else if !self.lookups.is_empty() {
+            let mut fwd = Vec::new();
+            self.lookup(&mut fwd);
+            return send_all(daemon, &fwd);
+        } else {
+            self.verdict = Some(Ok(()));
+        }
+
+        self.requests(daemon)
+    }
+
+    /// the response the front of the queue waited for is complete
+    fn answered(&mut self, daemon: &mut DaemonChan) -> Result<(), FwError<DockerErr>> {
+        match self.queue.pop_front() {
+            Some(Due::Daemon { upgrade, hijack, call, .. }) => {
+                if let Some(x) = &call {
+                    self.write(x);
+                }
+
+                // not taken over after all, what the client sent behind the request is read as requests again
+                if upgrade || hijack {
+                    self.upgrading = false;
+                    self.requests(daemon)?;
+                }
+            }
+            Some(Due::Lookup { object, of }) => {
+                let (code, body) = self.found.take().unwrap_or_default();
+                self.looked_up(daemon, object, of, code, body)?;
+            }
+            _ => {}
+        }
+
+        self.advance();
+
+        Ok(())
+    }
+
+    /// follow the responses in `data` and queue them for the client
+    fn responses(&mut self, daemon: &mut DaemonChan, data: &[u8]) -> Result<(), FwError<DockerErr>> {
+        let mut data = data;
+        let mut buff;
+
+        if !self.head.is_empty() {
+            buff = mem::take(&mut self.head);
+            buff.extend_from_slice(data);
+            data = &buff;
+        }
+
+        let mut pos = 0;
+
+        while pos < data.len() && !self.raw {
+            if let Some(body) = &mut self.response {
+                // the answers to lookups are kept from the client
+                let (len, end) = match &mut self.found {
+                    Some((_, found)) => {
+                        let ret = body.consume(&data[pos..], Some(found))?;
+
+                        if found.len() > MAX_BODY {
+                            return Err("lookup answered with too much data".into());
+                        }
+
+                        ret
+                    }
+                    None => {
+                        let ret = body.consume(&data[pos..], None)?;
+                        self.output.extend_from_slice(&data[pos..pos + ret.0]);
+
+                        if let Some(Due::Daemon { call: Some(x), .. }) = self.queue.front_mut() {
+                            x.tx += ret.0 as u64;
+                        }
+
+                        ret
+                    }
+                };
+
+                pos += len;
+
+                if end {
+                    self.response = None;
+                    self.answered(daemon)?;
+                }
+
+                continue;
+            }
+
+            let (status, len) = match Status::parse(&data[pos..])? {
+                Some(x) => x,
+                None => {
+                    self.head = data[pos..].to_vec();
+                    return Ok(());
+                }
+            };
+
+            let (head, upgrade, hijack) = match self.queue.front_mut() {
+                Some(Due::Daemon { head, upgrade, hijack, call }) => {
+                    if let Some(x) = call {
+                        x.status = Some(status.code);
+                    }
+
+                    (*head, *upgrade, *hijack)
+                }
+                Some(Due::Lookup { .. }) => {
+                    self.found = Some((status.code, Vec::new()));
+                    (false, false, false)
+                }
+                _ => return Err("response without a request".into()),
+            };
+
+            if self.found.is_none() {
+                self.output.extend_from_slice(&data[pos..pos + len]);
+            }
+
+            pos += len;
+
+            let te = status.header_has("Transfer-Encoding", "chunked");
+            let body = Body::of(te, status.header("Content-Length"))?;
+
+            let framed = te || status.header("Content-Length").is_some();
+
+            // `101 UPGRADED`, or the raw stream of `attach` and `exec` for clients not asking for an upgrade
+            if upgrade && status.code == 101 || hijack && status.code == 200 && !framed {
+                debug!(conn_id = self.conn_id, peer = self.peer(); "connection taken over by the daemon");
+
+                self.raw = true;
+
+                if let Some(Due::Daemon { call, .. }) = self.queue.pop_front() {
+                    self.upgraded = call;
+                }
+
+                self.queue.clear();
+
+                let held = mem::take(&mut self.input);
+                send_all(daemon, &held)?;
+
+                if let Some(x) = &mut self.upgraded {
+                    x.rx += held.len() as u64;
+                }
+
+                break;
+            }
+
+            if status.code == 101 {
+                return Err("switching protocols without an upgrade".into());
+            }
+
+            if status.code / 100 == 1 {
+                continue;
+            }
+
+            let body = match head || status.code == 204 || status.code == 304 {
+                true => None,
+                false => match body {
+                    Some(x) => Some(x),
+                    None if framed => None,
+                    None => Some(Body::Close),
+                },
+            };
+
+            match body {
+                Some(x) => self.response = Some(x),
+                None => self.answered(daemon)?,
+            }
+        }
+
+        self.output.extend_from_slice(&data[pos..]);
+
+        if let Some(x) = &mut self.upgraded {
+            x.tx += (data.len() - pos) as u64;
+        }
+
+        Ok(())
+    }
+}
+
+impl Drop for Filter {
+    /// requests still waiting for their answer, or whose answer lasted as long as the connection
+    fn drop(&mut self) {
+        let upgraded = self.upgraded.take();
+
+        let calls = self.queue.drain(..)
+            .filter_map(|x| match x {
+                Due::Daemon { call, .. } | Due::Local { call, .. } => call,
+                Due::Lookup { .. } => None,
+            })
+            .collect::<Vec<_>>();
+
+        for x in upgraded.iter().chain(calls.iter()) {
+            self.write(x);
+        }
+    }
+}
+
+/// Connection to the daemon carrying the requests the policy allows
+pub struct DockerChan {
+    inner: DaemonChan,
+    filter: Filter,
+}
+
+impl Pollable for DockerChan {
+    fn register(&self, poll: &Poll, tok: usize) -> Result<(), IoError> {
+        self.inner.register(poll, tok)
+    }
+
+    fn deregister(&self, poll: &Poll) -> Result<(), IoError> {
+        self.inner.deregister(poll)
+    }
+}
+
+impl Chan for DockerChan {
+    type Err = DockerErr;
+
+    fn send(&mut self, buff: &[u8]) -> Result<usize, FwError<Self::Err>> {
+        if self.filter.raw {
+            let ret = self.inner.send(buff)?;
+
+            if let Some(x) = &mut self.filter.upgraded {
+                x.rx += ret as u64;
+            }
+
+            return Ok(ret);
+        }
+
+        // whatever follows a request the connection is closed after is dropped
+        if !self.filter.closing {
+            self.filter.input.extend_from_slice(buff);
+            self.filter.requests(&mut self.inner)?;
+        }
+
+        Ok(buff.len())
+    }
+
+    fn recv(&mut self, buff: &mut [u8]) -> Result<Option<usize>, FwError<Self::Err>> {
+        loop {
+            let output = &mut self.filter.output;
+
+            if !output.is_empty() {
+                let len = buff.len().min(output.len());
+                buff[..len].copy_from_slice(&output[..len]);
+                output.drain(..len);
+                return Ok(Some(len));
+            }
+
+            if self.filter.raw {
+                let ret = self.inner.recv(buff)?;
+
+                if let (Some(x), Some(len)) = (&mut self.filter.upgraded, ret) {
+                    x.tx += len as u64;
+                }
+
+                return Ok(ret);
+            }
+
+            if self.filter.done() {
+                return Ok(Some(0));
+            }
+
+            match self.inner.recv(buff)? {
+                Some(0) => return Ok(Some(0)),
+                Some(x) => self.filter.responses(&mut self.inner, &buff[..x])?,
+                None => return Ok(None),
+            }
+        }
+    }
+
+    fn pending(&self) -> bool {
+        !self.filter.output.is_empty() || self.filter.done()
+    }
+
+    fn peer(&self) -> Option<String> {
+        self.inner.peer()
+    }
+
+    fn identity(&self) -> Option<String> {
+        self.inner.identity()
+    }
+
+    fn dest(&self) -> Option<&Dest> {
+        self.inner.dest()
+    }
+
+    fn routed(&mut self, res: Result<(), RouteErr>) -> Result<(), FwError<Self::Err>> {
+        self.inner.routed(res)
+    }
+}
+
+/// Connection to the daemon that is not established yet
+pub struct MidDockerChan {
+    inner: DaemonMidChan,
+    filter: Filter,
+}
+
+impl Pollable for MidDockerChan {
+    fn register(&self, poll: &Poll, tok: usize) -> Result<(), IoError> {
+        self.inner.register(poll, tok)
+    }
+
+    fn deregister(&self, poll: &Poll) -> Result<(), IoError> {
+        self.inner.deregister(poll)
+    }
+}
+
+impl MidChan for MidDockerChan {
+    type Err = DockerErr;
+    type C = DockerChan;
+
+    fn peer(&self) -> Option<String> {
+        self.inner.peer()
+    }
+
+    fn try_channel(self, poll: &Poll) -> Result<NextState<Self::Err, Self::C, Self>, FwError<Self::Err>> {
+        let MidDockerChan { inner, filter } = self;
+
+        match inner.try_channel(poll)? {
+            NextState::Pending(inner) => Ok(NextState::Pending(MidDockerChan { inner, filter })),
+            NextState::Active(inner) => Ok(NextState::Active(DockerChan { inner, filter })),
+        }
+    }
+}
+
+/// Forwards the Docker Engine API of a daemon, refusing the requests `policy` does not allow
+/// with a `403` the Docker client shows like an error of the daemon
+#[derive(Clone)]
+pub struct DockerConnector {
+    inner: DaemonConnector,
+    policy: Arc<Policy>,
+    audit: Option<AuditLog>,
+}
+
+impl DockerConnector {
+    pub fn new(inner: DaemonConnector, policy: Policy) -> Self {
+        DockerConnector { inner, policy: Arc::new(policy), audit: None }
+    }
+
+    /// write a line per request into `audit`
+    pub fn with_audit(mut self, audit: AuditLog) -> Self {
+        self.audit = Some(audit);
+        self
+    }
+}
+
+impl Connector for DockerConnector {
+    type Err = DockerErr;
+    type C = DockerChan;
+    type PC = MidDockerChan;
+
+    fn connect(&mut self, info: &ConnInfo) -> Result<NextState<Self::Err, Self::C, Self::PC>, FwError<Self::Err>> {
+        let filter = Filter::new(self.policy.clone(), self.audit.clone(), info);
+
+        match self.inner.connect(info)? {
+            NextState::Pending(inner) => Ok(NextState::Pending(MidDockerChan { inner, filter })),
+            NextState::Active(inner) => Ok(NextState::Active(DockerChan { inner, filter })),
+        }
+    }
+
+    /// owners are told apart, and requests audited, by the identity of the client, only known once its channel is active
+    fn deferred(&self) -> bool {
+        self.policy.owner.is_some() || self.audit.is_some()
+    }
+
+    /// the filter logs the requests it refuses under the connection id of the client
+    fn pooled(&self) -> bool {
+        false
+    }
+}
+
+impl Parsable<Result<DockerConnector, FwError<DockerErr>>> for DockerConnector {
+    fn parser<'a, 'b>(app: App<'a, 'b>) -> App<'a, 'b> {
+        let app = app
+            .arg(
+                Arg::with_name("daemon")
+                    .help("unix:<path>, tcp:<host:port>, or just the path or host:port of the Docker daemon")
+                    .required(true)
+                    .index(1)
+            )
+            .arg(
+                Arg::with_name("profile")
+                    .long("profile")
+                    .help("allow the requests of a built-in profile")
+                    .possible_values(PROFILES)
+                    .multiple(true)
+                    .number_of_values(1)
+            )
+            .arg(
+                Arg::with_name("allow")
+                    .long("allow")
+                    .help("allow requests matching `<method> <path>`, `*` matches within a path segment and `**` across them")
+                    .multiple(true)
+                    .number_of_values(1)
+            )
+            .arg(
+                Arg::with_name("deny")
+                    .long("deny")
+                    .help("refuse containers and exec sessions reaching into the host this way")
+                    .possible_values(ESCAPES)
+                    .multiple(true)
+                    .number_of_values(1)
+            )
+            .arg(
+                Arg::with_name("allow_bind")
+                    .long("allow-bind")
+                    .help("host path that may still be mounted, along with what is below it, when host mounts are denied")
+                    .multiple(true)
+                    .number_of_values(1)
+            )
+            .arg(
+                Arg::with_name("allow_cap")
+                    .long("allow-cap")
+                    .help("capability that may still be added when adding capabilities is denied")
+                    .multiple(true)
+                    .number_of_values(1)
+            )
+            .arg(
+                Arg::with_name("owner_label")
+                    .long("owner-label")
+                    .help("label containers, volumes and networks with the identity of the client creating them, and keep clients to their own")
+                    .takes_value(true)
+            )
+            .arg(
+                Arg::with_name("audit")
+                    .long("audit")
+                    .help("file receiving a JSON line per request, with the client, the response status and the bytes of both bodies")
+                    .takes_value(true)
+            );
+
+        StreamConf::parser(app)
+    }
+
+    fn parse(matches: &ArgMatches) -> Result<DockerConnector, FwError<DockerErr>> {
+        let conf = StreamConf::parse(matches)?;
+
+        let daemon = matches.value_of("daemon").ok_or("daemon not given")?;
+        let daemon = backend(daemon, &conf)?;
+
+        let mut rules = Vec::new();
+
+        for x in matches.values_of("profile").into_iter().flatten() {
+            rules.extend(Policy::profile(x).ok_or("unknown profile")?);
+        }
+
+        for x in matches.values_of("allow").into_iter().flatten() {
+            rules.push(x.parse::<Rule>()?);
+        }
+
+        if rules.is_empty() {
+            return Err("every request would be refused, give a --profile or --allow".into());
+        }
+
+        let mut deny = Vec::new();
+
+        for x in matches.values_of("deny").into_iter().flatten() {
+            match x {
+                "all" => deny.extend_from_slice(Escape::ALL),
+                x => deny.push(x.parse::<Escape>().map_err(|_| "unknown escape")?),
+            }
+        }
+
+        for x in matches.values_of("allow_bind").into_iter().flatten() {
+            if !x.starts_with('/') {
+                return Err("--allow-bind takes absolute paths".into());
+            }
+        }
+
+        let binds = matches.values_of("allow_bind").into_iter().flatten().map(|x| x.to_string()).collect();
+        let caps = matches.values_of("allow_cap").into_iter().flatten().map(|x| x.to_string()).collect();
+
+        let mut policy = Policy::new(rules).with_deny(deny).with_binds(binds).with_caps(caps);
+
+        if let Some(x) = matches.value_of("owner_label") {
+            if x.is_empty() {
+                return Err("--owner-label must not be empty".into());
+            }
+
+            policy = policy.with_owner(x);
+        }
+
+        let mut ret = DockerConnector::new(daemon, policy);
+
+        if let Some(path) = matches.value_of("audit") {
+            let audit = AuditLog::open(path)
+                .map_err(|x| FwError::Io(EitherErr::A(UnixErr::Str(format!("audit log {}: {}", path, x)))))?;
+            ret = ret.with_audit(audit);
+        }
+
+        Ok(ret)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn policy() -> Policy {
+        Policy::new(vec![])
+            .with_deny(Escape::ALL.to_vec())
+            .with_binds(vec!["/srv/ci/".to_string()])
+            .with_caps(vec!["net_admin".to_string()])
+    }
+
+    fn check(kind: Inspected, body: &str) -> Result<(), String> {
+        policy().check_body(kind, &Json::parse(body.as_bytes()).unwrap())
+    }
+
+    fn create(host: &str) -> Result<(), String> {
+        check(Inspected::Create, &format!("{{\"Image\":\"alpine\",\"HostConfig\":{}}}", host))
+    }
+
+    #[test]
+    fn allows_confined_containers() {
+        assert!(create("{}").is_ok());
+        assert!(create(r#"{"Binds":["vol:/v","/srv/ci/work:/w:ro"],"Mounts":[{"Type":"tmpfs","Target":"/t"}]}"#).is_ok());
+        assert!(create(r#"{"Mounts":[{"Type":"volume","Source":"v","VolumeOptions":{"DriverConfig":{"Name":"local"}}}]}"#).is_ok());
+        assert!(create(r#"{"CapAdd":["cap_net_admin"],"Capabilities":["CHOWN","KILL"],"NetworkMode":"bridge"}"#).is_ok());
+        assert!(create(r#"{"SecurityOpt":["no-new-privileges","apparmor=docker-default","label=type:svirt_t"],"Runtime":"runc"}"#).is_ok());
+        assert!(check(Inspected::Exec, r#"{"Cmd":["sh"],"Privileged":false}"#).is_ok());
+        assert!(check(Inspected::Update, r#"{"Memory":1024}"#).is_ok());
+        assert!(check(Inspected::VolumeCreate, r#"{"Name":"v","DriverOpts":{"type":"tmpfs","device":"tmpfs","o":"size=64m"}}"#).is_ok());
+    }
+
+    #[test]
+    fn refuses_host_mounts() {
+        assert!(create(r#"{"Binds":["/etc:/x"]}"#).is_err());
+        assert!(create(r#"{"Binds":["/srv/ci/../../etc:/x"]}"#).is_err());
+        assert!(create(r#"{"Binds":["/srv/cix:/x"]}"#).is_err());
+        assert!(create(r#"{"Mounts":[{"Type":"bind","Source":"/"}]}"#).is_err());
+        assert!(create(r#"{"Mounts":[{"Type":"npipe","Source":"x"}]}"#).is_err());
+        assert!(create(r#"{"Mounts":[{"Source":"/"}]}"#).is_err());
+        assert!(create(r#"{"VolumeDriver":"local-persist","Binds":["v:/x"]}"#).is_err());
+
+        let inline = r#"{"Mounts":[{"Type":"volume","Source":"v","VolumeOptions":{"DriverConfig":{"Options":{"type":"none","o":"bind","device":"/"}}}}]}"#;
+        assert_eq!(create(inline).unwrap_err(), "volume of type none");
+    }
+
+    #[test]
+    fn refuses_host_volumes() {
+        let volume = |x: &str| check(Inspected::VolumeCreate, x);
+
+        assert!(volume(r#"{"Name":"v","DriverOpts":{"type":"none","o":"bind","device":"/"}}"#).is_err());
+        assert!(volume(r#"{"Name":"v","DriverOpts":{"type":"ext4","device":"/dev/sda1"}}"#).is_err());
+        assert!(volume(r#"{"Name":"v","Driver":"local","DriverOpts":{"type":"none","o":"bind","device":"/srv/ci/x"}}"#).is_err());
+        assert!(volume(r#"{"Name":"v","DriverOpts":{"o":"ro,rbind","device":"tmpfs"}}"#).is_err());
+        assert!(volume(r#"{"Name":"v","DriverOpts":{"mountpoint":"/"}}"#).is_err());
+        assert!(volume(r#"{"Name":"v","Driver":"local-persist"}"#).is_err());
+        // the options are a map, the last `type` is used
+        assert!(volume(r#"{"Name":"v","DriverOpts":{"type":"tmpfs","type":"none","device":"tmpfs"}}"#).is_err());
+        // and the fields are matched ignoring their case
+        assert!(volume(r#"{"Name":"v","driveropts":{"type":"none","o":"bind","device":"/"}}"#).is_err());
+    }
+
+    #[test]
+    fn refuses_escapes() {
+        assert!(create(r#"{"Privileged":true}"#).is_err());
+        assert!(create(r#"{"privileged":true}"#).is_err());
+        assert!(check(Inspected::Exec, r#"{"Privileged":true}"#).is_err());
+        assert!(create(r#"{"PidMode":"host"}"#).is_err());
+        assert!(create(r#"{"NetworkMode":"HOST"}"#).is_err());
+        assert!(create(r#"{"IpcMode":"host"}"#).is_err());
+        assert!(create(r#"{"UTSMode":"host"}"#).is_err());
+        assert!(create(r#"{"UsernsMode":"host"}"#).is_err());
+        assert!(create(r#"{"CgroupnsMode":"host"}"#).is_err());
+        assert!(create(r#"{"CapAdd":["SYS_ADMIN"]}"#).is_err());
+        assert!(create(r#"{"CapAdd":["ALL"]}"#).is_err());
+        assert!(create(r#"{"Capabilities":["CAP_SYS_ADMIN"]}"#).is_err());
+        assert!(create(r#"{"Devices":[{"PathOnHost":"/dev/sda"}]}"#).is_err());
+        assert!(create(r#"{"DeviceRequests":[{"Count":-1}]}"#).is_err());
+        assert!(create(r#"{"DeviceCgroupRules":["a *:* rwm"]}"#).is_err());
+        assert!(check(Inspected::Update, r#"{"DeviceCgroupRules":["a *:* rwm"]}"#).is_err());
+        assert!(create(r#"{"SecurityOpt":["seccomp=unconfined"]}"#).is_err());
+        assert!(create(r#"{"SecurityOpt":["seccomp={}"]}"#).is_err());
+        assert!(create(r#"{"SecurityOpt":["apparmor=unconfined"]}"#).is_err());
+        assert!(create(r#"{"SecurityOpt":["apparmor:unconfined"]}"#).is_err());
+        assert!(create(r#"{"SecurityOpt":["label=disable"]}"#).is_err());
+        assert!(create(r#"{"SecurityOpt":["systempaths=unconfined"]}"#).is_err());
+        assert!(create(r#"{"MaskedPaths":[]}"#).is_err());
+        assert!(create(r#"{"ReadonlyPaths":[]}"#).is_err());
+        assert!(create(r#"{"Runtime":"sysbox"}"#).is_err());
+        assert!(create(r#"{"NewEscapeHatch":true}"#).is_err());
+    }
+
+    #[test]
+    fn matches_rules() {
+        let rule = |x: &str| x.parse::<Rule>().unwrap();
+
+        assert!(rule("get /containers/*/json").allows("GET", "/containers/abc/json"));
+        assert!(!rule("GET /containers/*/json").allows("GET", "/containers/a/b/json"));
+        assert!(!rule("GET /containers/*/json").allows("POST", "/containers/abc/json"));
+        assert!(!rule("GET /containers/*").allows("GET", "/containers/abc/json"));
+        assert!(rule("* /images/**/json").allows("DELETE", "/images/registry:5000/a/b/json"));
+        assert!(rule("* /**").allows("GET", "/"));
+        assert!(!rule("GET /_ping").allows("GET", "/_pingx"));
+
+        assert!("GET".parse::<Rule>().is_err());
+        assert!("GET containers".parse::<Rule>().is_err());
+        assert!("GET /a /b".parse::<Rule>().is_err());
+    }
+
+    #[test]
+    fn canonicalizes_paths() {
+        assert_eq!(canonical("/v1.43/containers/json").as_deref(), Some("/containers/json"));
+        assert_eq!(canonical("/containers/json").as_deref(), Some("/containers/json"));
+        assert_eq!(canonical("/%63ontainers/create").as_deref(), Some("/containers/create"));
+        assert_eq!(canonical("/vx/containers").as_deref(), Some("/vx/containers"));
+
+        assert_eq!(canonical("/_ping/../containers/create"), None);
+        assert_eq!(canonical("/_ping/%2e%2e/containers/create"), None);
+        assert_eq!(canonical("/containers//create"), None);
+        assert_eq!(canonical("/containers/./create"), None);
+        assert_eq!(canonical("/containers/"), None);
+        assert_eq!(canonical("containers"), None);
+        assert_eq!(canonical("/a%2"), None);
+        assert_eq!(canonical("/a%zz"), None);
+    }
+
+    #[test]
+    fn checks_request_paths() {
+        let policy = Policy::new(Policy::profile("read-only").unwrap());
+        let head = |x: &str| Head::parse(x.as_bytes()).unwrap().unwrap().0;
+
+        assert!(policy.check(&head("GET /v1.43/containers/json?all=1 HTTP/1.1\r\n\r\n")).is_ok());
+        assert!(policy.check(&head("GET /_ping HTTP/1.1\r\n\r\n")).is_ok());
+        assert!(policy.check(&head("POST /containers/create HTTP/1.1\r\n\r\n")).is_err());
+        assert!(policy.check(&head("GET /containers/json/../../containers/create HTTP/1.1\r\n\r\n")).is_err());
+        assert!(policy.check(&head("GET /containers/abc%2Fexec/json HTTP/1.1\r\n\r\n")).is_err());
+    }
+
+    fn consume(mut body: Body, data: &[u8]) -> Result<(usize, bool, Vec<u8>), &'static str> {
+        let mut content = Vec::new();
+        let (len, end) = body.consume(data, Some(&mut content))?;
+
+        Ok((len, end, content))
+    }
+
+    #[test]
+    fn frames_bodies() {
+        assert!(Body::of(false, None).unwrap().is_none());
+        assert!(Body::of(false, Some("0")).unwrap().is_none());
+        assert!(Body::of(true, Some("5")).is_err());
+        assert!(Body::of(false, Some("x")).is_err());
+
+        assert_eq!(consume(Body::Length(3), b"abcdef").unwrap(), (3, true, b"abc".to_vec()));
+        assert_eq!(consume(Body::Length(8), b"abcdef").unwrap(), (6, false, b"abcdef".to_vec()));
+        assert_eq!(consume(Body::Close, b"abc").unwrap(), (3, false, b"abc".to_vec()));
+
+        let data = b"5;ext=1\r\nhello\r\n1\r\n!\r\n0\r\nX-Trailer: a\r\n\r\nGET /";
+        assert_eq!(consume(Body::chunked(), data).unwrap(), (data.len() - 5, true, b"hello!".to_vec()));
+    }
+
+    #[test]
+    fn frames_chunks_across_reads() {
+        let data = b"A\r\n0123456789\r\n0\r\n\r\n";
+        let mut body = Body::chunked();
+        let mut content = Vec::new();
+
+        for (i, x) in data.iter().enumerate() {
+            let (len, end) = body.consume(&[*x], Some(&mut content)).unwrap();
+
+            assert_eq!(len, 1);
+            assert_eq!(end, i == data.len() - 1);
+        }
+
+        assert_eq!(content, b"0123456789");
+    }
+
+    #[test]
+    fn refuses_loose_chunks() {
+        assert!(consume(Body::chunked(), b"5\nhello\r\n").is_err());
+        assert!(consume(Body::chunked(), b"5\r\nhello\n0\r\n").is_err());
+        assert!(consume(Body::chunked(), b"5\r\nhelloX\r\n").is_err());
+        assert!(consume(Body::chunked(), b"+5\r\nhello\r\n").is_err());
+        assert!(consume(Body::chunked(), b"5 \r\nhello\r\n").is_err());
+        assert!(consume(Body::chunked(), b"\r\n").is_err());
+        assert!(consume(Body::chunked(), b"10000000000000000\r\n").is_err());
+        assert!(consume(Body::chunked(), &[b'1'; MAX_LINE + 1]).is_err());
+    }
+
+    #[test]
+    fn finds_objects() {
+        assert_eq!(path_objects("/containers/web/stop", ""), vec![Object::Container("web".into())]);
+        assert_eq!(path_objects("/containers/json", ""), vec![]);
+        assert_eq!(path_objects("/volumes/v", ""), vec![Object::Volume("v".into())]);
+        assert_eq!(path_objects("/networks/create", ""), vec![]);
+        assert_eq!(path_objects("/exec/e/start", ""), vec![Object::Exec("e".into())]);
+        assert_eq!(path_objects("/commit", "container=w%65b&repo=x"), vec![Object::Container("web".into())]);
+        assert_eq!(path_objects("/images/json", ""), vec![]);
+
+        let body = Json::parse(br#"{"HostConfig":{"VolumesFrom":["a:ro"],"Links":["/b:alias"],"PidMode":"container:c",
+            "Binds":["v:/v","/srv:/srv"],"Mounts":[{"Type":"volume","Source":"m"}],"NetworkMode":"n"},
+            "NetworkingConfig":{"EndpointsConfig":{"bridge":{},"o":{}}}}"#).unwrap();
+
+        let objects = body_objects(Inspected::Create, &body);
+
+        for x in &[Object::Container("a".into()), Object::Container("b".into()), Object::Container("c".into()),
+            Object::Volume("v".into()), Object::Volume("m".into()), Object::Network("n".into()), Object::Network("o".into())] {
+            assert!(objects.contains(x), "{:?}", x);
+        }
+
+        assert_eq!(objects.len(), 7);
+    }
+
+    #[test]
+    fn scopes_lists() {
+        let filters = |x: &str| Json::parse(query_param(x.split_once('?').unwrap().1, "filters").unwrap().as_bytes()).unwrap();
+
+        let target = scope("/containers/json?all=1", "owner", "CN=a").unwrap();
+        assert!(target.starts_with("/containers/json?all=1&filters="));
+        assert_eq!(filters(&target).to_string(), r#"{"label":["owner=CN=a"]}"#);
+
+        let target = scope("/volumes?filters=%7B%22label%22%3A%5B%22x%22%5D%2C%22dangling%22%3A%5B%22true%22%5D%7D", "owner", "CN=a").unwrap();
+        assert_eq!(filters(&target).to_string(), r#"{"dangling":["true"],"label":["x","owner=CN=a"]}"#);
+
+        let target = scope("/events?filters=%7B%22label%22%3A%7B%22owner%3DCN%3Db%22%3Atrue%7D%7D", "owner", "CN=a").unwrap();
+        assert_eq!(filters(&target).to_string(), r#"{"label":{"owner=CN=b":true,"owner=CN=a":true}}"#);
+
+        assert!(scope("/containers/json?filters=%5B%5D", "owner", "CN=a").is_err());
+        assert!(scope("/containers/json?filters=%7B%22label%22%3A1%7D", "owner", "CN=a").is_err());
+    }
+
+    #[test]
+    fn reads_keys_like_go() {
+        // the last of the keys given twice wins, `ſ` folds to `s`
+        assert!(create(r#"{"Privileged":true,"Privileged":false}"#).is_ok());
+        assert!(create(r#"{"Privileged":false,"privileged":true}"#).is_err());
+        assert!(check(Inspected::Create, r#"{"Image":"alpine","hoſtconfig":{"pidmode":"host"}}"#).is_err());
+    }
+}
@@ -0,0 +1,258 @@
+use std::io::Error as IoError;
+use std::error::Error;
+use std::fmt::{Debug, Display, Formatter, Result as FmtResult};
+use std::sync::Arc;
+use mio::Poll;
+
+use crate::{
+    Classify, ErrorClass, MidChan, Chan, Connector, ConnInfo, Dest, FwError, Listener, NextResult, NextState, Pollable, RouteErr,
+    SharedListener,
+};
+use crate::health::{HealthConf, Upstream};
+
+/// One of two channels, connectors or listeners, lets a single connector hand out channels of different protocols
+/// and a single event loop serve listeners of different protocols while keeping the dispatch static
+#[derive(Debug, Clone)]
+pub enum Either<A, B> {
+    A(A),
+    B(B),
+}
+
+#[derive(Debug)]
+pub enum EitherErr<A: Debug, B: Debug> {
+    A(A),
+    B(B),
+}
+
+impl<A: Debug + Display, B: Debug + Display> Display for EitherErr<A, B> {
+    fn fmt(&self, f: &mut Formatter) -> FmtResult {
+        match self {
+            EitherErr::A(x) => Display::fmt(x, f),
+            EitherErr::B(x) => Display::fmt(x, f),
+        }
+    }
+}
+
+impl<A: Error + 'static, B: Error + 'static> Error for EitherErr<A, B> {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            EitherErr::A(x) => x.source(),
+            EitherErr::B(x) => x.source(),
+        }
+    }
+}
+
+impl<A: Debug + Classify, B: Debug + Classify> Classify for EitherErr<A, B> {
+    fn class(&self) -> ErrorClass {
+        match self {
+            EitherErr::A(x) => x.class(),
+            EitherErr::B(x) => x.class(),
+        }
+    }
+
+    fn tls_failure(&self) -> Option<&'static str> {
+        match self {
+            EitherErr::A(x) => x.tls_failure(),
+            EitherErr::B(x) => x.tls_failure(),
+        }
+    }
+}
+
+pub fn left<
+    Ae: Debug, Ac: Chan<Err=Ae>, Ap: MidChan<Err=Ae, C=Ac>,
+    Be: Debug, Bc: Chan<Err=Be>, Bp: MidChan<Err=Be, C=Bc>,
+>(
+    x: NextResult<Ae, Ac, Ap>
+) -> NextResult<EitherErr<Ae, Be>, Either<Ac, Bc>, Either<Ap, Bp>> {
+    match x.map_err(|x| x.map(EitherErr::A))? {
+        NextState::Pending(x) => Ok(NextState::Pending(Either::A(x))),
+        NextState::Active(x) => Ok(NextState::Active(Either::A(x))),
+    }
+}
+
+pub fn right<
+    Ae: Debug, Ac: Chan<Err=Ae>, Ap: MidChan<Err=Ae, C=Ac>,
+    Be: Debug, Bc: Chan<Err=Be>, Bp: MidChan<Err=Be, C=Bc>,
+>(
+    x: NextResult<Be, Bc, Bp>
+) -> NextResult<EitherErr<Ae, Be>, Either<Ac, Bc>, Either<Ap, Bp>> {
+    match x.map_err(|x| x.map(EitherErr::B))? {
+        NextState::Pending(x) => Ok(NextState::Pending(Either::B(x))),
+        NextState::Active(x) => Ok(NextState::Active(Either::B(x))),
+    }
+}
+
+impl<A: Pollable, B: Pollable> Pollable for Either<A, B> {
+    fn register(&self, poll: &Poll, tok: usize) -> Result<(), IoError> {
+        match self {
+            Either::A(x) => x.register(poll, tok),
+            Either::B(x) => x.register(poll, tok),
+        }
+    }
+
+    fn deregister(&self, poll: &Poll) -> Result<(), IoError> {
+        match self {
+            Either::A(x) => x.deregister(poll),
+            Either::B(x) => x.deregister(poll),
+        }
+    }
+}
+
+impl<A: Chan, B: Chan> Chan for Either<A, B> {
+    type Err = EitherErr<A::Err, B::Err>;
+
+    fn peer(&self) -> Option<String> {
+        match self {
+            Either::A(x) => x.peer(),
+            Either::B(x) => x.peer(),
+        }
+    }
+
+    fn identity(&self) -> Option<String> {
+        match self {
+            Either::A(x) => x.identity(),
+            Either::B(x) => x.identity(),
+        }
+    }
+
+    fn send(&mut self, buff: &[u8]) -> Result<usize, FwError<Self::Err>> {
+        match self {
+            Either::A(x) => x.send(buff).map_err(|x| x.map(EitherErr::A)),
+            Either::B(x) => x.send(buff).map_err(|x| x.map(EitherErr::B)),
+        }
+    }
+
+    fn recv(&mut self, buff: &mut [u8]) -> Result<Option<usize>, FwError<Self::Err>> {
+        match self {
+            Either::A(x) => x.recv(buff).map_err(|x| x.map(EitherErr::A)),
+            Either::B(x) => x.recv(buff).map_err(|x| x.map(EitherErr::B)),
+        }
+    }
+
+    fn pending(&self) -> bool {
+        match self {
+            Either::A(x) => x.pending(),
+            Either::B(x) => x.pending(),
+        }
+    }
+
+    fn dest(&self) -> Option<&Dest> {
+        match self {
+            Either::A(x) => x.dest(),
+            Either::B(x) => x.dest(),
+        }
+    }
+
+    fn routed(&mut self, res: Result<(), RouteErr>) -> Result<(), FwError<Self::Err>> {
+        match self {
+            Either::A(x) => x.routed(res).map_err(|x| x.map(EitherErr::A)),
+            Either::B(x) => x.routed(res).map_err(|x| x.map(EitherErr::B)),
+        }
+    }
+}
+
+impl<
+    Ae: Debug, Ac: Chan<Err=Ae>, Ap: MidChan<Err=Ae, C=Ac>,
+    Be: Debug, Bc: Chan<Err=Be>, Bp: MidChan<Err=Be, C=Bc>,
+> MidChan for Either<Ap, Bp> {
+    type Err = EitherErr<Ae, Be>;
+    type C = Either<Ac, Bc>;
+
+    fn peer(&self) -> Option<String> {
+        match self {
+            Either::A(x) => x.peer(),
+            Either::B(x) => x.peer(),
+        }
+    }
+
+    fn try_channel(self, poll: &Poll) -> Result<NextState<Self::Err, Self::C, Self>, FwError<Self::Err>> {
+        match self {
+            Either::A(x) => left(x.try_channel(poll)),
+            Either::B(x) => right(x.try_channel(poll)),
+        }
+    }
+}
+
+impl<A: Connector, B: Connector> Connector for Either<A, B> {
+    type Err = EitherErr<A::Err, B::Err>;
+    type C = Either<A::C, B::C>;
+    type PC = Either<A::PC, B::PC>;
+
+    fn connect(&mut self, info: &ConnInfo) -> Result<NextState<Self::Err, Self::C, Self::PC>, FwError<Self::Err>> {
+        match self {
+            Either::A(x) => left(x.connect(info)),
+            Either::B(x) => right(x.connect(info)),
+        }
+    }
+
+    fn deferred(&self) -> bool {
+        match self {
+            Either::A(x) => x.deferred(),
+            Either::B(x) => x.deferred(),
+        }
+    }
+
+    fn pooled(&self) -> bool {
+        match self {
+            Either::A(x) => x.pooled(),
+            Either::B(x) => x.pooled(),
+        }
+    }
+
+    fn upstreams(&self) -> Option<Arc<[Upstream]>> {
+        match self {
+            Either::A(x) => x.upstreams(),
+            Either::B(x) => x.upstreams(),
+        }
+    }
+
+    fn health(&self) -> Option<&HealthConf> {
+        match self {
+            Either::A(x) => x.health(),
+            Either::B(x) => x.health(),
+        }
+    }
+
+    fn sockets(&self) -> Vec<(usize, String)> {
+        match self {
+            Either::A(x) => x.sockets(),
+            Either::B(x) => x.sockets(),
+        }
+    }
+
+    fn connect_upstream(&mut self, idx: usize, info: &ConnInfo) -> Result<NextState<Self::Err, Self::C, Self::PC>, FwError<Self::Err>> {
+        match self {
+            Either::A(x) => left(x.connect_upstream(idx, info)),
+            Either::B(x) => right(x.connect_upstream(idx, info)),
+        }
+    }
+}
+
+impl<A: Listener, B: Listener> Listener for Either<A, B> {
+    type Err = EitherErr<A::Err, B::Err>;
+    type C = Either<A::C, B::C>;
+    type PC = Either<A::PC, B::PC>;
+
+    fn accept(&mut self) -> Result<Option<NextState<Self::Err, Self::C, Self::PC>>, FwError<Self::Err>> {
+        match self {
+            Either::A(x) => x.accept().transpose().map(left).transpose(),
+            Either::B(x) => x.accept().transpose().map(right).transpose(),
+        }
+    }
+
+    fn reload(&mut self) -> Result<(), FwError<Self::Err>> {
+        match self {
+            Either::A(x) => x.reload().map_err(|x| x.map(EitherErr::A)),
+            Either::B(x) => x.reload().map_err(|x| x.map(EitherErr::B)),
+        }
+    }
+}
+
+impl<A: SharedListener, B: SharedListener> SharedListener for Either<A, B> {
+    fn try_clone(&self) -> Result<Self, IoError> {
+        match self {
+            Either::A(x) => Ok(Either::A(x.try_clone()?)),
+            Either::B(x) => Ok(Either::B(x.try_clone()?)),
+        }
+    }
+}
@@ -0,0 +1,287 @@
+use std::error::Error;
+use std::fmt::{Display, Formatter, Result as FmtResult};
+use std::io::{Error as IoError, ErrorKind, Write, Read};
+use std::os::unix::io::AsRawFd;
+use std::process::{Child, ChildStdin, ChildStdout, ChildStderr, Command, Stdio};
+use mio::{Token, Poll, Ready, PollOpt};
+use mio::unix::EventedFd;
+use clap::{App, AppSettings, Arg, ArgMatches};
+use log::{info, warn};
+
+use crate::{Classify, ErrorClass, FwError, MidChan, Chan, Connector, ConnInfo, NextState, Pollable};
+use crate::args::Parsable;
+use crate::proto::common::set_nonblocking;
+
+#[derive(Debug)]
+pub enum ExecErr {
+    Io(IoError),
+    Str(String),
+    Spawn(String, IoError),
+}
+
+impl Display for ExecErr {
+    fn fmt(&self, f: &mut Formatter) -> FmtResult {
+        match self {
+            ExecErr::Io(x) => Display::fmt(x, f),
+            ExecErr::Str(x) => f.write_str(x),
+            ExecErr::Spawn(cmd, _) => write!(f, "failed to spawn {}", cmd),
+        }
+    }
+}
+
+impl Error for ExecErr {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            ExecErr::Spawn(_, x) => Some(x),
+            _ => None,
+        }
+    }
+}
+
+impl Classify for ExecErr {
+    fn class(&self) -> ErrorClass {
+        match self {
+            ExecErr::Str(_) => ErrorClass::Config,
+            _ => ErrorClass::Other,
+        }
+    }
+}
+
+impl From<IoError> for ExecErr {
+    fn from(x: IoError) -> Self {
+        ExecErr::Io(x)
+    }
+}
+
+impl From<IoError> for FwError<ExecErr> {
+    fn from(x: IoError) -> Self {
+        FwError::Io(ExecErr::Io(x))
+    }
+}
+
+impl From<&str> for FwError<ExecErr> {
+    fn from(x: &str) -> FwError<ExecErr> {
+        FwError::Io(ExecErr::Str(x.to_string()))
+    }
+}
+
+/// A spawned child process together with its (non-blocking) standard pipes
+struct Proc {
+    conn_id: usize,
+    child: Child,
+    stdin: ChildStdin,
+    stdout: ChildStdout,
+    stderr: Option<ChildStderr>,
+    stderr_line: Vec<u8>,
+}
+
+impl Proc {
+    fn spawn(conn_id: usize, cmd: &str, args: &[String]) -> Result<Self, IoError> {
+        let mut child = Command::new(cmd)
+            .args(args)
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .spawn()?;
+
+        let stdin = child.stdin.take().expect("stdin is piped");
+        let stdout = child.stdout.take().expect("stdout is piped");
+        let stderr = child.stderr.take().expect("stderr is piped");
+
+        set_nonblocking(&stdin)?;
+        set_nonblocking(&stdout)?;
+        set_nonblocking(&stderr)?;
+
+        Ok(Proc { conn_id, child, stdin, stdout, stderr: Some(stderr), stderr_line: Vec::new() })
+    }
+
+    fn register(&self, poll: &Poll, tok: usize) -> Result<(), IoError> {
+        poll.register(&EventedFd(&self.stdin.as_raw_fd()), Token(tok), Ready::writable(), PollOpt::edge())?;
+        poll.register(&EventedFd(&self.stdout.as_raw_fd()), Token(tok), Ready::readable(), PollOpt::edge())?;
+
+        if let Some(stderr) = &self.stderr {
+            poll.register(&EventedFd(&stderr.as_raw_fd()), Token(tok), Ready::readable(), PollOpt::edge())?;
+        }
+
+        Ok(())
+    }
+
+    fn deregister(&self, poll: &Poll) -> Result<(), IoError> {
+        poll.deregister(&EventedFd(&self.stdin.as_raw_fd()))?;
+        poll.deregister(&EventedFd(&self.stdout.as_raw_fd()))?;
+
+        if let Some(stderr) = &self.stderr {
+            poll.deregister(&EventedFd(&stderr.as_raw_fd()))?;
+        }
+
+        Ok(())
+    }
+
+    fn log_stderr(&mut self, flush: bool) {
+        while let Some(pos) = self.stderr_line.iter().position(|x| *x == b'\n') {
+            let line: Vec<u8> = self.stderr_line.drain(..=pos).collect();
+            info!(conn_id = self.conn_id, side = "S"; "{}", String::from_utf8_lossy(&line[..line.len() - 1]));
+        }
+
+        if flush && !self.stderr_line.is_empty() {
+            info!(conn_id = self.conn_id, side = "S"; "{}", String::from_utf8_lossy(&self.stderr_line));
+            self.stderr_line.clear();
+        }
+    }
+
+    /// forward everything the child has written to its stderr so far
+    fn drain_stderr(&mut self) {
+        let mut buff = [0; 1024];
+
+        while let Some(stderr) = &mut self.stderr {
+            match stderr.read(&mut buff) {
+                Ok(0) => {
+                    // a closed stderr stays readable forever, so it must not be left in the poll
+                    self.stderr = None;
+                }
+                Ok(x) => self.stderr_line.extend_from_slice(&buff[..x]),
+                Err(x) => match x.kind() {
+                    ErrorKind::WouldBlock => break,
+                    ErrorKind::Interrupted => continue,
+                    _ => {
+                        warn!(conn_id = self.conn_id, side = "S"; "failed to read stderr: {}", x);
+                        self.stderr = None;
+                    }
+                }
+            }
+        }
+
+        let flush = self.stderr.is_none();
+        self.log_stderr(flush);
+    }
+}
+
+impl Drop for Proc {
+    fn drop(&mut self) {
+        self.drain_stderr();
+        self.log_stderr(true);
+
+        // reap the child so it does not stay around as a zombie
+        match self.child.try_wait() {
+            Ok(Some(_)) => {}
+            _ => {
+                let _ = self.child.kill();
+                let _ = self.child.wait();
+            }
+        }
+    }
+}
+
+pub struct ExecChan {
+    proc: Proc,
+}
+
+pub struct MidExecChan {
+    proc: Proc,
+}
+
+impl Pollable for ExecChan {
+    fn register(&self, poll: &Poll, tok: usize) -> Result<(), IoError> {
+        self.proc.register(poll, tok)
+    }
+
+    fn deregister(&self, poll: &Poll) -> Result<(), IoError> {
+        self.proc.deregister(poll)
+    }
+}
+
+impl Pollable for MidExecChan {
+    fn register(&self, poll: &Poll, tok: usize) -> Result<(), IoError> {
+        self.proc.register(poll, tok)
+    }
+
+    fn deregister(&self, poll: &Poll) -> Result<(), IoError> {
+        self.proc.deregister(poll)
+    }
+}
+
+impl MidChan for MidExecChan {
+    type Err = ExecErr;
+    type C = ExecChan;
+
+    fn try_channel(self, _poll: &Poll) -> Result<NextState<Self::Err, Self::C, Self>, FwError<Self::Err>> {
+        Ok(NextState::Active(ExecChan { proc: self.proc }))
+    }
+}
+
+impl Chan for ExecChan {
+    type Err = ExecErr;
+    fn send(&mut self, buff: &[u8]) -> Result<usize, FwError<Self::Err>> {
+        Ok(self.proc.stdin.write(buff)?)
+    }
+
+    fn recv(&mut self, buff: &mut [u8]) -> Result<Option<usize>, FwError<Self::Err>> {
+        self.proc.drain_stderr();
+
+        let read = self.proc.stdout.read(buff);
+        let read = match read {
+            Ok(y) => Some(y),
+            Err(x) => match x.kind() {
+                ErrorKind::WouldBlock => {
+                    // the child may have exited while something else still holds its stdout
+                    self.proc.child.try_wait()?.map(|_| 0)
+                }
+                _ => return Err(x.into())
+            }
+        };
+        Ok(read)
+    }
+}
+
+#[derive(Clone)]
+pub struct ExecConnector {
+    cmd: String,
+    args: Vec<String>,
+}
+
+impl ExecConnector {
+    pub fn new(cmd: &str, args: &[&str]) -> Self {
+        ExecConnector {
+            cmd: cmd.to_string(),
+            args: args.iter().map(|x| x.to_string()).collect(),
+        }
+    }
+}
+
+impl Connector for ExecConnector {
+    type Err = ExecErr;
+    type C = ExecChan;
+    type PC = MidExecChan;
+
+    fn connect(&mut self, info: &ConnInfo) -> Result<NextState<Self::Err, Self::C, Self::PC>, FwError<Self::Err>> {
+        let proc = Proc::spawn(info.conn_id, &self.cmd, &self.args).map_err(|x| ExecErr::Spawn(self.cmd.clone(), x))?;
+
+        Ok(NextState::Pending(MidExecChan { proc }))
+    }
+
+    /// the standard error of the command is logged under the connection id of the client
+    fn pooled(&self) -> bool {
+        false
+    }
+}
+
+impl Parsable<Result<ExecConnector, FwError<ExecErr>>> for ExecConnector {
+    fn parser<'a, 'b>(app: App<'a, 'b>) -> App<'a, 'b> {
+        app
+            .setting(AppSettings::TrailingVarArg)
+            .arg(
+                Arg::with_name("cmd")
+                    .help("command to spawn per connection, followed by its arguments")
+                    .required(true)
+                    .multiple(true)
+                    .allow_hyphen_values(true)
+                    .index(1)
+            )
+    }
+    fn parse(matches: &ArgMatches) -> Result<ExecConnector, FwError<ExecErr>> {
+        let mut cmd = matches.values_of("cmd").ok_or("command not found")?;
+        let prog = cmd.next().ok_or("command not found")?;
+        let args: Vec<&str> = cmd.collect();
+        Ok(ExecConnector::new(prog, &args))
+    }
+}
@@ -0,0 +1,310 @@
+/// Largest request head accepted from a client before the stream is upgraded or tunnelled
+pub const MAX_HEAD: usize = 8192;
+
+/// Request head sent by a client before the stream is upgraded or tunnelled
+#[derive(Debug, Clone)]
+pub struct Head {
+    pub method: String,
+    pub target: String,
+    pub version: String,
+    pub headers: Vec<(String, String)>,
+}
+
+impl Head {
+    /// parse a request head from the start of `buff`,
+    /// returns the head together with its length or `None` if it is not complete yet
+    pub fn parse(buff: &[u8]) -> Result<Option<(Head, usize)>, &'static str> {
+        let (head, len) = match split_head(buff)? {
+            Some(x) => x,
+            None if buff.len() >= MAX_HEAD => return Err("request head too long"),
+            None => return Ok(None),
+        };
+
+        let mut lines = head.split("\r\n");
+        let line = lines.next().ok_or("request line missing")?;
+
+        let (method, target, version) = match line.split(' ').collect::<Vec<_>>()[..] {
+            [method, target, version] => (method, target, version),
+            _ => return Err("invalid request line"),
+        };
+
+        if method.is_empty() || !method.bytes().all(is_tchar) {
+            return Err("invalid method");
+        }
+
+        if target.is_empty() || target.bytes().any(|x| x <= b' ' || x == 0x7f) {
+            return Err("invalid request target");
+        }
+
+        if version != "HTTP/1.1" && version != "HTTP/1.0" {
+            return Err("invalid http version");
+        }
+
+        let headers = parse_headers(lines)?;
+
+        Ok(Some((
+            Head {
+                method: method.to_string(),
+                target: target.to_string(),
+                version: version.to_string(),
+                headers,
+            },
+            len
+        )))
+    }
+
+    pub fn header(&self, name: &str) -> Option<&str> {
+        header(&self.headers, name)
+    }
+
+    /// header holds a comma-separated list containing `token`
+    pub fn header_has(&self, name: &str, token: &str) -> bool {
+        header_has(&self.headers, name, token)
+    }
+
+    pub fn path(&self) -> &str {
+        match self.target.find('?') {
+            Some(x) => &self.target[..x],
+            None => &self.target,
+        }
+    }
+}
+
+/// Response head sent back by an upstream speaking HTTP
+#[derive(Debug, Clone)]
+pub struct Status {
+    pub code: u16,
+    pub headers: Vec<(String, String)>,
+}
+
+impl Status {
+    /// parse a response head from the start of `buff`,
+    /// returns the head together with its length or `None` if it is not complete yet
+    pub fn parse(buff: &[u8]) -> Result<Option<(Status, usize)>, &'static str> {
+        let (head, len) = match split_head(buff)? {
+            Some(x) => x,
+            None if buff.len() >= MAX_HEAD => return Err("response head too long"),
+            None => return Ok(None),
+        };
+
+        let mut lines = head.split("\r\n");
+
+        // the reason phrase may contain spaces
+        let mut line = lines.next().ok_or("status line missing")?.splitn(3, ' ');
+
+        line.next().filter(|x| *x == "HTTP/1.1" || *x == "HTTP/1.0").ok_or("invalid http version")?;
+
+        let code = line.next()
+            .filter(|x| x.len() == 3 && x.bytes().all(|x| x.is_ascii_digit()))
+            .and_then(|x| x.parse::<u16>().ok())
+            .ok_or("invalid status code")?;
+
+        let headers = parse_headers(lines)?;
+
+        Ok(Some((Status { code, headers }, len)))
+    }
+
+    pub fn header(&self, name: &str) -> Option<&str> {
+        header(&self.headers, name)
+    }
+
+    /// header holds a comma-separated list containing `token`
+    pub fn header_has(&self, name: &str, token: &str) -> bool {
+        header_has(&self.headers, name, token)
+    }
+}
+
+/// head at the start of `buff` without its final empty line, and the length of the head with it.
+/// Lines must end with CRLF: a bare CR or LF is refused rather than read differently than the upstream would
+fn split_head(buff: &[u8]) -> Result<Option<(&str, usize)>, &'static str> {
+    let end = buff.windows(4).position(|x| x == b"\r\n\r\n");
+    let scan = end.map(|x| x + 4).unwrap_or(buff.len());
+
+    for (i, c) in buff[..scan].iter().enumerate() {
+        match c {
+            b'\r' if i + 1 == scan && end.is_none() => {}
+            b'\r' if buff[i + 1] != b'\n' => return Err("bare carriage return in head"),
+            b'\n' if i == 0 || buff[i - 1] != b'\r' => return Err("bare line feed in head"),
+            _ => {}
+        }
+    }
+
+    let len = match end {
+        Some(x) => x + 4,
+        None => return Ok(None),
+    };
+
+    let head = std::str::from_utf8(&buff[..len - 4]).map_err(|_| "head is not utf-8")?;
+
+    Ok(Some((head, len)))
+}
+
+/// characters of a token, such as a method or a header name (RFC 9110 5.6.2)
+fn is_tchar(c: u8) -> bool {
+    c.is_ascii_alphanumeric() || b"!#$%&'*+-.^_`|~".contains(&c)
+}
+
+fn parse_headers<'a>(lines: impl Iterator<Item=&'a str>) -> Result<Vec<(String, String)>, &'static str> {
+    let mut headers: Vec<(String, String)> = Vec::new();
+
+    for line in lines {
+        let sep = line.find(':').ok_or("invalid header")?;
+        let (name, value) = (&line[..sep], line[sep + 1..].trim_matches(|x| x == ' ' || x == '\t'));
+
+        // whitespace before the colon or at the start of the line (obsolete folding) is refused
+        if name.is_empty() || !name.bytes().all(is_tchar) {
+            return Err("invalid header name");
+        }
+
+        if value.bytes().any(|x| x < b' ' && x != b'\t' || x == 0x7f) {
+            return Err("invalid header value");
+        }
+
+        // the framing of the body must not be read differently by the upstream
+        if name.eq_ignore_ascii_case("Content-Length") || name.eq_ignore_ascii_case("Transfer-Encoding") {
+            if headers.iter().any(|(k, _)| k.eq_ignore_ascii_case(name)) {
+                return Err("repeated body framing header");
+            }
+
+            if name.eq_ignore_ascii_case("Content-Length") && (value.is_empty() || !value.bytes().all(|x| x.is_ascii_digit())) {
+                return Err("invalid content-length");
+            }
+
+            if name.eq_ignore_ascii_case("Transfer-Encoding") && !value.eq_ignore_ascii_case("chunked") {
+                return Err("unsupported transfer-encoding");
+            }
+        }
+
+        headers.push((name.to_string(), value.to_string()));
+    }
+
+    if header(&headers, "Content-Length").is_some() && header(&headers, "Transfer-Encoding").is_some() {
+        return Err("both transfer-encoding and content-length given");
+    }
+
+    Ok(headers)
+}
+
+fn header<'a>(headers: &'a [(String, String)], name: &str) -> Option<&'a str> {
+    headers.iter()
+        .find(|(k, _)| k.eq_ignore_ascii_case(name))
+        .map(|(_, v)| v.as_str())
+}
+
+fn header_has(headers: &[(String, String)], name: &str, token: &str) -> bool {
+    header(headers, name)
+        .map(|x| x.split(',').any(|x| x.trim().eq_ignore_ascii_case(token)))
+        .unwrap_or(false)
+}
+
+/// a complete response without a body, used to answer or refuse a handshake
+pub fn reply(status: &str, headers: &[(&str, &str)]) -> Vec<u8> {
+    let mut res = format!("HTTP/1.1 {}\r\n", status);
+
+    for (k, v) in headers {
+        res.push_str(&format!("{}: {}\r\n", k, v));
+    }
+
+    res.push_str("\r\n");
+    res.into_bytes()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn head(x: &str) -> Result<Option<(Head, usize)>, &'static str> {
+        Head::parse(x.as_bytes())
+    }
+
+    #[test]
+    fn parses_request_head() {
+        let data = "GET /v1.43/info?x=1 HTTP/1.1\r\nHost: docker\r\nX-Empty:\r\nAccept:  a,\tb \r\n\r\nbody";
+        let (head, len) = head(data).unwrap().unwrap();
+
+        assert_eq!(len, data.len() - 4);
+        assert_eq!(head.method, "GET");
+        assert_eq!(head.path(), "/v1.43/info");
+        assert_eq!(head.version, "HTTP/1.1");
+        assert_eq!(head.header("host"), Some("docker"));
+        assert_eq!(head.header("x-empty"), Some(""));
+        assert_eq!(head.header("Accept"), Some("a,\tb"));
+        assert!(head.header_has("accept", "B"));
+    }
+
+    #[test]
+    fn waits_for_complete_head() {
+        assert!(head("GET / HTTP/1.1\r\nHost: x\r").unwrap().is_none());
+        assert!(head("GET / HTTP/1.1\r\nHost: x\r\n\r").unwrap().is_none());
+        assert_eq!(head(&"a".repeat(MAX_HEAD)).unwrap_err(), "request head too long");
+    }
+
+    #[test]
+    fn refuses_bare_line_endings() {
+        // read by Go as a `GET /_ping` with a body, followed by another request
+        let smuggled = "GET /_ping HTTP/1.1\nHost: x\nContent-Length: 5\r\n\r\nPOST /containers/create HTTP/1.1\r\n\r\n";
+        assert!(head(smuggled).is_err());
+
+        assert!(head("GET / HTTP/1.1\nHost: x").is_err());
+        assert!(head("GET / HTTP/1.1\r\nHost: x\rY: z\r\n\r\n").is_err());
+        assert!(head("GET / HTTP/1.1\r\nHost: x\n\r\n\r\n").is_err());
+    }
+
+    #[test]
+    fn refuses_invalid_request_line() {
+        assert!(head("GET / HTTP/1.1 x\r\n\r\n").is_err());
+        assert!(head("GET  / HTTP/1.1\r\n\r\n").is_err());
+        assert!(head("GET /\r\n\r\n").is_err());
+        assert!(head("GET / HTTP/1.2\r\n\r\n").is_err());
+        assert!(head("GET / HTTP/1.1x\r\n\r\n").is_err());
+        assert!(head("GET / http/1.1\r\n\r\n").is_err());
+        assert!(head("G(T / HTTP/1.1\r\n\r\n").is_err());
+        assert!(head("GET /a\x7fb HTTP/1.1\r\n\r\n").is_err());
+        assert!(head("GET / HTTP/1.0\r\n\r\n").unwrap().is_some());
+    }
+
+    #[test]
+    fn refuses_invalid_header_names() {
+        assert!(head("GET / HTTP/1.1\r\nContent-Length : 5\r\n\r\n").is_err());
+        assert!(head("GET / HTTP/1.1\r\n Content-Length: 5\r\n\r\n").is_err());
+        assert!(head("GET / HTTP/1.1\r\nHost: x\r\n\tfolded\r\n\r\n").is_err());
+        assert!(head("GET / HTTP/1.1\r\n: x\r\n\r\n").is_err());
+        assert!(head("GET / HTTP/1.1\r\nA\"b: x\r\n\r\n").is_err());
+        assert!(head("GET / HTTP/1.1\r\nno colon\r\n\r\n").is_err());
+        assert!(head("GET / HTTP/1.1\r\nX: a\x00b\r\n\r\n").is_err());
+    }
+
+    #[test]
+    fn refuses_ambiguous_body_framing() {
+        assert!(head("POST / HTTP/1.1\r\nContent-Length: 5\r\ncontent-length: 5\r\n\r\n").is_err());
+        assert!(head("POST / HTTP/1.1\r\nContent-Length: 5\r\nContent-Length: 6\r\n\r\n").is_err());
+        assert!(head("POST / HTTP/1.1\r\nTransfer-Encoding: chunked\r\nTransfer-Encoding: chunked\r\n\r\n").is_err());
+        assert!(head("POST / HTTP/1.1\r\nTransfer-Encoding: gzip, chunked\r\n\r\n").is_err());
+        assert!(head("POST / HTTP/1.1\r\nTransfer-Encoding: chunked, identity\r\n\r\n").is_err());
+        assert!(head("POST / HTTP/1.1\r\nTransfer-Encoding: xchunked\r\n\r\n").is_err());
+        assert!(head("POST / HTTP/1.1\r\nTransfer-Encoding: chunked\r\nContent-Length: 5\r\n\r\n").is_err());
+        assert!(head("POST / HTTP/1.1\r\nContent-Length: +5\r\n\r\n").is_err());
+        assert!(head("POST / HTTP/1.1\r\nContent-Length: 5, 5\r\n\r\n").is_err());
+        assert!(head("POST / HTTP/1.1\r\nContent-Length:\r\n\r\n").is_err());
+
+        let (head, _) = head("POST / HTTP/1.1\r\nTransfer-Encoding: Chunked\r\n\r\n").unwrap().unwrap();
+        assert!(head.header_has("Transfer-Encoding", "chunked"));
+    }
+
+    #[test]
+    fn parses_status() {
+        let data = b"HTTP/1.1 404 Not Found Here\r\nContent-Length: 2\r\n\r\n{}";
+        let (status, len) = Status::parse(data).unwrap().unwrap();
+
+        assert_eq!(len, data.len() - 2);
+        assert_eq!(status.code, 404);
+        assert_eq!(status.header("content-length"), Some("2"));
+
+        assert_eq!(Status::parse(b"HTTP/1.1 204\r\n\r\n").unwrap().unwrap().0.code, 204);
+        assert!(Status::parse(b"HTTP/1.1 200 OK\r\n").unwrap().is_none());
+        assert!(Status::parse(b"HTTP/2 200 OK\r\n\r\n").is_err());
+        assert!(Status::parse(b"HTTP/1.1 2000 OK\r\n\r\n").is_err());
+        assert!(Status::parse(b"HTTP/1.1 +20 OK\r\n\r\n").is_err());
+        assert!(Status::parse(b"HTTP/1.1 200 OK\nContent-Length: 2\r\n\r\n").is_err());
+    }
+}
@@ -1,4 +1,13 @@
 pub mod tcp;
 pub mod unix;
 pub mod ssl;
+pub mod exec;
+pub mod ws;
+pub mod http;
+pub mod connect;
+pub mod socks;
+pub mod either;
+pub mod route;
+pub mod balance;
+pub mod docker;
 pub mod common;
\ No newline at end of file
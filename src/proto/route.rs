@@ -0,0 +1,152 @@
+use std::collections::HashMap;
+use clap::{App, Arg, ArgMatches};
+
+use crate::{Connector, ConnInfo, Dest, FwError, NextState};
+use crate::args::Parsable;
+use crate::proto::common::StreamConf;
+use crate::proto::either::{Either, EitherErr, left, right};
+use crate::proto::tcp::{TcpChan, TcpConnector, TcpErr, MidTcpChan};
+use crate::proto::unix::{UnixChan, UnixConnector, UnixErr, MidUnixChan};
+
+pub type RouteConnErr = EitherErr<UnixErr, TcpErr>;
+
+impl From<&str> for FwError<RouteConnErr> {
+    fn from(x: &str) -> FwError<RouteConnErr> {
+        FwError::Io(EitherErr::B(TcpErr::Str(x.to_string())))
+    }
+}
+
+/// Destination pattern a client is allowed to request
+#[derive(Debug, Clone, PartialEq)]
+pub struct Rule {
+    /// exact host, `*` for any host or `*.domain` for any subdomain
+    pub host: String,
+    /// `None` allows any port
+    pub port: Option<u16>,
+}
+
+impl Rule {
+    pub fn parse(x: &str) -> Result<Rule, &'static str> {
+        let sep = x.rfind(':').ok_or("rule must be host:port")?;
+        let host = x[..sep].trim_start_matches('[').trim_end_matches(']');
+
+        let port = match &x[sep + 1..] {
+            "*" => None,
+            port => Some(port.parse::<u16>().map_err(|_| "invalid rule port")?),
+        };
+
+        Ok(Rule { host: host.to_ascii_lowercase(), port })
+    }
+
+    pub fn matches(&self, dest: &Dest) -> bool {
+        let host = dest.host.to_ascii_lowercase();
+
+        let host_ok = if self.host == "*" {
+            true
+        } else if let Some(suffix) = self.host.strip_prefix("*.") {
+            host.ends_with(&format!(".{}", suffix))
+        } else {
+            self.host == host
+        };
+
+        host_ok && self.port.map(|x| x == dest.port).unwrap_or(true)
+    }
+}
+
+/// Connects every client to the destination it requested,
+/// provided the destination is either an allowed `host:port` or the name of a Unix socket alias
+#[derive(Debug, Clone)]
+pub struct RouteConnector {
+    allow: Vec<Rule>,
+    aliases: HashMap<String, String>,
+    conf: StreamConf,
+}
+
+impl RouteConnector {
+    pub fn new(allow: Vec<Rule>, aliases: HashMap<String, String>, conf: &StreamConf) -> Self {
+        RouteConnector { allow, aliases, conf: conf.clone() }
+    }
+
+    /// Unix socket path for `dest` if it names an alias, the port is not relevant for aliases
+    pub fn alias(&self, dest: &Dest) -> Option<&str> {
+        self.aliases.get(&dest.host).map(|x| x.as_str())
+    }
+
+    pub fn allowed(&self, dest: &Dest) -> bool {
+        self.alias(dest).is_some() || self.allow.iter().any(|x| x.matches(dest))
+    }
+}
+
+impl Connector for RouteConnector {
+    type Err = RouteConnErr;
+    type C = Either<UnixChan, TcpChan>;
+    type PC = Either<MidUnixChan, MidTcpChan>;
+
+    fn connect(&mut self, info: &ConnInfo) -> Result<NextState<Self::Err, Self::C, Self::PC>, FwError<Self::Err>> {
+        let dest = info.dest.ok_or("client did not request a destination")?;
+
+        if !self.allowed(dest) {
+            return Err(FwError::Denied);
+        }
+
+        match self.alias(dest) {
+            Some(path) => left(UnixConnector::new(path).connect(info)),
+            None => {
+                let addr = if dest.host.contains(':') {
+                    format!("[{}]:{}", dest.host, dest.port)
+                } else {
+                    format!("{}:{}", dest.host, dest.port)
+                };
+
+                right(TcpConnector::connect_to(&addr, &self.conf).map(NextState::Pending))
+            }
+        }
+    }
+
+    fn deferred(&self) -> bool {
+        true
+    }
+}
+
+impl Parsable<Result<RouteConnector, FwError<RouteConnErr>>> for RouteConnector {
+    fn parser<'a, 'b>(app: App<'a, 'b>) -> App<'a, 'b> {
+        let app = app
+            .arg(
+                Arg::with_name("allow")
+                    .long("allow")
+                    .help("allowed destination as host:port, host may be * or *.domain and port may be *")
+                    .takes_value(true)
+                    .multiple(true)
+                    .number_of_values(1)
+            )
+            .arg(
+                Arg::with_name("alias")
+                    .long("alias")
+                    .help("name=path of a Unix socket clients may request by name")
+                    .takes_value(true)
+                    .multiple(true)
+                    .number_of_values(1)
+            );
+
+        StreamConf::parser(app)
+    }
+
+    fn parse(matches: &ArgMatches) -> Result<RouteConnector, FwError<RouteConnErr>> {
+        let mut allow = Vec::new();
+
+        for x in matches.values_of("allow").into_iter().flatten() {
+            allow.push(Rule::parse(x)?);
+        }
+
+        let mut aliases = HashMap::new();
+
+        for x in matches.values_of("alias").into_iter().flatten() {
+            let sep = x.find('=').ok_or("alias must be name=path")?;
+            aliases.insert(x[..sep].to_string(), x[sep + 1..].to_string());
+        }
+
+        let conf = StreamConf::parse(matches)?;
+
+        Ok(RouteConnector::new(allow, aliases, &conf))
+    }
+}
@@ -0,0 +1,640 @@
+use std::io::Error as IoError;
+use std::error::Error;
+use std::fmt::{Debug, Display, Formatter, Result as FmtResult};
+use std::net::{Ipv4Addr, Ipv6Addr};
+use std::sync::Arc;
+use mio::Poll;
+use clap::{App, Arg, ArgMatches};
+
+use crate::{Classify, ErrorClass, Listener, MidChan, Chan, Dest, FwError, NextState, Pollable, RouteErr, SharedListener};
+use crate::args::Parsable;
+use crate::proto::common::{fill, send_all};
+
+const VERSION: u8 = 0x05;
+const AUTH_VERSION: u8 = 0x01;
+
+const METHOD_NONE: u8 = 0x00;
+const METHOD_PASSWORD: u8 = 0x02;
+const METHOD_UNACCEPTABLE: u8 = 0xff;
+
+const CMD_CONNECT: u8 = 0x01;
+
+const ATYP_IPV4: u8 = 0x01;
+const ATYP_DOMAIN: u8 = 0x03;
+const ATYP_IPV6: u8 = 0x04;
+
+const REP_SUCCEEDED: u8 = 0x00;
+const REP_FAILURE: u8 = 0x01;
+const REP_NOT_ALLOWED: u8 = 0x02;
+const REP_HOST_UNREACHABLE: u8 = 0x04;
+const REP_CMD_UNSUPPORTED: u8 = 0x07;
+const REP_ATYP_UNSUPPORTED: u8 = 0x08;
+
+#[derive(Debug)]
+pub enum SocksErr<E: Debug> {
+    Inner(E),
+    Str(String),
+}
+
+impl<E: Debug + Display> Display for SocksErr<E> {
+    fn fmt(&self, f: &mut Formatter) -> FmtResult {
+        match self {
+            SocksErr::Inner(x) => Display::fmt(x, f),
+            SocksErr::Str(x) => f.write_str(x),
+        }
+    }
+}
+
+impl<E: Error + 'static> Error for SocksErr<E> {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            SocksErr::Inner(x) => x.source(),
+            SocksErr::Str(_) => None,
+        }
+    }
+}
+
+impl<E: Debug + Classify> Classify for SocksErr<E> {
+    fn class(&self) -> ErrorClass {
+        match self {
+            SocksErr::Inner(x) => x.class(),
+            SocksErr::Str(_) => ErrorClass::Config,
+        }
+    }
+
+    fn tls_failure(&self) -> Option<&'static str> {
+        match self {
+            SocksErr::Inner(x) => x.tls_failure(),
+            SocksErr::Str(_) => None,
+        }
+    }
+}
+
+impl<E: Debug> From<&str> for FwError<SocksErr<E>> {
+    fn from(x: &str) -> FwError<SocksErr<E>> {
+        FwError::Io(SocksErr::Str(x.to_string()))
+    }
+}
+
+/// a parsed message, `None` while it is incomplete
+type Parsed<T, E> = Result<Option<T>, FwError<SocksErr<E>>>;
+
+fn lift<E: Debug>(x: FwError<E>) -> FwError<SocksErr<E>> {
+    x.map(SocksErr::Inner)
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct SocksConf {
+    /// username/password pairs, authentication is not required when empty
+    pub users: Vec<(String, String)>,
+}
+
+/// A stream tunnelled through a SOCKS5 `CONNECT` request
+pub struct SocksChan<C> {
+    chan: C,
+    dest: Dest,
+    rbuf: Vec<u8>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Stage {
+    Greeting,
+    Auth,
+    Request,
+}
+
+/// SOCKS negotiation is running over an already established inner channel
+pub struct SocksHandshake<C> {
+    chan: C,
+    conf: Arc<SocksConf>,
+    stage: Stage,
+    buff: Vec<u8>,
+}
+
+pub enum SocksMidChan<C, PC> {
+    Inner(PC, Arc<SocksConf>),
+    Handshake(SocksHandshake<C>),
+}
+
+pub struct SocksListener<L> {
+    inner: L,
+    conf: Arc<SocksConf>,
+}
+
+enum Step<C> {
+    Pending(SocksHandshake<C>),
+    Active(SocksChan<C>),
+}
+
+fn reply(rep: u8) -> [u8; 10] {
+    [VERSION, rep, 0x00, ATYP_IPV4, 0, 0, 0, 0, 0, 0]
+}
+
+impl<C: Chan> SocksHandshake<C> {
+    fn new(chan: C, conf: Arc<SocksConf>) -> Self {
+        SocksHandshake { chan, conf, stage: Stage::Greeting, buff: Vec::new() }
+    }
+
+    fn refuse(&mut self, reply: &[u8], reason: &str) -> FwError<SocksErr<C::Err>> {
+        let _ = send_all(&mut self.chan, reply);
+        reason.into()
+    }
+
+    /// parse the message of the current stage, returns its length or `None` if it is incomplete
+    fn greeting(&mut self) -> Parsed<usize, C::Err> {
+        let b = &self.buff;
+
+        if b.len() < 2 || b.len() < 2 + b[1] as usize {
+            return Ok(None);
+        }
+
+        if b[0] != VERSION {
+            return Err("unsupported socks version".into());
+        }
+
+        let method = if self.conf.users.is_empty() { METHOD_NONE } else { METHOD_PASSWORD };
+
+        if !b[2..2 + b[1] as usize].contains(&method) {
+            return Err(self.refuse(&[VERSION, METHOD_UNACCEPTABLE], "no acceptable authentication method"));
+        }
+
+        send_all(&mut self.chan, &[VERSION, method]).map_err(lift)?;
+
+        self.stage = if method == METHOD_PASSWORD { Stage::Auth } else { Stage::Request };
+
+        Ok(Some(2 + b[1] as usize))
+    }
+
+    fn auth(&mut self) -> Parsed<usize, C::Err> {
+        let b = &self.buff;
+
+        if b.len() < 2 {
+            return Ok(None);
+        }
+
+        let ulen = b[1] as usize;
+
+        if b.len() < 3 + ulen {
+            return Ok(None);
+        }
+
+        let plen = b[2 + ulen] as usize;
+
+        if b.len() < 3 + ulen + plen {
+            return Ok(None);
+        }
+
+        if b[0] != AUTH_VERSION {
+            return Err("unsupported authentication version".into());
+        }
+
+        let user = String::from_utf8_lossy(&b[2..2 + ulen]).to_string();
+        let password = String::from_utf8_lossy(&b[3 + ulen..3 + ulen + plen]).to_string();
+
+        if !self.conf.users.iter().any(|(u, p)| *u == user && *p == password) {
+            return Err(self.refuse(&[AUTH_VERSION, 0x01], "invalid credentials"));
+        }
+
+        send_all(&mut self.chan, &[AUTH_VERSION, 0x00]).map_err(lift)?;
+
+        self.stage = Stage::Request;
+
+        Ok(Some(3 + ulen + plen))
+    }
+
+    fn request(&mut self) -> Parsed<(Dest, usize), C::Err> {
+        let b = &self.buff;
+
+        if b.len() < 5 {
+            return Ok(None);
+        }
+
+        if b[0] != VERSION {
+            return Err("unsupported socks version".into());
+        }
+
+        if b[1] != CMD_CONNECT {
+            return Err(self.refuse(&reply(REP_CMD_UNSUPPORTED), "only CONNECT is supported"));
+        }
+
+        let (host, at) = match b[3] {
+            ATYP_IPV4 if b.len() >= 4 + 4 => {
+                (Ipv4Addr::new(b[4], b[5], b[6], b[7]).to_string(), 8)
+            }
+            ATYP_IPV6 if b.len() >= 4 + 16 => {
+                let mut x = [0; 16];
+                x.copy_from_slice(&b[4..20]);
+                (Ipv6Addr::from(x).to_string(), 20)
+            }
+            ATYP_DOMAIN if b.len() >= 5 + b[4] as usize => {
+                let name = &b[5..5 + b[4] as usize];
+
+                // the name is matched against the allowed destinations as is, it must not hide another one
+                if name.is_empty() || !name.iter().all(|x| x.is_ascii_alphanumeric() || b"-._".contains(x)) {
+                    return Err(self.refuse(&reply(REP_FAILURE), "invalid domain name"));
+                }
+
+                (String::from_utf8_lossy(name).to_string(), 5 + name.len())
+            }
+            ATYP_IPV4 | ATYP_IPV6 | ATYP_DOMAIN => return Ok(None),
+            _ => return Err(self.refuse(&reply(REP_ATYP_UNSUPPORTED), "unsupported address type")),
+        };
+
+        if b.len() < at + 2 {
+            return Ok(None);
+        }
+
+        let port = u16::from_be_bytes([b[at], b[at + 1]]);
+
+        Ok(Some((Dest { host, port }, at + 2)))
+    }
+
+    fn step(mut self) -> Result<Step<C>, FwError<SocksErr<C::Err>>> {
+        loop {
+            let done = match self.stage {
+                Stage::Greeting => self.greeting()?,
+                Stage::Auth => self.auth()?,
+                Stage::Request => match self.request()? {
+                    Some((dest, len)) => {
+                        self.buff.drain(..len);
+                        return Ok(Step::Active(SocksChan { chan: self.chan, dest, rbuf: self.buff }));
+                    }
+                    None => None,
+                }
+            };
+
+            if let Some(len) = done {
+                self.buff.drain(..len);
+                continue;
+            }
+
+            match fill(&mut self.chan, &mut self.buff).map_err(lift)? {
+                Some(0) => return Err(FwError::Disconnected),
+                Some(_) => continue,
+                None => return Ok(Step::Pending(self)),
+            }
+        }
+    }
+}
+
+impl<C: Chan> Chan for SocksChan<C> {
+    type Err = SocksErr<C::Err>;
+
+    fn peer(&self) -> Option<String> {
+        self.chan.peer()
+    }
+
+    fn identity(&self) -> Option<String> {
+        self.chan.identity()
+    }
+
+    fn send(&mut self, buff: &[u8]) -> Result<usize, FwError<Self::Err>> {
+        self.chan.send(buff).map_err(lift)
+    }
+
+    fn recv(&mut self, buff: &mut [u8]) -> Result<Option<usize>, FwError<Self::Err>> {
+        if !self.rbuf.is_empty() {
+            let len = buff.len().min(self.rbuf.len());
+            buff[..len].copy_from_slice(&self.rbuf[..len]);
+            self.rbuf.drain(..len);
+            return Ok(Some(len));
+        }
+
+        self.chan.recv(buff).map_err(lift)
+    }
+
+    fn pending(&self) -> bool {
+        !self.rbuf.is_empty()
+    }
+
+    fn dest(&self) -> Option<&Dest> {
+        Some(&self.dest)
+    }
+
+    fn routed(&mut self, res: Result<(), RouteErr>) -> Result<(), FwError<Self::Err>> {
+        let rep = match res {
+            Ok(()) => REP_SUCCEEDED,
+            Err(RouteErr::Denied) => REP_NOT_ALLOWED,
+            Err(RouteErr::Unreachable) => REP_HOST_UNREACHABLE,
+        };
+
+        send_all(&mut self.chan, &reply(rep)).map_err(lift)
+    }
+}
+
+impl<C: Pollable> Pollable for SocksChan<C> {
+    fn register(&self, poll: &Poll, tok: usize) -> Result<(), IoError> {
+        self.chan.register(poll, tok)
+    }
+
+    fn deregister(&self, poll: &Poll) -> Result<(), IoError> {
+        self.chan.deregister(poll)
+    }
+}
+
+impl<C: Pollable, PC: Pollable> Pollable for SocksMidChan<C, PC> {
+    fn register(&self, poll: &Poll, tok: usize) -> Result<(), IoError> {
+        match self {
+            SocksMidChan::Inner(x, _) => x.register(poll, tok),
+            SocksMidChan::Handshake(x) => x.chan.register(poll, tok),
+        }
+    }
+
+    fn deregister(&self, poll: &Poll) -> Result<(), IoError> {
+        match self {
+            SocksMidChan::Inner(x, _) => x.deregister(poll),
+            SocksMidChan::Handshake(x) => x.chan.deregister(poll),
+        }
+    }
+}
+
+impl<E: Debug, C: Chan<Err=E>, PC: MidChan<Err=E, C=C>> MidChan for SocksMidChan<C, PC> {
+    type Err = SocksErr<E>;
+    type C = SocksChan<C>;
+
+    fn peer(&self) -> Option<String> {
+        match self {
+            SocksMidChan::Inner(x, _) => x.peer(),
+            SocksMidChan::Handshake(x) => x.chan.peer(),
+        }
+    }
+
+    fn try_channel(self, poll: &Poll) -> Result<NextState<Self::Err, Self::C, Self>, FwError<Self::Err>> {
+        let handshake = match self {
+            SocksMidChan::Inner(x, conf) => match x.try_channel(poll).map_err(lift)? {
+                NextState::Pending(x) => return Ok(NextState::Pending(SocksMidChan::Inner(x, conf))),
+                NextState::Active(chan) => SocksHandshake::new(chan, conf),
+            },
+            SocksMidChan::Handshake(x) => x,
+        };
+
+        match handshake.step()? {
+            Step::Pending(x) => Ok(NextState::Pending(SocksMidChan::Handshake(x))),
+            Step::Active(x) => Ok(NextState::Active(x)),
+        }
+    }
+}
+
+impl<L> SocksListener<L> {
+    pub fn new(inner: L, conf: SocksConf) -> Self {
+        SocksListener { inner, conf: Arc::new(conf) }
+    }
+}
+
+impl<L: SharedListener> SharedListener for SocksListener<L> {
+    fn try_clone(&self) -> Result<Self, IoError> {
+        Ok(SocksListener { inner: self.inner.try_clone()?, conf: self.conf.clone() })
+    }
+}
+
+impl<L: Pollable> Pollable for SocksListener<L> {
+    fn register(&self, poll: &Poll, tok: usize) -> Result<(), IoError> {
+        self.inner.register(poll, tok)
+    }
+
+    fn deregister(&self, poll: &Poll) -> Result<(), IoError> {
+        self.inner.deregister(poll)
+    }
+}
+
+impl<L: Listener> Listener for SocksListener<L> {
+    type Err = SocksErr<L::Err>;
+    type C = SocksChan<L::C>;
+    type PC = SocksMidChan<L::C, L::PC>;
+
+    fn accept(&mut self) -> Result<Option<NextState<Self::Err, Self::C, Self::PC>>, FwError<Self::Err>> {
+        let conf = self.conf.clone();
+
+        Ok(
+            self.inner.accept().map_err(lift)?.map(|x| match x {
+                NextState::Pending(x) => NextState::Pending(SocksMidChan::Inner(x, conf)),
+                NextState::Active(chan) => NextState::Pending(SocksMidChan::Handshake(SocksHandshake::new(chan, conf))),
+            })
+        )
+    }
+
+    fn reload(&mut self) -> Result<(), FwError<Self::Err>> {
+        self.inner.reload().map_err(lift)
+    }
+}
+
+impl<E: Debug, L: Parsable<Result<L, FwError<E>>>> Parsable<Result<SocksListener<L>, FwError<SocksErr<E>>>> for SocksListener<L> {
+    fn parser<'a, 'b>(app: App<'a, 'b>) -> App<'a, 'b> {
+        L::parser(app)
+            .arg(
+                Arg::with_name("socks_user")
+                    .long("user")
+                    .help("user:password allowed to connect, may be repeated; no authentication is required if omitted")
+                    .takes_value(true)
+                    .multiple(true)
+                    .number_of_values(1)
+            )
+    }
+
+    fn parse(matches: &ArgMatches) -> Result<SocksListener<L>, FwError<SocksErr<E>>> {
+        let inner = L::parse(matches).map_err(lift)?;
+
+        let mut users = Vec::new();
+
+        for x in matches.values_of("socks_user").into_iter().flatten() {
+            let sep = x.find(':').ok_or("user must be user:password")?;
+            users.push((x[..sep].to_string(), x[sep + 1..].to_string()));
+        }
+
+        Ok(SocksListener::new(inner, SocksConf { users }))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::cell::RefCell;
+    use std::rc::Rc;
+
+    /// channel returning what was pushed to `input` and recording what is sent
+    struct Mock {
+        input: Rc<RefCell<Vec<u8>>>,
+        output: Rc<RefCell<Vec<u8>>>,
+    }
+
+    impl Chan for Mock {
+        type Err = IoError;
+
+        fn send(&mut self, buff: &[u8]) -> Result<usize, FwError<IoError>> {
+            self.output.borrow_mut().extend_from_slice(buff);
+            Ok(buff.len())
+        }
+
+        fn recv(&mut self, buff: &mut [u8]) -> Result<Option<usize>, FwError<IoError>> {
+            let mut input = self.input.borrow_mut();
+
+            if input.is_empty() {
+                return Ok(None);
+            }
+
+            let len = buff.len().min(input.len());
+            buff[..len].copy_from_slice(&input[..len]);
+            input.drain(..len);
+            Ok(Some(len))
+        }
+    }
+
+    struct Client {
+        input: Rc<RefCell<Vec<u8>>>,
+        output: Rc<RefCell<Vec<u8>>>,
+    }
+
+    impl Client {
+        fn new(users: &[(&str, &str)]) -> (Self, SocksHandshake<Mock>) {
+            let input = Rc::new(RefCell::new(Vec::new()));
+            let output = Rc::new(RefCell::new(Vec::new()));
+            let conf = SocksConf { users: users.iter().map(|(u, p)| (u.to_string(), p.to_string())).collect() };
+            let chan = Mock { input: input.clone(), output: output.clone() };
+
+            (Client { input, output }, SocksHandshake::new(chan, Arc::new(conf)))
+        }
+
+        fn write(&self, data: &[u8]) {
+            self.input.borrow_mut().extend_from_slice(data);
+        }
+
+        fn read(&self) -> Vec<u8> {
+            self.output.borrow_mut().drain(..).collect()
+        }
+    }
+
+    fn pending(step: Result<Step<Mock>, FwError<SocksErr<IoError>>>) -> SocksHandshake<Mock> {
+        match step {
+            Ok(Step::Pending(x)) => x,
+            _ => panic!("handshake is not pending"),
+        }
+    }
+
+    fn active(step: Result<Step<Mock>, FwError<SocksErr<IoError>>>) -> SocksChan<Mock> {
+        match step {
+            Ok(Step::Active(x)) => x,
+            _ => panic!("handshake is not done"),
+        }
+    }
+
+    fn connect(atyp: u8, addr: &[u8]) -> Vec<u8> {
+        let mut res = vec![VERSION, CMD_CONNECT, 0, atyp];
+        res.extend_from_slice(addr);
+        res.extend_from_slice(&443u16.to_be_bytes());
+        res
+    }
+
+    #[test]
+    fn connects_without_auth() {
+        let (client, hs) = Client::new(&[]);
+
+        client.write(&[VERSION, 2, METHOD_PASSWORD, METHOD_NONE]);
+        let hs = pending(hs.step());
+        assert_eq!(client.read(), [VERSION, METHOD_NONE]);
+
+        // the request may arrive in pieces, with data behind it
+        let mut data = connect(ATYP_DOMAIN, b"\x0bexample.com");
+        data.extend_from_slice(b"data");
+        client.write(&data[..6]);
+        let hs = pending(hs.step());
+        client.write(&data[6..]);
+        let mut chan = active(hs.step());
+
+        assert_eq!(chan.dest(), Some(&Dest { host: "example.com".into(), port: 443 }));
+        assert!(chan.pending());
+
+        let mut buff = [0; 16];
+        assert_eq!(chan.recv(&mut buff).unwrap(), Some(4));
+        assert_eq!(&buff[..4], b"data");
+
+        chan.routed(Err(RouteErr::Denied)).unwrap();
+        assert_eq!(client.read(), reply(REP_NOT_ALLOWED));
+    }
+
+    #[test]
+    fn parses_addresses() {
+        for (atyp, addr, host) in [
+            (ATYP_IPV4, &[10, 0, 0, 1][..], "10.0.0.1"),
+            (ATYP_IPV6, &[0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 1][..], "::1"),
+            (ATYP_DOMAIN, &b"\x0cDocker_1.lan"[..], "Docker_1.lan"),
+        ] {
+            let (client, hs) = Client::new(&[]);
+            client.write(&[VERSION, 1, METHOD_NONE]);
+            client.write(&connect(atyp, addr));
+
+            assert_eq!(active(hs.step()).dest().unwrap().host, host);
+        }
+    }
+
+    #[test]
+    fn authenticates() {
+        let (client, hs) = Client::new(&[("alice", "secret")]);
+
+        client.write(&[VERSION, 1, METHOD_PASSWORD]);
+        client.write(b"\x01\x05alice\x06secret");
+        client.write(&connect(ATYP_IPV4, &[127, 0, 0, 1]));
+        let chan = active(hs.step());
+
+        assert_eq!(client.read(), [VERSION, METHOD_PASSWORD, AUTH_VERSION, 0]);
+        assert_eq!(chan.dest(), Some(&Dest { host: "127.0.0.1".into(), port: 443 }));
+    }
+
+    #[test]
+    fn refuses_credentials() {
+        let (client, hs) = Client::new(&[("alice", "secret")]);
+
+        // password authentication cannot be skipped
+        client.write(&[VERSION, 1, METHOD_NONE]);
+        assert!(hs.step().is_err());
+        assert_eq!(client.read(), [VERSION, METHOD_UNACCEPTABLE]);
+
+        let (client, hs) = Client::new(&[("alice", "secret")]);
+
+        client.write(&[VERSION, 1, METHOD_PASSWORD]);
+        client.write(b"\x01\x05alice\x06secreT");
+        client.write(&connect(ATYP_IPV4, &[127, 0, 0, 1]));
+        assert!(hs.step().is_err());
+        assert_eq!(client.read(), [VERSION, METHOD_PASSWORD, AUTH_VERSION, 1]);
+
+        let (client, hs) = Client::new(&[("alice", "secret")]);
+
+        client.write(&[VERSION, 1, METHOD_PASSWORD]);
+        client.write(b"\x02\x05alice\x06secret");
+        assert!(hs.step().is_err());
+    }
+
+    #[test]
+    fn refuses_requests() {
+        let refused = |request: &[u8]| {
+            let (client, hs) = Client::new(&[]);
+            client.write(&[VERSION, 1, METHOD_NONE]);
+            client.write(request);
+
+            assert!(hs.step().is_err());
+            client.read()[2..].to_vec()
+        };
+
+        assert_eq!(refused(&[4, CMD_CONNECT, 0, ATYP_IPV4, 1, 1, 1, 1, 0, 80]), b"");
+        // BIND
+        assert_eq!(refused(&[VERSION, 2, 0, ATYP_IPV4, 1, 1, 1, 1, 0, 80]), reply(REP_CMD_UNSUPPORTED));
+        assert_eq!(refused(&[VERSION, CMD_CONNECT, 0, 2, 1, 1, 1, 1, 0, 80]), reply(REP_ATYP_UNSUPPORTED));
+        // names that could be read as another destination
+        assert_eq!(refused(&connect(ATYP_DOMAIN, b"\x00")), reply(REP_FAILURE));
+        assert_eq!(refused(&connect(ATYP_DOMAIN, b"\x0devil.com\x00.lan")), reply(REP_FAILURE));
+        assert_eq!(refused(&connect(ATYP_DOMAIN, b"\x0bevil.com:22")), reply(REP_FAILURE));
+        assert_eq!(refused(&connect(ATYP_DOMAIN, b"\x05[::1]")), reply(REP_FAILURE));
+    }
+
+    #[test]
+    fn waits_for_client() {
+        let (client, hs) = Client::new(&[]);
+
+        client.write(&[VERSION, 3, METHOD_NONE]);
+        let hs = pending(hs.step());
+        assert_eq!(client.read(), b"");
+
+        client.write(&[METHOD_PASSWORD, 0x80]);
+        pending(hs.step());
+        assert_eq!(client.read(), [VERSION, METHOD_NONE]);
+    }
+}
@@ -1,16 +1,21 @@
+use std::error::Error;
+use std::fmt::{Display, Formatter, Result as FmtResult};
 use std::io::{Error as IoError, ErrorKind, Read, Write};
 use std::net::SocketAddr;
-use openssl::ssl::{HandshakeError, MidHandshakeSslStream, SslStream, SslAcceptor, SslMethod, SslFiletype};
+use openssl::ssl::{ErrorCode, HandshakeError, MidHandshakeSslStream, SslStream, SslAcceptor, SslMethod, SslFiletype, SslVerifyMode};
 use openssl::error::{Error as OrigSslError, ErrorStack};
 use openssl::pkey::{PKey, Private};
-use openssl::x509::X509;
-use mio::tcp::{TcpListener as MioTcpListener, TcpStream};
+use openssl::x509::{X509, X509NameRef, X509VerifyResult};
+use openssl::x509::store::X509Lookup;
+use openssl::x509::verify::X509VerifyFlags;
+use mio::tcp::TcpStream;
 use mio::{Poll, Token, Ready, PollOpt};
 use clap::{App, Arg, ArgMatches};
 
-use crate::{Listener, MidChan, Chan, FwError, Pollable, NextState};
+use crate::{Classify, ErrorClass, Listener, MidChan, Chan, FwError, Pollable, NextState, SharedListener};
 use crate::args::Parsable;
 use crate::proto::common::StreamConf;
+use crate::proto::tcp::{TcpBinds, TcpErr};
 
 
 #[derive(Debug)]
@@ -20,6 +25,64 @@ pub enum SslError {
     SslStack(ErrorStack),
     Handshake(HandshakeError<TcpStream>),
     Str(String),
+    /// the listening sockets could not be set up
+    Tcp(TcpErr),
+    /// a certificate, key or CA file could not be loaded
+    File(String, ErrorStack),
+}
+
+impl Display for SslError {
+    fn fmt(&self, f: &mut Formatter) -> FmtResult {
+        match self {
+            SslError::Io(x) => Display::fmt(x, f),
+            SslError::Ssl(x) => Display::fmt(x, f),
+            SslError::SslStack(x) => Display::fmt(x, f),
+            SslError::Handshake(x) => Display::fmt(x, f),
+            SslError::Str(x) => f.write_str(x),
+            SslError::Tcp(x) => Display::fmt(x, f),
+            SslError::File(path, _) => write!(f, "failed to load {}", path),
+        }
+    }
+}
+
+impl Error for SslError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            SslError::Handshake(x) => x.source(),
+            SslError::Tcp(x) => x.source(),
+            SslError::File(_, x) => Some(x),
+            _ => None,
+        }
+    }
+}
+
+impl Classify for SslError {
+    fn class(&self) -> ErrorClass {
+        match self {
+            SslError::Io(_) => ErrorClass::Other,
+            SslError::Str(_) => ErrorClass::Config,
+            SslError::Tcp(x) => x.class(),
+            _ => ErrorClass::Tls,
+        }
+    }
+
+    fn tls_failure(&self) -> Option<&'static str> {
+        let mid = match self {
+            SslError::Handshake(HandshakeError::Failure(x)) => x,
+            SslError::Handshake(HandshakeError::SetupFailure(_)) => return Some("setup"),
+            _ => return None,
+        };
+
+        if mid.ssl().verify_result() != X509VerifyResult::OK {
+            return Some("certificate");
+        }
+
+        match mid.error().code() {
+            ErrorCode::SSL => Some("protocol"),
+            ErrorCode::SYSCALL | ErrorCode::ZERO_RETURN => Some("eof"),
+            _ => Some("other"),
+        }
+    }
 }
 
 impl From<OrigSslError> for SslError {
@@ -35,6 +98,12 @@ impl From<ErrorStack> for SslError {
 }
 
 
+impl From<TcpErr> for SslError {
+    fn from(x: TcpErr) -> Self {
+        SslError::Tcp(x)
+    }
+}
+
 impl From<IoError> for SslError {
     fn from(x: IoError) -> Self {
         SslError::Io(x)
@@ -73,7 +142,6 @@ impl From<&str> for FwError<SslError> {
 
 
 pub struct SslChan {
-    #[allow(dead_code)]
     addr: SocketAddr,
     stream: SslStream<TcpStream>,
 }
@@ -84,10 +152,48 @@ pub struct SslMidChan {
     stream: MidHandshakeSslStream<TcpStream>,
 }
 
+/// Files the acceptor of a listener is built from, read again on reload
+#[derive(Debug, Clone)]
+pub struct SslFiles {
+    pub ca: String,
+    pub cert: String,
+    pub privkey: String,
+    pub crl: Option<String>,
+    /// whether client certificates are requested, `NONE` leaves clients unauthenticated
+    pub client_cert: SslVerifyMode,
+}
+
 pub struct SslListener {
-    listener: MioTcpListener,
+    listener: TcpBinds,
     acceptor: SslAcceptor,
-    conf: StreamConf
+    conf: StreamConf,
+    /// `None` when built from an acceptor, which can not be reloaded then
+    files: Option<SslFiles>,
+}
+
+impl SslFiles {
+    pub fn acceptor(&self) -> Result<SslAcceptor, SslError> {
+        let file_err = |path: &str| {
+            let path = path.to_string();
+            move |x| SslError::File(path, x)
+        };
+
+        let mut acceptor = SslAcceptor::mozilla_intermediate(SslMethod::tls())?;
+        acceptor.set_certificate_file(&self.cert, SslFiletype::PEM).map_err(file_err(&self.cert))?;
+        acceptor.set_private_key_file(&self.privkey, SslFiletype::PEM).map_err(file_err(&self.privkey))?;
+        acceptor.set_ca_file(&self.ca).map_err(file_err(&self.ca))?;
+        acceptor.check_private_key()?;
+
+        if let Some(crl) = &self.crl {
+            let store = acceptor.cert_store_mut();
+            store.add_lookup(X509Lookup::file())?.load_crl_file(crl, SslFiletype::PEM).map_err(file_err(crl))?;
+            store.set_flags(X509VerifyFlags::CRL_CHECK)?;
+        }
+
+        acceptor.set_verify(self.client_cert);
+
+        Ok(acceptor.build())
+    }
 }
 
 impl Pollable for SslChan {
@@ -110,8 +216,69 @@ impl Pollable for SslMidChan {
     }
 }
 
+/// format `name` like RFC 2253 does, but in certificate order, so that no two names give the same string
+fn dn(name: &X509NameRef) -> String {
+    let mut res = String::new();
+
+    for x in name.entries() {
+        if !res.is_empty() {
+            res.push(',');
+        }
+
+        match x.object().nid().short_name() {
+            Ok(x) => res.push_str(x),
+            Err(_) => res.push_str(&x.object().to_string()),
+        }
+
+        res.push('=');
+
+        match x.data().to_string() {
+            Ok(value) => {
+                let len = value.chars().count();
+
+                for (i, c) in value.chars().enumerate() {
+                    match c {
+                        ',' | '+' | '"' | '\\' | '<' | '>' | ';' => {
+                            res.push('\\');
+                            res.push(c);
+                        }
+                        '#' if i == 0 => res.push_str("\\#"),
+                        ' ' if i == 0 || i + 1 == len => res.push_str("\\ "),
+                        c if c.is_control() => {
+                            for b in c.to_string().bytes() {
+                                res.push_str(&format!("\\{:02X}", b));
+                            }
+                        }
+                        c => res.push(c),
+                    }
+                }
+            }
+            // values that are not strings are given as the hex of their bytes
+            Err(_) => {
+                res.push('#');
+
+                for b in x.data().as_slice() {
+                    res.push_str(&format!("{:02x}", b));
+                }
+            }
+        }
+    }
+
+    res
+}
+
 impl Chan for SslChan {
     type Err = SslError;
+
+    fn peer(&self) -> Option<String> {
+        Some(self.addr.to_string())
+    }
+
+    /// subject of the certificate the client presented, e.g. `CN=client,O=example`
+    fn identity(&self) -> Option<String> {
+        Some(dn(self.stream.ssl().peer_certificate()?.subject_name()))
+    }
+
     fn send(&mut self, buff: &[u8]) -> Result<usize, FwError<Self::Err>> {
         Ok(self.stream.write(buff)?)
     }
@@ -126,7 +293,7 @@ impl Chan for SslChan {
                 _ => return Err(x.into())
             }
         };
-        return Ok(read);
+        Ok(read)
     }
 }
 
@@ -134,6 +301,10 @@ impl MidChan for SslMidChan {
     type Err = SslError;
     type C = SslChan;
 
+    fn peer(&self) -> Option<String> {
+        Some(self.addr.to_string())
+    }
+
     fn try_channel(self, poll: &Poll) -> Result<NextState<Self::Err, Self::C, Self>, FwError<Self::Err>> {
         match self.stream.handshake() {
             Ok(x) => Ok(
@@ -159,31 +330,62 @@ impl MidChan for SslMidChan {
 
 impl Pollable for SslListener {
     fn register(&self, poll: &Poll, tok: usize) -> Result<(), IoError> {
-        poll.register(&self.listener, Token(tok), Ready::all(), PollOpt::level())
+        self.listener.register(poll, tok)
     }
 
     fn deregister(&self, poll: &Poll) -> Result<(), IoError> {
-        poll.deregister(&self.listener)
+        self.listener.deregister(poll)
+    }
+}
+
+impl SharedListener for SslListener {
+    fn try_clone(&self) -> Result<Self, IoError> {
+        Ok(SslListener {
+            listener: self.listener.try_clone()?,
+            acceptor: self.acceptor.clone(),
+            conf: self.conf.clone(),
+            files: self.files.clone(),
+        })
     }
 }
 
 impl SslListener {
     pub fn bind(addr: &SocketAddr, acceptor: SslAcceptor, conf: StreamConf) -> Result<Self, IoError> {
-        Ok(SslListener {
-            listener: MioTcpListener::bind(addr)?,
+        Ok(SslListener::new(TcpBinds::bind(&[*addr], None)?, acceptor, conf))
+    }
+
+    pub fn new(listener: TcpBinds, acceptor: SslAcceptor, conf: StreamConf) -> Self {
+        SslListener {
+            listener,
             acceptor,
             conf,
-        })
+            files: None,
+        }
+    }
+
+    /// bind with an acceptor built from `files`, which can be reloaded later on
+    pub fn bind_files(addr: &SocketAddr, files: SslFiles, conf: StreamConf) -> Result<Self, SslError> {
+        SslListener::new_files(TcpBinds::bind(&[*addr], None)?, files, conf)
+    }
+
+    /// listen with an acceptor built from `files`, which can be reloaded later on
+    pub fn new_files(listener: TcpBinds, files: SslFiles, conf: StreamConf) -> Result<Self, SslError> {
+        let acceptor = files.acceptor()?;
+        let mut ret = SslListener::new(listener, acceptor, conf);
+
+        ret.files = Some(files);
+
+        Ok(ret)
     }
 
-    pub fn pkey_from_file(file: &mut Read) -> Result<PKey<Private>, SslError> {
+    pub fn pkey_from_file(file: &mut dyn Read) -> Result<PKey<Private>, SslError> {
         let mut pkey_bytes = Vec::<u8>::with_capacity(2048);
         file.read_to_end(&mut pkey_bytes)?;
         let res = PKey::<Private>::private_key_from_pem(pkey_bytes.as_ref())?;
         Ok(res)
     }
 
-    pub fn cert_from_file(file: &mut Read) -> Result<X509, SslError> {
+    pub fn cert_from_file(file: &mut dyn Read) -> Result<X509, SslError> {
         let mut pkey_bytes = Vec::<u8>::with_capacity(2048);
         file.read_to_end(&mut pkey_bytes)?;
         let res = X509::from_pem(pkey_bytes.as_ref())?;
@@ -197,23 +399,13 @@ impl Listener for SslListener {
     type PC = SslMidChan;
 
     fn accept(&mut self) -> Result<Option<NextState<Self::Err, Self::C, Self::PC>>, FwError<Self::Err>> {
-        if let Some((stream, addr)) = {
-            match self.listener.accept() {
-                Err(x) => match x.kind() {
-                    ErrorKind::WouldBlock => {
-                        Ok(None)
-                    }
-                    _ => Err(x)
-                }
-                Ok(x) => Ok(Some(x))
-            }?
-        } {
+        if let Some((stream, addr)) = self.listener.accept()? {
             stream.set_nodelay(true)?;
 
             stream.set_keepalive(self.conf.keepalive)?;
             stream.set_linger(self.conf.linger)?;
 
-            let chan = match self.acceptor.accept(stream) {
+            match self.acceptor.accept(stream) {
                 Ok(x) => Ok(
                     Some(NextState::Active(
                         SslChan { addr, stream: x }
@@ -227,27 +419,24 @@ impl Listener for SslListener {
                     }
                     x => Err(x.into())
                 }
-            };
-
-            chan
+            }
         } else {
             Ok(None)
         }
     }
-}
 
-pub enum SslParseError {
-    Stack(ErrorStack),
+    fn reload(&mut self) -> Result<(), FwError<Self::Err>> {
+        let files = self.files.as_ref().ok_or("the listener was not set up from files")?;
+
+        self.acceptor = files.acceptor()?;
+
+        Ok(())
+    }
 }
 
 impl Parsable<Result<SslListener, FwError<SslError>>> for SslListener {
     fn parser<'a, 'b>(app: App<'a, 'b>) -> App<'a, 'b> {
-        let app = app
-            .arg(
-                Arg::with_name("addr")
-                    .required(true)
-                    .index(1)
-            )
+        let app = TcpBinds::parser(app)
             .arg(
                 Arg::with_name("ca")
                     .required(true)
@@ -262,33 +451,70 @@ impl Parsable<Result<SslListener, FwError<SslError>>> for SslListener {
                 Arg::with_name("privkey")
                     .required(true)
                     .index(4)
+            )
+            .arg(
+                Arg::with_name("client-cert")
+                    .long("client-cert")
+                    .help("request a certificate signed by the CA from clients, which may still connect without one when optional")
+                    .takes_value(true)
+                    .possible_values(&["optional", "required"])
+                    .required(false)
+            )
+            .arg(
+                Arg::with_name("crl")
+                    .long("crl")
+                    .help("certificate revocation list the client certificates are checked against")
+                    .takes_value(true)
+                    .required(false)
             );
         StreamConf::parser(app)
     }
     fn parse<'a>(matches: &ArgMatches) -> Result<SslListener, FwError<SslError>> {
-        let addr = matches.value_of("addr").ok_or("address not found")?;
         let ca = matches.value_of("ca").ok_or("ca not found")?;
         let cert = matches.value_of("cert").ok_or("cert not found")?;
         let privkey = matches.value_of("privkey").ok_or("privkey not found")?;
 
-        let addr = addr.parse::<SocketAddr>().map_err(|_| "invalid socket address")?;
+        let conf = StreamConf::parse(matches)?;
+
+        let files = SslFiles {
+            ca: ca.to_string(),
+            cert: cert.to_string(),
+            privkey: privkey.to_string(),
+            crl: matches.value_of("crl").map(|x| x.to_string()),
+            client_cert: match matches.value_of("client-cert") {
+                Some("optional") => SslVerifyMode::PEER,
+                Some(_) => SslVerifyMode::PEER | SslVerifyMode::FAIL_IF_NO_PEER_CERT,
+                None => SslVerifyMode::NONE,
+            },
+        };
 
-        let conf = StreamConf::parse(&matches)?;
+        let binds = TcpBinds::parse(matches).map_err(SslError::Tcp)?;
 
-        let mut acceptor = SslAcceptor::mozilla_intermediate(SslMethod::tls())?;
-        acceptor.set_certificate_file(cert, SslFiletype::PEM)?;
-        acceptor.set_private_key_file(privkey, SslFiletype::PEM)?;
-        acceptor.set_ca_file(ca)?;
-        acceptor.check_private_key()?;
+        Ok(SslListener::new_files(binds, files, conf)?)
+    }
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use openssl::x509::X509Name;
+
+    fn name(entries: &[(&str, &str)]) -> String {
+        let mut name = X509Name::builder().unwrap();
+
+        for (field, value) in entries {
+            name.append_entry_by_text(field, value).unwrap();
+        }
 
-        let acceptor = acceptor.build();
+        dn(&name.build())
+    }
 
-        Ok(
-            SslListener::bind(
-                &addr,
-                acceptor,
-                conf,
-            )?
-        )
+    #[test]
+    fn formats_names() {
+        assert_eq!(name(&[("CN", "ci"), ("O", "example")]), "CN=ci,O=example");
+        assert_eq!(name(&[("CN", "a,O=b")]), "CN=a\\,O=b");
+        assert_ne!(name(&[("CN", "a,O=b")]), name(&[("CN", "a"), ("O", "b")]));
+        assert_eq!(name(&[("CN", "a+b;c\"<d>\\")]), "CN=a\\+b\\;c\\\"\\<d\\>\\\\");
+        assert_eq!(name(&[("CN", "#a b ")]), "CN=\\#a b\\ ");
+        assert_eq!(name(&[("CN", " \n")]), "CN=\\ \\0A");
     }
-}
\ No newline at end of file
+}
@@ -1,17 +1,69 @@
 use mio::tcp::{TcpListener as MioTcpListener, TcpStream};
 use std::io::{Error as IoError, ErrorKind, Read, Write};
-use std::net::SocketAddr;
+use std::error::Error;
+use std::fmt::{Display, Formatter, Result as FmtResult};
+use std::cell::Cell;
+use std::net::{SocketAddr, ToSocketAddrs};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::mpsc::TryRecvError;
+use std::thread;
 use mio::{Poll, Token, Ready, PollOpt};
+use mio_extras::channel::{channel, Receiver};
+use net2::TcpBuilder;
 use clap::{App, Arg, ArgMatches};
+use log::debug;
 
-use crate::{Listener, MidChan, Chan, FwError, NextState, Pollable};
+use crate::{
+    Classify, ErrorClass, Listener, MidChan, Chan, Connector, ConnInfo, FwError, NextState, Pollable, SharedListener,
+    MAX_BINDS,
+};
 use crate::args::Parsable;
 use crate::proto::common::StreamConf;
 
+/// Host names being resolved at once, each lookup runs on a thread of its own
+const MAX_LOOKUPS: usize = 64;
+
+static LOOKUPS: AtomicUsize = AtomicUsize::new(0);
+
 #[derive(Debug)]
 pub enum TcpErr {
     Io(IoError),
     Str(String),
+    Bind(SocketAddr, IoError),
+    Connect(String, IoError),
+    /// an address to listen on could not be resolved
+    Resolve(String, IoError),
+}
+
+impl Display for TcpErr {
+    fn fmt(&self, f: &mut Formatter) -> FmtResult {
+        match self {
+            TcpErr::Io(x) => Display::fmt(x, f),
+            TcpErr::Str(x) => f.write_str(x),
+            TcpErr::Bind(addr, _) => write!(f, "failed to bind {}", addr),
+            TcpErr::Connect(addr, _) => write!(f, "failed to connect to {}", addr),
+            TcpErr::Resolve(addr, _) => write!(f, "failed to resolve {}", addr),
+        }
+    }
+}
+
+impl Error for TcpErr {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            TcpErr::Bind(_, x) | TcpErr::Connect(_, x) | TcpErr::Resolve(_, x) => Some(x),
+            _ => None,
+        }
+    }
+}
+
+impl Classify for TcpErr {
+    fn class(&self) -> ErrorClass {
+        match self {
+            TcpErr::Str(_) | TcpErr::Resolve(..) => ErrorClass::Config,
+            TcpErr::Bind(..) => ErrorClass::Bind,
+            _ => ErrorClass::Other,
+        }
+    }
 }
 
 impl From<IoError> for TcpErr {
@@ -20,6 +72,15 @@ impl From<IoError> for TcpErr {
     }
 }
 
+impl From<TcpErr> for IoError {
+    fn from(x: TcpErr) -> Self {
+        match x {
+            TcpErr::Io(x) | TcpErr::Bind(_, x) | TcpErr::Connect(_, x) | TcpErr::Resolve(_, x) => x,
+            TcpErr::Str(x) => IoError::new(ErrorKind::InvalidInput, x),
+        }
+    }
+}
+
 impl From<IoError> for FwError<TcpErr> {
     fn from(x: IoError) -> Self {
         FwError::Io(TcpErr::Io(x))
@@ -52,13 +113,21 @@ impl MidChan for TcpChan {
     type Err = TcpErr;
     type C = TcpChan;
 
+    fn peer(&self) -> Option<String> {
+        Some(self.addr.clone())
+    }
+
     fn try_channel(self, _poll: &Poll) -> Result<NextState<Self::Err, Self::C, Self>, FwError<Self::Err>> {
-        return Ok(NextState::Active(TcpChan { addr: self.addr, stream: self.stream }));
+        Ok(NextState::Active(TcpChan { addr: self.addr, stream: self.stream }))
     }
 }
 
 impl Chan for TcpChan {
     type Err = TcpErr;
+
+    fn peer(&self) -> Option<String> {
+        Some(self.addr.clone())
+    }
     fn send(&mut self, buff: &[u8]) -> Result<usize, FwError<Self::Err>> {
         Ok(self.stream.write(buff)?)
     }
@@ -74,12 +143,356 @@ impl Chan for TcpChan {
                 _ => return Err(x.into())
             }
         };
-        return Ok(read);
+        Ok(read)
+    }
+}
+
+/// Outgoing connection that is not established yet, the next resolved address is connected in its place if it fails
+pub struct MidTcpChan {
+    addr: String,
+    conf: StreamConf,
+    step: Connect,
+    /// token the channel was registered under, the socket that replaces it is registered under the same one
+    tok: Cell<Option<usize>>,
+}
+
+enum Connect {
+    /// the host name is being resolved off the event loop
+    Resolving(Receiver<Result<Vec<SocketAddr>, IoError>>),
+    Connecting(TcpStream, Vec<SocketAddr>),
+}
+
+impl Pollable for MidTcpChan {
+    fn register(&self, poll: &Poll, tok: usize) -> Result<(), IoError> {
+        self.tok.set(Some(tok));
+
+        match &self.step {
+            Connect::Resolving(x) => poll.register(x, Token(tok), Ready::readable(), PollOpt::edge()),
+            Connect::Connecting(x, _) => poll.register(x, Token(tok), Ready::all(), PollOpt::edge()),
+        }
+    }
+
+    fn deregister(&self, poll: &Poll) -> Result<(), IoError> {
+        self.tok.set(None);
+
+        match &self.step {
+            Connect::Resolving(x) => poll.deregister(x),
+            Connect::Connecting(x, _) => poll.deregister(x),
+        }
+    }
+}
+
+impl MidTcpChan {
+    fn new(addr: &str, conf: &StreamConf, step: Connect) -> Self {
+        MidTcpChan { addr: addr.to_string(), conf: conf.clone(), step, tok: Cell::new(None) }
+    }
+
+    /// connect to the first of `addrs` that can be connected to, in place of the lookup or socket polled so far
+    fn next(mut self, poll: &Poll, mut addrs: Vec<SocketAddr>) -> Result<Self, FwError<TcpErr>> {
+        let tok = self.tok.get();
+
+        if tok.is_some() {
+            self.deregister(poll)?;
+        }
+
+        let mut err = None;
+
+        while !addrs.is_empty() {
+            let addr = addrs.remove(0);
+
+            match TcpConnector::stream(&addr, &self.conf) {
+                Ok(x) => {
+                    self.step = Connect::Connecting(x, addrs);
+
+                    if let Some(x) = tok {
+                        self.register(poll, x).map_err(FwError::Register)?;
+                    }
+
+                    return Ok(self);
+                }
+                Err(x) => {
+                    debug!("failed to connect to {} at {}: {}", self.addr, addr, x);
+                    err = Some(x);
+                }
+            }
+        }
+
+        match err {
+            Some(x) => Err(FwError::Io(TcpErr::Connect(self.addr, x))),
+            None => Err("address did not resolve".into()),
+        }
+    }
+
+    fn established(self) -> TcpChan {
+        match self.step {
+            Connect::Connecting(stream, _) => TcpChan { addr: self.addr, stream },
+            Connect::Resolving(_) => unreachable!("connection established before its address was resolved"),
+        }
+    }
+}
+
+impl MidChan for MidTcpChan {
+    type Err = TcpErr;
+    type C = TcpChan;
+
+    fn peer(&self) -> Option<String> {
+        Some(self.addr.clone())
+    }
+
+    fn try_channel(self, poll: &Poll) -> Result<NextState<Self::Err, Self::C, Self>, FwError<Self::Err>> {
+        match &self.step {
+            Connect::Resolving(rx) => match rx.try_recv() {
+                Ok(Ok(addrs)) => self.next(poll, addrs).map(NextState::Pending),
+                Ok(Err(x)) => Err(FwError::Io(TcpErr::Connect(self.addr, x))),
+                Err(TryRecvError::Empty) => Ok(NextState::Pending(self)),
+                Err(TryRecvError::Disconnected) => Err("address lookup failed".into()),
+            },
+            Connect::Connecting(stream, rest) => {
+                let err = match stream.take_error()? {
+                    Some(x) => x,
+                    None => match stream.peer_addr() {
+                        Ok(_) => return Ok(NextState::Active(self.established())),
+                        Err(x) if x.kind() == ErrorKind::NotConnected => return Ok(NextState::Pending(self)),
+                        Err(x) => x,
+                    }
+                };
+
+                if rest.is_empty() {
+                    return Err(FwError::Io(TcpErr::Connect(self.addr, err)));
+                }
+
+                debug!("failed to connect to {}: {}, trying its next address", self.addr, err);
+
+                let rest = rest.clone();
+                self.next(poll, rest).map(NextState::Pending)
+            }
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct TcpConnector {
+    addr: String,
+    conf: StreamConf,
+}
+
+impl TcpConnector {
+    pub fn new(addr: &str, conf: &StreamConf) -> Self {
+        TcpConnector { addr: addr.to_string(), conf: conf.clone() }
+    }
+
+    /// start connecting to `addr`, host names are resolved on a thread that reports back to the event loop
+    pub fn connect_to(addr: &str, conf: &StreamConf) -> Result<MidTcpChan, FwError<TcpErr>> {
+        if let Ok(x) = addr.parse::<SocketAddr>() {
+            let stream = Self::stream(&x, conf).map_err(|x| FwError::Io(TcpErr::Connect(addr.to_string(), x)))?;
+
+            return Ok(MidTcpChan::new(addr, conf, Connect::Connecting(stream, Vec::new())));
+        }
+
+        if LOOKUPS.fetch_add(1, Ordering::Relaxed) >= MAX_LOOKUPS {
+            LOOKUPS.fetch_sub(1, Ordering::Relaxed);
+            return Err("too many address lookups in progress".into());
+        }
+
+        let (tx, rx) = channel();
+        let name = addr.to_string();
+
+        let spawned = thread::Builder::new().name("sfw-resolve".into()).spawn(move || {
+            let _ = tx.send(name.to_socket_addrs().map(|x| x.collect()));
+            LOOKUPS.fetch_sub(1, Ordering::Relaxed);
+        });
+
+        if let Err(x) = spawned {
+            LOOKUPS.fetch_sub(1, Ordering::Relaxed);
+            return Err(x.into());
+        }
+
+        Ok(MidTcpChan::new(addr, conf, Connect::Resolving(rx)))
+    }
+
+    fn stream(addr: &SocketAddr, conf: &StreamConf) -> Result<TcpStream, IoError> {
+        let stream = TcpStream::connect(addr)?;
+
+        stream.set_nodelay(true)?;
+
+        stream.set_keepalive(conf.keepalive)?;
+        stream.set_linger(conf.linger)?;
+
+        Ok(stream)
+    }
+}
+
+impl Connector for TcpConnector {
+    type Err = TcpErr;
+    type C = TcpChan;
+    type PC = MidTcpChan;
+
+    fn connect(&mut self, _info: &ConnInfo) -> Result<NextState<Self::Err, Self::C, Self::PC>, FwError<Self::Err>> {
+        Ok(NextState::Pending(Self::connect_to(&self.addr, &self.conf)?))
+    }
+}
+
+impl Parsable<Result<TcpConnector, FwError<TcpErr>>> for TcpConnector {
+    fn parser<'a, 'b>(app: App<'a, 'b>) -> App<'a, 'b> {
+        let app = app
+            .arg(
+                Arg::with_name("addr")
+                    .help("host:port to connect to")
+                    .required(true)
+                    .index(1)
+            );
+
+        StreamConf::parser(app)
+    }
+
+    fn parse(matches: &ArgMatches) -> Result<TcpConnector, FwError<TcpErr>> {
+        let addr = matches.value_of("addr").ok_or("address not found")?;
+
+        let conf = StreamConf::parse(matches)?;
+
+        Ok(TcpConnector::new(addr, &conf))
+    }
+}
+
+/// Listening sockets of a single listener, each polled under a token of its own
+pub struct TcpBinds {
+    sockets: Vec<MioTcpListener>,
+    /// socket accepted from first next time, so that none of them is starved
+    next: usize,
+}
+
+impl TcpBinds {
+    /// bind every one of `addrs`, IPv6 sockets accept IPv4 clients as well unless `v6only`,
+    /// which is left to the system default if not given
+    pub fn bind(addrs: &[SocketAddr], v6only: Option<bool>) -> Result<Self, TcpErr> {
+        if addrs.is_empty() || addrs.len() > MAX_BINDS {
+            return Err(TcpErr::Str(format!("expected 1 to {} addresses to listen on", MAX_BINDS)));
+        }
+
+        let mut sockets = Vec::with_capacity(addrs.len());
+
+        for addr in addrs {
+            let bind = || -> Result<MioTcpListener, IoError> {
+                let sock = match addr {
+                    SocketAddr::V4(_) => TcpBuilder::new_v4()?,
+                    SocketAddr::V6(_) => {
+                        let sock = TcpBuilder::new_v6()?;
+
+                        if let Some(x) = v6only {
+                            sock.only_v6(x)?;
+                        }
+
+                        sock
+                    }
+                };
+
+                sock.reuse_address(true)?;
+                sock.bind(addr)?;
+
+                MioTcpListener::from_std(sock.listen(1024)?)
+            };
+
+            sockets.push(bind().map_err(|x| TcpErr::Bind(*addr, x))?);
+        }
+
+        Ok(TcpBinds { sockets, next: 0 })
+    }
+
+    /// accept a single client from any of the sockets, `None` if none of them has one waiting
+    pub fn accept(&mut self) -> Result<Option<(TcpStream, SocketAddr)>, IoError> {
+        let len = self.sockets.len();
+
+        for i in 0..len {
+            let idx = (self.next + i) % len;
+
+            match self.sockets[idx].accept() {
+                Ok(x) => {
+                    self.next = (idx + 1) % len;
+                    return Ok(Some(x));
+                }
+                Err(x) => match x.kind() {
+                    ErrorKind::WouldBlock => {}
+                    _ => return Err(x),
+                }
+            }
+        }
+
+        Ok(None)
+    }
+
+    pub fn try_clone(&self) -> Result<Self, IoError> {
+        Ok(TcpBinds {
+            sockets: self.sockets.iter().map(|x| x.try_clone()).collect::<Result<_, _>>()?,
+            next: 0,
+        })
+    }
+}
+
+/// resolve a comma separated list of `host:port`, all of the addresses a host name resolves to are kept
+pub fn resolve(addrs: &str) -> Result<Vec<SocketAddr>, TcpErr> {
+    let mut ret = Vec::new();
+
+    for addr in addrs.split(',').map(|x| x.trim()).filter(|x| !x.is_empty()) {
+        for x in addr.to_socket_addrs().map_err(|x| TcpErr::Resolve(addr.to_string(), x))? {
+            if !ret.contains(&x) {
+                ret.push(x);
+            }
+        }
+    }
+
+    Ok(ret)
+}
+
+impl Pollable for TcpBinds {
+    /// the n-th socket is registered under `tok + n`
+    fn register(&self, poll: &Poll, tok: usize) -> Result<(), IoError> {
+        for (idx, x) in self.sockets.iter().enumerate() {
+            poll.register(x, Token(tok + idx), Ready::all(), PollOpt::level())?;
+        }
+
+        Ok(())
+    }
+
+    fn deregister(&self, poll: &Poll) -> Result<(), IoError> {
+        for x in &self.sockets {
+            poll.deregister(x)?;
+        }
+
+        Ok(())
+    }
+}
+
+impl Parsable<Result<TcpBinds, TcpErr>> for TcpBinds {
+    fn parser<'a, 'b>(app: App<'a, 'b>) -> App<'a, 'b> {
+        app
+            .arg(
+                Arg::with_name("addr")
+                    .help("host:port to listen on, several can be separated by commas")
+                    .required(true)
+                    .index(1)
+            )
+            .arg(
+                Arg::with_name("ipv6_only")
+                    .long("ipv6-only")
+                    .help("whether IPv6 sockets refuse IPv4 clients, the system default applies if not given")
+                    .possible_values(&["on", "off"])
+                    .takes_value(true)
+                    .required(false)
+            )
+    }
+
+    fn parse(matches: &ArgMatches) -> Result<TcpBinds, TcpErr> {
+        let addrs = matches.value_of("addr").ok_or(TcpErr::Str("address not found".to_string()))?;
+        let addrs = resolve(addrs)?;
+
+        let v6only = matches.value_of("ipv6_only").map(|x| x == "on");
+
+        TcpBinds::bind(&addrs, v6only)
     }
 }
 
 pub struct TcpListener {
-    listener: MioTcpListener,
+    listener: TcpBinds,
     conf: StreamConf,
 }
 
@@ -88,20 +501,33 @@ impl TcpListener {
         addr: &SocketAddr,
         conf: &StreamConf,
     ) -> Result<Self, IoError> {
+        Ok(TcpListener::new(TcpBinds::bind(&[*addr], None)?, conf))
+    }
+
+    pub fn new(listener: TcpBinds, conf: &StreamConf) -> Self {
+        TcpListener {
+            listener,
+            conf: conf.clone(),
+        }
+    }
+}
+
+impl SharedListener for TcpListener {
+    fn try_clone(&self) -> Result<Self, IoError> {
         Ok(TcpListener {
-            listener: MioTcpListener::bind(addr)?,
-            conf: conf.clone()
+            listener: self.listener.try_clone()?,
+            conf: self.conf.clone(),
         })
     }
 }
 
 impl Pollable for TcpListener {
     fn register(&self, poll: &Poll, tok: usize) -> Result<(), IoError> {
-        poll.register(&self.listener, Token(tok), Ready::all(), PollOpt::level())
+        self.listener.register(poll, tok)
     }
 
     fn deregister(&self, poll: &Poll) -> Result<(), IoError> {
-        poll.deregister(&self.listener)
+        self.listener.deregister(poll)
     }
 }
 
@@ -111,60 +537,101 @@ impl Listener for TcpListener {
     type PC = TcpChan;
 
     fn accept(&mut self) -> Result<Option<NextState<Self::Err, Self::C, Self::PC>>, FwError<Self::Err>> {
-        if let Some((sock, addr)) = {
-            match self.listener.accept() {
-                Err(x) => match x.kind() {
-                    ErrorKind::WouldBlock => {
-                        Ok(None)
-                    }
-                    _ => Err(x)
-                }
-                Ok(x) => Ok(Some(x))
-            }?
-        } {
+        if let Some((sock, addr)) = self.listener.accept()? {
             sock.set_nodelay(true)?;
 
             sock.set_keepalive(self.conf.keepalive)?;
             sock.set_linger(self.conf.linger)?;
 
-            return Ok(
+            Ok(
                 Some(
                     NextState::Pending(TcpChan {
                         addr: addr.to_string(),
                         stream: sock,
                     })
                 )
-            );
+            )
         } else {
-            return Ok(None);
+            Ok(None)
         }
     }
 }
 
 impl Parsable<Result<TcpListener, FwError<TcpErr>>> for TcpListener {
     fn parser<'a, 'b>(app: App<'a, 'b>) -> App<'a, 'b> {
-        let app = app
-            .arg(
-                Arg::with_name("addr")
-                    .required(true)
-                    .index(1)
-            );
+        let app = TcpBinds::parser(app);
 
         StreamConf::parser(app)
     }
 
 
     fn parse(matches: &ArgMatches) -> Result<TcpListener, FwError<TcpErr>> {
-        let addr = matches.value_of("addr").ok_or("address not found")?;
-        let addr = addr.parse::<SocketAddr>().map_err(|_| "invalid socket address")?;
+        let binds = TcpBinds::parse(matches)?;
+
+        let conf = StreamConf::parse(matches)?;
+
+        Ok(TcpListener::new(binds, &conf))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::TcpListener;
+    use std::time::Duration;
+    use mio::Events;
+
+    fn conf() -> StreamConf {
+        StreamConf { linger: None, keepalive: None }
+    }
+
+    /// drive `chan` registered under token 1 until it is connected or fails
+    fn established(poll: &Poll, mut chan: MidTcpChan) -> Result<TcpChan, String> {
+        let mut events = Events::with_capacity(8);
+
+        chan.register(poll, 1).unwrap();
+
+        for _ in 0..50 {
+            poll.poll(&mut events, Some(Duration::from_millis(100))).unwrap();
+
+            chan = match chan.try_channel(poll).map_err(|x| x.to_string())? {
+                NextState::Active(x) => return Ok(x),
+                NextState::Pending(x) => x,
+            };
+        }
+
+        Err("timed out".into())
+    }
+
+    fn closed_port() -> SocketAddr {
+        TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap()
+    }
+
+    #[test]
+    fn resolves_off_the_loop() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = format!("localhost:{}", listener.local_addr().unwrap().port());
+        let poll = Poll::new().unwrap();
+
+        let chan = TcpConnector::connect_to(&addr, &conf()).unwrap();
+
+        assert!(matches!(chan.step, Connect::Resolving(_)));
+        assert_eq!(Chan::peer(&established(&poll, chan).unwrap()), Some(addr));
+    }
+
+    #[test]
+    fn falls_back_to_next_address() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let poll = Poll::new().unwrap();
+
+        let chan = MidTcpChan::new("name:1", &conf(), Connect::Resolving(channel().1));
+        let chan = chan.next(&poll, vec![closed_port(), listener.local_addr().unwrap()]).unwrap();
+
+        assert!(established(&poll, chan).is_ok());
 
-        let conf = StreamConf::parse(&matches)?;
+        let chan = MidTcpChan::new("name:1", &conf(), Connect::Resolving(channel().1));
+        let chan = chan.next(&poll, vec![closed_port(), closed_port()]).unwrap();
 
-        Ok(
-            TcpListener::bind(
-                &addr,
-                &conf
-            )?
-        )
+        assert_eq!(established(&poll, chan).err().as_deref(), Some("failed to connect to name:1"));
     }
 }
@@ -1,15 +1,46 @@
+use std::error::Error;
+use std::fmt::{Display, Formatter, Result as FmtResult};
 use std::io::{Error as IoError, ErrorKind, Write, Read};
 use mio::{Token, Poll, Ready, PollOpt};
 use mio_uds::UnixStream;
 use clap::{App, Arg, ArgMatches};
 
-use crate::{FwError, MidChan, Chan, Connector, NextState, Pollable};
+use crate::{Classify, ErrorClass, FwError, MidChan, Chan, Connector, ConnInfo, NextState, Pollable};
 use crate::args::Parsable;
 
 #[derive(Debug)]
 pub enum UnixErr {
     Io(IoError),
-    Str(String)
+    Str(String),
+    Connect(String, IoError),
+}
+
+impl Display for UnixErr {
+    fn fmt(&self, f: &mut Formatter) -> FmtResult {
+        match self {
+            UnixErr::Io(x) => Display::fmt(x, f),
+            UnixErr::Str(x) => f.write_str(x),
+            UnixErr::Connect(path, _) => write!(f, "failed to connect to {}", path),
+        }
+    }
+}
+
+impl Error for UnixErr {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            UnixErr::Connect(_, x) => Some(x),
+            _ => None,
+        }
+    }
+}
+
+impl Classify for UnixErr {
+    fn class(&self) -> ErrorClass {
+        match self {
+            UnixErr::Str(_) => ErrorClass::Config,
+            _ => ErrorClass::Other,
+        }
+    }
 }
 
 impl From<IoError> for UnixErr {
@@ -32,13 +63,11 @@ impl From<&str> for FwError<UnixErr> {
 
 
 pub struct UnixChan {
-    #[allow(dead_code)]
     addr: Option<String>,
     stream: UnixStream,
 }
 
 pub struct MidUnixChan {
-    #[allow(dead_code)]
     addr: Option<String>,
     stream: UnixStream,
 }
@@ -67,13 +96,21 @@ impl MidChan for MidUnixChan {
     type Err = UnixErr;
     type C = UnixChan;
 
+    fn peer(&self) -> Option<String> {
+        self.addr.clone()
+    }
+
     fn try_channel(self, _poll: &Poll) -> Result<NextState<Self::Err, Self::C, Self>, FwError<Self::Err>> {
-        return Ok(NextState::Active(UnixChan { addr: self.addr, stream: self.stream }));
+        Ok(NextState::Active(UnixChan { addr: self.addr, stream: self.stream }))
     }
 }
 
 impl Chan for UnixChan {
     type Err = UnixErr;
+
+    fn peer(&self) -> Option<String> {
+        self.addr.clone()
+    }
     fn send(&mut self, buff: &[u8]) -> Result<usize, FwError<Self::Err>> {
         Ok(self.stream.write(buff)?)
     }
@@ -89,17 +126,29 @@ impl Chan for UnixChan {
                 _ => return Err(x.into())
             }
         };
-        return Ok(read);
+        Ok(read)
     }
 }
 
+#[derive(Clone)]
 pub struct UnixConnector {
-    addr: String
+    addr: String,
+    /// the event loop watches the socket file for re-creation
+    watch: bool,
 }
 
 impl UnixConnector {
     pub fn new(addr: &str) -> Self {
-        UnixConnector { addr: addr.to_string() }
+        UnixConnector { addr: addr.to_string(), watch: false }
+    }
+
+    pub fn with_watch(mut self, watch: bool) -> Self {
+        self.watch = watch;
+        self
+    }
+
+    pub fn path(&self) -> &str {
+        &self.addr
     }
 }
 
@@ -108,23 +157,36 @@ impl Connector for UnixConnector {
     type C = UnixChan;
     type PC = MidUnixChan;
 
-    fn connect(&mut self) -> Result<NextState<Self::Err, Self::C, Self::PC>, FwError<Self::Err>> {
-        let conn = UnixStream::connect(&self.addr)?;
+    fn connect(&mut self, _info: &ConnInfo) -> Result<NextState<Self::Err, Self::C, Self::PC>, FwError<Self::Err>> {
+        let conn = UnixStream::connect(&self.addr).map_err(|x| UnixErr::Connect(self.addr.clone(), x))?;
+
+        Ok(NextState::Pending(MidUnixChan { addr: None, stream: conn }))
+    }
 
-        return Ok(NextState::Pending(MidUnixChan { addr: None, stream: conn }));
+    fn sockets(&self) -> Vec<(usize, String)> {
+        match self.watch {
+            true => vec![(0, self.addr.clone())],
+            false => Vec::new(),
+        }
     }
 }
 
 impl Parsable<Result<UnixConnector, FwError<UnixErr>>> for UnixConnector {
     fn parser<'a, 'b>(app: App<'a, 'b>) -> App<'a, 'b> {
-        app.arg(
-            Arg::with_name("addr")
-                .required(true)
-                .index(1)
-        )
+        app
+            .arg(
+                Arg::with_name("addr")
+                    .required(true)
+                    .index(1)
+            )
+            .arg(
+                Arg::with_name("watch")
+                    .long("watch")
+                    .help("react to the socket file being removed and created again right away")
+            )
     }
     fn parse(matches: &ArgMatches) -> Result<UnixConnector, FwError<UnixErr>> {
         let addr = matches.value_of("addr").ok_or("address not found")?;
-        Ok(UnixConnector::new(addr))
+        Ok(UnixConnector::new(addr).with_watch(matches.is_present("watch")))
     }
 }
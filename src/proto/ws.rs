@@ -0,0 +1,726 @@
+use std::io::Error as IoError;
+use std::error::Error;
+use std::fmt::{Debug, Display, Formatter, Result as FmtResult};
+use std::sync::Arc;
+use mio::Poll;
+use clap::{App, Arg, ArgMatches};
+use openssl::sha::sha1;
+use openssl::base64::encode_block;
+
+use crate::{Classify, ErrorClass, Listener, MidChan, Chan, FwError, NextState, Pollable, SharedListener};
+use crate::args::Parsable;
+use crate::proto::common::{fill, send_all};
+use crate::proto::http::{Head, reply};
+
+const ACCEPT_GUID: &str = "258EAFA5-E914-47DA-95CA-C5AB0DC85B11";
+
+const OP_CONTINUATION: u8 = 0x0;
+const OP_TEXT: u8 = 0x1;
+const OP_BINARY: u8 = 0x2;
+const OP_CLOSE: u8 = 0x8;
+const OP_PING: u8 = 0x9;
+const OP_PONG: u8 = 0xA;
+
+/// Largest control frame payload allowed by RFC 6455
+const MAX_CONTROL: usize = 125;
+
+const CLOSE_NORMAL: u16 = 1000;
+const CLOSE_PROTOCOL: u16 = 1002;
+const CLOSE_UNSUPPORTED: u16 = 1003;
+
+#[derive(Debug)]
+pub enum WsErr<E: Debug> {
+    Inner(E),
+    Str(String),
+}
+
+impl<E: Debug + Display> Display for WsErr<E> {
+    fn fmt(&self, f: &mut Formatter) -> FmtResult {
+        match self {
+            WsErr::Inner(x) => Display::fmt(x, f),
+            WsErr::Str(x) => f.write_str(x),
+        }
+    }
+}
+
+impl<E: Error + 'static> Error for WsErr<E> {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            WsErr::Inner(x) => x.source(),
+            WsErr::Str(_) => None,
+        }
+    }
+}
+
+impl<E: Debug + Classify> Classify for WsErr<E> {
+    fn class(&self) -> ErrorClass {
+        match self {
+            WsErr::Inner(x) => x.class(),
+            WsErr::Str(_) => ErrorClass::Config,
+        }
+    }
+
+    fn tls_failure(&self) -> Option<&'static str> {
+        match self {
+            WsErr::Inner(x) => x.tls_failure(),
+            WsErr::Str(_) => None,
+        }
+    }
+}
+
+impl<E: Debug> From<&str> for FwError<WsErr<E>> {
+    fn from(x: &str) -> FwError<WsErr<E>> {
+        FwError::Io(WsErr::Str(x.to_string()))
+    }
+}
+
+fn lift<E: Debug>(x: FwError<E>) -> FwError<WsErr<E>> {
+    x.map(WsErr::Inner)
+}
+
+#[derive(Debug, Clone)]
+pub struct WsConf {
+    /// path the upgrade request must be sent to
+    pub path: String,
+    /// values of the `Origin` header that are accepted, any origin is accepted when empty
+    pub origins: Vec<String>,
+}
+
+/// Header of the data frame currently being received
+struct Frame {
+    remaining: u64,
+    mask: [u8; 4],
+    offset: usize,
+}
+
+/// A byte stream tunnelled through binary WebSocket messages
+pub struct WsChan<C: Chan> {
+    chan: C,
+    rbuf: Vec<u8>,
+    wbuf: Vec<u8>,
+    frame: Option<Frame>,
+    /// the last data frame did not end its message, the next one must continue it
+    fragmented: bool,
+    /// a close frame was sent, none is sent when the channel is dropped
+    closed: bool,
+}
+
+/// Header of a client frame
+#[derive(Debug, PartialEq)]
+struct Header {
+    fin: bool,
+    opcode: u8,
+    len: u64,
+    mask: [u8; 4],
+    /// length of the header itself
+    at: usize,
+}
+
+/// Upgrade request is being received over an already established inner channel
+pub struct WsUpgrade<C> {
+    chan: C,
+    conf: Arc<WsConf>,
+    buff: Vec<u8>,
+}
+
+/// Outcome of a handshake step, the upgrade stays pending until the whole request is received
+enum Step<C: Chan> {
+    Pending(WsUpgrade<C>),
+    Active(WsChan<C>),
+}
+
+pub enum WsMidChan<C, PC> {
+    Inner(PC, Arc<WsConf>),
+    Upgrade(WsUpgrade<C>),
+}
+
+pub struct WsListener<L> {
+    inner: L,
+    conf: Arc<WsConf>,
+}
+
+fn frame_header(opcode: u8, len: usize) -> Vec<u8> {
+    let mut res = Vec::with_capacity(10);
+
+    res.push(0x80 | opcode);
+
+    if len < 126 {
+        res.push(len as u8);
+    } else if len <= 0xffff {
+        res.push(126);
+        res.extend_from_slice(&(len as u16).to_be_bytes());
+    } else {
+        res.push(127);
+        res.extend_from_slice(&(len as u64).to_be_bytes());
+    }
+
+    res
+}
+
+/// parse a client frame header, refusing what RFC 6455 does not allow a client to send and text frames,
+/// as the stream is carried in binary messages; errors come with the close code to send
+fn parse_header(buff: &[u8]) -> Result<Option<Header>, (u16, &'static str)> {
+    if buff.len() < 2 {
+        return Ok(None);
+    }
+
+    let fin = buff[0] & 0x80 != 0;
+    let opcode = buff[0] & 0x0f;
+
+    if buff[0] & 0x70 != 0 {
+        return Err((CLOSE_PROTOCOL, "reserved bits set"));
+    }
+
+    if buff[1] & 0x80 == 0 {
+        return Err((CLOSE_PROTOCOL, "client frames must be masked"));
+    }
+
+    match opcode {
+        OP_TEXT => return Err((CLOSE_UNSUPPORTED, "text frames are not accepted")),
+        OP_CONTINUATION | OP_BINARY | OP_CLOSE | OP_PING | OP_PONG => {}
+        _ => return Err((CLOSE_PROTOCOL, "unknown opcode")),
+    }
+
+    let (len, at) = match buff[1] & 0x7f {
+        126 if buff.len() >= 4 => (u16::from_be_bytes([buff[2], buff[3]]) as u64, 4),
+        127 if buff.len() >= 10 => {
+            let mut x = [0; 8];
+            x.copy_from_slice(&buff[2..10]);
+            (u64::from_be_bytes(x), 10)
+        }
+        126 | 127 => return Ok(None),
+        x => (x as u64, 2),
+    };
+
+    // lengths use the shortest encoding, and the most significant bit is 0
+    if at == 4 && len < 126 || at == 10 && (len <= 0xffff || len >> 63 != 0) {
+        return Err((CLOSE_PROTOCOL, "invalid frame length"));
+    }
+
+    if opcode >= OP_CLOSE && (!fin || len as usize > MAX_CONTROL) {
+        return Err((CLOSE_PROTOCOL, "control frames must be whole and at most 125 bytes long"));
+    }
+
+    if buff.len() < at + 4 {
+        return Ok(None);
+    }
+
+    let mut mask = [0; 4];
+    mask.copy_from_slice(&buff[at..at + 4]);
+
+    Ok(Some(Header { fin, opcode, len, mask, at: at + 4 }))
+}
+
+impl<C: Chan> WsChan<C> {
+    fn new(chan: C, rbuf: Vec<u8>) -> Self {
+        WsChan { chan, rbuf, wbuf: Vec::new(), frame: None, fragmented: false, closed: false }
+    }
+
+    fn queue(&mut self, opcode: u8, payload: &[u8]) {
+        self.wbuf.extend_from_slice(&frame_header(opcode, payload.len()));
+        self.wbuf.extend_from_slice(payload);
+    }
+
+    fn flush(&mut self) -> Result<(), FwError<WsErr<C::Err>>> {
+        while !self.wbuf.is_empty() {
+            match self.chan.send(&self.wbuf).map_err(lift)? {
+                0 => return Err(FwError::Disconnected),
+                x => { self.wbuf.drain(..x); }
+            }
+        }
+
+        Ok(())
+    }
+
+    /// handle a control frame whose payload is complete in the read buffer,
+    /// returns false if the peer is closing the connection
+    fn control(&mut self, opcode: u8, payload: Vec<u8>) -> Result<bool, FwError<WsErr<C::Err>>> {
+        match opcode {
+            OP_PING => {
+                self.queue(OP_PONG, &payload);
+                self.flush()?;
+                Ok(true)
+            }
+            OP_PONG => Ok(true),
+            OP_CLOSE if payload.len() == 1 => Err(self.fail(CLOSE_PROTOCOL, "invalid close frame")),
+            OP_CLOSE => {
+                let code = if payload.len() >= 2 { &payload[..2] } else { &[] };
+                self.queue(OP_CLOSE, code);
+                self.closed = true;
+                self.flush()?;
+                Ok(false)
+            }
+            _ => Err(self.fail(CLOSE_PROTOCOL, "unknown opcode")),
+        }
+    }
+
+    /// send a close frame with `code` and the error to close the connection with
+    fn fail(&mut self, code: u16, reason: &str) -> FwError<WsErr<C::Err>> {
+        self.close(code);
+        reason.into()
+    }
+
+    /// queue a close frame with `code` and send what can be sent, the connection is being closed
+    fn close(&mut self, code: u16) {
+        if !self.closed {
+            self.closed = true;
+            self.queue(OP_CLOSE, &code.to_be_bytes());
+            let _ = self.flush();
+        }
+    }
+}
+
+impl<C: Chan> Drop for WsChan<C> {
+    /// tell the client the stream ended, e.g. because the upstream closed it
+    fn drop(&mut self) {
+        self.close(CLOSE_NORMAL);
+    }
+}
+
+impl<C: Chan> Chan for WsChan<C> {
+    type Err = WsErr<C::Err>;
+
+    fn peer(&self) -> Option<String> {
+        self.chan.peer()
+    }
+
+    fn identity(&self) -> Option<String> {
+        self.chan.identity()
+    }
+
+    fn send(&mut self, buff: &[u8]) -> Result<usize, FwError<Self::Err>> {
+        self.queue(OP_BINARY, buff);
+        self.flush()?;
+        Ok(buff.len())
+    }
+
+    fn recv(&mut self, buff: &mut [u8]) -> Result<Option<usize>, FwError<Self::Err>> {
+        // replies to control frames may have been left over from a short write
+        self.flush()?;
+
+        loop {
+            if let Some(frame) = &mut self.frame {
+                if frame.remaining == 0 {
+                    self.frame = None;
+                    continue;
+                }
+
+                if !self.rbuf.is_empty() {
+                    let len = buff.len().min(self.rbuf.len()).min(frame.remaining as usize);
+
+                    for (i, x) in self.rbuf.drain(..len).enumerate() {
+                        buff[i] = x ^ frame.mask[(frame.offset + i) % 4];
+                    }
+
+                    frame.offset += len;
+                    frame.remaining -= len as u64;
+
+                    return Ok(Some(len));
+                }
+            } else if let Some(Header { fin, opcode, len, mask, at }) = parse_header(&self.rbuf)
+                .map_err(|(code, reason)| self.fail(code, reason))? {
+                match opcode {
+                    OP_CONTINUATION | OP_BINARY => {
+                        if self.fragmented != (opcode == OP_CONTINUATION) {
+                            return Err(self.fail(CLOSE_PROTOCOL, "data frame out of sequence"));
+                        }
+
+                        self.fragmented = !fin;
+                        self.rbuf.drain(..at);
+                        self.frame = Some(Frame { remaining: len, mask, offset: 0 });
+                        continue;
+                    }
+                    _ if self.rbuf.len() >= at + len as usize => {
+                        let payload = self.rbuf.drain(..at + len as usize)
+                            .skip(at)
+                            .enumerate()
+                            .map(|(i, x)| x ^ mask[i % 4])
+                            .collect();
+
+                        if self.control(opcode, payload)? {
+                            continue;
+                        } else {
+                            return Ok(Some(0));
+                        }
+                    }
+                    _ => {}
+                }
+            }
+
+            match fill(&mut self.chan, &mut self.rbuf).map_err(lift)? {
+                Some(0) => {
+                    // nobody is left to read a close frame
+                    self.closed = true;
+                    return Ok(Some(0));
+                }
+                Some(_) => continue,
+                None => return Ok(None),
+            }
+        }
+    }
+
+    fn pending(&self) -> bool {
+        !self.rbuf.is_empty()
+    }
+}
+
+impl<C: Chan> WsUpgrade<C> {
+    fn refuse(&mut self, status: &str, reason: &str) -> FwError<WsErr<C::Err>> {
+        let _ = send_all(&mut self.chan, &reply(status, &[("Connection", "close"), ("Content-Length", "0")]));
+        reason.into()
+    }
+
+    /// make progress on the upgrade handshake, returns the channel once the handshake is done
+    fn step(mut self) -> Result<Step<C>, FwError<WsErr<C::Err>>> {
+        let (head, len) = loop {
+            match Head::parse(&self.buff) {
+                Ok(Some(x)) => break x,
+                Ok(None) => {}
+                Err(x) => return Err(self.refuse("400 Bad Request", x)),
+            }
+
+            match fill(&mut self.chan, &mut self.buff).map_err(lift)? {
+                Some(0) => return Err(FwError::Disconnected),
+                Some(_) => continue,
+                None => return Ok(Step::Pending(self)),
+            }
+        };
+
+        if head.method != "GET" {
+            return Err(self.refuse("405 Method Not Allowed", "upgrade must be a GET request"));
+        }
+
+        if head.path() != self.conf.path {
+            return Err(self.refuse("404 Not Found", "invalid upgrade path"));
+        }
+
+        if !self.conf.origins.is_empty() {
+            let allowed = head.header("Origin")
+                .map(|x| self.conf.origins.iter().any(|y| y == x))
+                .unwrap_or(false);
+
+            if !allowed {
+                return Err(self.refuse("403 Forbidden", "origin not allowed"));
+            }
+        }
+
+        if !head.header_has("Upgrade", "websocket") || !head.header_has("Connection", "upgrade") {
+            return Err(self.refuse("426 Upgrade Required", "not a websocket upgrade"));
+        }
+
+        if head.header("Sec-WebSocket-Version") != Some("13") {
+            return Err(self.refuse("426 Upgrade Required", "unsupported websocket version"));
+        }
+
+        let key = match head.header("Sec-WebSocket-Key") {
+            Some(x) => x.to_string(),
+            None => return Err(self.refuse("400 Bad Request", "websocket key missing")),
+        };
+
+        let accept = encode_block(&sha1(format!("{}{}", key, ACCEPT_GUID).as_bytes()));
+
+        send_all(
+            &mut self.chan,
+            &reply(
+                "101 Switching Protocols",
+                &[("Upgrade", "websocket"), ("Connection", "Upgrade"), ("Sec-WebSocket-Accept", &accept)],
+            ),
+        ).map_err(lift)?;
+
+        // the client may have sent its first frames right behind the upgrade request
+        self.buff.drain(..len);
+
+        Ok(Step::Active(WsChan::new(self.chan, self.buff)))
+    }
+}
+
+impl<C: Chan + Pollable> Pollable for WsChan<C> {
+    fn register(&self, poll: &Poll, tok: usize) -> Result<(), IoError> {
+        self.chan.register(poll, tok)
+    }
+
+    fn deregister(&self, poll: &Poll) -> Result<(), IoError> {
+        self.chan.deregister(poll)
+    }
+}
+
+impl<C: Pollable, PC: Pollable> Pollable for WsMidChan<C, PC> {
+    fn register(&self, poll: &Poll, tok: usize) -> Result<(), IoError> {
+        match self {
+            WsMidChan::Inner(x, _) => x.register(poll, tok),
+            WsMidChan::Upgrade(x) => x.chan.register(poll, tok),
+        }
+    }
+
+    fn deregister(&self, poll: &Poll) -> Result<(), IoError> {
+        match self {
+            WsMidChan::Inner(x, _) => x.deregister(poll),
+            WsMidChan::Upgrade(x) => x.chan.deregister(poll),
+        }
+    }
+}
+
+impl<E: Debug, C: Chan<Err=E>, PC: MidChan<Err=E, C=C>> MidChan for WsMidChan<C, PC> {
+    type Err = WsErr<E>;
+    type C = WsChan<C>;
+
+    fn peer(&self) -> Option<String> {
+        match self {
+            WsMidChan::Inner(x, _) => x.peer(),
+            WsMidChan::Upgrade(x) => x.chan.peer(),
+        }
+    }
+
+    fn try_channel(self, poll: &Poll) -> Result<NextState<Self::Err, Self::C, Self>, FwError<Self::Err>> {
+        let upgrade = match self {
+            WsMidChan::Inner(x, conf) => match x.try_channel(poll).map_err(lift)? {
+                NextState::Pending(x) => return Ok(NextState::Pending(WsMidChan::Inner(x, conf))),
+                NextState::Active(chan) => WsUpgrade { chan, conf, buff: Vec::new() },
+            },
+            WsMidChan::Upgrade(x) => x,
+        };
+
+        match upgrade.step()? {
+            Step::Pending(x) => Ok(NextState::Pending(WsMidChan::Upgrade(x))),
+            Step::Active(x) => Ok(NextState::Active(x)),
+        }
+    }
+}
+
+impl<L> WsListener<L> {
+    pub fn new(inner: L, conf: WsConf) -> Self {
+        WsListener { inner, conf: Arc::new(conf) }
+    }
+}
+
+impl<L: SharedListener> SharedListener for WsListener<L> {
+    fn try_clone(&self) -> Result<Self, IoError> {
+        Ok(WsListener { inner: self.inner.try_clone()?, conf: self.conf.clone() })
+    }
+}
+
+impl<L: Pollable> Pollable for WsListener<L> {
+    fn register(&self, poll: &Poll, tok: usize) -> Result<(), IoError> {
+        self.inner.register(poll, tok)
+    }
+
+    fn deregister(&self, poll: &Poll) -> Result<(), IoError> {
+        self.inner.deregister(poll)
+    }
+}
+
+impl<L: Listener> Listener for WsListener<L> {
+    type Err = WsErr<L::Err>;
+    type C = WsChan<L::C>;
+    type PC = WsMidChan<L::C, L::PC>;
+
+    fn accept(&mut self) -> Result<Option<NextState<Self::Err, Self::C, Self::PC>>, FwError<Self::Err>> {
+        let conf = self.conf.clone();
+
+        Ok(
+            self.inner.accept().map_err(lift)?.map(|x| match x {
+                NextState::Pending(x) => NextState::Pending(WsMidChan::Inner(x, conf)),
+                NextState::Active(chan) => NextState::Pending(WsMidChan::Upgrade(WsUpgrade { chan, conf, buff: Vec::new() })),
+            })
+        )
+    }
+
+    fn reload(&mut self) -> Result<(), FwError<Self::Err>> {
+        self.inner.reload().map_err(lift)
+    }
+}
+
+impl<E: Debug, L: Parsable<Result<L, FwError<E>>>> Parsable<Result<WsListener<L>, FwError<WsErr<E>>>> for WsListener<L> {
+    fn parser<'a, 'b>(app: App<'a, 'b>) -> App<'a, 'b> {
+        L::parser(app)
+            .arg(
+                Arg::with_name("ws_path")
+                    .long("path")
+                    .help("path the upgrade request must be sent to")
+                    .default_value("/")
+            )
+            .arg(
+                Arg::with_name("ws_origin")
+                    .long("origin")
+                    .help("accepted value of the Origin header, may be repeated; any origin is accepted if omitted")
+                    .takes_value(true)
+                    .multiple(true)
+                    .number_of_values(1)
+            )
+    }
+
+    fn parse(matches: &ArgMatches) -> Result<WsListener<L>, FwError<WsErr<E>>> {
+        let inner = L::parse(matches).map_err(lift)?;
+
+        let path = matches.value_of("ws_path").ok_or("path not found")?;
+        let origins = matches.values_of("ws_origin").map(|x| x.map(|x| x.to_string()).collect()).unwrap_or_default();
+
+        Ok(
+            WsListener::new(
+                inner,
+                WsConf { path: path.to_string(), origins },
+            )
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::cell::RefCell;
+    use std::rc::Rc;
+
+    /// channel returning `input` once and recording what is sent
+    struct Mock {
+        input: Vec<u8>,
+        output: Rc<RefCell<Vec<u8>>>,
+    }
+
+    impl Chan for Mock {
+        type Err = IoError;
+
+        fn send(&mut self, buff: &[u8]) -> Result<usize, FwError<IoError>> {
+            self.output.borrow_mut().extend_from_slice(buff);
+            Ok(buff.len())
+        }
+
+        fn recv(&mut self, buff: &mut [u8]) -> Result<Option<usize>, FwError<IoError>> {
+            if self.input.is_empty() {
+                return Ok(None);
+            }
+
+            let len = buff.len().min(self.input.len());
+            buff[..len].copy_from_slice(&self.input[..len]);
+            self.input.drain(..len);
+            Ok(Some(len))
+        }
+    }
+
+    /// a masked client frame
+    fn frame(first: u8, payload: &[u8]) -> Vec<u8> {
+        let mask = [1, 2, 3, 4];
+        let mut res = frame_header(first & 0x0f, payload.len());
+        res[0] = first;
+        res[1] |= 0x80;
+        res.extend_from_slice(&mask);
+        res.extend(payload.iter().enumerate().map(|(i, x)| x ^ mask[i % 4]));
+        res
+    }
+
+    fn ws(input: Vec<u8>) -> (WsChan<Mock>, Rc<RefCell<Vec<u8>>>) {
+        let output = Rc::new(RefCell::new(Vec::new()));
+        (WsChan::new(Mock { input, output: output.clone() }, Vec::new()), output)
+    }
+
+    fn read(chan: &mut WsChan<Mock>) -> Result<Vec<u8>, String> {
+        let mut res = Vec::new();
+        let mut buff = [0; 64];
+
+        loop {
+            match chan.recv(&mut buff) {
+                Ok(Some(0)) | Ok(None) => return Ok(res),
+                Ok(Some(x)) => res.extend_from_slice(&buff[..x]),
+                Err(x) => return Err(format!("{:?}", x)),
+            }
+        }
+    }
+
+    fn close(code: u16) -> Vec<u8> {
+        let mut res = frame_header(OP_CLOSE, 2);
+        res.extend_from_slice(&code.to_be_bytes());
+        res
+    }
+
+    #[test]
+    fn parses_headers() {
+        let data = frame(0x82, b"abc");
+        let head = parse_header(&data).unwrap().unwrap();
+
+        assert_eq!(head, Header { fin: true, opcode: OP_BINARY, len: 3, mask: [1, 2, 3, 4], at: 6 });
+        assert_eq!(parse_header(&data[..5]), Ok(None));
+        assert!(!parse_header(&frame(0x02, &[0; 200])).unwrap().unwrap().fin);
+        assert_eq!(parse_header(&frame(0x82, &[0; 200])).unwrap().unwrap().at, 8);
+        assert_eq!(parse_header(&frame(0x82, &[0; 70000])).unwrap().unwrap().at, 14);
+    }
+
+    #[test]
+    fn refuses_headers() {
+        let code = |x: &[u8]| parse_header(x).unwrap_err().0;
+
+        assert_eq!(code(&frame(0x81, b"abc")), CLOSE_UNSUPPORTED);
+        assert_eq!(code(&frame(0x83, b"abc")), CLOSE_PROTOCOL);
+        assert_eq!(code(&frame(0xc2, b"abc")), CLOSE_PROTOCOL);
+        assert_eq!(code(&frame_header(OP_BINARY, 3)), CLOSE_PROTOCOL);
+        // control frames are whole and short
+        assert_eq!(code(&frame(0x09, b"abc")), CLOSE_PROTOCOL);
+        assert_eq!(code(&frame(0x89, &[0; 126])), CLOSE_PROTOCOL);
+        assert!(parse_header(&frame(0x89, &[0; 125])).is_ok());
+        // lengths use the shortest encoding
+        assert_eq!(code(&[0x82, 0xfe, 0, 125, 0, 0, 0, 0]), CLOSE_PROTOCOL);
+        assert_eq!(code(&[0x82, 0xff, 0, 0, 0, 0, 0, 0, 0xff, 0xff, 0, 0, 0, 0]), CLOSE_PROTOCOL);
+        assert_eq!(code(&[0x82, 0xff, 0x80, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0]), CLOSE_PROTOCOL);
+    }
+
+    #[test]
+    fn reads_fragmented_messages() {
+        let mut input = frame(0x02, b"ab");
+        input.extend(frame(0x89, b"hi"));
+        input.extend(frame(0x00, b"cd"));
+        input.extend(frame(0x80, b"ef"));
+        input.extend(frame(0x82, b"gh"));
+
+        let (mut chan, output) = ws(input);
+
+        assert_eq!(read(&mut chan).unwrap(), b"abcdefgh");
+
+        let mut pong = frame_header(OP_PONG, 2);
+        pong.extend_from_slice(b"hi");
+        assert_eq!(*output.borrow(), pong);
+    }
+
+    #[test]
+    fn refuses_frames_out_of_sequence() {
+        let mut input = frame(0x02, b"ab");
+        input.extend(frame(0x82, b"cd"));
+
+        let (mut chan, output) = ws(input);
+
+        assert!(read(&mut chan).is_err());
+        assert_eq!(*output.borrow(), close(CLOSE_PROTOCOL));
+
+        let (mut chan, output) = ws(frame(0x80, b"ab"));
+
+        assert!(read(&mut chan).is_err());
+        drop(chan);
+        assert_eq!(*output.borrow(), close(CLOSE_PROTOCOL));
+    }
+
+    #[test]
+    fn refuses_text() {
+        let (mut chan, output) = ws(frame(0x81, b"ab"));
+
+        assert!(read(&mut chan).is_err());
+        assert_eq!(*output.borrow(), close(CLOSE_UNSUPPORTED));
+    }
+
+    #[test]
+    fn closes() {
+        // the client closes, its code is echoed once
+        let (mut chan, output) = ws(frame(0x88, &1001u16.to_be_bytes()));
+
+        assert_eq!(read(&mut chan).unwrap(), b"");
+        drop(chan);
+        assert_eq!(*output.borrow(), close(1001));
+
+        // the upstream closes, the client is told before the channel goes away
+        let (mut chan, output) = ws(Vec::new());
+
+        chan.send(b"ab").unwrap();
+        drop(chan);
+
+        let mut data = frame_header(OP_BINARY, 2);
+        data.extend_from_slice(b"ab");
+        data.extend(close(CLOSE_NORMAL));
+        assert_eq!(*output.borrow(), data);
+    }
+}
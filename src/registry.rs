@@ -0,0 +1,275 @@
+use std::fmt::{Debug, Display, Formatter, Result as FmtResult};
+use clap::{App, AppSettings, ArgMatches, SubCommand};
+
+use crate::{describe, Chan, Classify, Connector, ErrorClass, Listener, MidChan, Pollable, Route, SharedListener};
+use crate::args::Parsable;
+use crate::config::RouteConf;
+use crate::proto;
+use crate::proto::either::Either;
+
+/// Receives the listener of the input protocol picked on the command line
+pub trait ListenerVisitor {
+    type R;
+
+    fn visit<
+        Le: Debug + Display + Classify, Lc: Chan<Err=Le> + Pollable, Lp: MidChan<C=Lc, Err=Le> + Pollable,
+        LL: Listener<C=Lc, PC=Lp, Err=Le> + SharedListener + Pollable + Send + 'static,
+    >(self, listener: LL, matches: &ArgMatches) -> Self::R;
+}
+
+/// Receives the connector of the output protocol picked on the command line
+pub trait ConnectorVisitor {
+    type R;
+
+    fn visit<
+        Se: Debug + Display + Classify, Sc: Chan<Err=Se> + Pollable, Sp: MidChan<C=Sc, Err=Se> + Pollable,
+        SS: Connector<C=Sc, PC=Sp, Err=Se> + Clone + Send + 'static,
+    >(self, connector: SS) -> Self::R;
+}
+
+#[derive(Debug)]
+pub enum RegistryError {
+    /// no protocol of this kind was given, carries the kind and the names that would be accepted
+    Missing(&'static str, &'static [&'static str]),
+    Unknown(&'static str, String),
+    /// the arguments of a protocol were rejected, carries the protocol, the kind of failure and the reason
+    Parse(&'static str, ErrorClass, String),
+    /// the arguments did not match the protocols, carries what the argument parser reported
+    Args(String),
+    /// error in the protocols of a route of the configuration file, carries the name of the route
+    Route(String, Box<RegistryError>),
+    /// the output cannot work after the input, carries the input, the output and the inputs it can follow
+    Unsupported(String, &'static str, &'static [&'static str]),
+}
+
+impl Display for RegistryError {
+    fn fmt(&self, f: &mut Formatter) -> FmtResult {
+        match self {
+            RegistryError::Missing(kind, names) => write!(f, "no {} protocol given, expected one of: {}", kind, names.join(", ")),
+            RegistryError::Unknown(kind, name) => write!(f, "unknown {} protocol `{}`", kind, name),
+            RegistryError::Parse(name, _, reason) => write!(f, "{}: {}", name, reason),
+            RegistryError::Args(x) => f.write_str(x),
+            RegistryError::Route(name, x) => write!(f, "route {}: {}", name, x),
+            RegistryError::Unsupported(input, output, inputs) =>
+                write!(f, "the {} output cannot follow the {} input, expected one of: {}", output, input, inputs.join(", ")),
+        }
+    }
+}
+
+impl std::error::Error for RegistryError {}
+
+impl Classify for RegistryError {
+    fn class(&self) -> ErrorClass {
+        match self {
+            RegistryError::Parse(_, x, _) => *x,
+            RegistryError::Route(_, x) => x.class(),
+            _ => ErrorClass::Config,
+        }
+    }
+}
+
+/// outputs only working after some of the inputs, `route` needs the destination requested by a proxy input
+const REQUIRES: &[(&str, &[&str])] = &[("route", &["connect", "socks"])];
+
+/// refuse to pair `input` with an `output` that would fail every connection
+fn check(input: &str, output: Option<&str>) -> Result<(), RegistryError> {
+    match REQUIRES.iter().find(|(name, _)| Some(*name) == output) {
+        Some((name, inputs)) if !inputs.contains(&input) => Err(RegistryError::Unsupported(input.to_string(), name, inputs)),
+        _ => Ok(()),
+    }
+}
+
+/// `Either` nesting all of the given types, e.g. `Either<A, Either<B, C>>`
+macro_rules! any {
+    ($ty:ty) => { $ty };
+    ($ty:ty, $($rest:ty),+) => { Either<$ty, any!($($rest),+)> };
+}
+
+/// parse the protocol called `name` into the matching variant of `any!` of the given types
+macro_rules! any_parse {
+    ($name:expr, $matches:expr; $n:literal => $ty:ty) => {
+        match $name {
+            $n => Some(<$ty>::parse($matches).map_err(|x| RegistryError::Parse($n, x.class(), describe(&x)))),
+            _ => None,
+        }
+    };
+    ($name:expr, $matches:expr; $n:literal => $ty:ty, $($rn:literal => $rty:ty),+) => {
+        match $name {
+            $n => Some(<$ty>::parse($matches).map(Either::A).map_err(|x| RegistryError::Parse($n, x.class(), describe(&x)))),
+            _ => any_parse!($name, $matches; $($rn => $rty),+).map(|x| x.map(Either::B)),
+        }
+    };
+}
+
+macro_rules! registry {
+    (
+        inputs { $($in_name:literal => $in_ty:ty,)* }
+        outputs { $($out_name:literal => $out_ty:ty,)* }
+    ) => {
+        /// names of the input protocols, in the order they are listed by `--help`
+        pub const INPUTS: &[&str] = &[$($in_name),*];
+        /// names of the output protocols, in the order they are listed by `--help`
+        pub const OUTPUTS: &[&str] = &[$($out_name),*];
+
+        /// listener of any of the input protocols, for event loops serving routes of different protocols
+        pub type AnyListener = any!($($in_ty),*);
+        /// connector of any of the output protocols
+        pub type AnyConnector = any!($($out_ty),*);
+
+        fn any_listener(name: &str, matches: &ArgMatches) -> Result<AnyListener, RegistryError> {
+            any_parse!(name, matches; $($in_name => $in_ty),*)
+                .unwrap_or_else(|| Err(RegistryError::Unknown("input", name.to_string())))
+        }
+
+        fn any_connector(name: &str, matches: &ArgMatches) -> Result<AnyConnector, RegistryError> {
+            any_parse!(name, matches; $($out_name => $out_ty),*)
+                .unwrap_or_else(|| Err(RegistryError::Unknown("output", name.to_string())))
+        }
+
+        fn input_parser<'a, 'b>(app: App<'a, 'b>, name: &str) -> App<'a, 'b> {
+            match name {
+                $($in_name => <$in_ty>::parser(app),)*
+                _ => app,
+            }
+        }
+
+        fn output_parser<'a, 'b>(app: App<'a, 'b>, name: &str) -> App<'a, 'b> {
+            match name {
+                $($out_name => <$out_ty>::parser(app),)*
+                _ => app,
+            }
+        }
+
+        /// parse the input protocol picked in `matches` and hand its listener to `visitor`
+        pub fn listener<V: ListenerVisitor>(matches: &ArgMatches, visitor: V) -> Result<V::R, RegistryError> {
+            match matches.subcommand() {
+                $(
+                    ($in_name, Some(matches)) => {
+                        check($in_name, matches.subcommand_name())?;
+
+                        let listener = <$in_ty>::parse(matches)
+                            .map_err(|x| RegistryError::Parse($in_name, x.class(), describe(&x)))?;
+
+                        Ok(visitor.visit(listener, matches))
+                    }
+                )*
+                ("", _) => Err(RegistryError::Missing("input", INPUTS)),
+                (x, _) => Err(RegistryError::Unknown("input", x.to_string())),
+            }
+        }
+
+        /// parse the output protocol picked in `matches` and hand its connector to `visitor`
+        pub fn connector<V: ConnectorVisitor>(matches: &ArgMatches, visitor: V) -> Result<V::R, RegistryError> {
+            match matches.subcommand() {
+                $(
+                    ($out_name, Some(matches)) => {
+                        let connector = <$out_ty>::parse(matches)
+                            .map_err(|x| RegistryError::Parse($out_name, x.class(), describe(&x)))?;
+
+                        Ok(visitor.visit(connector))
+                    }
+                )*
+                ("", _) => Err(RegistryError::Missing("output", OUTPUTS)),
+                (x, _) => Err(RegistryError::Unknown("output", x.to_string())),
+            }
+        }
+    };
+}
+
+registry! {
+    inputs {
+        "tcp" => proto::tcp::TcpListener,
+        "tls" => proto::ssl::SslListener,
+        "ws" => proto::ws::WsListener<proto::tcp::TcpListener>,
+        "wss" => proto::ws::WsListener<proto::ssl::SslListener>,
+        "connect" => proto::connect::ConnectListener<proto::tcp::TcpListener>,
+        "socks" => proto::socks::SocksListener<proto::tcp::TcpListener>,
+    }
+    outputs {
+        "unix" => proto::unix::UnixConnector,
+        "exec" => proto::exec::ExecConnector,
+        "tcp" => proto::tcp::TcpConnector,
+        "route" => proto::route::RouteConnector,
+        "balance" => proto::balance::BalanceConnector,
+        "docker" => proto::docker::DockerConnector,
+    }
+}
+
+/// add a subcommand for every input protocol, each taking a subcommand for every output protocol
+pub fn parser<'a, 'b>(mut app: App<'a, 'b>) -> App<'a, 'b> {
+    for in_name in INPUTS {
+        let mut sc = input_parser(SubCommand::with_name(in_name), in_name);
+
+        for out_name in OUTPUTS {
+            sc = sc.subcommand(output_parser(SubCommand::with_name(out_name), out_name));
+        }
+
+        app = app.subcommand(sc);
+    }
+
+    app
+}
+
+fn any_route(conf: &RouteConf) -> Result<Route<AnyListener, AnyConnector>, RegistryError> {
+    let app = parser(App::new(conf.name.as_str()).setting(AppSettings::NoBinaryName));
+
+    let matches = app.get_matches_from_safe(conf.listen.iter().chain(conf.connect.iter()))
+        .map_err(|x| {
+            // the message is followed by the usage, which does not apply to a configuration file
+            let message = x.message.split("USAGE:").next().unwrap_or_default();
+            RegistryError::Args(message.trim_start_matches("error: ").split_whitespace().collect::<Vec<_>>().join(" "))
+        })?;
+
+    let (listener, matches) = match matches.subcommand() {
+        (name, Some(matches)) => {
+            check(name, matches.subcommand_name())?;
+
+            (any_listener(name, matches)?, matches)
+        }
+        _ => return Err(RegistryError::Missing("input", INPUTS)),
+    };
+
+    let connector = match matches.subcommand() {
+        (name, Some(matches)) => any_connector(name, matches)?,
+        _ => return Err(RegistryError::Missing("output", OUTPUTS)),
+    };
+
+    let route = Route::new(&conf.name, listener, connector);
+
+    Ok(match conf.max_connections {
+        Some(x) => route.with_limit(x),
+        None => route,
+    })
+}
+
+/// parse the protocols of a route of the configuration file, the same way as they are parsed from the command line
+pub fn route(conf: &RouteConf) -> Result<Route<AnyListener, AnyConnector>, RegistryError> {
+    any_route(conf).map_err(|x| RegistryError::Route(conf.name.clone(), Box::new(x)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn conf(listen: &[&str], connect: &[&str]) -> RouteConf {
+        RouteConf {
+            name: "r".to_string(),
+            listen: listen.iter().map(|x| x.to_string()).collect(),
+            connect: connect.iter().map(|x| x.to_string()).collect(),
+            max_connections: None,
+        }
+    }
+
+    #[test]
+    fn refuses_route_without_destination() {
+        let err = route(&conf(&["tcp", "127.0.0.1:0"], &["route", "--allow", "*:443"])).err().unwrap();
+
+        assert_eq!(err.to_string(), "route r: the route output cannot follow the tcp input, expected one of: connect, socks");
+        assert_eq!(err.class(), ErrorClass::Config);
+
+        assert!(route(&conf(&["ws", "127.0.0.1:0"], &["route"])).is_err());
+        assert!(route(&conf(&["connect", "127.0.0.1:0"], &["route", "--allow", "*:443"])).is_ok());
+        assert!(route(&conf(&["socks", "127.0.0.1:0"], &["route"])).is_ok());
+        assert!(route(&conf(&["tcp", "127.0.0.1:0"], &["unix", "/tmp/x.sock"])).is_ok());
+    }
+}
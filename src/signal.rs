@@ -0,0 +1,98 @@
+use std::fs::File;
+use std::io::{Error as IoError, ErrorKind, Read};
+use std::os::unix::io::{AsRawFd, FromRawFd};
+use std::sync::atomic::{AtomicI32, Ordering};
+use mio::{Poll, PollOpt, Ready, Token};
+use mio::unix::EventedFd;
+
+use crate::Pollable;
+
+/// write end of the pipe the signal handler reports to, -1 until installed
+static PIPE_WR: AtomicI32 = AtomicI32::new(-1);
+
+extern "C" fn on_signal(_: libc::c_int) {
+    let fd = PIPE_WR.load(Ordering::SeqCst);
+
+    if fd >= 0 {
+        let x = 1u8;
+
+        // the interrupted code may be about to read errno
+        unsafe {
+            let errno = *libc::__errno_location();
+            libc::write(fd, &x as *const u8 as *const libc::c_void, 1);
+            *libc::__errno_location() = errno;
+        }
+    }
+}
+
+/// Read end of a self-pipe receiving a byte for every SIGTERM/SIGINT, so that signals can be polled like sockets
+pub struct Signals {
+    rd: File,
+    /// write end of the pipe, signals are no longer reported once it is dropped
+    wr: File,
+}
+
+impl Signals {
+    /// install the handlers, signals are no longer delivered to the previous instance if called twice
+    pub fn install() -> Result<Signals, IoError> {
+        let mut fds = [0; 2];
+
+        // children started by the exec connector must not be able to write to it
+        if unsafe { libc::pipe2(fds.as_mut_ptr(), libc::O_CLOEXEC | libc::O_NONBLOCK) } < 0 {
+            return Err(IoError::last_os_error());
+        }
+
+        let (rd, wr) = unsafe { (File::from_raw_fd(fds[0]), File::from_raw_fd(fds[1])) };
+
+        PIPE_WR.store(wr.as_raw_fd(), Ordering::SeqCst);
+
+        for sig in &[libc::SIGTERM, libc::SIGINT] {
+            unsafe {
+                let mut action: libc::sigaction = std::mem::zeroed();
+                action.sa_sigaction = on_signal as extern "C" fn(libc::c_int) as libc::sighandler_t;
+                action.sa_flags = libc::SA_RESTART;
+
+                if libc::sigaction(*sig, &action, std::ptr::null_mut()) < 0 {
+                    return Err(IoError::last_os_error());
+                }
+            }
+        }
+
+        Ok(Signals { rd, wr })
+    }
+
+    /// consume the pending signals and return their count
+    pub fn take(&mut self) -> Result<usize, IoError> {
+        let mut buff = [0; 64];
+        let mut total = 0;
+
+        loop {
+            match self.rd.read(&mut buff) {
+                Ok(0) => return Ok(total),
+                Ok(x) => total += x,
+                Err(x) => match x.kind() {
+                    ErrorKind::WouldBlock => return Ok(total),
+                    ErrorKind::Interrupted => continue,
+                    _ => return Err(x),
+                }
+            }
+        }
+    }
+}
+
+impl Drop for Signals {
+    fn drop(&mut self) {
+        // the handler is left writing to the pipe of a newer instance, if any
+        let _ = PIPE_WR.compare_exchange(self.wr.as_raw_fd(), -1, Ordering::SeqCst, Ordering::SeqCst);
+    }
+}
+
+impl Pollable for Signals {
+    fn register(&self, poll: &Poll, tok: usize) -> Result<(), IoError> {
+        poll.register(&EventedFd(&self.rd.as_raw_fd()), Token(tok), Ready::readable(), PollOpt::edge())
+    }
+
+    fn deregister(&self, poll: &Poll) -> Result<(), IoError> {
+        poll.deregister(&EventedFd(&self.rd.as_raw_fd()))
+    }
+}
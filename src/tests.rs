@@ -0,0 +1,72 @@
+use std::io::{Read, Write};
+use std::net::{TcpListener as StdTcpListener, TcpStream as StdTcpStream};
+use std::os::unix::net::UnixListener;
+use std::sync::mpsc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crate::Fw;
+use crate::proto::common::StreamConf;
+use crate::proto::connect::ConnectListener;
+use crate::proto::tcp::TcpListener;
+use crate::proto::unix::UnixConnector;
+
+#[test]
+fn connect_listener_replies_before_unix_upstream() {
+    let path = std::env::temp_dir().join(format!("sfw-connect-{}.sock", std::process::id()));
+    let _ = std::fs::remove_file(&path);
+
+    let upstream = UnixListener::bind(&path).unwrap();
+
+    thread::spawn(move || {
+        let (mut stream, _) = upstream.accept().unwrap();
+        let mut buff = [0; 4];
+        stream.read_exact(&mut buff).unwrap();
+        stream.write_all(&buff).unwrap();
+    });
+
+    let addr = StdTcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap();
+    let conf = StreamConf { linger: None, keepalive: None };
+
+    let listener = ConnectListener::new(TcpListener::bind(&addr, &conf).unwrap());
+    let connector = UnixConnector::new(path.to_str().unwrap());
+    let mut fw = Fw::new(listener, connector, 16, 16, 1024).unwrap();
+
+    let (tx, rx) = mpsc::channel();
+
+    thread::spawn(move || {
+        let mut stream = StdTcpStream::connect(addr).unwrap();
+        stream.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+        stream.write_all(b"CONNECT example.com:443 HTTP/1.1\r\nHost: example.com:443\r\n\r\n").unwrap();
+
+        let mut reply = Vec::new();
+        let mut buff = [0; 1];
+
+        while !reply.ends_with(b"\r\n\r\n") && stream.read(&mut buff).unwrap_or(0) == 1 {
+            reply.push(buff[0]);
+        }
+
+        stream.write_all(b"ping").unwrap();
+
+        let mut echo = [0; 4];
+        let echoed = stream.read_exact(&mut echo).is_ok() && &echo == b"ping";
+
+        tx.send((String::from_utf8_lossy(&reply).into_owned(), echoed)).unwrap();
+    });
+
+    let deadline = Instant::now() + Duration::from_secs(10);
+
+    let res = loop {
+        assert!(Instant::now() < deadline, "timed out");
+        fw.run_once(Some(Duration::from_millis(10))).unwrap();
+
+        if let Ok(x) = rx.try_recv() {
+            break x;
+        }
+    };
+
+    let _ = std::fs::remove_file(&path);
+
+    assert!(res.0.starts_with("HTTP/1.1 200 Connection established\r\n"), "{:?}", res.0);
+    assert!(res.1);
+}
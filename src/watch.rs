@@ -0,0 +1,120 @@
+use std::ffi::{CString, OsStr, OsString};
+use std::fs::File;
+use std::io::{Error as IoError, ErrorKind, Read};
+use std::mem;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::io::{AsRawFd, FromRawFd};
+use std::path::Path;
+use mio::{Poll, PollOpt, Ready, Token};
+use mio::unix::EventedFd;
+
+use crate::Pollable;
+
+/// Socket file seen by a `SocketWatch` being created or removed, carrying the key it was added with
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Change {
+    Created(usize),
+    Removed(usize),
+}
+
+struct Watched {
+    wd: libc::c_int,
+    name: OsString,
+    key: usize,
+}
+
+/// Inotify instance watching the directories of socket files, so that their re-creation can be polled like sockets
+pub struct SocketWatch {
+    fd: File,
+    paths: Vec<Watched>,
+}
+
+impl SocketWatch {
+    pub fn new() -> Result<SocketWatch, IoError> {
+        let fd = unsafe { libc::inotify_init1(libc::IN_NONBLOCK | libc::IN_CLOEXEC) };
+
+        if fd < 0 {
+            return Err(IoError::last_os_error());
+        }
+
+        Ok(SocketWatch { fd: unsafe { File::from_raw_fd(fd) }, paths: Vec::new() })
+    }
+
+    /// report the creation and removal of the file at `path` as `key`, its directory must exist
+    pub fn add(&mut self, path: &str, key: usize) -> Result<(), IoError> {
+        let path = Path::new(path);
+
+        let name = path.file_name().ok_or_else(|| IoError::new(ErrorKind::InvalidInput, "not a file path"))?;
+
+        let dir = match path.parent() {
+            Some(x) if !x.as_os_str().is_empty() => x,
+            _ => Path::new("."),
+        };
+
+        let dir = CString::new(dir.as_os_str().as_bytes()).map_err(|x| IoError::new(ErrorKind::InvalidInput, x))?;
+
+        let mask = libc::IN_CREATE | libc::IN_DELETE | libc::IN_MOVED_FROM | libc::IN_MOVED_TO;
+
+        // watching a directory twice returns the descriptor of the first watch
+        let wd = unsafe { libc::inotify_add_watch(self.fd.as_raw_fd(), dir.as_ptr(), mask) };
+
+        if wd < 0 {
+            return Err(IoError::last_os_error());
+        }
+
+        self.paths.push(Watched { wd, name: name.to_os_string(), key });
+
+        Ok(())
+    }
+
+    /// consume the pending events and return the changes to the watched files
+    pub fn take(&mut self) -> Result<Vec<Change>, IoError> {
+        let mut ret = Vec::new();
+        let mut buff = [0u8; 4096];
+
+        loop {
+            let read = match self.fd.read(&mut buff) {
+                Ok(0) => return Ok(ret),
+                Ok(x) => x,
+                Err(x) => match x.kind() {
+                    ErrorKind::WouldBlock => return Ok(ret),
+                    ErrorKind::Interrupted => continue,
+                    _ => return Err(x),
+                }
+            };
+
+            let mut pos = 0;
+
+            while pos + mem::size_of::<libc::inotify_event>() <= read {
+                let event = unsafe { (buff.as_ptr().add(pos) as *const libc::inotify_event).read_unaligned() };
+
+                let start = pos + mem::size_of::<libc::inotify_event>();
+                let end = (start + event.len as usize).min(read);
+
+                // the name is padded with NULs
+                let name = &buff[start..end];
+                let name = OsStr::from_bytes(&name[..name.iter().position(|x| *x == 0).unwrap_or(name.len())]);
+
+                for x in self.paths.iter().filter(|x| x.wd == event.wd && x.name == name) {
+                    if event.mask & (libc::IN_CREATE | libc::IN_MOVED_TO) != 0 {
+                        ret.push(Change::Created(x.key));
+                    } else if event.mask & (libc::IN_DELETE | libc::IN_MOVED_FROM) != 0 {
+                        ret.push(Change::Removed(x.key));
+                    }
+                }
+
+                pos = end;
+            }
+        }
+    }
+}
+
+impl Pollable for SocketWatch {
+    fn register(&self, poll: &Poll, tok: usize) -> Result<(), IoError> {
+        poll.register(&EventedFd(&self.fd.as_raw_fd()), Token(tok), Ready::readable(), PollOpt::edge())
+    }
+
+    fn deregister(&self, poll: &Poll) -> Result<(), IoError> {
+        poll.deregister(&EventedFd(&self.fd.as_raw_fd()))
+    }
+}
@@ -0,0 +1,174 @@
+use std::fmt::{Debug, Display};
+use std::io::Error as IoError;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use mio::{Events, Poll, PollOpt, Ready, Token};
+use mio_extras::channel::{channel, Sender};
+use log::{error, warn};
+
+use crate::{Chan, Classify, Connector, Fw, FwConf, FwCtl, FwStats, Listener, MidChan, Pollable, Route, SharedListener};
+use crate::admin;
+use crate::signal::Signals;
+
+const EXITS: Token = Token(0);
+const SIGNAL: Token = Token(1);
+
+/// Reports the exit of a worker to the coordinator, also when the worker panics
+struct Exit {
+    idx: usize,
+    tx: Sender<Result<FwCtl, usize>>,
+}
+
+impl Drop for Exit {
+    fn drop(&mut self) {
+        let _ = self.tx.send(Err(self.idx));
+    }
+}
+
+/// Run `conf.workers()` event loops, each in its own thread and each polling its own copy of the listener socket.
+///
+/// Every worker gets its own copy of the connector, counters are shared. SIGTERM/SIGINT or the exit of any
+/// of the workers shuts all of them down gracefully, a second signal closes the remaining pairs right away.
+/// The admin socket of `conf` is served with commands passed on to every worker.
+/// Returns once all of the workers are done.
+pub fn run<
+    Le: Debug + Display + Classify, Lc: Chan<Err=Le> + Pollable, Lp: MidChan<C=Lc, Err=Le> + Pollable,
+    Se: Debug + Display + Classify, Sc: Chan<Err=Se> + Pollable, Sp: MidChan<C=Sc, Err=Se> + Pollable,
+    LL: Listener<C=Lc, PC=Lp, Err=Le> + SharedListener + Pollable + Send + 'static,
+    SS: Connector<C=Sc, PC=Sp, Err=Se> + Clone + Send + 'static,
+>(conf: &FwConf, listener: LL, connector: SS) -> Result<Arc<FwStats>, IoError> {
+    run_routes(conf, vec![Route::new("default", listener, connector)])
+}
+
+/// Same as `run`, with every worker serving all of `routes` from its event loop
+pub fn run_routes<
+    Le: Debug + Display + Classify, Lc: Chan<Err=Le> + Pollable, Lp: MidChan<C=Lc, Err=Le> + Pollable,
+    Se: Debug + Display + Classify, Sc: Chan<Err=Se> + Pollable, Sp: MidChan<C=Sc, Err=Se> + Pollable,
+    LL: Listener<C=Lc, PC=Lp, Err=Le> + SharedListener + Pollable + Send + 'static,
+    SS: Connector<C=Sc, PC=Sp, Err=Se> + Clone + Send + 'static,
+>(conf: &FwConf, routes: Vec<Route<LL, SS>>) -> Result<Arc<FwStats>, IoError> {
+    let stats = Arc::new(FwStats::default());
+    let mut signals = Signals::install()?;
+    let ctls = Arc::new(Mutex::new(Vec::with_capacity(conf.workers())));
+
+    if let Some(x) = conf.admin() {
+        admin::spawn(x.try_clone()?, ctls.clone())?;
+    }
+
+    if conf.workers() == 1 {
+        let mut fw = Fw::from_conf_routes(conf, routes)?.with_stats(stats.clone()).with_signals(signals);
+
+        if let Ok(mut x) = ctls.lock() {
+            x.push(fw.ctl());
+        }
+
+        fw.run()?;
+
+        return Ok(stats);
+    }
+
+    let poll = Poll::new()?;
+    let (tx, rx) = channel();
+
+    poll.register(&rx, EXITS, Ready::readable(), PollOpt::edge())?;
+    signals.register(&poll, SIGNAL.0)?;
+
+    let mut threads = Vec::with_capacity(conf.workers());
+
+    for idx in 0..conf.workers() {
+        let routes = routes.iter().map(|x| x.try_clone()).collect::<Result<Vec<_>, _>>()?;
+        let conf = conf.clone();
+        let stats = stats.clone();
+        let exit = Exit { idx, tx: tx.clone() };
+
+        let handle = thread::Builder::new()
+            .name(format!("worker-{}", idx))
+            .spawn(move || {
+                let mut fw = Fw::from_conf_routes(&conf, routes)?.with_stats(stats);
+
+                let _ = exit.tx.send(Ok(fw.ctl()));
+
+                fw.run()?;
+
+                Ok(())
+            })?;
+
+        threads.push(handle);
+    }
+
+    drop(routes);
+    drop(tx);
+
+    let mut events = Events::with_capacity(16);
+    let mut running = conf.workers();
+    let mut draining = false;
+    let mut stopping = false;
+
+    while running > 0 {
+        poll.poll(&mut events, None)?;
+
+        for event in &events {
+            match event.token() {
+                EXITS => {
+                    while let Ok(x) = rx.try_recv() {
+                        match x {
+                            Ok(ctl) => {
+                                if stopping {
+                                    ctl.stop();
+                                } else if draining {
+                                    ctl.shutdown();
+                                }
+
+                                if let Ok(mut x) = ctls.lock() {
+                                    x.push(ctl);
+                                }
+                            }
+                            Err(idx) => {
+                                running -= 1;
+
+                                if !draining {
+                                    warn!(worker = idx; "worker exited, shutting down");
+                                    draining = true;
+
+                                    for ctl in ctls.lock().iter().flat_map(|x| x.iter()) {
+                                        ctl.shutdown();
+                                    }
+                                }
+                            }
+                        }
+                    }
+                }
+                SIGNAL => {
+                    signals.take()?;
+
+                    if draining {
+                        stopping = true;
+                    }
+
+                    draining = true;
+
+                    for ctl in ctls.lock().iter().flat_map(|x| x.iter()) {
+                        if stopping {
+                            ctl.stop();
+                        } else {
+                            ctl.shutdown();
+                        }
+                    }
+                }
+                _ => {}
+            }
+        }
+    }
+
+    for handle in threads {
+        match handle.join() {
+            Ok(Err::<(), IoError>(x)) => return Err(x),
+            Ok(Ok(())) => {}
+            Err(_) => {
+                error!("worker panicked");
+            }
+        }
+    }
+
+    Ok(stats)
+}